@@ -0,0 +1,23 @@
+# Shared helpers for korasi preset scripts.
+# Instance: {{ instance_name }} ({{ instance_type }})
+set -euxo pipefail
+exec > >(tee -a /var/log/korasi-setup.log) 2>&1
+
+# User data runs as root, so look up the distro's default login user.
+KORASI_USER=$(getent passwd 1000 | cut -d: -f1)
+
+pkg_install() {
+    if command -v apt-get >/dev/null; then
+        export DEBIAN_FRONTEND=noninteractive
+        apt-get update -y
+        apt-get install -y "$@"
+    elif command -v dnf >/dev/null; then
+        dnf install -y "$@"
+    else
+        yum install -y "$@"
+    fi
+}
+
+as_user() {
+    sudo -u "$KORASI_USER" -i bash -c "$1"
+}
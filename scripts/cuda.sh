@@ -0,0 +1,14 @@
+#!/bin/bash
+{{ common }}
+
+if ! command -v apt-get >/dev/null; then
+    echo "The cuda preset only supports Ubuntu AMIs, consider an AWS Deep Learning AMI instead."
+    exit 1
+fi
+
+pkg_install build-essential ubuntu-drivers-common git
+ubuntu-drivers install --gpgpu
+pkg_install nvidia-cuda-toolkit
+
+# Drivers are only loaded after a reboot.
+reboot
@@ -0,0 +1,12 @@
+#!/bin/bash
+{{ common }}
+
+if command -v apt-get >/dev/null; then
+    pkg_install ca-certificates curl
+    curl -fsSL https://get.docker.com | sh
+else
+    pkg_install docker
+fi
+
+systemctl enable --now docker
+usermod -aG docker "$KORASI_USER"
@@ -0,0 +1,8 @@
+#!/bin/bash
+{{ common }}
+
+if command -v apt-get >/dev/null; then
+    pkg_install build-essential git curl
+else
+    pkg_install gcc gcc-c++ make git curl-minimal
+fi
@@ -0,0 +1,13 @@
+#!/bin/bash
+{{ common }}
+
+if command -v apt-get >/dev/null; then
+    pkg_install build-essential python3 python3-pip python3-venv git
+else
+    pkg_install gcc gcc-c++ make python3 python3-pip git
+fi
+
+as_user 'python3 -m venv $HOME/venv'
+as_user '$HOME/venv/bin/pip install --upgrade pip'
+as_user '$HOME/venv/bin/pip install numpy scipy pandas scikit-learn matplotlib jupyterlab'
+as_user 'echo "source \$HOME/venv/bin/activate" >> $HOME/.bashrc'
@@ -0,0 +1,11 @@
+#!/bin/bash
+{{ common }}
+
+if command -v apt-get >/dev/null; then
+    pkg_install build-essential pkg-config libssl-dev git curl
+else
+    pkg_install gcc gcc-c++ make pkgconf openssl-devel git curl-minimal
+fi
+
+as_user 'curl --proto "=https" --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y'
+as_user '$HOME/.cargo/bin/rustup component add clippy rustfmt'
@@ -0,0 +1,125 @@
+//! Gzipped tar archives of in-memory files, readable with `tar -xzf`.
+//!
+//! Only regular files are supported, which is all bundles need.
+
+use std::io::{Read, Write};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+const BLOCK: usize = 512;
+
+/// Longest path that fits in the ustar `name` field.
+const MAX_NAME: usize = 100;
+
+fn octal(field: &mut [u8], value: u64) {
+    let s = format!("{value:0width$o}", width = field.len() - 1);
+    field[..s.len()].copy_from_slice(s.as_bytes());
+}
+
+fn header(name: &str, size: u64, mtime: u64) -> std::io::Result<[u8; BLOCK]> {
+    if name.len() > MAX_NAME {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("file name too long for archive: {name}"),
+        ));
+    }
+
+    let mut h = [0u8; BLOCK];
+    h[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut h[100..108], 0o644);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], size);
+    octal(&mut h[136..148], mtime);
+    h[156] = b'0';
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces.
+    h[148..156].fill(b' ');
+    let sum: u64 = h.iter().map(|b| *b as u64).sum();
+    let s = format!("{sum:06o}\0 ");
+    h[148..156].copy_from_slice(s.as_bytes());
+    Ok(h)
+}
+
+fn parse_octal(field: &[u8]) -> u64 {
+    field
+        .iter()
+        .take_while(|b| **b != 0 && **b != b' ')
+        .fold(0, |acc, b| acc * 8 + (b.wrapping_sub(b'0') as u64 & 7))
+}
+
+/// Write `files` as `(path, contents)` pairs.
+pub fn write_tar_gz<W: Write>(out: W, files: &[(String, Vec<u8>)]) -> std::io::Result<()> {
+    let mtime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    let mut gz = GzEncoder::new(out, Compression::default());
+    for (name, data) in files {
+        gz.write_all(&header(name, data.len() as u64, mtime)?)?;
+        gz.write_all(data)?;
+        let padding = (BLOCK - data.len() % BLOCK) % BLOCK;
+        gz.write_all(&vec![0; padding])?;
+    }
+    // End of archive marker.
+    gz.write_all(&[0; 2 * BLOCK])?;
+    gz.finish()?;
+    Ok(())
+}
+
+/// Read the regular files of an archive, skipping any other entries.
+pub fn read_tar_gz<R: Read>(input: R) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+    let mut data = vec![];
+    GzDecoder::new(input).read_to_end(&mut data)?;
+
+    let mut files = vec![];
+    let mut pos = 0;
+    while pos + BLOCK <= data.len() {
+        let h = &data[pos..pos + BLOCK];
+        if h.iter().all(|b| *b == 0) {
+            break;
+        }
+        let name_len = h[..MAX_NAME]
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(MAX_NAME);
+        let name = String::from_utf8_lossy(&h[..name_len]).to_string();
+        let size = parse_octal(&h[124..136]) as usize;
+        let start = pos + BLOCK;
+        let end = start + size;
+        if end > data.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("truncated archive entry {name}"),
+            ));
+        }
+        if matches!(h[156], b'0' | 0) {
+            files.push((name, data[start..end].to_vec()));
+        }
+        pos = start + size.div_ceil(BLOCK) * BLOCK;
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_tar_gz, write_tar_gz};
+
+    #[test]
+    fn tar_round_trip() {
+        let files = vec![
+            (
+                "launch.toml".to_string(),
+                b"instance_type = \"t3.micro\"\n".to_vec(),
+            ),
+            ("empty".to_string(), vec![]),
+            ("big.bin".to_string(), vec![7; 1500]),
+        ];
+
+        let mut buf = vec![];
+        write_tar_gz(&mut buf, &files).unwrap();
+        pretty_assertions::assert_eq!(read_tar_gz(buf.as_slice()).unwrap(), files);
+    }
+}
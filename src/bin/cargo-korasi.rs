@@ -1,6 +1,6 @@
 use clap::Parser;
 
-use korasi_cli::{opt::Opt, run};
+use korasi_cli::{exit_code, opt::Opt, run, telemetry};
 
 // `cargo` invokes this binary as `cargo-korasi korasi <args>`
 // so the parser below is defined with that in mind.
@@ -14,9 +14,12 @@ enum Cli {
 async fn main() -> anyhow::Result<()> {
     let Cli::Korasi(opts) = Cli::parse();
 
-    if opts.debug {
-        tracing_subscriber::fmt().init();
-    }
+    let telemetry = telemetry::init(opts.debug, opts.timings);
 
-    run(opts).await
+    let res = run(opts).await;
+    telemetry.finish().await;
+    if let Some(code) = exit_code(&res) {
+        std::process::exit(code);
+    }
+    res
 }
@@ -0,0 +1,144 @@
+//! Stream the serial console of an instance, so bootstrap scripts can be
+//! followed while `create --wait` waits for status checks, or debugged
+//! with `korasi console`.
+
+use std::time::Duration;
+
+use crate::{
+    ec2::{EC2Error, EC2Impl as EC2},
+    stream::OutputGuard,
+};
+
+/// EC2 refreshes console output every few seconds at best.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Lines matched to find where the previous snapshot left off.
+const ANCHOR_LINES: usize = 3;
+
+/// Tracks which console lines were shown. Every poll returns the whole
+/// (last 64 KB of) output, so only what follows the lines shown last is
+/// new.
+#[derive(Debug, Default)]
+pub struct BootLog {
+    /// Last lines shown, oldest first.
+    anchor: Vec<String>,
+}
+
+impl BootLog {
+    /// Complete lines of `output` not returned before.
+    pub fn new_lines(&mut self, output: &[u8]) -> Vec<String> {
+        let text = OutputGuard::new(true).feed(output);
+        // The last line may still be written to.
+        let Some((complete, _)) = text.rsplit_once('\n') else {
+            return vec![];
+        };
+        let lines: Vec<&str> = complete.split('\n').collect();
+
+        // Match as much of the anchor as is still in the buffer. When none
+        // of it is, everything is new.
+        let start = (1..=self.anchor.len())
+            .rev()
+            .find_map(|n| {
+                let anchor = &self.anchor[self.anchor.len() - n..];
+                lines.windows(n).rposition(|w| w == anchor).map(|i| i + n)
+            })
+            .unwrap_or(0);
+        let new: Vec<String> = lines[start..].iter().map(|l| l.to_string()).collect();
+        if !new.is_empty() {
+            let keep = lines.len().saturating_sub(ANCHOR_LINES);
+            self.anchor = lines[keep..].iter().map(|l| l.to_string()).collect();
+        }
+        new
+    }
+}
+
+/// Wait up to `timeout` for `instance_id` to pass its status checks,
+/// printing its console output meanwhile.
+pub async fn wait_with_boot_log(
+    ec2: &EC2,
+    instance_id: &str,
+    timeout: Duration,
+) -> Result<(), EC2Error> {
+    let ready = ec2.wait_for_instance_ready(instance_id, Some(timeout));
+    tokio::pin!(ready);
+    let mut log = BootLog::default();
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            res = &mut ready => return res,
+            _ = poll.tick() => match ec2.get_console_output(instance_id).await {
+                Ok(Some(output)) => {
+                    // Clear the ETA line, it is redrawn below.
+                    let clear = if termion::is_tty(&std::io::stdout()) {
+                        format!("\r{}", termion::clear::CurrentLine)
+                    } else {
+                        String::new()
+                    };
+                    for line in log.new_lines(&output) {
+                        println!("{clear}  | {line}");
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => tracing::debug!("No console output yet: {err}"),
+            },
+        }
+    }
+}
+
+/// Print the console output of `instance_id`. With `follow`, keep
+/// printing new lines until interrupted.
+pub async fn print_console(ec2: &EC2, instance_id: &str, follow: bool) -> Result<(), EC2Error> {
+    if !follow {
+        match ec2.get_console_output(instance_id).await? {
+            Some(output) => print!("{}", OutputGuard::new(true).feed(&output)),
+            None => eprintln!("No console output yet, it may take a few minutes after boot."),
+        }
+        return Ok(());
+    }
+
+    let mut log = BootLog::default();
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        poll.tick().await;
+        if let Some(output) = ec2.get_console_output(instance_id).await? {
+            for line in log.new_lines(&output) {
+                println!("{line}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BootLog;
+
+    #[test]
+    fn follow_console_snapshots() {
+        let mut log = BootLog::default();
+        let cases: [(&[u8], &[&str]); 5] = [
+            (b"", &[]),
+            (
+                b"[  0.1] Linux\r\n[  0.2] eth0 up\r\ncloud-",
+                &["[  0.1] Linux", "[  0.2] eth0 up"],
+            ),
+            (
+                b"[  0.1] Linux\r\n[  0.2] eth0 up\r\ncloud-init: \x1b[32mok\x1b[0m\r\n",
+                &["cloud-init: ok"],
+            ),
+            (
+                b"[  0.1] Linux\r\n[  0.2] eth0 up\r\ncloud-init: ok\r\n",
+                &[],
+            ),
+            // The start scrolled out of the 64 KB buffer.
+            (
+                b"eth0 up\r\ncloud-init: ok\r\nsetup done\r\n",
+                &["setup done"],
+            ),
+        ];
+
+        for (output, expected) in cases {
+            println!("output = {:?}", String::from_utf8_lossy(output));
+            pretty_assertions::assert_eq!(log.new_lines(output), expected);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Remote build workflow behind `cargo korasi build`.
+//!
+//! Syncs the crate in the working directory to the instance, runs
+//! `cargo build` there, then downloads the artifacts into
+//! `target/korasi/` so they don't clobber locally built ones.
+
+use std::path::{Path, PathBuf};
+
+use termion::raw::IntoRawMode;
+
+use crate::{ssh::Session, state::Stats};
+
+/// Directory (relative to the crate root) where cargo places the
+/// artifacts for the given `cargo build` arguments.
+pub fn artifact_dir(args: &[String]) -> PathBuf {
+    fn profile_dir(profile: &str) -> String {
+        match profile {
+            "dev" | "test" => "debug".into(),
+            "bench" => "release".into(),
+            other => other.into(),
+        }
+    }
+
+    let mut profile = "debug".to_string();
+    let mut target = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--release" | "-r" => profile = "release".into(),
+            "--profile" => profile = profile_dir(iter.next().map_or("dev", |p| p.as_str())),
+            "--target" => target = iter.next().cloned(),
+            a => {
+                if let Some(p) = a.strip_prefix("--profile=") {
+                    profile = profile_dir(p);
+                } else if let Some(t) = a.strip_prefix("--target=") {
+                    target = Some(t.to_string());
+                }
+            }
+        }
+    }
+
+    let mut dir = PathBuf::from("target");
+    if let Some(t) = target {
+        dir.push(t);
+    }
+    dir.push(profile);
+    dir
+}
+
+/// Binaries and libraries live at the top of the profile directory,
+/// next to dep-info files and cargo's lock file.
+pub fn is_artifact(name: &str) -> bool {
+    !name.starts_with('.') && !name.ends_with(".d")
+}
+
+/// Upload the crate, except for files matching `exclude`, build it
+/// remotely and download the artifacts.
+///
+/// Returns the exit code of the remote `cargo build`.
+pub async fn remote_build(
+    session: &Session,
+    args: &[String],
+    exclude: &[String],
+) -> anyhow::Result<u32> {
+    let cwd = std::env::current_dir()?;
+    if !cwd.join("Cargo.toml").exists() {
+        anyhow::bail!("No Cargo.toml found, run this from the crate root.");
+    }
+    let crate_dir = cwd
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow::anyhow!("Invalid crate directory {:?}", cwd))?
+        .to_string();
+
+    println!("Syncing {crate_dir} to remote...");
+    let uploaded = session.upload(None, None, exclude).await?;
+    Stats::record(|s| s.transfer_bytes += uploaded);
+
+    let build_cmd = std::iter::once("cargo build".to_string())
+        .chain(args.iter().map(|a| shell_escape::escape(a.into()).into()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let script = format!(
+        "cd {} && {build_cmd}",
+        shell_escape::escape(crate_dir.as_str().into())
+    );
+    // A login shell picks up `~/.cargo/env` added by rustup.
+    let command = format!("bash -lc {}", shell_escape::escape(script.into()));
+    let code = {
+        let _raw_term = std::io::stdout().into_raw_mode()?;
+        session.exec(&command).await?
+    };
+    if code != 0 {
+        return Ok(code);
+    }
+
+    let rel_dir = artifact_dir(args);
+    let remote_dir = Path::new(&crate_dir).join(&rel_dir);
+    let local_dir = cwd
+        .join("target/korasi")
+        .join(rel_dir.strip_prefix("target").unwrap_or(&rel_dir));
+    let downloaded = session
+        .download_files(remote_dir.to_str().unwrap(), &local_dir, is_artifact)
+        .await?;
+    println!(
+        "Downloaded {} artifact(s) to {}",
+        downloaded.len(),
+        local_dir.display()
+    );
+
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::artifact_dir;
+
+    #[test]
+    fn resolve_artifact_dir() {
+        let cases = [
+            (vec![], "target/debug"),
+            (vec!["--release"], "target/release"),
+            (vec!["--profile", "bench"], "target/release"),
+            (vec!["--profile=prof"], "target/prof"),
+            (
+                vec!["--target", "aarch64-unknown-linux-gnu", "-r"],
+                "target/aarch64-unknown-linux-gnu/release",
+            ),
+            (
+                vec!["--features", "x", "--target=x86_64"],
+                "target/x86_64/debug",
+            ),
+        ];
+
+        for (args, expected) in cases {
+            let args: Vec<String> = args.into_iter().map(String::from).collect();
+            println!("args = {args:?}");
+            pretty_assertions::assert_eq!(artifact_dir(&args), PathBuf::from(expected));
+        }
+    }
+}
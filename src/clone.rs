@@ -0,0 +1,195 @@
+//! Launch a copy of an instance: same AMI, type, security groups, IAM
+//! profile, user data and volume layout.
+//!
+//! Volumes are recreated rather than copied, so the copy gets the AMI's
+//! root file system and empty data volumes of the same size and type.
+
+use aws_sdk_ec2::types::{
+    BlockDeviceMapping, IamInstanceProfileSpecification, InstanceType, KeyPairInfo, SecurityGroup,
+    Volume, VolumeType,
+};
+use base64::prelude::*;
+
+use crate::{
+    create::{RootVolume, AUTO_STOP_TAG, STAGED_USER_DATA_TAG},
+    ec2::{EC2Error, EC2Impl as EC2, LaunchOptions, TagSelector},
+};
+
+/// Settings to change on the copy.
+#[derive(Debug, Default, Clone)]
+pub struct CloneOverrides {
+    pub name: Option<String>,
+    pub instance_type: Option<InstanceType>,
+    /// Don't run the source's user data on the copy.
+    pub no_user_data: bool,
+}
+
+/// Mapping recreating `volume` at `device_name`. Provisioned IOPS and
+/// throughput are only kept for the volume types that accept them.
+pub fn volume_mapping(device_name: &str, volume: &Volume) -> BlockDeviceMapping {
+    let volume_type = volume.volume_type().cloned();
+    let provisioned_iops = matches!(
+        volume_type,
+        Some(VolumeType::Gp3 | VolumeType::Io1 | VolumeType::Io2)
+    );
+    RootVolume {
+        size: volume.size(),
+        iops: volume.iops().filter(|_| provisioned_iops),
+        throughput: volume
+            .throughput()
+            .filter(|_| volume_type == Some(VolumeType::Gp3)),
+        volume_type,
+    }
+    .to_mapping(device_name)
+}
+
+/// Launch a copy of `source_id` with `key_pair`, returning the new
+/// instance ids.
+pub async fn clone_instance(
+    ec2: &EC2,
+    source_id: &str,
+    key_pair: &KeyPairInfo,
+    overrides: CloneOverrides,
+) -> Result<Vec<String>, EC2Error> {
+    let source = ec2.get_instance(source_id).await?;
+    let image_id = source
+        .image_id()
+        .ok_or_else(|| EC2Error::new(format!("{source_id} has no AMI")))?;
+    let instance_type = overrides
+        .instance_type
+        .or(source.instance_type().cloned())
+        .ok_or_else(|| EC2Error::new(format!("{source_id} has no instance type")))?;
+    let name = overrides.name.unwrap_or_else(|| {
+        let source_name = source
+            .tags()
+            .iter()
+            .find(|t| t.key() == Some("Name"))
+            .and_then(|t| t.value())
+            .unwrap_or(source_id);
+        format!("{source_name}-clone")
+    });
+
+    let mut mappings = vec![];
+    for mapping in source.block_device_mappings() {
+        let (Some(device_name), Some(volume_id)) = (
+            mapping.device_name(),
+            mapping.ebs().and_then(|e| e.volume_id()),
+        ) else {
+            continue;
+        };
+        let volume = ec2.describe_volume(volume_id).await?;
+        mappings.push(volume_mapping(device_name, &volume));
+    }
+
+    let user_data = if overrides.no_user_data {
+        None
+    } else {
+        ec2.get_user_data(source_id)
+            .await?
+            .map(|script| BASE64_STANDARD.encode(script))
+    };
+    let groups: Vec<SecurityGroup> = source
+        .security_groups()
+        .iter()
+        .filter_map(|g| g.group_id())
+        .map(|id| SecurityGroup::builder().group_id(id).build())
+        .collect();
+    let opts = LaunchOptions {
+        user_data,
+        block_device_mappings: (!mappings.is_empty()).then_some(mappings),
+        iam_instance_profile: source
+            .iam_instance_profile()
+            .and_then(|p| p.arn())
+            .map(|arn| IamInstanceProfileSpecification::builder().arn(arn).build()),
+        placement_group: source
+            .placement()
+            .and_then(|p| p.group_name())
+            .filter(|g| !g.is_empty())
+            .map(str::to_string),
+        efa: source
+            .network_interfaces()
+            .iter()
+            .any(|n| n.interface_type() == Some("efa")),
+        availability_zone: None,
+        // The user data carries `--auto-stop` and the staged script along,
+        // so do the tags.
+        tags: source
+            .tags()
+            .iter()
+            .filter(|_| !overrides.no_user_data)
+            .filter_map(|t| {
+                let key = t
+                    .key()
+                    .filter(|k| [AUTO_STOP_TAG, STAGED_USER_DATA_TAG].contains(k))?;
+                Some(TagSelector {
+                    key: key.into(),
+                    value: t.value().unwrap_or_default().into(),
+                })
+            })
+            .collect(),
+    };
+
+    println!("Cloning {source_id} as {name} ({instance_type}, {image_id})...");
+    ec2.create_instances(
+        &name,
+        image_id,
+        instance_type,
+        key_pair,
+        groups.iter().collect(),
+        opts,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_ec2::types::{BlockDeviceMapping, EbsBlockDevice, Volume, VolumeType};
+
+    use super::volume_mapping;
+
+    #[test]
+    fn recreate_volumes() {
+        let volume = |volume_type, iops, throughput| {
+            Volume::builder()
+                .size(200)
+                .volume_type(volume_type)
+                .iops(iops)
+                .throughput(throughput)
+                .build()
+        };
+        let mapping = |volume_type, iops: Option<i32>, throughput: Option<i32>| {
+            BlockDeviceMapping::builder()
+                .device_name("/dev/sda1")
+                .ebs(
+                    EbsBlockDevice::builder()
+                        .delete_on_termination(true)
+                        .volume_size(200)
+                        .volume_type(volume_type)
+                        .set_iops(iops)
+                        .set_throughput(throughput)
+                        .build(),
+                )
+                .build()
+        };
+        let cases = [
+            (
+                volume(VolumeType::Gp3, 3000, 250),
+                mapping(VolumeType::Gp3, Some(3000), Some(250)),
+            ),
+            // gp2 reports its baseline IOPS, which can't be set.
+            (
+                volume(VolumeType::Gp2, 600, 0),
+                mapping(VolumeType::Gp2, None, None),
+            ),
+            (
+                volume(VolumeType::Io2, 8000, 0),
+                mapping(VolumeType::Io2, Some(8000), None),
+            ),
+        ];
+
+        for (volume, expected) in cases {
+            println!("volume = {volume:?}");
+            pretty_assertions::assert_eq!(volume_mapping("/dev/sda1", &volume), expected);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Minimal CloudWatch client, for the CPU statistics used to spot idle
+//! instances and chart them in `report`. Calls the query API directly, signed like [`crate::s3`].
+
+use aws_sdk_ec2::primitives::{DateTime, DateTimeFormat};
+use aws_sigv4::http_request::{SignableBody, SigningSettings};
+use aws_types::SdkConfig as AwsSdkConfig;
+
+use crate::{ec2::EC2Error, sigv4};
+
+#[derive(Clone)]
+pub struct CloudWatchImpl {
+    config: AwsSdkConfig,
+}
+
+impl CloudWatchImpl {
+    pub fn new(config: &AwsSdkConfig) -> Self {
+        CloudWatchImpl {
+            config: config.clone(),
+        }
+    }
+
+    /// Highest CPU utilization (percent) of an instance over the last
+    /// `hours`, or `None` when CloudWatch has no data for the period.
+    pub async fn max_cpu(&self, instance_id: &str, hours: u64) -> Result<Option<f64>, EC2Error> {
+        let body = self
+            .cpu_statistics(instance_id, hours, hours * 3600, "Maximum")
+            .await?;
+        Ok(parse_statistic(&body, "Maximum")
+            .into_iter()
+            .reduce(f64::max))
+    }
+
+    /// Hourly average CPU utilization (percent) of an instance over the
+    /// last `hours`, oldest first.
+    pub async fn cpu_series(&self, instance_id: &str, hours: u64) -> Result<Vec<f64>, EC2Error> {
+        let body = self
+            .cpu_statistics(instance_id, hours, 3600, "Average")
+            .await?;
+        Ok(parse_series(&body, "Average")
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect())
+    }
+
+    /// `GetMetricStatistics` response for the CPU utilization of an
+    /// instance over the last `hours`, in `period_secs` datapoints.
+    async fn cpu_statistics(
+        &self,
+        instance_id: &str,
+        hours: u64,
+        period_secs: u64,
+        stat: &str,
+    ) -> Result<String, EC2Error> {
+        let now = DateTime::from(std::time::SystemTime::now());
+        let start = DateTime::from_secs(now.secs() - (hours * 3600) as i64);
+        let format = |t: &DateTime| t.fmt(DateTimeFormat::DateTime).unwrap_or_default();
+        let period = period_secs.to_string();
+
+        let mut url = reqwest::Url::parse(&format!(
+            "https://monitoring.{}.amazonaws.com/",
+            sigv4::region(&self.config)
+        ))
+        .map_err(|e| EC2Error::new(format!("Invalid CloudWatch url: {e}")))?;
+        url.query_pairs_mut().extend_pairs([
+            ("Action", "GetMetricStatistics"),
+            ("Version", "2010-08-01"),
+            ("Namespace", "AWS/EC2"),
+            ("MetricName", "CPUUtilization"),
+            ("Dimensions.member.1.Name", "InstanceId"),
+            ("Dimensions.member.1.Value", instance_id),
+            ("StartTime", &format(&start)),
+            ("EndTime", &format(&now)),
+            ("Period", &period),
+            ("Statistics.member.1", stat),
+        ]);
+
+        let (headers, _) = sigv4::sign(
+            &self.config,
+            "monitoring",
+            "GET",
+            url.as_str(),
+            SignableBody::Bytes(&[]),
+            SigningSettings::default(),
+        )
+        .await?;
+        let mut req = reqwest::Client::new().get(url.as_str());
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+        req.send()
+            .await
+            .map_err(|e| EC2Error::new(format!("Could not query CloudWatch: {e:?}")))?
+            .error_for_status()
+            .map_err(|e| EC2Error::new(format!("Failure status from CloudWatch: {e:?}")))?
+            .text()
+            .await
+            .map_err(|e| EC2Error::new(format!("Could not read CloudWatch response: {e:?}")))
+    }
+}
+
+/// Values of `<stat>` elements in a `GetMetricStatistics` response.
+fn parse_statistic(xml: &str, stat: &str) -> Vec<f64> {
+    let (open, close) = (format!("<{stat}>"), format!("</{stat}>"));
+    xml.split(&open)
+        .skip(1)
+        .filter_map(|rest| rest.split_once(&close))
+        .filter_map(|(value, _)| value.trim().parse().ok())
+        .collect()
+}
+
+/// `(timestamp, <stat>)` of each datapoint in a `GetMetricStatistics`
+/// response, oldest first. CloudWatch doesn't sort them.
+fn parse_series(xml: &str, stat: &str) -> Vec<(String, f64)> {
+    let mut points: Vec<(String, f64)> = xml
+        .split("<member>")
+        .skip(1)
+        .filter_map(|member| {
+            let timestamp = member
+                .split_once("<Timestamp>")?
+                .1
+                .split_once("</Timestamp>")?
+                .0;
+            let value = parse_statistic(member, stat).into_iter().next()?;
+            Some((timestamp.trim().to_string(), value))
+        })
+        .collect();
+    // ISO 8601 timestamps sort as strings.
+    points.sort_by(|a, b| a.0.cmp(&b.0));
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_series, parse_statistic};
+
+    #[test]
+    fn parse_datapoints() {
+        let xml = r#"<GetMetricStatisticsResponse xmlns="http://monitoring.amazonaws.com/doc/2010-08-01/">
+  <GetMetricStatisticsResult>
+    <Datapoints>
+      <member><Timestamp>2024-11-01T10:00:00Z</Timestamp><Maximum>3.5</Maximum><Unit>Percent</Unit></member>
+      <member><Timestamp>2024-11-01T11:00:00Z</Timestamp><Maximum>12.25</Maximum><Unit>Percent</Unit></member>
+    </Datapoints>
+    <Label>CPUUtilization</Label>
+  </GetMetricStatisticsResult>
+</GetMetricStatisticsResponse>"#;
+
+        pretty_assertions::assert_eq!(parse_statistic(xml, "Maximum"), vec![3.5, 12.25]);
+        pretty_assertions::assert_eq!(parse_statistic(xml, "Average"), Vec::<f64>::new());
+        pretty_assertions::assert_eq!(
+            parse_series(xml, "Maximum"),
+            vec![
+                ("2024-11-01T10:00:00Z".to_string(), 3.5),
+                ("2024-11-01T11:00:00Z".to_string(), 12.25)
+            ]
+        );
+    }
+}
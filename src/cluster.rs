@@ -0,0 +1,103 @@
+//! `korasi cluster create`: launch nodes into one cluster placement group
+//! and hand them an MPI hostfile listing every node's private IP, eg. for
+//! `mpirun --hostfile ~/hostfile`.
+
+use crate::ec2::{EC2Error, EC2Impl as EC2};
+
+/// Tag naming the cluster an instance belongs to.
+pub const CLUSTER_TAG: &str = "korasi:cluster";
+
+/// Placement groups of clusters are named with this prefix.
+const PLACEMENT_GROUP_PREFIX: &str = "korasi-";
+
+/// A launched cluster node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub name: String,
+    pub instance_id: String,
+    pub private_ip: String,
+    pub public_dns_name: String,
+}
+
+/// Name of the `i`th node (from 1) of `cluster`.
+pub fn node_name(cluster: &str, i: u32) -> String {
+    format!("{cluster}-{i}")
+}
+
+pub fn placement_group_name(cluster: &str) -> String {
+    format!("{PLACEMENT_GROUP_PREFIX}{cluster}")
+}
+
+/// Open MPI style hostfile, one node per line. Without `slots`, MPI
+/// defaults to the number of cores of each node.
+pub fn hostfile(nodes: &[Node], slots: Option<u32>) -> String {
+    nodes
+        .iter()
+        .map(|node| match slots {
+            Some(slots) => format!("{} slots={slots}\n", node.private_ip),
+            None => format!("{}\n", node.private_ip),
+        })
+        .collect()
+}
+
+/// Shell command writing `contents` to `path` on a node.
+pub fn write_file_command(path: &str, contents: &str) -> String {
+    format!("cat > {path} <<'KORASI_EOF'\n{contents}KORASI_EOF\n")
+}
+
+/// The nodes of `instance_ids`, once they are running and have addresses.
+pub async fn cluster_nodes(ec2: &EC2, instance_ids: &[String]) -> Result<Vec<Node>, EC2Error> {
+    let mut nodes = vec![];
+    for id in instance_ids {
+        let instance = ec2.get_instance(id).await?;
+        let name = instance
+            .tags()
+            .iter()
+            .find(|t| t.key() == Some("Name"))
+            .and_then(|t| t.value())
+            .unwrap_or(id);
+        let private_ip = instance
+            .private_ip_address()
+            .ok_or_else(|| EC2Error::new(format!("{name} has no private IP")))?;
+        nodes.push(Node {
+            name: name.into(),
+            instance_id: id.clone(),
+            private_ip: private_ip.into(),
+            public_dns_name: instance.public_dns_name().unwrap_or_default().into(),
+        });
+    }
+    Ok(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hostfile, node_name, write_file_command, Node};
+
+    #[test]
+    fn mpi_hostfile() {
+        let nodes: Vec<Node> = ["10.0.0.4", "10.0.0.9"]
+            .iter()
+            .enumerate()
+            .map(|(i, ip)| Node {
+                name: node_name("hpc", i as u32 + 1),
+                instance_id: format!("i-{i}"),
+                private_ip: ip.to_string(),
+                public_dns_name: String::new(),
+            })
+            .collect();
+        let cases = [
+            (None, "10.0.0.4\n10.0.0.9\n"),
+            (Some(8), "10.0.0.4 slots=8\n10.0.0.9 slots=8\n"),
+        ];
+        for (slots, expected) in cases {
+            println!("slots = {slots:?}");
+            pretty_assertions::assert_eq!(hostfile(&nodes, slots), expected);
+        }
+
+        pretty_assertions::assert_eq!(nodes[1].name, "hpc-2");
+        pretty_assertions::assert_eq!(
+            write_file_command("~/hostfile", "10.0.0.4\n"),
+            "cat > ~/hostfile <<'KORASI_EOF'\n10.0.0.4\nKORASI_EOF\n"
+        );
+    }
+}
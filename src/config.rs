@@ -0,0 +1,852 @@
+//! Project configuration read from `korasi.toml` (or `.korasi.toml`) in the
+//! working directory.
+//!
+//! ```toml
+//! [launch]
+//! # Defaults for `create`, so it runs without flags or prompts.
+//! ami = "al2023"
+//! instance_type = "t3.large"
+//! user = "ec2-user"
+//! setup = "preset:rust-dev"
+//! # Zones to try in order when one is out of capacity, defaults to all.
+//! availability_zones = ["ap-southeast-1b", "ap-southeast-1a"]
+//! # Key pair of this project, instead of the shared `ec2-ssh-key`.
+//! key_name = "my-project"
+//!
+//! [upload]
+//! # Gitignore style globs never uploaded by `upload` and `sync`.
+//! exclude = ["target", "*.ckpt"]
+//!
+//! [verify]
+//! # Commands that must exit 0 once the instance is provisioned.
+//! commands = ["cargo --version"]
+//! # TCP ports that must be listening on the instance.
+//! ports = [8888]
+//!
+//! [idle]
+//! # Remind about instances whose CPU stayed below `cpu_percent` for the
+//! # last `hours` when opening a shell.
+//! hours = 2
+//! cpu_percent = 5
+//!
+//! [retry]
+//! # Attempts per AWS request when throttled (eg. `RequestLimitExceeded`)
+//! # or on transient 5xx errors, backing off exponentially in between.
+//! max_attempts = 5
+//! initial_backoff_ms = 500
+//! max_backoff_secs = 20
+//!
+//! [ssh]
+//! # Port sshd listens on, and the security group lets in.
+//! port = 2222
+//! # OpenSSH user certificate signed by the org CA, defaults to
+//! # `<key>-cert.pub` when that exists.
+//! certificate = "~/.ssh/id_ed25519-cert.pub"
+//! # CA whose certificates instances accept, set up by `create`.
+//! user_ca_key = "~/.ssh/org_user_ca.pub"
+//!
+//! # With this table set, `obliterate` has the project tag typed to confirm.
+//! [safety]
+//! # Refuse `obliterate` altogether, eg. in production-adjacent accounts.
+//! protect_obliterate = true
+//! # In shared accounts, only obliterate resources older than this.
+//! obliterate_min_age = "7d"
+//!
+//! [plugins]
+//! # External subcommands, on top of `korasi-<name>` executables on PATH.
+//! corp-login = "/opt/corp/bin/korasi-corp-login"
+//!
+//! [tags]
+//! # Tags `create` applies to every instance at launch. Empty values are
+//! # asked for (or given with `--label`), launching without them is refused.
+//! cost-center = "ml-research"
+//! owner = ""
+//! ```
+//!
+//! Unknown tables and keys are rejected, with a suggestion when they look
+//! like a typo of a known one. Renamed keys are still read, with a warning.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use aws_sdk_ec2::types::InstanceType;
+
+use crate::{
+    toml::{self, ParseError, Table, Value},
+    units::parse_duration,
+};
+
+pub const CONFIG_FILE: &str = "korasi.toml";
+
+/// Hidden alternative to `CONFIG_FILE`, used when that doesn't exist.
+pub const HIDDEN_CONFIG_FILE: &str = ".korasi.toml";
+
+/// Known keys of each table. Tables without any take arbitrary keys.
+const SCHEMA: &[(&str, &[&str])] = &[
+    (
+        "launch",
+        &[
+            "ami",
+            "instance_type",
+            "user",
+            "setup",
+            "availability_zones",
+            "key_name",
+        ],
+    ),
+    ("upload", &["exclude"]),
+    ("verify", &["commands", "ports", "timeout"]),
+    ("idle", &["hours", "cpu_percent"]),
+    (
+        "retry",
+        &["max_attempts", "initial_backoff_ms", "max_backoff_secs"],
+    ),
+    ("ssh", &["port", "certificate", "user_ca_key"]),
+    ("safety", &["protect_obliterate", "obliterate_min_age"]),
+    ("plugins", &[]),
+    ("tags", &[]),
+];
+
+/// `(table, old key, new key)` of renamed keys.
+const DEPRECATED: &[(&str, &str, &str)] = &[
+    ("launch", "ami_id", "ami"),
+    ("verify", "timeout_secs", "timeout"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.path.display(), self.line, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Config {
+    /// Launch defaults of `Create`, overridden by flags.
+    pub launch: LaunchConfig,
+
+    /// Files left out of `Upload` and `Sync`.
+    pub upload: UploadConfig,
+
+    /// Post-create checks run by `Create`.
+    pub verify: VerifyConfig,
+
+    /// Idle instance reminders shown by `Shell`.
+    pub idle: IdleConfig,
+
+    /// Backoff on throttled or failed AWS requests.
+    pub retry: RetryConfig,
+
+    /// Certificate based authentication.
+    pub ssh: SshConfig,
+
+    /// Guards against tearing down resources of others.
+    pub safety: SafetyConfig,
+
+    /// Paths of external subcommands, by name.
+    pub plugins: BTreeMap<String, String>,
+
+    /// Tags required on launched instances, by key. Empty values have to
+    /// be provided at launch.
+    pub tags: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LaunchConfig {
+    /// AMI id or alias (see `korasi.lock`).
+    pub ami: Option<String>,
+
+    /// Instance type, skipping the machine picker.
+    pub instance_type: Option<String>,
+
+    /// User to connect as.
+    pub user: Option<String>,
+
+    /// Setup script path or `preset:<name>`.
+    pub setup: Option<String>,
+
+    /// Availability zones to launch in, in order of preference.
+    pub availability_zones: Vec<String>,
+
+    /// EC2 key pair name.
+    pub key_name: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SshConfig {
+    /// Port sshd listens on, instead of 22.
+    pub port: Option<u16>,
+
+    /// Path of the user certificate to present.
+    pub certificate: Option<String>,
+
+    /// Path of the CA public key instances trust user certificates of.
+    pub user_ca_key: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SafetyConfig {
+    /// Whether `[safety]` is set at all, which has `Obliterate` ask for
+    /// the project tag to be typed.
+    pub configured: bool,
+
+    /// Refuse to run `Obliterate`.
+    pub protect_obliterate: bool,
+
+    /// Seconds a resource must have existed for `Obliterate` to touch
+    /// it.
+    pub obliterate_min_age: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UploadConfig {
+    /// Gitignore style globs, on top of the project's .gitignore.
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VerifyConfig {
+    /// Commands that must exit 0.
+    pub commands: Vec<String>,
+
+    /// TCP ports that must be listening.
+    pub ports: Vec<u16>,
+
+    /// Seconds to wait for the instance to pass status checks before
+    /// verifying. Defaults to 10 minutes. Set as seconds or with units,
+    /// eg. `timeout = "15m"`.
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdleConfig {
+    /// How long an instance must have been idle.
+    pub hours: u64,
+
+    /// CPU utilization (percent) below which an instance counts as idle.
+    pub cpu_percent: u64,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        IdleConfig {
+            hours: 2,
+            cpu_percent: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryConfig {
+    /// Attempts per request, including the first. 1 disables retries.
+    pub max_attempts: u32,
+
+    /// Delay before the first retry, doubled (with jitter) on each retry.
+    pub initial_backoff_ms: u64,
+
+    /// Upper bound for the delay between retries.
+    pub max_backoff_secs: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 5,
+            initial_backoff_ms: 500,
+            max_backoff_secs: 20,
+        }
+    }
+}
+
+impl VerifyConfig {
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty() && self.ports.is_empty()
+    }
+}
+
+/// `path` with a leading `~/` replaced by the home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+impl Config {
+    /// Load `korasi.toml`, or else `.korasi.toml`, from the current
+    /// directory, or the default config when there is neither.
+    pub fn load() -> Result<Config, ConfigError> {
+        Self::load_from(Self::path())
+    }
+
+    /// The config file in use, which may not exist.
+    pub fn path() -> &'static Path {
+        [CONFIG_FILE, HIDDEN_CONFIG_FILE]
+            .map(Path::new)
+            .into_iter()
+            .find(|p| p.exists())
+            .unwrap_or(Path::new(CONFIG_FILE))
+    }
+
+    pub fn load_from(path: &Path) -> Result<Config, ConfigError> {
+        let Ok(src) = std::fs::read_to_string(path) else {
+            return Ok(Config::default());
+        };
+        tracing::info!("Loading config from {}", path.display());
+
+        let (config, warnings) = Self::parse_with_warnings(&src).map_err(|e| ConfigError {
+            path: path.to_path_buf(),
+            line: e.line,
+            message: e.message,
+        })?;
+        for w in &warnings {
+            eprintln!("warning: {}:{}: {}", path.display(), w.line, w.message);
+        }
+        if !warnings.is_empty() {
+            eprintln!("Run `korasi migrate-config` to update {}.", path.display());
+        }
+        Ok(config)
+    }
+
+    pub fn parse(src: &str) -> Result<Config, ParseError> {
+        Self::parse_with_warnings(src).map(|(config, _)| config)
+    }
+
+    /// Like `parse`, also returning warnings about deprecated keys.
+    pub fn parse_with_warnings(src: &str) -> Result<(Config, Vec<ParseError>), ParseError> {
+        let mut root = toml::parse(src)?;
+        let warnings = check_schema(&mut root)?;
+
+        let mut config = Config::default();
+        if let Some(launch) = get_table(&root, "launch")? {
+            config.launch = LaunchConfig {
+                ami: get_str(launch, "ami")?,
+                instance_type: get_str(launch, "instance_type")?,
+                user: get_str(launch, "user")?,
+                setup: get_str(launch, "setup")?,
+                availability_zones: get_str_array(launch, "availability_zones")?
+                    .unwrap_or_default(),
+                key_name: get_str(launch, "key_name")?,
+            };
+            if let Some(t) = &config.launch.instance_type {
+                if !InstanceType::values().contains(&t.as_str()) {
+                    let line = launch.get("instance_type").map_or(0, |i| i.line);
+                    return Err(ParseError::new(
+                        line,
+                        format!("unknown instance type `{t}`"),
+                    ));
+                }
+            }
+        }
+        if let Some(upload) = get_table(&root, "upload")? {
+            config.upload = UploadConfig {
+                exclude: get_str_array(upload, "exclude")?.unwrap_or_default(),
+            };
+        }
+        if let Some(verify) = get_table(&root, "verify")? {
+            config.verify = VerifyConfig {
+                commands: get_str_array(verify, "commands")?.unwrap_or_default(),
+                ports: get_int_array(verify, "ports")?
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(line, p)| {
+                        u16::try_from(p)
+                            .map_err(|_| ParseError::new(line, format!("invalid port `{p}`")))
+                    })
+                    .collect::<Result<_, _>>()?,
+                timeout: get_duration(verify, "timeout")?.map(|t| t.as_secs()),
+            };
+        }
+        if let Some(idle) = get_table(&root, "idle")? {
+            let defaults = IdleConfig::default();
+            config.idle = IdleConfig {
+                hours: get_int(idle, "hours")?.map_or(defaults.hours, |h| h.max(1) as u64),
+                cpu_percent: get_int(idle, "cpu_percent")?
+                    .map_or(defaults.cpu_percent, |p| p.clamp(0, 100) as u64),
+            };
+        }
+
+        if let Some(retry) = get_table(&root, "retry")? {
+            let defaults = RetryConfig::default();
+            config.retry = RetryConfig {
+                max_attempts: get_int(retry, "max_attempts")?
+                    .map_or(defaults.max_attempts, |n| n.clamp(1, 100) as u32),
+                initial_backoff_ms: get_int(retry, "initial_backoff_ms")?
+                    .map_or(defaults.initial_backoff_ms, |ms| ms.max(0) as u64),
+                max_backoff_secs: get_int(retry, "max_backoff_secs")?
+                    .map_or(defaults.max_backoff_secs, |s| s.max(1) as u64),
+            };
+        }
+
+        if let Some(ssh) = get_table(&root, "ssh")? {
+            let port = match get_int(ssh, "port")? {
+                Some(p) => Some(u16::try_from(p).map_err(|_| {
+                    let line = ssh.get("port").map_or(0, |i| i.line);
+                    ParseError::new(line, format!("invalid port `{p}`"))
+                })?),
+                None => None,
+            };
+            config.ssh = SshConfig {
+                port,
+                certificate: get_str(ssh, "certificate")?,
+                user_ca_key: get_str(ssh, "user_ca_key")?,
+            };
+        }
+
+        if let Some(safety) = get_table(&root, "safety")? {
+            config.safety = SafetyConfig {
+                configured: true,
+                protect_obliterate: get_bool(safety, "protect_obliterate")?.unwrap_or(false),
+                obliterate_min_age: get_duration(safety, "obliterate_min_age")?
+                    .map(|t| t.as_secs()),
+            };
+        }
+
+        if let Some(plugins) = get_table(&root, "plugins")? {
+            for name in plugins.iter().map(|(k, _)| k) {
+                if let Some(path) = get_str(plugins, name)? {
+                    config.plugins.insert(name.clone(), path);
+                }
+            }
+        }
+
+        if let Some(tags) = get_table(&root, "tags")? {
+            for key in tags.iter().map(|(k, _)| k) {
+                if let Some(value) = get_str(tags, key)? {
+                    config.tags.insert(key.clone(), value);
+                }
+            }
+        }
+
+        Ok((config, warnings))
+    }
+}
+
+/// Rename deprecated keys in `src`, leaving everything else (comments,
+/// layout) untouched.
+///
+/// Returns the migrated source and the number of keys renamed.
+pub fn migrate(src: &str) -> Result<(String, usize), ParseError> {
+    let (_, warnings) = Config::parse_with_warnings(src)?;
+    let mut lines: Vec<String> = src.lines().map(str::to_string).collect();
+    for w in &warnings {
+        let line = &mut lines[w.line - 1];
+        let indent = line.len() - line.trim_start().len();
+        for (table, old, new) in DEPRECATED {
+            let dotted = format!("{table}.{old}");
+            let rest = &line[indent..];
+            let key = [dotted.as_str(), old].into_iter().find(|k| {
+                rest.strip_prefix(k)
+                    .is_some_and(|r| r.trim_start().starts_with('='))
+            });
+            if let Some(key) = key {
+                let renamed = key.replace(old, new);
+                line.replace_range(indent..indent + key.len(), &renamed);
+                break;
+            }
+        }
+    }
+    let mut migrated = lines.join("\n");
+    if src.ends_with('\n') {
+        migrated.push('\n');
+    }
+    Ok((migrated, warnings.len()))
+}
+
+/// Reject unknown tables and keys, and move deprecated keys to their new
+/// name. Returns a warning for each deprecated key.
+fn check_schema(root: &mut Table) -> Result<Vec<ParseError>, ParseError> {
+    let tables: Vec<&str> = SCHEMA.iter().map(|(t, _)| *t).collect();
+    let mut warnings = vec![];
+    for (name, item) in root.0.iter_mut() {
+        let Some((_, keys)) = SCHEMA.iter().find(|(t, _)| t == name) else {
+            let mut message = format!("unknown table `[{name}]`");
+            if let Some(known) = suggest(name, &tables) {
+                message += &format!(", did you mean `[{known}]`?");
+            }
+            return Err(ParseError::new(item.line, message));
+        };
+        let Value::Table(table) = &mut item.value else {
+            continue;
+        };
+
+        for (_, old, new) in DEPRECATED.iter().filter(|(t, _, _)| t == name) {
+            let Some(value) = table.0.remove(*old) else {
+                continue;
+            };
+            if table.0.contains_key(*new) {
+                return Err(ParseError::new(
+                    value.line,
+                    format!("`{old}` is deprecated and `{new}` is also set, remove `{old}`"),
+                ));
+            }
+            warnings.push(ParseError::new(
+                value.line,
+                format!("`{old}` is deprecated, rename it to `{new}`"),
+            ));
+            table.0.insert(new.to_string(), value);
+        }
+
+        if keys.is_empty() {
+            continue;
+        }
+        if let Some((key, value)) = table.iter().find(|(k, _)| !keys.contains(&k.as_str())) {
+            let mut message = format!("unknown key `{key}` in `[{name}]`");
+            match suggest(key, keys) {
+                Some(known) => message += &format!(", did you mean `{known}`?"),
+                None => message += &format!(", expected one of {}", keys.join(", ")),
+            }
+            return Err(ParseError::new(value.line, message));
+        }
+    }
+    warnings.sort_by_key(|w| w.line);
+    Ok(warnings)
+}
+
+/// The known name closest to `name`, if close enough to be a typo.
+fn suggest<'a>(name: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|k| (edit_distance(name, k), *k))
+        .filter(|(d, k)| *d <= (k.len() / 3).max(1))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, k)| k)
+}
+
+/// Levenshtein distance.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            row.push((prev[j] + cost).min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+fn type_error(key: &str, expected: &str, line: usize, value: &Value) -> ParseError {
+    ParseError::new(
+        line,
+        format!(
+            "expected {expected} for `{key}`, found {}",
+            value.type_name()
+        ),
+    )
+}
+
+fn get_table<'a>(table: &'a Table, key: &str) -> Result<Option<&'a Table>, ParseError> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(item) => match &item.value {
+            Value::Table(t) => Ok(Some(t)),
+            v => Err(type_error(key, "a table", item.line, v)),
+        },
+    }
+}
+
+fn get_int(table: &Table, key: &str) -> Result<Option<i64>, ParseError> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(item) => match &item.value {
+            Value::Integer(i) => Ok(Some(*i)),
+            v => Err(type_error(key, "an integer", item.line, v)),
+        },
+    }
+}
+
+fn get_bool(table: &Table, key: &str) -> Result<Option<bool>, ParseError> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(item) => match &item.value {
+            Value::Boolean(b) => Ok(Some(*b)),
+            v => Err(type_error(key, "a boolean", item.line, v)),
+        },
+    }
+}
+
+/// Integer seconds, or a string with units such as `"10m"`.
+fn get_duration(table: &Table, key: &str) -> Result<Option<Duration>, ParseError> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(item) => match &item.value {
+            Value::Integer(i) => Ok(Some(Duration::from_secs((*i).max(0) as u64))),
+            Value::String(s) => parse_duration(s)
+                .map(Some)
+                .map_err(|e| ParseError::new(item.line, e)),
+            v => Err(type_error(key, "a duration", item.line, v)),
+        },
+    }
+}
+
+fn get_str(table: &Table, key: &str) -> Result<Option<String>, ParseError> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(item) => match &item.value {
+            Value::String(s) => Ok(Some(s.clone())),
+            v => Err(type_error(key, "a string", item.line, v)),
+        },
+    }
+}
+
+fn get_str_array(table: &Table, key: &str) -> Result<Option<Vec<String>>, ParseError> {
+    let Some(item) = table.get(key) else {
+        return Ok(None);
+    };
+    match &item.value {
+        Value::Array(arr) => arr
+            .iter()
+            .map(|v| match v {
+                Value::String(s) => Ok(s.clone()),
+                v => Err(type_error(key, "an array of strings", item.line, v)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Some),
+        v => Err(type_error(key, "an array of strings", item.line, v)),
+    }
+}
+
+/// Returns each integer along with the line of its key.
+fn get_int_array(table: &Table, key: &str) -> Result<Option<Vec<(usize, i64)>>, ParseError> {
+    let Some(item) = table.get(key) else {
+        return Ok(None);
+    };
+    match &item.value {
+        Value::Array(arr) => arr
+            .iter()
+            .map(|v| match v {
+                Value::Integer(i) => Ok((item.line, *i)),
+                v => Err(type_error(key, "an array of integers", item.line, v)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Some),
+        v => Err(type_error(key, "an array of integers", item.line, v)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{migrate, Config, LaunchConfig, RetryConfig, SafetyConfig, VerifyConfig};
+    use crate::toml::ParseError;
+
+    #[test]
+    fn parse_verify() {
+        let config = Config::parse(
+            r#"
+[verify]
+commands = ["cargo --version", "docker info"]
+ports = [22, 8888]
+timeout = 300
+"#,
+        )
+        .unwrap();
+
+        pretty_assertions::assert_eq!(
+            config.verify,
+            VerifyConfig {
+                commands: vec!["cargo --version".into(), "docker info".into()],
+                ports: vec![22, 8888],
+                timeout: Some(300),
+            }
+        );
+
+        let err = Config::parse("[verify]\nports = [70000]").unwrap_err();
+        pretty_assertions::assert_eq!(err.line, 2);
+        let err = Config::parse("[verify]\n\ncommands = \"ls\"").unwrap_err();
+        pretty_assertions::assert_eq!(err.line, 3);
+    }
+
+    #[test]
+    fn parse_retry() {
+        let cases = [
+            ("", RetryConfig::default()),
+            (
+                "[retry]\nmax_attempts = 8",
+                RetryConfig {
+                    max_attempts: 8,
+                    ..RetryConfig::default()
+                },
+            ),
+            (
+                "[retry]\nmax_attempts = 0\ninitial_backoff_ms = 100\nmax_backoff_secs = 0",
+                RetryConfig {
+                    max_attempts: 1,
+                    initial_backoff_ms: 100,
+                    max_backoff_secs: 1,
+                },
+            ),
+        ];
+
+        for (src, expected) in cases {
+            println!("src = {src:?}");
+            pretty_assertions::assert_eq!(Config::parse(src).unwrap().retry, expected);
+        }
+    }
+
+    #[test]
+    fn parse_safety() {
+        let cases = [
+            ("", SafetyConfig::default()),
+            (
+                "[safety]",
+                SafetyConfig {
+                    configured: true,
+                    ..SafetyConfig::default()
+                },
+            ),
+            (
+                "[safety]\nprotect_obliterate = true",
+                SafetyConfig {
+                    configured: true,
+                    protect_obliterate: true,
+                    obliterate_min_age: None,
+                },
+            ),
+            (
+                "[safety]\nobliterate_min_age = \"7d\"",
+                SafetyConfig {
+                    configured: true,
+                    protect_obliterate: false,
+                    obliterate_min_age: Some(7 * 86_400),
+                },
+            ),
+        ];
+        for (src, expected) in cases {
+            println!("src = {src:?}");
+            pretty_assertions::assert_eq!(Config::parse(src).unwrap().safety, expected);
+        }
+
+        let err = Config::parse("[safety]\nprotect_obliterate = \"yes\"").unwrap_err();
+        pretty_assertions::assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn parse_launch() {
+        let config = Config::parse(
+            r#"
+[launch]
+ami = "al2023"
+instance_type = "t3.large"
+user = "ec2-user"
+availability_zones = ["ap-southeast-1b", "ap-southeast-1a"]
+key_name = "my-project"
+
+[upload]
+exclude = ["target", "*.ckpt"]
+
+[ssh]
+port = 2222
+user_ca_key = "~/.ssh/org_user_ca.pub"
+"#,
+        )
+        .unwrap();
+
+        pretty_assertions::assert_eq!(
+            config.launch,
+            LaunchConfig {
+                ami: Some("al2023".into()),
+                instance_type: Some("t3.large".into()),
+                user: Some("ec2-user".into()),
+                setup: None,
+                availability_zones: vec!["ap-southeast-1b".into(), "ap-southeast-1a".into()],
+                key_name: Some("my-project".into()),
+            }
+        );
+        pretty_assertions::assert_eq!(config.upload.exclude, vec!["target", "*.ckpt"]);
+        pretty_assertions::assert_eq!(
+            config.ssh.user_ca_key.as_deref(),
+            Some("~/.ssh/org_user_ca.pub")
+        );
+        pretty_assertions::assert_eq!(config.ssh.port, Some(2222));
+
+        let err = Config::parse("[launch]\n\ninstance_type = \"t3.hug\"").unwrap_err();
+        pretty_assertions::assert_eq!(err.line, 3);
+        pretty_assertions::assert_eq!(err.message, "unknown instance type `t3.hug`");
+    }
+
+    #[test]
+    fn check_schema() {
+        let cases = [
+            (
+                "[launch]\ninstanse_type = \"t3.large\"",
+                ParseError::new(
+                    2,
+                    "unknown key `instanse_type` in `[launch]`, did you mean `instance_type`?",
+                ),
+            ),
+            (
+                "[verify]\ncommands = []\n\nfoo = 1",
+                ParseError::new(
+                    4,
+                    "unknown key `foo` in `[verify]`, expected one of commands, ports, timeout",
+                ),
+            ),
+            (
+                "[lanch]\nami = \"al2023\"",
+                ParseError::new(1, "unknown table `[lanch]`, did you mean `[launch]`?"),
+            ),
+            (
+                "[launch]\nami = \"al2023\"\nami_id = \"al2\"",
+                ParseError::new(
+                    3,
+                    "`ami_id` is deprecated and `ami` is also set, remove `ami_id`",
+                ),
+            ),
+        ];
+        for (src, expected) in cases {
+            println!("src = {src:?}");
+            pretty_assertions::assert_eq!(Config::parse(src).unwrap_err(), expected);
+        }
+
+        let (config, warnings) =
+            Config::parse_with_warnings("[launch]\nami_id = \"al2023\"").unwrap();
+        pretty_assertions::assert_eq!(config.launch.ami.as_deref(), Some("al2023"));
+        pretty_assertions::assert_eq!(
+            warnings,
+            vec![ParseError::new(
+                2,
+                "`ami_id` is deprecated, rename it to `ami`"
+            )]
+        );
+    }
+
+    #[test]
+    fn migrate_deprecated_keys() {
+        let src = r#"# Launch defaults.
+[launch]
+ami_id = "al2023" # pinned
+user = "ec2-user"
+
+[verify]
+  timeout_secs=300
+"#;
+        let (migrated, renamed) = migrate(src).unwrap();
+        pretty_assertions::assert_eq!(renamed, 2);
+        pretty_assertions::assert_eq!(
+            migrated,
+            r#"# Launch defaults.
+[launch]
+ami = "al2023" # pinned
+user = "ec2-user"
+
+[verify]
+  timeout=300
+"#
+        );
+        pretty_assertions::assert_eq!(migrate(&migrated).unwrap(), (migrated.clone(), 0));
+    }
+}
@@ -0,0 +1,258 @@
+//! `cp` between two instances. Data is relayed through this machine over
+//! SFTP, so the instances don't need to reach each other or share keys.
+
+use russh_sftp::client::SftpSession;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{progress::Progress, ssh::Session, util::SelectOption};
+
+/// Chunk size of the relay, matching uploads.
+const RELAY_CHUNK_SIZE: usize = 256 * 1024;
+
+/// `instance:path`, where the instance is given by name or id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyTarget {
+    pub instance: String,
+    pub path: String,
+}
+
+impl std::str::FromStr for CopyTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((instance, path)) if !instance.is_empty() => Ok(CopyTarget {
+                instance: instance.into(),
+                path: if path.is_empty() { "." } else { path }.into(),
+            }),
+            _ => Err(format!("invalid target `{s}`, expected <instance>:<path>")),
+        }
+    }
+}
+
+/// Instances named `name`, or with that id. Clones and adopted instances
+/// may share a name.
+pub fn named<'a>(instances: &'a [SelectOption], name: &str) -> Vec<&'a SelectOption> {
+    instances
+        .iter()
+        .filter(|i| i.instance_id == name || i.name == name)
+        .collect()
+}
+
+/// The instance named `name`, or with that id. Fails when several
+/// instances share the name, listing them.
+pub fn find_instance<'a>(
+    instances: &'a [SelectOption],
+    name: &str,
+) -> anyhow::Result<&'a SelectOption> {
+    let matches = named(instances, name);
+    match matches[..] {
+        [instance] => Ok(instance),
+        [] => anyhow::bail!("No instance is named `{name}`."),
+        _ => {
+            let listed: Vec<String> = matches
+                .iter()
+                .map(|i| {
+                    format!(
+                        "{} ({}, {})",
+                        i.instance_id,
+                        i.instance_type().unwrap_or("unknown type"),
+                        i.state().map_or("unknown state", |s| s.as_str())
+                    )
+                })
+                .collect();
+            anyhow::bail!(
+                "Several instances are named `{name}`: {}. Use an instance id instead.",
+                listed.join(", ")
+            )
+        }
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    format!("{}/{name}", dir.trim_end_matches('/'))
+}
+
+/// Files under `root` with their size, and the directories to create,
+/// parents first. Paths are relative to `root`.
+async fn walk(sftp: &SftpSession, root: &str) -> anyhow::Result<(Vec<(String, u64)>, Vec<String>)> {
+    let (mut files, mut dirs) = (vec![], vec![]);
+    let mut pending = vec![String::new()];
+    while let Some(rel) = pending.pop() {
+        let dir = if rel.is_empty() {
+            root.to_string()
+        } else {
+            join(root, &rel)
+        };
+        for entry in sftp.read_dir(&dir).await? {
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let rel = if rel.is_empty() {
+                name
+            } else {
+                join(&rel, &name)
+            };
+            if entry.file_type().is_dir() {
+                dirs.push(rel.clone());
+                pending.push(rel);
+            } else if entry.file_type().is_file() {
+                files.push((rel, entry.metadata().len()));
+            }
+        }
+    }
+    Ok((files, dirs))
+}
+
+async fn relay_file(
+    src: &SftpSession,
+    src_path: &str,
+    dst: &SftpSession,
+    dst_path: &str,
+    progress: &mut Progress,
+) -> anyhow::Result<u64> {
+    let mut reader = src.open(src_path).await?;
+    let mut writer = dst.create(dst_path).await?;
+    let mut buf = vec![0; RELAY_CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buf[..n]).await?;
+        copied += n as u64;
+        progress.add_bytes(n as u64);
+    }
+    writer.shutdown().await?;
+    Ok(copied)
+}
+
+/// Copy a file or directory from one instance to another, like `cp -r`:
+/// when `dst` is an existing directory, `src` is copied into it.
+///
+/// Returns the number of bytes copied.
+#[tracing::instrument(skip(src, dst), fields(phase = "transfer"))]
+pub async fn copy_between(
+    src: &Session,
+    src_path: &str,
+    dst: &Session,
+    dst_path: &str,
+) -> anyhow::Result<u64> {
+    let src_sftp = src.open_sftp_session().await?;
+    let dst_sftp = dst.open_sftp_session().await?;
+
+    let src_path = src_sftp.canonicalize(src_path).await?;
+    // The destination may not exist yet, so it can't be canonicalized.
+    let dst_path = if dst_path.starts_with('/') {
+        dst_path.to_string()
+    } else {
+        join(&dst_sftp.canonicalize(".").await?, dst_path)
+    };
+    let name = src_path.rsplit('/').next().unwrap_or_default();
+    let dst_path = match dst_sftp.metadata(&dst_path).await {
+        Ok(m) if m.is_dir() => join(&dst_path, name),
+        _ => dst_path,
+    };
+
+    let (files, dirs) = if src_sftp.metadata(&src_path).await?.is_dir() {
+        if !dst_sftp.try_exists(&dst_path).await? {
+            dst_sftp.create_dir(&dst_path).await?;
+        }
+        walk(&src_sftp, &src_path).await?
+    } else {
+        let len = src_sftp.metadata(&src_path).await?.len();
+        (vec![(String::new(), len)], vec![])
+    };
+    for dir in &dirs {
+        let path = join(&dst_path, dir);
+        if !dst_sftp.try_exists(&path).await? {
+            dst_sftp.create_dir(&path).await?;
+        }
+    }
+
+    let total_bytes = files.iter().map(|(_, len)| len).sum();
+    let mut progress = Progress::new(files.len(), total_bytes);
+    let mut copied = 0;
+    for (rel, _) in &files {
+        let (from, to) = if rel.is_empty() {
+            (src_path.clone(), dst_path.clone())
+        } else {
+            (join(&src_path, rel), join(&dst_path, rel))
+        };
+        tracing::info!("Copying {from} to {to}");
+        copied += relay_file(&src_sftp, &from, &dst_sftp, &to, &mut progress).await?;
+        progress.file_done();
+    }
+    progress.finish();
+
+    src_sftp.close().await?;
+    dst_sftp.close().await?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_ec2::types::InstanceStateName;
+
+    use super::{find_instance, CopyTarget};
+    use crate::{mock::instance, util::SelectOption};
+
+    #[test]
+    fn parse_copy_target() {
+        let target = |instance: &str, path: &str| CopyTarget {
+            instance: instance.into(),
+            path: path.into(),
+        };
+        let cases = [
+            (
+                "trainer:ckpt/model.pt",
+                Ok(target("trainer", "ckpt/model.pt")),
+            ),
+            ("i-0123:/data", Ok(target("i-0123", "/data"))),
+            ("infer:", Ok(target("infer", "."))),
+            (
+                "model.pt",
+                Err("invalid target `model.pt`, expected <instance>:<path>".to_string()),
+            ),
+            (
+                ":/data",
+                Err("invalid target `:/data`, expected <instance>:<path>".to_string()),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            println!("input = {input}");
+            pretty_assertions::assert_eq!(input.parse::<CopyTarget>(), expected);
+        }
+    }
+
+    #[test]
+    fn ambiguous_names() {
+        let instances: Vec<SelectOption> = [
+            instance("i-1", "trainer", InstanceStateName::Running, &[]),
+            instance("i-2", "trainer", InstanceStateName::Stopped, &[]),
+            instance("i-3", "infer", InstanceStateName::Running, &[]),
+        ]
+        .into_iter()
+        .map(SelectOption::from)
+        .collect();
+        let cases = [
+            ("infer", Ok("i-3")),
+            ("i-2", Ok("i-2")),
+            (
+                "trainer",
+                Err("Several instances are named `trainer`: i-1 (t3.micro, running), i-2 (t3.micro, stopped). Use an instance id instead."),
+            ),
+            ("eval", Err("No instance is named `eval`.")),
+        ];
+        for (name, expected) in cases {
+            println!("name = {name}");
+            let found = find_instance(&instances, name)
+                .map(|i| i.instance_id.as_str())
+                .map_err(|e| e.to_string());
+            pretty_assertions::assert_eq!(found, expected.map_err(String::from));
+        }
+    }
+}
@@ -1,38 +1,843 @@
-use std::fs::read_to_string;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use aws_sdk_ec2::types::{InstanceType, KeyPairInfo};
+use aws_sdk_ec2::types::{
+    BlockDeviceMapping, EbsBlockDevice, IamInstanceProfileSpecification, InstanceType, KeyPairInfo,
+    SecurityGroup, VolumeType,
+};
 use base64::prelude::*;
 use petname::{Generator, Petnames};
 
-use super::ec2::{EC2Error, EC2Impl as EC2};
+use super::ec2::{EC2Error, Ec2Api, LaunchOptions, TagSelector};
+use super::progress::format_duration;
+use super::s3::S3Impl;
+use super::scripts::load_setup;
+use super::ssh::SSH_PORT;
+
+/// EC2 rejects user data larger than 16 KB. The limit applies to the
+/// whole message (installers included), before it is base64 encoded.
+pub const USER_DATA_LIMIT: usize = 16 * 1024;
+
+/// Tag holding the `s3://` URI of the startup script an instance was
+/// launched to fetch, for `obliterate` to delete it.
+pub const STAGED_USER_DATA_TAG: &str = "korasi:user-data";
+
+/// Tag recording `--auto-stop`, for `show`.
+pub const AUTO_STOP_TAG: &str = "korasi:auto-stop";
+
+/// Installs a systemd timer that powers the instance off (which stops it)
+/// once it had no SSH connection and a load below 10% of its CPUs for
+/// `@IDLE_SECS@` seconds.
+const AUTO_STOP_SCRIPT: &str = r#"#!/bin/bash
+cat > /usr/local/bin/korasi-idle-check <<'EOF'
+#!/bin/bash
+idle_secs=@IDLE_SECS@
+since=/var/lib/korasi/idle-since
+mkdir -p /var/lib/korasi
+sessions=$(ss -Htn state established '( sport = :@SSH_PORT@ )' | wc -l)
+load=$(cut -d' ' -f1 /proc/loadavg)
+if [ "$sessions" -eq 0 ] && awk -v l="$load" -v c="$(nproc)" 'BEGIN { exit !(l < 0.1 * c) }'; then
+  now=$(date +%s)
+  [ -f "$since" ] || echo "$now" > "$since"
+  if [ $((now - $(cat "$since"))) -ge "$idle_secs" ]; then
+    rm -f "$since"
+    shutdown -h now "korasi: idle for ${idle_secs}s"
+  fi
+else
+  rm -f "$since"
+fi
+EOF
+chmod +x /usr/local/bin/korasi-idle-check
+cat > /etc/systemd/system/korasi-idle-check.service <<'EOF'
+[Unit]
+Description=Stop the instance when idle (korasi --auto-stop)
+
+[Service]
+Type=oneshot
+ExecStart=/usr/local/bin/korasi-idle-check
+EOF
+cat > /etc/systemd/system/korasi-idle-check.timer <<'EOF'
+[Timer]
+OnBootSec=5min
+OnUnitActiveSec=1min
+
+[Install]
+WantedBy=timers.target
+EOF
+systemctl daemon-reload
+systemctl enable --now korasi-idle-check.timer
+"#;
+
+/// Has sshd accept user certificates signed by the CA key `@CA_KEY@`.
+const TRUSTED_CA_SCRIPT: &str = r#"#!/bin/bash
+set -euo pipefail
+echo '@CA_KEY@' > /etc/ssh/korasi_user_ca.pub
+echo 'TrustedUserCAKeys /etc/ssh/korasi_user_ca.pub' >> /etc/ssh/sshd_config
+systemctl restart sshd || systemctl restart ssh
+"#;
+
+const MIME_BOUNDARY: &str = "==korasi-user-data==";
+
+/// Installer trusting user certificates of `ca_key`, an OpenSSH public
+/// key that was already parsed (so it can't break out of the quotes).
+pub fn trusted_ca_installer(ca_key: &str) -> String {
+    TRUSTED_CA_SCRIPT.replace("@CA_KEY@", ca_key.trim())
+}
+
+fn auto_stop_installer(idle: Duration, ssh_port: u16) -> String {
+    AUTO_STOP_SCRIPT
+        .replace("@IDLE_SECS@", &idle.as_secs().to_string())
+        .replace("@SSH_PORT@", &ssh_port.to_string())
+}
+
+/// User data running the `--auto-stop` installer, watching for sessions on
+/// `ssh_port`, before `script`.
+pub fn with_auto_stop(script: Option<String>, idle: Duration, ssh_port: u16) -> String {
+    with_installers(vec![auto_stop_installer(idle, ssh_port)], script)
+}
+
+/// User data running the shell scripts `installers` before `script`. They
+/// go into a MIME multipart message, which cloud-init runs part by part,
+/// so `script` may be a shell script or cloud-config.
+pub fn with_installers(installers: Vec<String>, script: Option<String>) -> String {
+    let mut parts: Vec<_> = installers
+        .into_iter()
+        .map(|installer| ("text/x-shellscript", installer))
+        .collect();
+    if let Some(script) = script {
+        let content_type = if script.starts_with("#cloud-config") {
+            "text/cloud-config"
+        } else {
+            "text/x-shellscript"
+        };
+        parts.push((content_type, script));
+    }
+
+    let mut message =
+        format!("Content-Type: multipart/mixed; boundary=\"{MIME_BOUNDARY}\"\nMIME-Version: 1.0\n");
+    for (content_type, body) in parts {
+        message.push_str(&format!(
+            "\n--{MIME_BOUNDARY}\nContent-Type: {content_type}; charset=\"utf-8\"\n\n{body}"
+        ));
+        if !message.ends_with('\n') {
+            message.push('\n');
+        }
+    }
+    message.push_str(&format!("--{MIME_BOUNDARY}--\n"));
+    message
+}
+
+/// Tags to launch with: `given` ones, then the `required` ones (from
+/// `[tags]`) with a preset value. Also returns the required keys that are
+/// still missing a value.
+pub fn required_tags(
+    required: &BTreeMap<String, String>,
+    given: &[TagSelector],
+) -> (Vec<TagSelector>, Vec<String>) {
+    let mut tags = given.to_vec();
+    let mut missing = vec![];
+    for (key, value) in required {
+        if given.iter().any(|t| &t.key == key) {
+            continue;
+        }
+        if value.is_empty() {
+            missing.push(key.clone());
+        } else {
+            tags.push(TagSelector {
+                key: key.clone(),
+                value: value.clone(),
+            });
+        }
+    }
+    (tags, missing)
+}
+
+/// Availability zones to launch in, in order: `pinned` first, then those of
+/// `[launch] availability_zones`. Without either, AWS picks the zone
+/// (`None`) first, and every zone of the region is tried after it.
+pub fn zone_order(
+    pinned: Option<&str>,
+    configured: &[String],
+    available: &[String],
+) -> Vec<Option<String>> {
+    let mut zones = match pinned {
+        Some(zone) => vec![Some(zone.to_string())],
+        None if configured.is_empty() => vec![None],
+        None => vec![],
+    };
+    let fallback = if configured.is_empty() {
+        available
+    } else {
+        configured
+    };
+    for zone in fallback {
+        let zone = Some(zone.clone());
+        if !zones.contains(&zone) {
+            zones.push(zone);
+        }
+    }
+    zones
+}
+
+/// Random, memorable instance name, eg. `happy:otter`.
+pub fn instance_name() -> String {
+    Petnames::default().generate_one(1, ":").unwrap()
+}
+
+/// Instance profiles can be referenced by either name or ARN.
+pub fn instance_profile(name_or_arn: &str) -> IamInstanceProfileSpecification {
+    let builder = IamInstanceProfileSpecification::builder();
+    if name_or_arn.starts_with("arn:") {
+        builder.arn(name_or_arn)
+    } else {
+        builder.name(name_or_arn)
+    }
+    .build()
+}
+
+/// Root EBS volume overrides. Unset fields fall back to the AMI's
+/// default block device mapping.
+#[derive(Debug, Default, Clone)]
+pub struct RootVolume {
+    /// Volume size in GiB.
+    pub size: Option<i32>,
+    pub volume_type: Option<VolumeType>,
+    /// Provisioned IOPS (gp3, io1, io2).
+    pub iops: Option<i32>,
+    /// Provisioned throughput in MiB/s (gp3 only).
+    pub throughput: Option<i32>,
+}
+
+impl RootVolume {
+    pub fn is_default(&self) -> bool {
+        self.size.is_none()
+            && self.volume_type.is_none()
+            && self.iops.is_none()
+            && self.throughput.is_none()
+    }
+
+    /// Catch invalid combinations before they reach `run_instances`,
+    /// which only reports them after a round trip.
+    pub fn validate(&self) -> Result<(), EC2Error> {
+        let vol_type = self.volume_type.as_ref();
+        if self.throughput.is_some() && vol_type != Some(&VolumeType::Gp3) {
+            return Err(EC2Error::new(
+                "--throughput is only supported with --volume-type gp3",
+            ));
+        }
+        if self.iops.is_some()
+            && !matches!(
+                vol_type,
+                Some(VolumeType::Gp3 | VolumeType::Io1 | VolumeType::Io2)
+            )
+        {
+            return Err(EC2Error::new(
+                "--iops is only supported with --volume-type gp3, io1 or io2",
+            ));
+        }
+        if matches!(vol_type, Some(VolumeType::Io1 | VolumeType::Io2)) && self.iops.is_none() {
+            return Err(EC2Error::new("--iops is required for io1/io2 volumes"));
+        }
+        Ok(())
+    }
+
+    /// Builds the mapping for the AMI's root device.
+    pub fn to_mapping(&self, device_name: &str) -> BlockDeviceMapping {
+        BlockDeviceMapping::builder()
+            .device_name(device_name)
+            .ebs(
+                EbsBlockDevice::builder()
+                    .delete_on_termination(true)
+                    .set_volume_size(self.size)
+                    .set_volume_type(self.volume_type.clone())
+                    .set_iops(self.iops)
+                    .set_throughput(self.throughput)
+                    .build(),
+            )
+            .build()
+    }
+}
+
+/// Small script that downloads the real startup script from `uri` (in
+/// `region`) with the instance profile's credentials, and runs it. The
+/// AWS CLI is installed first where the AMI lacks it. Only shell scripts
+/// can be staged this way.
+pub fn bootstrap_user_data(uri: &str, region: &str) -> String {
+    let uri = shell_escape::escape(uri.into());
+    let region = shell_escape::escape(region.into());
+    format!(
+        r#"#!/bin/bash
+set -euo pipefail
+export PATH="$PATH:/snap/bin:/usr/local/bin"
+if ! command -v aws >/dev/null; then
+  snap install aws-cli --classic || dnf install -y awscli || apt-get install -y awscli
+fi
+script=/var/lib/korasi-user-data.sh
+aws s3 cp --region {region} {uri} "$script"
+chmod +x "$script"
+exec "$script"
+"#
+    )
+}
+
+/// Bucket and key of an `s3://bucket/key` URI.
+pub fn parse_s3_uri(uri: &str) -> Option<(&str, &str)> {
+    let (bucket, key) = uri.strip_prefix("s3://")?.split_once('/')?;
+    (!bucket.is_empty() && !key.is_empty()).then_some((bucket, key))
+}
 
 #[derive(Default)]
-pub struct CreateCommand;
+pub struct CreateCommand {
+    pub root_volume: RootVolume,
+
+    /// Where to stage user data that exceeds `USER_DATA_LIMIT`. The
+    /// instance fetches it with the role of `iam_profile`.
+    pub user_data_store: Option<S3Impl>,
+
+    /// Name or ARN of the instance profile to attach.
+    pub iam_profile: Option<String>,
+
+    /// Stop the instance after being idle this long.
+    pub auto_stop: Option<Duration>,
+
+    /// Port sshd listens on when not `SSH_PORT`, where `auto_stop` looks
+    /// for sessions.
+    pub ssh_port: Option<u16>,
+
+    /// Name of an existing placement group to launch into.
+    pub placement_group: Option<String>,
+
+    /// Launch with an Elastic Fabric Adapter.
+    pub efa: bool,
+
+    /// Security groups to join besides the SSH one, eg. the EFA group of
+    /// the placement group.
+    pub security_groups: Vec<SecurityGroup>,
+
+    /// Availability zones to try in order, moving on when one is out of
+    /// capacity. AWS picks the zone for `None`, or when empty.
+    pub availability_zones: Vec<Option<String>>,
+
+    /// OpenSSH public key of a CA whose user certificates sshd accepts.
+    pub user_ca_key: Option<String>,
+}
 
 impl CreateCommand {
     pub async fn launch(
         &self,
-        ec2: &EC2,
+        ec2: &impl Ec2Api,
         machine: InstanceType,
         ami_id: String,
         info: KeyPairInfo,
         setup: String,
-    ) -> Result<(), EC2Error> {
+    ) -> Result<Vec<String>, EC2Error> {
+        let name = instance_name();
+
+        let vars = HashMap::from([
+            ("instance_name", name.clone()),
+            ("instance_type", machine.to_string()),
+        ]);
+        let script = load_setup(&setup, &vars)?;
+
+        self.launch_script(ec2, &name, machine, ami_id, info, script)
+            .await
+    }
+
+    /// Launch with an already rendered startup script, eg. one recovered
+    /// from a repro bundle.
+    pub async fn launch_script(
+        &self,
+        ec2: &impl Ec2Api,
+        name: &str,
+        machine: InstanceType,
+        ami_id: String,
+        info: KeyPairInfo,
+        script: Option<String>,
+    ) -> Result<Vec<String>, EC2Error> {
+        self.root_volume.validate()?;
+
         let group = ec2.get_ssh_security_group().await?;
         tracing::info!("Security Group used = {:?}", group.group_id);
 
-        let user_data = read_to_string(setup)
-            .map(|data| BASE64_STANDARD.encode(data.as_bytes()))
-            .ok();
+        let mut tags = vec![];
+        if let Some(idle) = self.auto_stop {
+            tags.push(TagSelector {
+                key: AUTO_STOP_TAG.into(),
+                value: format_duration(idle),
+            });
+        }
+        let (user_data, staged) = self.user_data(name, script).await?;
+        if let Some(uri) = staged {
+            tags.push(TagSelector {
+                key: STAGED_USER_DATA_TAG.into(),
+                value: uri,
+            });
+        }
+        let user_data = user_data.map(|s| BASE64_STANDARD.encode(s));
         tracing::info!("User data: {:?}", user_data);
 
-        let name = Petnames::default().generate_one(1, ":").unwrap();
+        let block_device_mappings = if self.root_volume.is_default() {
+            None
+        } else {
+            let image = ec2.describe_image(&ami_id).await?;
+            let device_name = image.root_device_name().ok_or_else(|| {
+                EC2Error::new(format!("AMI {ami_id} does not declare a root device"))
+            })?;
+            Some(vec![self.root_volume.to_mapping(device_name)])
+        };
+        tracing::info!("Block device mappings: {:?}", block_device_mappings);
 
-        let _instance_ids = ec2
-            .create_instances(&name, &ami_id, machine, &info, vec![&group], user_data)
-            .await?;
-        tracing::info!("Created instance with name = {}", name);
+        let opts = LaunchOptions {
+            user_data,
+            block_device_mappings,
+            iam_instance_profile: self.iam_profile.as_deref().map(instance_profile),
+            tags,
+            placement_group: self.placement_group.clone(),
+            efa: self.efa,
+            availability_zone: None,
+        };
+        let zones: Vec<Option<String>> = if self.availability_zones.is_empty() {
+            vec![None]
+        } else {
+            self.availability_zones.clone()
+        };
+        let mut zones = zones.into_iter().peekable();
+        loop {
+            let zone = zones.next().flatten();
+            let res = ec2
+                .create_instances(
+                    name,
+                    &ami_id,
+                    machine.clone(),
+                    &info,
+                    std::iter::once(&group)
+                        .chain(&self.security_groups)
+                        .collect(),
+                    LaunchOptions {
+                        availability_zone: zone.clone(),
+                        ..opts.clone()
+                    },
+                )
+                .await;
+            match res {
+                Err(err) if err.is_capacity_error() && zones.peek().is_some() => {
+                    eprintln!(
+                        "No {machine} capacity in {}, trying {}...",
+                        zone.as_deref().unwrap_or("the zone AWS picked"),
+                        zones.peek().cloned().flatten().unwrap_or_default()
+                    );
+                }
+                res => {
+                    let instance_ids = res?;
+                    tracing::info!("Created instance with name = {}", name);
+                    return Ok(instance_ids);
+                }
+            }
+        }
+    }
 
-        Ok(())
+    /// `script` after the installers of the enabled options, as one MIME
+    /// message when there are any.
+    fn with_options(&self, script: Option<String>) -> Option<String> {
+        let mut installers = vec![];
+        if let Some(idle) = self.auto_stop {
+            installers.push(auto_stop_installer(idle, self.ssh_port.unwrap_or(SSH_PORT)));
+        }
+        if let Some(ca_key) = &self.user_ca_key {
+            installers.push(trusted_ca_installer(ca_key));
+        }
+        if installers.is_empty() {
+            script
+        } else {
+            Some(with_installers(installers, script))
+        }
+    }
+
+    /// Returns the user data to launch with, swapping `script` for a
+    /// bootstrap that fetches it from S3 when the whole message would
+    /// exceed `USER_DATA_LIMIT`. Also returns the `s3://` URI of the
+    /// staged script, if any.
+    async fn user_data(
+        &self,
+        name: &str,
+        script: Option<String>,
+    ) -> Result<(Option<String>, Option<String>), EC2Error> {
+        let user_data = self.with_options(script.clone());
+        let len = user_data.as_ref().map_or(0, String::len);
+        let Some(script) = script.filter(|_| len > USER_DATA_LIMIT) else {
+            return Ok((user_data, None));
+        };
+        // The bootstrap execs the staged script, so it must be one.
+        if !script.starts_with("#!") {
+            return Err(EC2Error::new(format!(
+                "User data is {len} bytes, which exceeds the {USER_DATA_LIMIT} bytes limit, and \
+                 only scripts starting with `#!` can be staged on S3, eg. not cloud-config."
+            )));
+        }
+
+        let Some(store) = &self.user_data_store else {
+            return Err(EC2Error::new(format!(
+                "User data is {len} bytes, which exceeds the {USER_DATA_LIMIT} bytes limit. \
+                 Pass --user-data-bucket to stage the startup script on S3 instead."
+            )));
+        };
+        if self.iam_profile.is_none() {
+            return Err(EC2Error::new(format!(
+                "User data is {len} bytes, staging the startup script on s3://{} also needs \
+                 --iam-profile, with a role allowed to s3:GetObject it.",
+                store.bucket
+            )));
+        }
+
+        tracing::info!(
+            "User data is {len} bytes, staging the startup script on s3://{}",
+            store.bucket
+        );
+        // Names can be reused, so one launch can't overwrite the script of
+        // another still booting.
+        let launched = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let key = format!("korasi/user-data/{}-{launched}.sh", name.replace(':', "-"));
+        store.put_object(&key, script.into_bytes()).await?;
+        let uri = format!("s3://{}/{key}", store.bucket);
+
+        let user_data = self.with_options(Some(bootstrap_user_data(&uri, &store.region())));
+        let len = user_data.as_ref().map_or(0, String::len);
+        if len > USER_DATA_LIMIT {
+            return Err(EC2Error::new(format!(
+                "User data is still {len} bytes with the startup script on S3, \
+                 which exceeds the {USER_DATA_LIMIT} bytes limit."
+            )));
+        }
+        Ok((user_data, Some(uri)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_ec2::types::{Image, InstanceType, KeyPairInfo, VolumeType};
+    use base64::prelude::*;
+
+    use std::{collections::BTreeMap, time::Duration};
+
+    use super::{
+        bootstrap_user_data, parse_s3_uri, required_tags, trusted_ca_installer, with_auto_stop,
+        with_installers, zone_order, CreateCommand, RootVolume, USER_DATA_LIMIT,
+    };
+    use crate::ec2::TagSelector;
+    use crate::mock::MockEc2;
+
+    #[test]
+    fn validate_root_volume() {
+        let cases = [
+            (RootVolume::default(), true),
+            (
+                RootVolume {
+                    size: Some(200),
+                    ..RootVolume::default()
+                },
+                true,
+            ),
+            (
+                RootVolume {
+                    volume_type: Some(VolumeType::Gp3),
+                    iops: Some(6000),
+                    throughput: Some(500),
+                    ..RootVolume::default()
+                },
+                true,
+            ),
+            (
+                RootVolume {
+                    volume_type: Some(VolumeType::Gp2),
+                    throughput: Some(500),
+                    ..RootVolume::default()
+                },
+                false,
+            ),
+            (
+                RootVolume {
+                    volume_type: Some(VolumeType::Io2),
+                    ..RootVolume::default()
+                },
+                false,
+            ),
+            (
+                RootVolume {
+                    iops: Some(3000),
+                    ..RootVolume::default()
+                },
+                false,
+            ),
+        ];
+
+        for (input, valid) in cases {
+            println!("input = {:?}", input);
+            pretty_assertions::assert_eq!(input.validate().is_ok(), valid);
+        }
+    }
+
+    #[test]
+    fn auto_stop_user_data() {
+        let cases = [
+            (None, vec!["text/x-shellscript"]),
+            (
+                Some("#!/bin/bash\necho hi"),
+                vec!["text/x-shellscript", "text/x-shellscript"],
+            ),
+            (
+                Some("#cloud-config\npackages: [htop]\n"),
+                vec!["text/x-shellscript", "text/cloud-config"],
+            ),
+        ];
+
+        for (script, expected) in cases {
+            println!("script = {script:?}");
+            let user_data =
+                with_auto_stop(script.map(str::to_string), Duration::from_secs(3600), 22);
+            let content_types: Vec<_> = user_data
+                .lines()
+                .filter_map(|l| l.strip_prefix("Content-Type: "))
+                .filter_map(|l| l.strip_suffix("; charset=\"utf-8\""))
+                .collect();
+            pretty_assertions::assert_eq!(content_types, expected);
+            assert!(user_data.contains("idle_secs=3600\n"));
+            assert!(user_data.ends_with("--==korasi-user-data==--\n"));
+            if let Some(script) = script {
+                assert!(user_data.contains(script));
+            }
+        }
+
+        let user_data = with_installers(
+            vec![trusted_ca_installer(
+                "ssh-ed25519 AAAAC3Nz ca@example.com\n",
+            )],
+            None,
+        );
+        assert!(user_data.contains(
+            "echo 'ssh-ed25519 AAAAC3Nz ca@example.com' > /etc/ssh/korasi_user_ca.pub\n"
+        ));
+    }
+
+    #[test]
+    fn staged_user_data() {
+        let cases = [
+            ("s3://bucket/korasi/a.sh", Some(("bucket", "korasi/a.sh"))),
+            ("s3://bucket/", None),
+            ("s3:///key", None),
+            ("https://bucket/key", None),
+        ];
+
+        for (uri, expected) in cases {
+            println!("uri = {uri}");
+            pretty_assertions::assert_eq!(parse_s3_uri(uri), expected);
+        }
+
+        let script = bootstrap_user_data("s3://bucket/korasi/a b.sh", "eu-west-1");
+        assert!(script.starts_with("#!/bin/bash\n"));
+        assert!(script
+            .contains("aws s3 cp --region eu-west-1 's3://bucket/korasi/a b.sh' \"$script\"\n"));
+    }
+
+    #[test]
+    fn fill_required_tags() {
+        let tag = |key: &str, value: &str| TagSelector {
+            key: key.into(),
+            value: value.into(),
+        };
+        let required = BTreeMap::from([
+            ("cost-center".to_string(), "ml".to_string()),
+            ("owner".to_string(), String::new()),
+        ]);
+        let cases = [
+            (vec![], vec![tag("cost-center", "ml")], vec!["owner"]),
+            (
+                vec![tag("owner", "vc"), tag("cost-center", "infra")],
+                vec![tag("owner", "vc"), tag("cost-center", "infra")],
+                vec![],
+            ),
+            (
+                vec![tag("run", "7")],
+                vec![tag("run", "7"), tag("cost-center", "ml")],
+                vec!["owner"],
+            ),
+        ];
+
+        for (given, tags, missing) in cases {
+            println!("given = {given:?}");
+            pretty_assertions::assert_eq!(
+                required_tags(&required, &given),
+                (tags, missing.into_iter().map(String::from).collect())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fall_back_to_next_zone() {
+        let zones = |z: &[&str]| -> Vec<String> { z.iter().map(|z| z.to_string()).collect() };
+        let some =
+            |z: &[&str]| -> Vec<Option<String>> { z.iter().map(|z| Some(z.to_string())).collect() };
+        let available = zones(&["az-a", "az-b", "az-c"]);
+        let cases = [
+            (
+                None,
+                vec![],
+                [vec![None], some(&["az-a", "az-b", "az-c"])].concat(),
+            ),
+            (None, zones(&["az-b"]), some(&["az-b"])),
+            (Some("az-c"), vec![], some(&["az-c", "az-a", "az-b"])),
+            (
+                Some("az-b"),
+                zones(&["az-c", "az-b"]),
+                some(&["az-b", "az-c"]),
+            ),
+        ];
+        for (pinned, configured, expected) in cases {
+            println!("pinned = {pinned:?}, configured = {configured:?}");
+            pretty_assertions::assert_eq!(zone_order(pinned, &configured, &available), expected);
+        }
+
+        let ec2 = MockEc2 {
+            full_zones: zones(&["az-a"]),
+            ..MockEc2::default()
+        };
+        let create = CreateCommand {
+            availability_zones: some(&["az-a", "az-b"]),
+            ..CreateCommand::default()
+        };
+        let ids = create
+            .launch_script(
+                &ec2,
+                "dev",
+                InstanceType::T3Micro,
+                "ami-0abc".into(),
+                KeyPairInfo::builder().key_name("ec2-ssh-key").build(),
+                None,
+            )
+            .await
+            .unwrap();
+        pretty_assertions::assert_eq!(ids, vec!["i-0000"]);
+        let launches = ec2.launches.lock().unwrap().clone();
+        pretty_assertions::assert_eq!(launches[0].availability_zone.as_deref(), Some("az-b"));
+
+        // Out of zones, the capacity error is returned.
+        let create = CreateCommand {
+            availability_zones: some(&["az-a"]),
+            ..CreateCommand::default()
+        };
+        let err = create
+            .launch_script(
+                &ec2,
+                "dev",
+                InstanceType::T3Micro,
+                "ami-0abc".into(),
+                KeyPairInfo::builder().key_name("ec2-ssh-key").build(),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.is_capacity_error());
+    }
+
+    #[tokio::test]
+    async fn launch_on_mock() {
+        let ec2 = MockEc2 {
+            images: vec![Image::builder()
+                .image_id("ami-0abc")
+                .root_device_name("/dev/xvda")
+                .build()],
+            ..MockEc2::default()
+        };
+        let create = CreateCommand {
+            root_volume: RootVolume {
+                size: Some(100),
+                ..RootVolume::default()
+            },
+            ..CreateCommand::default()
+        };
+
+        let ids = create
+            .launch_script(
+                &ec2,
+                "dev",
+                InstanceType::T3Micro,
+                "ami-0abc".into(),
+                KeyPairInfo::builder().key_name("ec2-ssh-key").build(),
+                Some("echo hi".into()),
+            )
+            .await
+            .unwrap();
+
+        pretty_assertions::assert_eq!(ids, vec!["i-0000"]);
+        pretty_assertions::assert_eq!(
+            ec2.calls(),
+            vec![
+                "get_ssh_security_group",
+                "describe_image ami-0abc",
+                "create_instances ami-0abc t3.micro sg-mock",
+            ]
+        );
+        let launches = ec2.launches.lock().unwrap();
+        pretty_assertions::assert_eq!(
+            launches[0].user_data,
+            Some(BASE64_STANDARD.encode("echo hi"))
+        );
+        let mappings = launches[0].block_device_mappings.as_deref().unwrap();
+        pretty_assertions::assert_eq!(mappings[0].device_name(), Some("/dev/xvda"));
+        pretty_assertions::assert_eq!(mappings[0].ebs().and_then(|e| e.volume_size()), Some(100));
+    }
+
+    #[tokio::test]
+    async fn limit_whole_user_data() {
+        let ec2 = MockEc2::default();
+        let create = CreateCommand {
+            auto_stop: Some(Duration::from_secs(1800)),
+            ..CreateCommand::default()
+        };
+        // Fits alone, but not with the `--auto-stop` installer.
+        let script = format!("#!/bin/bash\n{}", "#".repeat(USER_DATA_LIMIT - 100));
+
+        let err = create
+            .launch_script(
+                &ec2,
+                "dev",
+                InstanceType::T3Micro,
+                "ami-0abc".into(),
+                KeyPairInfo::builder().key_name("ec2-ssh-key").build(),
+                Some(script),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("--user-data-bucket"), "{err}");
+        assert!(ec2.launches.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stage_only_scripts() {
+        let ec2 = MockEc2::default();
+        let create = CreateCommand::default();
+        let script = format!(
+            "#cloud-config
+{}",
+            "#".repeat(USER_DATA_LIMIT)
+        );
+
+        let err = create
+            .launch_script(
+                &ec2,
+                "dev",
+                InstanceType::T3Micro,
+                "ami-0abc".into(),
+                KeyPairInfo::builder().key_name("ec2-ssh-key").build(),
+                Some(script),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("starting with `#!`"), "{err}");
+        assert!(ec2.launches.lock().unwrap().is_empty());
     }
 }
@@ -0,0 +1,455 @@
+//! Long waits (status checks, stop, terminate) that can be left to the
+//! background by pressing Enter. Detached waits are recorded under
+//! `~/.korasi` and picked up again by `korasi wait --attach`.
+//!
+//! `delete --detach` records its deletions the same way, without waiting
+//! at all. They are checked on by every later command and by `korasi gc`.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use aws_sdk_ec2::types::InstanceStateName;
+use clap::ValueEnum;
+
+use crate::{
+    ec2::{EC2Error, EC2Impl as EC2},
+    progress::with_eta,
+    ssh::{wait_for_port, ConnectOptions},
+    state::{save_state, state_dir, Timings},
+    toml::{self, quote, Value},
+};
+
+/// How long resumed waits give an instance to reach its state.
+const RESUME_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How often the key listener checks whether the wait is over.
+const POLL_STDIN: Duration = Duration::from_millis(200);
+
+/// Instances usually terminate within minutes, after this long one is
+/// reported as stuck.
+pub const STUCK_AFTER: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum WaitState {
+    Running,
+    /// Passed its status checks.
+    Ready,
+    /// Accepts connections on the SSH port.
+    SshReady,
+    Stopped,
+    Terminated,
+}
+
+impl WaitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WaitState::Running => "running",
+            WaitState::Ready => "ready",
+            WaitState::SshReady => "ssh-ready",
+            WaitState::Stopped => "stopped",
+            WaitState::Terminated => "terminated",
+        }
+    }
+
+    fn parse(s: &str) -> Option<WaitState> {
+        WaitState::value_variants()
+            .iter()
+            .find(|w| w.as_str() == s)
+            .copied()
+    }
+}
+
+/// A wait the user detached from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingWait {
+    pub state: WaitState,
+    pub region: String,
+    /// Comma separated, as taken by `EC2Impl`.
+    pub instance_ids: String,
+    /// Unix time the wait was detached at.
+    pub since: u64,
+}
+
+impl PendingWait {
+    pub fn new(state: WaitState, region: &str, instance_ids: &str) -> Self {
+        PendingWait {
+            state,
+            region: region.into(),
+            instance_ids: instance_ids.into(),
+            since: now(),
+        }
+    }
+
+    /// Whether it has waited longer than `STUCK_AFTER`.
+    pub fn is_stuck(&self) -> bool {
+        now().saturating_sub(self.since) > STUCK_AFTER.as_secs()
+    }
+
+    /// Seconds since it was detached.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_secs(now().saturating_sub(self.since))
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn path() -> PathBuf {
+    state_dir().join("waits.toml")
+}
+
+/// Waits in `src`, skipping malformed entries.
+pub fn parse(src: &str) -> Vec<PendingWait> {
+    let Ok(table) = toml::parse(src) else {
+        return vec![];
+    };
+    let Some(Value::Array(waits)) = table.get("wait").map(|i| &i.value) else {
+        return vec![];
+    };
+    waits
+        .iter()
+        .filter_map(|w| {
+            let Value::Table(t) = w else {
+                return None;
+            };
+            let string = |key: &str| match t.get(key).map(|i| &i.value) {
+                Some(Value::String(s)) => Some(s.clone()),
+                _ => None,
+            };
+            Some(PendingWait {
+                state: WaitState::parse(&string("state")?)?,
+                region: string("region")?,
+                instance_ids: string("instance_ids")?,
+                since: match t.get("since").map(|i| &i.value) {
+                    Some(Value::Integer(i)) => (*i).max(0) as u64,
+                    _ => 0,
+                },
+            })
+        })
+        .collect()
+}
+
+pub fn to_toml(waits: &[PendingWait]) -> String {
+    waits
+        .iter()
+        .map(|w| {
+            format!(
+                "[[wait]]\nstate = {}\nregion = {}\ninstance_ids = {}\nsince = {}\n",
+                quote(w.state.as_str()),
+                quote(&w.region),
+                quote(&w.instance_ids),
+                w.since
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn load() -> Vec<PendingWait> {
+    std::fs::read_to_string(path())
+        .map(|src| parse(&src))
+        .unwrap_or_default()
+}
+
+/// Apply `f` to the recorded waits and save them. Like stats, failing to
+/// save is only logged.
+fn update(f: impl FnOnce(&mut Vec<PendingWait>)) {
+    let mut waits = load();
+    f(&mut waits);
+    let res = save_state(&path(), &to_toml(&waits));
+    if let Err(err) = res {
+        tracing::warn!("Failed to save detached waits: {err}");
+    }
+}
+
+/// Record `pending` to be checked on later, without waiting for it.
+pub fn record(pending: PendingWait) {
+    update(|waits| waits.push(pending));
+}
+
+/// Instances of a deletion that aren't terminated yet, given `states` of
+/// the instances EC2 still describes. The others are long gone.
+pub fn unfinished(
+    deletion: &PendingWait,
+    states: &HashMap<String, InstanceStateName>,
+) -> Vec<String> {
+    deletion
+        .instance_ids
+        .split(',')
+        .filter(|id| {
+            states
+                .get(*id)
+                .is_some_and(|s| *s != InstanceStateName::Terminated)
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Check on the deletions detached in `region`, forgetting the finished
+/// ones. Returns the others with their unfinished instances.
+pub async fn reap(ec2: &EC2, region: &str) -> Result<Vec<(PendingWait, Vec<String>)>, EC2Error> {
+    let deletions: Vec<_> = load()
+        .into_iter()
+        .filter(|w| w.region == region && w.state == WaitState::Terminated)
+        .collect();
+    if deletions.is_empty() {
+        return Ok(vec![]);
+    }
+    // One inventory for all of them, reused by whatever the command shows.
+    let states: HashMap<String, InstanceStateName> = ec2
+        .inventory()
+        .await?
+        .instances
+        .iter()
+        .filter_map(|i| {
+            let state = i.state().and_then(|s| s.name())?.clone();
+            Some((i.instance_id()?.to_string(), state))
+        })
+        .collect();
+    let mut pending = vec![];
+    for deletion in deletions {
+        let left = unfinished(&deletion, &states);
+        if left.is_empty() {
+            update(|waits| waits.retain(|w| *w != deletion));
+        } else {
+            pending.push((deletion, left));
+        }
+    }
+    Ok(pending)
+}
+
+/// Block until a line is entered on stdin, or `done` is set. Polls rather
+/// than reads, so nothing is left reading stdin once the wait is over.
+fn wait_for_enter(done: &AtomicBool) -> bool {
+    let mut fds = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    while !done.load(Ordering::Relaxed) {
+        if unsafe { libc::poll(&mut fds, 1, POLL_STDIN.as_millis() as i32) } > 0 {
+            let mut line = String::new();
+            return std::io::stdin().read_line(&mut line).is_ok();
+        }
+    }
+    false
+}
+
+/// Await `wait` for instances of `instance_types` to reach `state`, showing
+/// an ETA from earlier waits, and record how long it took.
+pub async fn timed<T>(
+    state: WaitState,
+    instance_types: &[String],
+    wait: impl Future<Output = Result<T, EC2Error>>,
+) -> Result<T, EC2Error> {
+    let timings = Timings::load();
+    // Batched waits last as long as the slowest instance.
+    let expected = instance_types
+        .iter()
+        .filter_map(|t| timings.expected(state.as_str(), t))
+        .max();
+    let started = std::time::Instant::now();
+    let res = with_eta(expected, wait).await?;
+    let mut types = instance_types.to_vec();
+    types.sort();
+    types.dedup();
+    for t in types {
+        Timings::record(state.as_str(), &t, started.elapsed());
+    }
+    Ok(res)
+}
+
+/// Await `wait`, unless the user presses Enter first, in which case it is
+/// recorded as `pending` for `korasi wait --attach`. See `timed` for
+/// `instance_types`.
+///
+/// Returns whether the wait completed. Without a terminal, there is
+/// nobody to press Enter and `wait` is simply awaited.
+pub async fn detachable(
+    wait: impl Future<Output = Result<(), EC2Error>>,
+    pending: PendingWait,
+    instance_types: &[String],
+) -> Result<bool, EC2Error> {
+    let wait = timed(pending.state, instance_types, wait);
+    if !termion::is_tty(&std::io::stdin()) {
+        return wait.await.map(|_| true);
+    }
+    println!("Press Enter to continue in the background.");
+    let done = Arc::new(AtomicBool::new(false));
+    let listener = tokio::task::spawn_blocking({
+        let done = done.clone();
+        move || wait_for_enter(&done)
+    });
+    tokio::pin!(wait);
+    tokio::select! {
+        res = &mut wait => {
+            done.store(true, Ordering::Relaxed);
+            res.map(|_| true)
+        }
+        Ok(true) = listener => {
+            println!(
+                "Left {} to become {} in the background. Resume with `korasi wait --attach`.",
+                pending.instance_ids,
+                pending.state.as_str()
+            );
+            record(pending);
+            Ok(false)
+        }
+    }
+}
+
+/// Wait for `instance_ids` (comma separated) to reach `state`, probing SSH
+/// the way `connect` says.
+pub async fn wait_for(
+    ec2: &EC2,
+    state: WaitState,
+    instance_ids: &str,
+    timeout: Duration,
+    connect: &ConnectOptions,
+) -> Result<(), EC2Error> {
+    match state {
+        WaitState::Running => {
+            ec2.wait_for_instance_running(instance_ids, Some(timeout))
+                .await
+        }
+        WaitState::SshReady => {
+            ec2.wait_for_instance_running(instance_ids, Some(timeout))
+                .await?;
+            for id in instance_ids.split(',') {
+                let instance = ec2.get_instance(id).await?;
+                let host = instance
+                    .public_dns_name()
+                    .filter(|h| !h.is_empty())
+                    .ok_or_else(|| EC2Error::new(format!("{id} has no public DNS name")))?;
+                wait_for_port(host, connect.port(), connect.relay.as_deref(), timeout)
+                    .await
+                    .map_err(|e| EC2Error::new(e.to_string()))?;
+            }
+            Ok(())
+        }
+        WaitState::Ready => {
+            for id in instance_ids.split(',') {
+                ec2.wait_for_instance_ready(id, Some(timeout)).await?;
+            }
+            Ok(())
+        }
+        WaitState::Stopped => {
+            ec2.wait_for_instance_stopped(instance_ids, Some(timeout))
+                .await
+        }
+        WaitState::Terminated => {
+            ec2.wait_for_instance_terminated(instance_ids, Some(timeout))
+                .await
+        }
+    }
+}
+
+/// Resume the waits detached in `region`, oldest first. Each of them can be
+/// detached again.
+pub async fn attach(ec2: &EC2, region: &str, connect: &ConnectOptions) -> Result<(), EC2Error> {
+    let waits: Vec<_> = load().into_iter().filter(|w| w.region == region).collect();
+    if waits.is_empty() {
+        println!("No detached waits in {region}.");
+        return Ok(());
+    }
+    for wait in waits {
+        println!(
+            "Waiting for {} to be {} (detached {}s ago)...",
+            wait.instance_ids,
+            wait.state.as_str(),
+            now().saturating_sub(wait.since)
+        );
+        let done = detachable(
+            wait_for(ec2, wait.state, &wait.instance_ids, RESUME_TIMEOUT, connect),
+            PendingWait::new(wait.state, region, &wait.instance_ids),
+            &[],
+        )
+        .await?;
+        // Failed waits are kept, to try again.
+        update(|waits| waits.retain(|w| *w != wait));
+        if done {
+            println!("{} {}.", wait.instance_ids, wait.state.as_str());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use aws_sdk_ec2::types::InstanceStateName;
+
+    use super::{parse, to_toml, unfinished, PendingWait, WaitState};
+
+    #[test]
+    fn pending_waits_round_trip() {
+        let waits = vec![
+            PendingWait {
+                state: WaitState::Stopped,
+                region: "ap-southeast-1".into(),
+                instance_ids: "i-1,i-2".into(),
+                since: 1700000000,
+            },
+            PendingWait {
+                state: WaitState::Terminated,
+                region: "us-east-1".into(),
+                instance_ids: "i-3".into(),
+                since: 1700000100,
+            },
+        ];
+        pretty_assertions::assert_eq!(parse(&to_toml(&waits)), waits);
+        pretty_assertions::assert_eq!(
+            parse("[[wait]]\nstate = \"rebooted\"\nregion = \"x\"\ninstance_ids = \"i-1\"\n"),
+            vec![]
+        );
+        pretty_assertions::assert_eq!(parse(""), vec![]);
+    }
+
+    #[test]
+    fn unfinished_deletions() {
+        let deletion = PendingWait {
+            state: WaitState::Terminated,
+            region: "ap-southeast-1".into(),
+            instance_ids: "i-1,i-2,i-3".into(),
+            since: 1700000000,
+        };
+        let cases = [
+            (vec![], vec![]),
+            (
+                vec![
+                    ("i-1", InstanceStateName::Terminated),
+                    ("i-2", InstanceStateName::ShuttingDown),
+                ],
+                vec!["i-2"],
+            ),
+            (
+                vec![
+                    ("i-1", InstanceStateName::ShuttingDown),
+                    ("i-3", InstanceStateName::Stopping),
+                ],
+                vec!["i-1", "i-3"],
+            ),
+        ];
+        for (states, expected) in cases {
+            println!("states = {states:?}");
+            let states: HashMap<String, InstanceStateName> = states
+                .into_iter()
+                .map(|(id, s)| (id.to_string(), s))
+                .collect();
+            pretty_assertions::assert_eq!(unfinished(&deletion, &states), expected);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! `korasi drift`: compare instances against what korasi.toml declares
+//! (instance type, AMI, open ports, volumes, setup recipe) and print the
+//! differences, like the plan of an IaC tool.
+
+use std::{collections::HashMap, fmt};
+
+use aws_sdk_ec2::types::{Instance, IpPermission, SecurityGroup, Volume};
+
+use crate::{
+    config::Config,
+    ec2::{EC2Error, EC2Impl as EC2},
+    lock::Lockfile,
+    scripts::load_setup,
+};
+
+/// Marks user data that only fetches the real script from S3.
+const STAGED_MARKER: &str = "/var/lib/korasi-user-data.sh";
+
+/// What korasi.toml declares for an instance.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Declared {
+    pub instance_type: Option<String>,
+    pub ami: Option<String>,
+    /// TCP ports that should be open, SSH included.
+    pub ports: Vec<u16>,
+    /// The rendered setup script, if any.
+    pub setup: Option<String>,
+}
+
+/// What an instance actually runs with.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Live {
+    pub instance_type: String,
+    pub ami: String,
+    /// Single TCP ports open in its security groups.
+    pub ports: Vec<u16>,
+    /// Volumes other than the root one, as `(id, size in GiB)`.
+    pub extra_volumes: Vec<(String, i32)>,
+    /// Decoded user data, `None` when launched without any.
+    pub user_data: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Drift {
+    Changed {
+        field: String,
+        declared: String,
+        live: String,
+    },
+    /// Live, but not declared.
+    Added(String),
+    /// Declared, but not live.
+    Removed(String),
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drift::Changed {
+                field,
+                declared,
+                live,
+            } => write!(f, "~ {field}: {declared} -> {live}"),
+            Drift::Added(what) => write!(f, "+ {what}"),
+            Drift::Removed(what) => write!(f, "- {what}"),
+        }
+    }
+}
+
+/// Differences of `live` from `declared`. Fields korasi.toml doesn't
+/// declare are not compared.
+pub fn diff(declared: &Declared, live: &Live) -> Vec<Drift> {
+    let mut drifts = vec![];
+    let mut changed = |field: &str, declared: &Option<String>, live: &str| {
+        if let Some(declared) = declared {
+            if declared != live {
+                drifts.push(Drift::Changed {
+                    field: field.into(),
+                    declared: declared.clone(),
+                    live: live.into(),
+                });
+            }
+        }
+    };
+    changed(
+        "instance_type",
+        &declared.instance_type,
+        &live.instance_type,
+    );
+    changed("ami", &declared.ami, &live.ami);
+
+    for port in &declared.ports {
+        if !live.ports.contains(port) {
+            drifts.push(Drift::Removed(format!("port {port}/tcp")));
+        }
+    }
+    for port in &live.ports {
+        if !declared.ports.contains(port) {
+            drifts.push(Drift::Added(format!("port {port}/tcp")));
+        }
+    }
+    for (id, size) in &live.extra_volumes {
+        drifts.push(Drift::Added(format!("volume {id} ({size} GiB)")));
+    }
+
+    match (&declared.setup, &live.user_data) {
+        (Some(_), None) => drifts.push(Drift::Removed("setup script".into())),
+        (Some(_), Some(user_data)) if user_data.contains(STAGED_MARKER) => {
+            tracing::info!("Setup script was staged on S3, not comparing it");
+        }
+        (Some(setup), Some(user_data)) if !user_data.contains(setup.trim()) => {
+            drifts.push(Drift::Changed {
+                field: "setup script".into(),
+                declared: "current".into(),
+                live: "changed since launch".into(),
+            })
+        }
+        _ => {}
+    }
+    drifts
+}
+
+/// Single TCP ports `groups` let in.
+fn open_ports<'a>(groups: impl Iterator<Item = &'a SecurityGroup>) -> Vec<u16> {
+    let mut ports: Vec<u16> = groups
+        .flat_map(|g| g.ip_permissions())
+        .filter(|p: &&IpPermission| p.ip_protocol() == Some("tcp"))
+        .filter(|p| p.from_port().is_some() && p.from_port() == p.to_port())
+        .filter_map(|p| p.from_port().and_then(|port| u16::try_from(port).ok()))
+        .collect();
+    ports.sort();
+    ports.dedup();
+    ports
+}
+
+fn extra_volumes(instance: &Instance, volumes: &[Volume]) -> Vec<(String, i32)> {
+    let id = instance.instance_id();
+    let root = instance.root_device_name();
+    volumes
+        .iter()
+        .filter(|v| {
+            v.attachments()
+                .iter()
+                .any(|a| a.instance_id() == id && a.device() != root)
+        })
+        .map(|v| {
+            (
+                v.volume_id().unwrap_or_default().to_string(),
+                v.size().unwrap_or_default(),
+            )
+        })
+        .collect()
+}
+
+/// Drift of every instance in `instances` (all of korasi's when empty),
+/// by instance name.
+pub async fn detect(
+    ec2: &EC2,
+    config: &Config,
+    region: &str,
+    setup: &str,
+    instances: &[String],
+) -> Result<Vec<(String, Vec<Drift>)>, EC2Error> {
+    let inventory = ec2.inventory().await?;
+    let lock = Lockfile::load()
+        .map_err(|e| EC2Error::new(e.to_string()))?
+        .unwrap_or_default();
+    let groups: HashMap<&str, &SecurityGroup> = inventory
+        .security_groups
+        .iter()
+        .filter_map(|g| g.group_id().map(|id| (id, g)))
+        .collect();
+    let mut ports = vec![ec2.ssh_port()];
+    ports.extend(&config.verify.ports);
+
+    let mut report = vec![];
+    for instance in &inventory.instances {
+        let id = instance.instance_id().unwrap_or_default();
+        let name = instance
+            .tags()
+            .iter()
+            .find(|t| t.key() == Some("Name"))
+            .and_then(|t| t.value())
+            .unwrap_or(id);
+        if !instances.is_empty() && !instances.iter().any(|i| i == id || i == name) {
+            continue;
+        }
+        let instance_type = instance
+            .instance_type()
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        let ami = match &config.launch.ami {
+            Some(ami) if ami.starts_with("ami-") => Some(ami.clone()),
+            Some(alias) => {
+                let arch = instance
+                    .architecture()
+                    .map(|a| a.as_str())
+                    .unwrap_or_default();
+                lock.ami(region, alias, arch).map(str::to_string)
+            }
+            None => None,
+        };
+        let vars = HashMap::from([
+            ("instance_name", name.to_string()),
+            ("instance_type", instance_type.clone()),
+        ]);
+        let declared = Declared {
+            instance_type: config.launch.instance_type.clone(),
+            ami,
+            ports: ports.clone(),
+            setup: load_setup(setup, &vars)?,
+        };
+        let live = Live {
+            ami: instance.image_id().unwrap_or_default().into(),
+            ports: open_ports(
+                instance
+                    .security_groups()
+                    .iter()
+                    .filter_map(|g| g.group_id())
+                    .filter_map(|id| groups.get(id).copied()),
+            ),
+            extra_volumes: extra_volumes(instance, &inventory.volumes),
+            user_data: ec2.get_user_data(id).await?,
+            instance_type,
+        };
+        report.push((name.to_string(), diff(&declared, &live)));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, Declared, Drift, Live};
+
+    #[test]
+    fn plan_drift() {
+        let declared = Declared {
+            instance_type: Some("t3.large".into()),
+            ami: None,
+            ports: vec![22, 8888],
+            setup: Some("#!/bin/bash\napt-get install -y htop\n".into()),
+        };
+        let live = Live {
+            instance_type: "t3.large".into(),
+            ami: "ami-1".into(),
+            ports: vec![22],
+            extra_volumes: vec![],
+            user_data: Some("#!/bin/bash\napt-get install -y htop\n".into()),
+        };
+        let cases = [
+            (live.clone(), vec!["- port 8888/tcp"]),
+            (
+                Live {
+                    instance_type: "t3.xlarge".into(),
+                    ports: vec![22, 8080, 8888],
+                    extra_volumes: vec![("vol-1".into(), 100)],
+                    user_data: Some("#!/bin/bash\napt-get install -y tmux\n".into()),
+                    ..live.clone()
+                },
+                vec![
+                    "~ instance_type: t3.large -> t3.xlarge",
+                    "+ port 8080/tcp",
+                    "+ volume vol-1 (100 GiB)",
+                    "~ setup script: current -> changed since launch",
+                ],
+            ),
+            (
+                Live {
+                    ports: vec![22, 8888],
+                    user_data: None,
+                    ..live.clone()
+                },
+                vec!["- setup script"],
+            ),
+        ];
+        for (live, expected) in cases {
+            println!("live = {live:?}");
+            let drifts: Vec<String> = diff(&declared, &live)
+                .iter()
+                .map(Drift::to_string)
+                .collect();
+            pretty_assertions::assert_eq!(drifts, expected);
+        }
+    }
+}
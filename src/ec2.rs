@@ -1,15 +1,31 @@
-use std::{net::Ipv4Addr, time::Duration};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+use async_trait::async_trait;
 use aws_sdk_ec2::{
     client::Waiters,
     error::ProvideErrorMetadata,
+    operation::RequestId,
     types::{
-        Filter, Instance, InstanceStateName, InstanceType, IpPermission, IpRange, KeyFormat,
-        KeyPairInfo, KeyType, ResourceType, SecurityGroup, Tag, TagSpecification,
+        Address, AttributeBooleanValue, BlockDeviceMapping, CopyTagsFromSource, DomainType, Filter,
+        IamInstanceProfileSpecification, Image, Instance, InstanceAttributeName,
+        InstanceNetworkInterfaceSpecification, InstanceSpecification, InstanceStateName,
+        InstanceStatus, InstanceType, InternetGateway, IpPermission, KeyFormat, KeyPairInfo,
+        KeyType, Placement, PlacementGroup, PlacementStrategy, ResourceType, SecurityGroup,
+        SecurityGroupRule, SummaryStatus, Tag, TagSpecification, UserIdGroupPair, Volume,
     },
     Client as EC2Client,
 };
+use base64::prelude::*;
 
+use crate::ports::{
+    current_rule, host_cidr, last_used, stale_rules, PortSpec, LAST_USED_TAG, STALE_RULE_AGE,
+};
+use crate::ssh::SSH_PORT;
 use crate::util::UtilImpl as Util;
 
 /// Co-locate all common keys here for now till a flexible
@@ -18,6 +34,196 @@ pub const GLOBAL_TAG_FILTER: &str = "hpc-launcher";
 pub const SSH_KEY_NAME: &str = "ec2-ssh-key";
 pub const SSH_SECURITY_GROUP: &str = "allow-ssh";
 
+/// Prefix of the security groups letting EFA instances of one placement
+/// group (or cluster) reach each other, see `efa_security_group_name`.
+pub const EFA_SECURITY_GROUP_PREFIX: &str = "korasi-efa-";
+
+/// Security group of EFA instances in `placement_group`, or of those in
+/// none.
+pub fn efa_security_group_name(placement_group: Option<&str>) -> String {
+    format!(
+        "{EFA_SECURITY_GROUP_PREFIX}{}",
+        placement_group.unwrap_or("default")
+    )
+}
+
+/// Services answering with the address a request came from, one per family.
+const CHECK_IPV4_URL: &str = "https://checkip.amazonaws.com";
+const CHECK_IPV6_URL: &str = "https://ipv6.icanhazip.com";
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// `key=value` tag that instances must have, eg. `team=ml`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagSelector {
+    pub key: String,
+    pub value: String,
+}
+
+impl std::str::FromStr for TagSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok(TagSelector {
+                key: key.into(),
+                value: value.into(),
+            }),
+            _ => Err(format!("invalid tag `{s}`, expected key=value")),
+        }
+    }
+}
+
+impl std::fmt::Display for TagSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+/// Optional parameters forwarded to `run_instances` when launching.
+#[derive(Debug, Default, Clone)]
+pub struct LaunchOptions {
+    /// Base64 encoded startup script.
+    pub user_data: Option<String>,
+
+    /// Overrides the AMI's default block device mapping (eg. root volume).
+    pub block_device_mappings: Option<Vec<BlockDeviceMapping>>,
+
+    /// Instance profile granting the instance an IAM role.
+    pub iam_instance_profile: Option<IamInstanceProfileSpecification>,
+
+    /// Tags applied along with the name.
+    pub tags: Vec<TagSelector>,
+
+    /// Name of the placement group to launch into.
+    pub placement_group: Option<String>,
+
+    /// Launch with an Elastic Fabric Adapter as the primary network
+    /// interface.
+    pub efa: bool,
+
+    /// Availability zone to launch in, AWS picks one otherwise.
+    pub availability_zone: Option<String>,
+}
+
+/// How long `EC2Impl::inventory` reuses its last answer.
+const INVENTORY_TTL: Duration = Duration::from_secs(30);
+
+/// An inventory and when it was fetched.
+type CachedInventory = (Instant, Arc<ResourceInventory>);
+
+/// Every resource korasi created in the region, as listed by
+/// `EC2Impl::inventory`.
+#[derive(Debug, Default, Clone)]
+pub struct ResourceInventory {
+    /// Non-terminated instances.
+    pub instances: Vec<Instance>,
+    /// Volumes tagged at launch or attached to `instances`.
+    pub volumes: Vec<Volume>,
+    /// The SSH group, then the EFA groups.
+    pub security_groups: Vec<SecurityGroup>,
+    pub addresses: Vec<Address>,
+    pub key_pairs: Vec<KeyPairInfo>,
+    /// AMIs owned by the account with the tool's tag.
+    pub images: Vec<Image>,
+    pub placement_groups: Vec<PlacementGroup>,
+    pub network: Option<Network>,
+}
+
+/// CIDR blocks of the VPC created by `EC2Impl::create_network`, and of its
+/// public subnet.
+pub const NETWORK_CIDR: &str = "10.77.0.0/16";
+pub const SUBNET_CIDR: &str = "10.77.0.0/20";
+
+/// Tag on the internet gateway of a network with the id of its VPC, to
+/// find it again when attaching it failed.
+pub const NETWORK_TAG: &str = "korasi:network";
+
+/// The VPC made by `korasi network init`, for accounts without a default
+/// VPC. Parts are optional so a half created network can be torn down.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Network {
+    pub vpc_id: String,
+    pub subnet_id: Option<String>,
+    pub internet_gateway_id: Option<String>,
+    pub internet_gateway_attached: bool,
+    pub route_table_id: Option<String>,
+}
+
+/// Result of the EC2 system/instance status checks along with any
+/// upcoming scheduled events (retirement, reboot, maintenance).
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct InstanceHealth {
+    pub system_status: Option<SummaryStatus>,
+    pub instance_status: Option<SummaryStatus>,
+    pub events: Vec<String>,
+}
+
+impl InstanceHealth {
+    pub fn is_impaired(&self) -> bool {
+        self.system_status == Some(SummaryStatus::Impaired)
+            || self.instance_status == Some(SummaryStatus::Impaired)
+    }
+}
+
+impl From<&InstanceStatus> for InstanceHealth {
+    fn from(value: &InstanceStatus) -> Self {
+        InstanceHealth {
+            system_status: value.system_status().and_then(|s| s.status()).cloned(),
+            instance_status: value.instance_status().and_then(|s| s.status()).cloned(),
+            events: value
+                .events()
+                .iter()
+                // Past events are kept around with a "[Completed]" description.
+                .filter(|e| !e.description().unwrap_or("").starts_with("[Completed]"))
+                .map(|e| {
+                    let code = e.code().map(|c| c.to_string()).unwrap_or_default();
+                    match e.not_before() {
+                        Some(t) => format!("{code} after {t}"),
+                        None => code,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for InstanceHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = |s: &Option<SummaryStatus>| {
+            s.as_ref()
+                .map(|s| s.to_string())
+                .unwrap_or("unknown".into())
+        };
+        if self.is_impaired() {
+            write!(
+                f,
+                "IMPAIRED (system = {}, instance = {})",
+                status(&self.system_status),
+                status(&self.instance_status)
+            )?;
+        } else if self.system_status == self.instance_status {
+            write!(f, "{}", status(&self.system_status))?;
+        } else {
+            write!(
+                f,
+                "system = {}, instance = {}",
+                status(&self.system_status),
+                status(&self.instance_status)
+            )?;
+        }
+        if !self.events.is_empty() {
+            write!(f, ", scheduled = [{}]", self.events.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct EC2Impl {
     /// AWS sdk client to access EC2 resources.
@@ -25,24 +231,93 @@ pub struct EC2Impl {
 
     /// Override default `GLOBAL_TAG_FILTER`.
     custom_tag: Option<String>,
+
+    /// Applied along with `application` to created resources, eg. tags
+    /// required by an organization's tag policy.
+    extra_tags: Vec<TagSelector>,
+
+    /// Last `inventory`, shared by clones.
+    inventory: Arc<Mutex<Option<CachedInventory>>>,
+
+    /// Tool's own VPC to launch into instead of the default one.
+    network: Option<Network>,
+
+    /// Key pair instances are launched with, `SSH_KEY_NAME` by default.
+    key_name: String,
+
+    /// Port sshd listens on, which SSH ingress rules open.
+    ssh_port: u16,
 }
 
 impl EC2Impl {
     pub fn new(client: EC2Client, custom_tag: Option<String>) -> Self {
-        EC2Impl { client, custom_tag }
+        EC2Impl {
+            client,
+            custom_tag,
+            extra_tags: vec![],
+            inventory: Arc::default(),
+            network: None,
+            key_name: SSH_KEY_NAME.into(),
+            ssh_port: SSH_PORT,
+        }
+    }
+
+    /// Create security groups and launch instances in `network`.
+    pub fn with_network(mut self, network: Option<Network>) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// The tool's own VPC, if instances launch in one.
+    pub fn network(&self) -> Option<&Network> {
+        self.network.as_ref()
+    }
+
+    /// Use the key pair `name` instead of `SSH_KEY_NAME`, eg. one per project.
+    pub fn with_key_name(mut self, name: impl Into<String>) -> Self {
+        self.key_name = name.into();
+        self
+    }
+
+    /// Value of the `application` tag on every resource of this tool.
+    pub fn project_tag(&self) -> &str {
+        self.custom_tag.as_deref().unwrap_or(GLOBAL_TAG_FILTER)
+    }
+
+    pub fn key_name(&self) -> &str {
+        &self.key_name
+    }
+
+    /// Let in `port` rather than `SSH_PORT`, for AMIs whose sshd listens
+    /// elsewhere.
+    pub fn with_ssh_port(mut self, port: u16) -> Self {
+        self.ssh_port = port;
+        self
+    }
+
+    pub fn ssh_port(&self) -> u16 {
+        self.ssh_port
+    }
+
+    /// Tag resources created from now on with `tags` too.
+    pub fn with_tags(mut self, tags: Vec<TagSelector>) -> Self {
+        self.extra_tags = tags;
+        self
     }
 
     pub fn create_tag(&self, res_type: ResourceType) -> TagSpecification {
+        let mut tags = vec![Tag::builder()
+            .set_key(Some("application".into()))
+            .set_value(Some(self.project_tag().to_string()))
+            .build()];
+        tags.extend(
+            self.extra_tags
+                .iter()
+                .map(|t| Tag::builder().key(&t.key).value(&t.value).build()),
+        );
         TagSpecification::builder()
             .set_resource_type(Some(res_type))
-            .set_tags(Some(vec![Tag::builder()
-                .set_key(Some("application".into()))
-                .set_value(Some(
-                    self.custom_tag
-                        .clone()
-                        .unwrap_or(GLOBAL_TAG_FILTER.to_string()),
-                ))
-                .build()]))
+            .set_tags(Some(tags))
             .build()
     }
 
@@ -74,15 +349,36 @@ impl EC2Impl {
         Ok((info, material))
     }
 
+    /// Register the OpenSSH `public_key` as key pair `name`, keeping its
+    /// private key wherever it already is.
+    pub async fn import_key_pair(
+        &self,
+        name: &str,
+        public_key: &str,
+    ) -> Result<KeyPairInfo, EC2Error> {
+        tracing::info!("Importing key pair {name}");
+        let output = self
+            .client
+            .import_key_pair()
+            .key_name(name)
+            .public_key_material(aws_sdk_ec2::primitives::Blob::new(public_key.trim()))
+            .set_tag_specifications(Some(vec![self.create_tag(ResourceType::KeyPair)]))
+            .send()
+            .await?;
+        Ok(KeyPairInfo::builder()
+            .set_key_name(output.key_name)
+            .set_key_fingerprint(output.key_fingerprint)
+            .set_key_pair_id(output.key_pair_id)
+            .build())
+    }
+
+    /// DescribeKeyPairs isn't paginated, it always returns every match.
     pub async fn list_key_pair(&self, key_names: &str) -> Result<Vec<KeyPairInfo>, EC2Error> {
         let output = self
             .client
             .describe_key_pairs()
             .key_names(key_names)
-            .set_filters(Some(vec![Filter::builder()
-                .set_name(Some("tag:application".into()))
-                .set_values(Some(vec![GLOBAL_TAG_FILTER.into()]))
-                .build()]))
+            .filters(self.tag_filter())
             .send()
             .await?;
         Ok(output.key_pairs.unwrap_or_default())
@@ -99,6 +395,449 @@ impl EC2Impl {
         Ok(())
     }
 
+    /// Filter on the tag applied by `create_tag`.
+    fn tag_filter(&self) -> Filter {
+        Filter::builder()
+            .name("tag:application")
+            .values(self.project_tag())
+            .build()
+    }
+
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
+    pub async fn allocate_address(&self) -> Result<Address, EC2Error> {
+        let output = self
+            .client
+            .allocate_address()
+            .domain(DomainType::Vpc)
+            .tag_specifications(self.create_tag(ResourceType::ElasticIp))
+            .send()
+            .await?;
+        tracing::info!("Allocated elastic IP {:?}", output.public_ip());
+
+        Ok(Address::builder()
+            .set_allocation_id(output.allocation_id)
+            .set_public_ip(output.public_ip)
+            .set_domain(output.domain)
+            .build())
+    }
+
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
+    pub async fn create_placement_group(
+        &self,
+        name: &str,
+        strategy: PlacementStrategy,
+    ) -> Result<PlacementGroup, EC2Error> {
+        let output = self
+            .client
+            .create_placement_group()
+            .group_name(name)
+            .strategy(strategy)
+            .tag_specifications(self.create_tag(ResourceType::PlacementGroup))
+            .send()
+            .await?;
+        tracing::info!("Created placement group {name}");
+        output
+            .placement_group
+            .ok_or_else(|| EC2Error::new(format!("Missing placement group {name} after creation")))
+    }
+
+    /// The placement group named `name`, whether created by this tool or not.
+    pub async fn describe_placement_group(
+        &self,
+        name: &str,
+    ) -> Result<Option<PlacementGroup>, EC2Error> {
+        let output = self
+            .client
+            .describe_placement_groups()
+            .filters(Filter::builder().name("group-name").values(name).build())
+            .send()
+            .await?;
+        Ok(output
+            .placement_groups
+            .unwrap_or_default()
+            .into_iter()
+            .next())
+    }
+
+    /// Placement groups created by this tool. Not paginated by the API.
+    pub async fn describe_placement_groups(&self) -> Result<Vec<PlacementGroup>, EC2Error> {
+        let output = self
+            .client
+            .describe_placement_groups()
+            .filters(self.tag_filter())
+            .send()
+            .await?;
+        Ok(output.placement_groups.unwrap_or_default())
+    }
+
+    /// Fails while instances are still in the group.
+    pub async fn delete_placement_group(&self, name: &str) -> Result<(), EC2Error> {
+        tracing::info!("Deleting placement group {name}");
+        self.client
+            .delete_placement_group()
+            .group_name(name)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Create a VPC with a public subnet routed to an internet gateway,
+    /// all tagged. DNS hostnames are enabled for public DNS names.
+    ///
+    /// Parts of `existing`, a network left half created, are reused, and
+    /// only the missing ones created, so a failed `network init` can be
+    /// run again.
+    pub async fn create_network(&self, existing: Option<Network>) -> Result<Network, EC2Error> {
+        let mut network = match existing {
+            Some(network) => network,
+            None => {
+                let vpc_id = self
+                    .client
+                    .create_vpc()
+                    .cidr_block(NETWORK_CIDR)
+                    .tag_specifications(self.create_tag(ResourceType::Vpc))
+                    .send()
+                    .await?
+                    .vpc
+                    .and_then(|v| v.vpc_id)
+                    .ok_or_else(|| EC2Error::new("Missing VPC id after creation"))?;
+                tracing::info!("Created VPC {vpc_id}");
+                Network {
+                    vpc_id,
+                    ..Default::default()
+                }
+            }
+        };
+        let vpc_id = network.vpc_id.clone();
+        // Only one attribute per call.
+        self.client
+            .modify_vpc_attribute()
+            .vpc_id(&vpc_id)
+            .enable_dns_hostnames(AttributeBooleanValue::builder().value(true).build())
+            .send()
+            .await?;
+
+        let gateway_id = match network.internet_gateway_id.clone() {
+            Some(gateway_id) => gateway_id,
+            None => {
+                let mut tags = self.create_tag(ResourceType::InternetGateway);
+                tags.tags
+                    .get_or_insert_with(Vec::new)
+                    .push(Tag::builder().key(NETWORK_TAG).value(&vpc_id).build());
+                let gateway_id = self
+                    .client
+                    .create_internet_gateway()
+                    .tag_specifications(tags)
+                    .send()
+                    .await?
+                    .internet_gateway
+                    .and_then(|g| g.internet_gateway_id)
+                    .ok_or_else(|| EC2Error::new("Missing internet gateway id after creation"))?;
+                network.internet_gateway_id = Some(gateway_id.clone());
+                gateway_id
+            }
+        };
+        if !network.internet_gateway_attached {
+            self.client
+                .attach_internet_gateway()
+                .internet_gateway_id(&gateway_id)
+                .vpc_id(&vpc_id)
+                .send()
+                .await?;
+            network.internet_gateway_attached = true;
+        }
+
+        let subnet_id = match network.subnet_id.clone() {
+            Some(subnet_id) => subnet_id,
+            None => {
+                let subnet_id = self
+                    .client
+                    .create_subnet()
+                    .vpc_id(&vpc_id)
+                    .cidr_block(SUBNET_CIDR)
+                    .tag_specifications(self.create_tag(ResourceType::Subnet))
+                    .send()
+                    .await?
+                    .subnet
+                    .and_then(|s| s.subnet_id)
+                    .ok_or_else(|| EC2Error::new("Missing subnet id after creation"))?;
+                network.subnet_id = Some(subnet_id.clone());
+                subnet_id
+            }
+        };
+        self.client
+            .modify_subnet_attribute()
+            .subnet_id(&subnet_id)
+            .map_public_ip_on_launch(AttributeBooleanValue::builder().value(true).build())
+            .send()
+            .await?;
+
+        let route_table_id = match network.route_table_id.clone() {
+            Some(route_table_id) => route_table_id,
+            None => {
+                let route_table_id = self
+                    .client
+                    .create_route_table()
+                    .vpc_id(&vpc_id)
+                    .tag_specifications(self.create_tag(ResourceType::RouteTable))
+                    .send()
+                    .await?
+                    .route_table
+                    .and_then(|r| r.route_table_id)
+                    .ok_or_else(|| EC2Error::new("Missing route table id after creation"))?;
+                network.route_table_id = Some(route_table_id.clone());
+                route_table_id
+            }
+        };
+        let route_table = self
+            .client
+            .describe_route_tables()
+            .route_table_ids(&route_table_id)
+            .send()
+            .await?
+            .route_tables
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .ok_or_else(|| EC2Error::new(format!("Route table {route_table_id} not found")))?;
+        let default_route = route_table
+            .routes()
+            .iter()
+            .find(|r| r.destination_cidr_block() == Some("0.0.0.0/0"));
+        match default_route {
+            Some(route) if route.gateway_id() == Some(gateway_id.as_str()) => {}
+            // Eg. left pointing at a deleted gateway.
+            Some(_) => {
+                self.client
+                    .replace_route()
+                    .route_table_id(&route_table_id)
+                    .destination_cidr_block("0.0.0.0/0")
+                    .gateway_id(&gateway_id)
+                    .send()
+                    .await?;
+            }
+            None => {
+                self.client
+                    .create_route()
+                    .route_table_id(&route_table_id)
+                    .destination_cidr_block("0.0.0.0/0")
+                    .gateway_id(&gateway_id)
+                    .send()
+                    .await?;
+            }
+        }
+        if !route_table
+            .associations()
+            .iter()
+            .any(|a| a.subnet_id() == Some(subnet_id.as_str()))
+        {
+            self.client
+                .associate_route_table()
+                .route_table_id(&route_table_id)
+                .subnet_id(&subnet_id)
+                .send()
+                .await?;
+        }
+
+        Ok(network)
+    }
+
+    /// The VPC created by this tool and what was made in it, if any.
+    pub async fn describe_network(&self) -> Result<Option<Network>, EC2Error> {
+        let output = self
+            .client
+            .describe_vpcs()
+            .filters(self.tag_filter())
+            .send()
+            .await?;
+        let Some(vpc_id) = output.vpcs().iter().find_map(|v| v.vpc_id()) else {
+            return Ok(None);
+        };
+        let in_vpc = |name: &str| Filter::builder().name(name).values(vpc_id).build();
+
+        let (subnets, gateways, route_tables) = tokio::try_join!(
+            async {
+                Ok::<_, EC2Error>(
+                    self.client
+                        .describe_subnets()
+                        .filters(in_vpc("vpc-id"))
+                        .filters(self.tag_filter())
+                        .send()
+                        .await?,
+                )
+            },
+            // Not by attachment, to also find one left detached.
+            async {
+                Ok::<_, EC2Error>(
+                    self.client
+                        .describe_internet_gateways()
+                        .filters(self.tag_filter())
+                        .send()
+                        .await?,
+                )
+            },
+            async {
+                Ok::<_, EC2Error>(
+                    self.client
+                        .describe_route_tables()
+                        .filters(in_vpc("vpc-id"))
+                        .filters(self.tag_filter())
+                        .send()
+                        .await?,
+                )
+            },
+        )?;
+
+        let attached =
+            |g: &InternetGateway| g.attachments().iter().any(|a| a.vpc_id() == Some(vpc_id));
+        let gateway = gateways
+            .internet_gateways()
+            .iter()
+            .find(|g| attached(g))
+            .or_else(|| {
+                gateways.internet_gateways().iter().find(|g| {
+                    g.attachments().is_empty()
+                        && g.tags()
+                            .iter()
+                            .any(|t| t.key() == Some(NETWORK_TAG) && t.value() == Some(vpc_id))
+                })
+            });
+        Ok(Some(Network {
+            vpc_id: vpc_id.into(),
+            subnet_id: subnets
+                .subnets()
+                .iter()
+                .find_map(|s| s.subnet_id())
+                .map(str::to_string),
+            internet_gateway_id: gateway
+                .and_then(|g| g.internet_gateway_id())
+                .map(str::to_string),
+            internet_gateway_attached: gateway.is_some_and(attached),
+            route_table_id: route_tables
+                .route_tables()
+                .iter()
+                .find_map(|r| r.route_table_id())
+                .map(str::to_string),
+        }))
+    }
+
+    /// Tear down `network`. Fails while instances or security groups are
+    /// still in it.
+    pub async fn delete_network(&self, network: &Network) -> Result<(), EC2Error> {
+        let vpc_id = &network.vpc_id;
+        if let Some(gateway_id) = &network.internet_gateway_id {
+            if network.internet_gateway_attached {
+                self.client
+                    .detach_internet_gateway()
+                    .internet_gateway_id(gateway_id)
+                    .vpc_id(vpc_id)
+                    .send()
+                    .await?;
+            }
+            self.client
+                .delete_internet_gateway()
+                .internet_gateway_id(gateway_id)
+                .send()
+                .await?;
+        }
+        // Deleting the subnet drops its route table association.
+        if let Some(subnet_id) = &network.subnet_id {
+            self.client
+                .delete_subnet()
+                .subnet_id(subnet_id)
+                .send()
+                .await?;
+        }
+        if let Some(route_table_id) = &network.route_table_id {
+            self.client
+                .delete_route_table()
+                .route_table_id(route_table_id)
+                .send()
+                .await?;
+        }
+        self.client.delete_vpc().vpc_id(vpc_id).send().await?;
+        tracing::info!("Deleted VPC {vpc_id}");
+        Ok(())
+    }
+
+    /// Names of the region's availability zones that are up, sorted.
+    pub async fn describe_availability_zones(&self) -> Result<Vec<String>, EC2Error> {
+        let output = self
+            .client
+            .describe_availability_zones()
+            .filters(
+                Filter::builder()
+                    .name("zone-type")
+                    .values("availability-zone")
+                    .build(),
+            )
+            .filters(Filter::builder().name("state").values("available").build())
+            .send()
+            .await?;
+        let mut zones: Vec<String> = output
+            .availability_zones()
+            .iter()
+            .filter_map(|z| z.zone_name().map(str::to_string))
+            .collect();
+        zones.sort();
+        Ok(zones)
+    }
+
+    /// Elastic IPs allocated by this tool.
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
+    pub async fn describe_addresses(&self) -> Result<Vec<Address>, EC2Error> {
+        let output = self
+            .client
+            .describe_addresses()
+            .filters(self.tag_filter())
+            .send()
+            .await?;
+        Ok(output.addresses.unwrap_or_default())
+    }
+
+    pub async fn associate_address(
+        &self,
+        allocation_id: &str,
+        instance_id: &str,
+    ) -> Result<(), EC2Error> {
+        tracing::info!("Associating {allocation_id} with {instance_id}");
+        self.client
+            .associate_address()
+            .allocation_id(allocation_id)
+            .instance_id(instance_id)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn disassociate_address(&self, association_id: &str) -> Result<(), EC2Error> {
+        tracing::info!("Disassociating {association_id}");
+        self.client
+            .disassociate_address()
+            .association_id(association_id)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Disassociates the address first if needed, since associated
+    /// addresses can't be released.
+    pub async fn release_address(&self, address: &Address) -> Result<(), EC2Error> {
+        if let Some(association_id) = address.association_id() {
+            self.disassociate_address(association_id).await?;
+        }
+        let allocation_id = address
+            .allocation_id()
+            .ok_or_else(|| EC2Error::new("Missing allocation id when releasing address"))?;
+        tracing::info!("Releasing {allocation_id}");
+        self.client
+            .release_address()
+            .allocation_id(allocation_id)
+            .send()
+            .await?;
+        Ok(())
+    }
+
     pub async fn create_security_group(
         &self,
         name: &str,
@@ -110,6 +849,7 @@ impl EC2Impl {
             .create_security_group()
             .group_name(name)
             .description(description)
+            .set_vpc_id(self.network.as_ref().map(|n| n.vpc_id.clone()))
             .set_tag_specifications(Some(vec![self.create_tag(ResourceType::SecurityGroup)]))
             .send()
             .await
@@ -131,24 +871,40 @@ impl EC2Impl {
         Ok(group)
     }
 
-    /// Find a single security group, by name. Returns Err if multiple groups are found.
+    /// Find a single security group, by name or id, in the tool's VPC if
+    /// there is one. Returns Err if multiple groups are found.
     pub async fn describe_security_group(
         &self,
         group_name: &str,
     ) -> Result<Option<SecurityGroup>, EC2Error> {
-        let describe_output = self
+        // `GroupNames` only matches groups of the default VPC.
+        let by = if group_name.starts_with("sg-") {
+            "group-id"
+        } else {
+            "group-name"
+        };
+        let mut filters = vec![
+            Filter::builder().name(by).values(group_name).build(),
+            self.tag_filter(),
+        ];
+        if let Some(network) = &self.network {
+            filters.push(
+                Filter::builder()
+                    .name("vpc-id")
+                    .values(&network.vpc_id)
+                    .build(),
+            );
+        }
+        let mut groups: Vec<_> = self
             .client
             .describe_security_groups()
-            .group_names(group_name)
-            .set_filters(Some(vec![Filter::builder()
-                .set_name(Some("tag:application".into()))
-                .set_values(Some(vec![GLOBAL_TAG_FILTER.into()]))
-                .build()]))
+            .set_filters(Some(filters))
+            .into_paginator()
+            .items()
             .send()
+            .collect::<Result<_, _>>()
             .await?;
 
-        let mut groups = describe_output.security_groups.unwrap_or_default();
-
         match groups.len() {
             0 => Ok(None),
             1 => Ok(Some(groups.remove(0))),
@@ -158,108 +914,703 @@ impl EC2Impl {
         }
     }
 
-    /// Add an ingress rule to a security group explicitly allowing IPv4 address
-    /// as {ip}/32 over TCP port 22.
-    pub async fn authorize_security_group_ssh_ingress(
+    /// Add an ingress rule to a security group explicitly allowing IPv4
+    /// ({ip}/32) or IPv6 ({ip}/128) addresses over the SSH port. Rules are
+    /// tagged with when they were last used, for `prune_ssh_rules`.
+    pub async fn authorize_security_group_ssh_ingress(
+        &self,
+        group_id: &str,
+        ingress_ips: Vec<IpAddr>,
+    ) -> Result<(), EC2Error> {
+        tracing::info!("Authorizing ingress for security group {group_id}");
+        let ssh = PortSpec {
+            from: self.ssh_port as i32,
+            to: self.ssh_port as i32,
+            protocol: "tcp".into(),
+        };
+        let cidrs: Vec<String> = ingress_ips.into_iter().map(host_cidr).collect();
+        self.client
+            .authorize_security_group_ingress()
+            .group_id(group_id)
+            .ip_permissions(ssh.to_permission(&cidrs))
+            .tag_specifications(
+                TagSpecification::builder()
+                    .resource_type(ResourceType::SecurityGroupRule)
+                    .tags(
+                        Tag::builder()
+                            .key(LAST_USED_TAG)
+                            .value(now().to_string())
+                            .build(),
+                    )
+                    .build(),
+            )
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Ingress and egress rules of a security group.
+    pub async fn describe_security_group_rules(
+        &self,
+        group_id: &str,
+    ) -> Result<Vec<SecurityGroupRule>, EC2Error> {
+        Ok(self
+            .client
+            .describe_security_group_rules()
+            .filters(Filter::builder().name("group-id").values(group_id).build())
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<_, _>>()
+            .await?)
+    }
+
+    /// Revoke the SSH rules `stale_rules` picks, returning their CIDRs.
+    pub async fn prune_ssh_rules(
+        &self,
+        group_id: &str,
+        rules: &[SecurityGroupRule],
+        current: &[String],
+        max_age: Duration,
+        all: bool,
+    ) -> Result<Vec<String>, EC2Error> {
+        let stale = stale_rules(rules, self.ssh_port, current, now(), max_age, all);
+        if stale.is_empty() {
+            return Ok(vec![]);
+        }
+        tracing::info!("Revoking {} stale SSH rules of {group_id}", stale.len());
+        self.client
+            .revoke_security_group_ingress()
+            .group_id(group_id)
+            .set_security_group_rule_ids(Some(
+                stale
+                    .iter()
+                    .filter_map(|r| r.security_group_rule_id().map(str::to_string))
+                    .collect(),
+            ))
+            .send()
+            .await?;
+        Ok(stale
+            .iter()
+            .filter_map(|r| r.cidr_ipv4().or(r.cidr_ipv6()).map(str::to_string))
+            .collect())
+    }
+
+    pub async fn authorize_security_group_ingress(
+        &self,
+        group_id: &str,
+        permissions: Vec<IpPermission>,
+    ) -> Result<(), EC2Error> {
+        self.client
+            .authorize_security_group_ingress()
+            .group_id(group_id)
+            .set_ip_permissions(Some(permissions))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn revoke_security_group_ingress(
+        &self,
+        group_id: &str,
+        permissions: Vec<IpPermission>,
+    ) -> Result<(), EC2Error> {
+        tracing::info!("Revoking ingress for security group {group_id}");
+        self.client
+            .revoke_security_group_ingress()
+            .group_id(group_id)
+            .set_ip_permissions(Some(permissions))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Revoke the ingress `rules` of a security group.
+    pub async fn revoke_security_group_rules(
+        &self,
+        group_id: &str,
+        rules: &[&SecurityGroupRule],
+    ) -> Result<(), EC2Error> {
+        tracing::info!(
+            "Revoking {} rules of security group {group_id}",
+            rules.len()
+        );
+        self.client
+            .revoke_security_group_ingress()
+            .group_id(group_id)
+            .set_security_group_rule_ids(Some(
+                rules
+                    .iter()
+                    .filter_map(|r| r.security_group_rule_id().map(str::to_string))
+                    .collect(),
+            ))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_security_group(&self, group_id: &str) -> Result<(), EC2Error> {
+        tracing::info!("Deleting security group {group_id}");
+        self.client
+            .delete_security_group()
+            .group_id(group_id)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, key_pair, security_groups, opts), fields(phase = "api"))]
+    pub async fn create_instances<'a>(
+        &self,
+        instance_name: &str,
+        image_id: &'a str,
+        instance_type: InstanceType,
+        key_pair: &'a KeyPairInfo,
+        security_groups: Vec<&'a SecurityGroup>,
+        opts: LaunchOptions,
+    ) -> Result<Vec<String>, EC2Error> {
+        self.forget_inventory();
+        let tags = opts.tags;
+        let group_ids: Vec<String> = security_groups
+            .iter()
+            .filter_map(|sg| sg.group_id.clone())
+            .collect();
+        let subnet_id = self.network.as_ref().and_then(|n| n.subnet_id.clone());
+        // Security groups (and the subnet) go on the interface when one is
+        // given.
+        let (group_ids, network_interfaces) = if opts.efa {
+            let interface = InstanceNetworkInterfaceSpecification::builder()
+                .device_index(0)
+                .interface_type("efa")
+                .associate_public_ip_address(true)
+                .delete_on_termination(true)
+                .set_groups(Some(group_ids))
+                .set_subnet_id(subnet_id.clone())
+                .build();
+            (None, Some(vec![interface]))
+        } else {
+            (Some(group_ids), None)
+        };
+        let run_instances = self
+            .client
+            .run_instances()
+            .image_id(image_id)
+            .instance_type(instance_type)
+            .key_name(
+                key_pair
+                    .key_name()
+                    .ok_or_else(|| EC2Error::new("Missing key name when launching instance"))?,
+            )
+            .set_security_group_ids(group_ids)
+            .set_subnet_id(subnet_id.filter(|_| network_interfaces.is_none()))
+            .set_network_interfaces(network_interfaces)
+            .set_user_data(opts.user_data)
+            .set_block_device_mappings(opts.block_device_mappings)
+            .set_iam_instance_profile(opts.iam_instance_profile)
+            .set_placement(
+                (opts.placement_group.is_some() || opts.availability_zone.is_some()).then(|| {
+                    Placement::builder()
+                        .set_group_name(opts.placement_group)
+                        .set_availability_zone(opts.availability_zone)
+                        .build()
+                }),
+            )
+            .set_tag_specifications(Some(vec![
+                self.create_tag(ResourceType::Instance),
+                self.create_tag(ResourceType::Volume),
+            ]))
+            .min_count(1)
+            .max_count(1)
+            .send()
+            .await?;
+
+        if run_instances.instances().is_empty() {
+            return Err(EC2Error::new("Failed to create instance"));
+        }
+
+        let instance_ids: Vec<String> = run_instances
+            .instances()
+            .iter()
+            .filter_map(|i| i.instance_id().map(str::to_string))
+            .collect();
+        for instance_id in &instance_ids {
+            let mut request = self
+                .client
+                .create_tags()
+                .resources(instance_id)
+                .tags(Tag::builder().key("Name").value(instance_name).build());
+            for t in &tags {
+                request = request.tags(Tag::builder().key(&t.key).value(&t.value).build());
+            }
+            let response = request.send().await;
+
+            match response {
+                Ok(_) => tracing::info!("Created {instance_id} and applied tags."),
+                Err(err) => {
+                    tracing::info!("Error applying tags to {instance_id}: {err:?}");
+                    // Untagged instances would be invisible to korasi, so
+                    // don't leave any running.
+                    self.rollback_instances(&instance_ids).await;
+                    return Err(err.into());
+                }
+            }
+        }
+
+        Ok(instance_ids)
+    }
+
+    /// Add (or overwrite) `tags` on `instance_ids` (comma separated).
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
+    pub async fn add_tags(&self, instance_ids: &str, tags: &[TagSelector]) -> Result<(), EC2Error> {
+        let mut request = self.client.create_tags();
+        for id in instance_ids.split(",") {
+            request = request.resources(id);
+        }
+        for t in tags {
+            request = request.tags(Tag::builder().key(&t.key).value(&t.value).build());
+        }
+        request.send().await?;
+        Ok(())
+    }
+
+    /// Remove the tags with `keys`, whatever their value, from `instance_ids`
+    /// (comma separated).
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
+    pub async fn remove_tags(&self, instance_ids: &str, keys: &[String]) -> Result<(), EC2Error> {
+        let mut request = self.client.delete_tags();
+        for id in instance_ids.split(",") {
+            request = request.resources(id);
+        }
+        for key in keys {
+            request = request.tags(Tag::builder().key(key).build());
+        }
+        request.send().await?;
+        Ok(())
+    }
+
+    /// Terminate instances of a launch that failed halfway. Failures are
+    /// only reported, the launch error matters more.
+    async fn rollback_instances(&self, instance_ids: &[String]) {
+        eprintln!("Launch failed, terminating {}...", instance_ids.join(", "));
+        let res = self
+            .client
+            .terminate_instances()
+            .set_instance_ids(Some(instance_ids.to_vec()))
+            .send()
+            .await;
+        if let Err(err) = res {
+            eprintln!(
+                "Failed to terminate {}, delete them by hand: {}",
+                instance_ids.join(", "),
+                EC2Error::from(err)
+            );
+        }
+    }
+
+    /// Status code and message of a spot request.
+    pub async fn describe_spot_request_status(
+        &self,
+        request_id: &str,
+    ) -> Result<Option<(String, String)>, EC2Error> {
+        let output = self
+            .client
+            .describe_spot_instance_requests()
+            .spot_instance_request_ids(request_id)
+            .send()
+            .await?;
+        Ok(output
+            .spot_instance_requests()
+            .first()
+            .and_then(|r| r.status())
+            .and_then(|s| {
+                Some((
+                    s.code()?.to_string(),
+                    s.message().unwrap_or_default().to_string(),
+                ))
+            }))
+    }
+
+    /// Find a single AMI by id.
+    pub async fn describe_image(&self, image_id: &str) -> Result<Image, EC2Error> {
+        let output = self
+            .client
+            .describe_images()
+            .image_ids(image_id)
+            .send()
+            .await?;
+
+        output
+            .images
+            .unwrap_or_default()
+            .pop()
+            .ok_or_else(|| EC2Error::new(format!("Could not find image with id {image_id}")))
+    }
+
+    /// Available machine images matching the filters, newest first.
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
+    pub async fn describe_images(
+        &self,
+        owner: &str,
+        architectures: Vec<String>,
+        name_pattern: &str,
+    ) -> Result<Vec<Image>, EC2Error> {
+        let mut filters = vec![
+            Filter::builder().name("state").values("available").build(),
+            Filter::builder()
+                .name("image-type")
+                .values("machine")
+                .build(),
+            Filter::builder().name("name").values(name_pattern).build(),
+        ];
+        if !architectures.is_empty() {
+            filters.push(
+                Filter::builder()
+                    .name("architecture")
+                    .set_values(Some(architectures))
+                    .build(),
+            );
+        }
+
+        let output = self
+            .client
+            .describe_images()
+            .owners(owner)
+            .set_filters(Some(filters))
+            .send()
+            .await?;
+
+        let mut images = output.images.unwrap_or_default();
+        // Creation dates are ISO 8601, so they sort lexicographically.
+        images.sort_by(|a, b| b.creation_date().cmp(&a.creation_date()));
+        Ok(images)
+    }
+
+    /// Current Linux spot price (USD/hour) of every instance type offered
+    /// in the region, taking the cheapest availability zone.
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
+    pub async fn describe_spot_prices(&self) -> Result<HashMap<String, f64>, EC2Error> {
+        // Starting from now only returns the price currently in effect.
+        let now = aws_sdk_ec2::primitives::DateTime::from(std::time::SystemTime::now());
+        let mut prices: HashMap<String, f64> = HashMap::new();
+        let mut next_token = None;
+        loop {
+            let output = self
+                .client
+                .describe_spot_price_history()
+                .product_descriptions("Linux/UNIX")
+                .start_time(now)
+                .set_next_token(next_token)
+                .send()
+                .await?;
+
+            for entry in output.spot_price_history() {
+                let (Some(t), Some(price)) = (
+                    entry.instance_type(),
+                    entry.spot_price().and_then(|p| p.parse::<f64>().ok()),
+                ) else {
+                    continue;
+                };
+                prices
+                    .entry(t.to_string())
+                    .and_modify(|p| *p = p.min(price))
+                    .or_insert(price);
+            }
+
+            next_token = output
+                .next_token()
+                .filter(|t| !t.is_empty())
+                .map(String::from);
+            if next_token.is_none() {
+                break;
+            }
+        }
+        Ok(prices)
+    }
+
+    /// Architectures an instance type can run, eg. `["x86_64", "i386"]`.
+    pub async fn supported_architectures(
+        &self,
+        instance_type: InstanceType,
+    ) -> Result<Vec<String>, EC2Error> {
+        let output = self
+            .client
+            .describe_instance_types()
+            .instance_types(instance_type)
+            .send()
+            .await?;
+
+        Ok(output
+            .instance_types()
+            .iter()
+            .filter_map(|t| t.processor_info())
+            .flat_map(|p| p.supported_architectures())
+            .map(|a| a.as_str().to_string())
+            .collect())
+    }
+
+    /// Whether an instance type supports Elastic Fabric Adapters.
+    pub async fn efa_supported(&self, instance_type: InstanceType) -> Result<bool, EC2Error> {
+        let output = self
+            .client
+            .describe_instance_types()
+            .instance_types(instance_type)
+            .send()
+            .await?;
+
+        Ok(output
+            .instance_types()
+            .iter()
+            .filter_map(|t| t.network_info())
+            .any(|n| n.efa_supported() == Some(true)))
+    }
+
+    /// The security group `name` (see `efa_security_group_name`), created
+    /// if needed, allowing all traffic between its members, which EFA
+    /// requires. Instances join it besides the SSH group, so other korasi
+    /// instances stay closed off.
+    pub async fn get_efa_security_group(&self, name: &str) -> Result<SecurityGroup, EC2Error> {
+        let group = match self.describe_security_group(name).await? {
+            Some(group) => group,
+            None => {
+                self.create_security_group(name, "Lets EFA instances reach each other.")
+                    .await?
+            }
+        };
+        let group_id = group
+            .group_id()
+            .ok_or_else(|| EC2Error::new(format!("Security group {name} has no id")))?;
+        self.authorize_security_group_self_ingress(group_id, None)
+            .await?;
+        Ok(group)
+    }
+
+    /// EFA security groups of the tool, see `get_efa_security_group`.
+    pub async fn describe_efa_security_groups(&self) -> Result<Vec<SecurityGroup>, EC2Error> {
+        let mut filters = vec![
+            Filter::builder()
+                .name("group-name")
+                .values(format!("{EFA_SECURITY_GROUP_PREFIX}*"))
+                .build(),
+            self.tag_filter(),
+        ];
+        if let Some(network) = &self.network {
+            filters.push(
+                Filter::builder()
+                    .name("vpc-id")
+                    .values(&network.vpc_id)
+                    .build(),
+            );
+        }
+        Ok(self
+            .client
+            .describe_security_groups()
+            .set_filters(Some(filters))
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<_, _>>()
+            .await?)
+    }
+
+    /// Allow traffic from members of the group on tcp `port`, or without
+    /// one, all traffic, which EFA requires. Does nothing when the rule
+    /// exists already.
+    pub async fn authorize_security_group_self_ingress(
         &self,
         group_id: &str,
-        ingress_ips: Vec<Ipv4Addr>,
+        port: Option<u16>,
     ) -> Result<(), EC2Error> {
-        tracing::info!("Authorizing ingress for security group {group_id}");
-        self.client
-            .authorize_security_group_ingress()
-            .group_id(group_id)
-            .set_ip_permissions(Some(
-                ingress_ips
-                    .into_iter()
-                    .map(|ip| {
-                        IpPermission::builder()
-                            .ip_protocol("tcp")
-                            .from_port(22)
-                            .to_port(22)
-                            .ip_ranges(IpRange::builder().cidr_ip(format!("{ip}/32")).build())
-                            .build()
-                    })
-                    .collect(),
-            ))
+        let permission = IpPermission::builder()
+            .ip_protocol(if port.is_some() { "tcp" } else { "-1" })
+            .set_from_port(port.map(i32::from))
+            .set_to_port(port.map(i32::from))
+            .user_id_group_pairs(UserIdGroupPair::builder().group_id(group_id).build())
+            .build();
+        match self
+            .authorize_security_group_ingress(group_id, vec![permission])
+            .await
+        {
+            Err(err) if err.code() == Some("InvalidPermission.Duplicate") => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Decoded user data the instance was launched with, if any.
+    pub async fn get_user_data(&self, instance_id: &str) -> Result<Option<String>, EC2Error> {
+        let output = self
+            .client
+            .describe_instance_attribute()
+            .instance_id(instance_id)
+            .attribute(InstanceAttributeName::UserData)
             .send()
             .await?;
-        Ok(())
+
+        let Some(encoded) = output.user_data().and_then(|d| d.value()) else {
+            return Ok(None);
+        };
+        let decoded = BASE64_STANDARD
+            .decode(encoded)
+            .map_err(|e| EC2Error::new(format!("Invalid user data on {instance_id}: {e}")))?;
+        Ok(Some(String::from_utf8_lossy(&decoded).into_owned()))
     }
 
-    pub async fn delete_security_group(&self, group_id: &str) -> Result<(), EC2Error> {
-        tracing::info!("Deleting security group {group_id}");
-        self.client
-            .delete_security_group()
-            .group_id(group_id)
+    /// Decoded serial console output of an instance, if any was captured
+    /// yet. EC2 keeps the last 64 KB.
+    pub async fn get_console_output(&self, instance_id: &str) -> Result<Option<Vec<u8>>, EC2Error> {
+        let output = self
+            .client
+            .get_console_output()
+            .instance_id(instance_id)
             .send()
             .await?;
-        Ok(())
+
+        let Some(encoded) = output.output() else {
+            return Ok(None);
+        };
+        BASE64_STANDARD
+            .decode(encoded)
+            .map(Some)
+            .map_err(|e| EC2Error::new(format!("Invalid console output of {instance_id}: {e}")))
     }
 
-    pub async fn create_instances<'a>(
+    /// Decoded JPEG screenshot of an instance's console. With `wake_up`,
+    /// an instance whose screen went to sleep is woken first.
+    pub async fn get_console_screenshot(
         &self,
-        instance_name: &str,
-        image_id: &'a str,
-        instance_type: InstanceType,
-        key_pair: &'a KeyPairInfo,
-        security_groups: Vec<&'a SecurityGroup>,
-        user_data: Option<String>,
-    ) -> Result<Vec<String>, EC2Error> {
-        let run_instances = self
+        instance_id: &str,
+        wake_up: bool,
+    ) -> Result<Vec<u8>, EC2Error> {
+        let output = self
             .client
-            .run_instances()
-            .image_id(image_id)
-            .instance_type(instance_type)
-            .key_name(
-                key_pair
-                    .key_name()
-                    .ok_or_else(|| EC2Error::new("Missing key name when launching instance"))?,
-            )
-            .set_security_group_ids(Some(
-                security_groups
-                    .iter()
-                    .filter_map(|sg| sg.group_id.clone())
-                    .collect(),
-            ))
-            .set_user_data(user_data)
-            .set_tag_specifications(Some(vec![self.create_tag(ResourceType::Instance)]))
-            .min_count(1)
-            .max_count(1)
+            .get_console_screenshot()
+            .instance_id(instance_id)
+            .wake_up(wake_up)
             .send()
             .await?;
 
-        if run_instances.instances().is_empty() {
-            return Err(EC2Error::new("Failed to create instance"));
+        let encoded = output
+            .image_data()
+            .ok_or_else(|| EC2Error::new(format!("No screenshot of {instance_id}")))?;
+        BASE64_STANDARD
+            .decode(encoded)
+            .map_err(|e| EC2Error::new(format!("Invalid screenshot of {instance_id}: {e}")))
+    }
+
+    /// Volumes matching `filters`.
+    pub async fn describe_volumes(&self, filters: Vec<Filter>) -> Result<Vec<Volume>, EC2Error> {
+        let volumes = self
+            .client
+            .describe_volumes()
+            .set_filters(Some(filters))
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<_, _>>()
+            .await?;
+        Ok(volumes)
+    }
+
+    /// Every resource this tool created, fetched concurrently. Answers are
+    /// reused for `INVENTORY_TTL`, so views built from several of them
+    /// cost one round of requests; call `forget_inventory` after changes.
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
+    pub async fn inventory(&self) -> Result<Arc<ResourceInventory>, EC2Error> {
+        if let Some((at, inventory)) = self.inventory.lock().unwrap().as_ref() {
+            if at.elapsed() < INVENTORY_TTL {
+                return Ok(inventory.clone());
+            }
         }
 
-        let mut instance_ids = vec![];
-        for i in run_instances.instances() {
-            let instance_id = i.instance_id().unwrap();
-            let response = self
+        let images = async {
+            let images: Vec<Image> = self
                 .client
-                .create_tags()
-                .resources(instance_id)
-                .tags(Tag::builder().key("Name").value(instance_name).build())
+                .describe_images()
+                .owners("self")
+                .filters(self.tag_filter())
+                .into_paginator()
+                .items()
                 .send()
-                .await;
+                .collect::<Result<_, _>>()
+                .await?;
+            Ok::<_, EC2Error>(images)
+        };
+        let (
+            instances,
+            mut volumes,
+            security_group,
+            efa_groups,
+            addresses,
+            key_pairs,
+            images,
+            placement_groups,
+            network,
+        ) = tokio::try_join!(
+            self.describe_instance(vec![]),
+            self.describe_volumes(vec![self.tag_filter()]),
+            self.describe_security_group(SSH_SECURITY_GROUP),
+            self.describe_efa_security_groups(),
+            self.describe_addresses(),
+            self.list_key_pair(&self.key_name),
+            images,
+            self.describe_placement_groups(),
+            self.describe_network(),
+        )?;
 
-            match response {
-                Ok(_) => {
-                    tracing::info!("Created {instance_id} and applied tags.");
-                    instance_ids.push(instance_id.to_string());
-                }
-                Err(err) => {
-                    tracing::info!("Error applying tags to {instance_id}: {err:?}");
-                    return Err(err.into());
+        // Volumes of instances launched before volumes were tagged.
+        let instance_ids: Vec<String> = instances
+            .iter()
+            .filter_map(|i| i.instance_id().map(str::to_string))
+            .collect();
+        if !instance_ids.is_empty() {
+            let attached = self
+                .describe_volumes(vec![Filter::builder()
+                    .name("attachment.instance-id")
+                    .set_values(Some(instance_ids))
+                    .build()])
+                .await?;
+            for volume in attached {
+                if !volumes.iter().any(|v| v.volume_id() == volume.volume_id()) {
+                    volumes.push(volume);
                 }
             }
         }
 
-        Ok(instance_ids)
+        let inventory = Arc::new(ResourceInventory {
+            instances,
+            volumes,
+            security_groups: security_group.into_iter().chain(efa_groups).collect(),
+            addresses,
+            key_pairs,
+            images,
+            placement_groups,
+            network,
+        });
+        *self.inventory.lock().unwrap() = Some((Instant::now(), inventory.clone()));
+        Ok(inventory)
+    }
+
+    /// Make the next `inventory` fetch everything again.
+    pub fn forget_inventory(&self) {
+        *self.inventory.lock().unwrap() = None;
+    }
+
+    pub async fn describe_volume(&self, volume_id: &str) -> Result<Volume, EC2Error> {
+        let output = self
+            .client
+            .describe_volumes()
+            .volume_ids(volume_id)
+            .send()
+            .await?;
+
+        output
+            .volumes
+            .unwrap_or_default()
+            .pop()
+            .ok_or_else(|| EC2Error::new(format!("Could not find volume {volume_id}")))
     }
 
     /// Wait for an instance to be ready and status ok (default wait 60 seconds)
+    #[tracing::instrument(skip(self), fields(phase = "boot"))]
     pub async fn wait_for_instance_ready(
         &self,
         instance_id: &str,
@@ -277,9 +1628,20 @@ impl EC2Impl {
     /// by this tool.
     ///
     /// If statuses is an empty `Vec`, return all non-terminated instances as the default.
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
     pub async fn describe_instance(
+        &self,
+        statuses: Vec<InstanceStateName>,
+    ) -> Result<Vec<Instance>, EC2Error> {
+        self.describe_instance_tagged(statuses, &[]).await
+    }
+
+    /// Like `describe_instance`, keeping only instances with every one of
+    /// the `tags`.
+    pub async fn describe_instance_tagged(
         &self,
         mut statuses: Vec<InstanceStateName>,
+        tags: &[TagSelector],
     ) -> Result<Vec<Instance>, EC2Error> {
         let non_terminated = vec![
             InstanceStateName::Pending,
@@ -291,31 +1653,150 @@ impl EC2Impl {
         if statuses.is_empty() {
             statuses = non_terminated;
         }
+        let mut filters = vec![
+            self.tag_filter(),
+            Filter::builder()
+                .set_name(Some("instance-state-name".into()))
+                .set_values(Some(statuses.into_iter().map(|s| s.to_string()).collect()))
+                .build(),
+        ];
+        filters.extend(tags.iter().map(|t| {
+            Filter::builder()
+                .name(format!("tag:{}", t.key))
+                .values(&t.value)
+                .build()
+        }));
+        let reservations: Vec<_> = self
+            .client
+            .describe_instances()
+            .set_filters(Some(filters))
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<_, _>>()
+            .await?;
+
+        let instances: Vec<_> = reservations
+            .into_iter()
+            .flat_map(|r| r.instances.unwrap_or_default())
+            .collect();
+
+        Ok(instances)
+    }
+
+    /// Find a single instance by id, regardless of its state.
+    pub async fn get_instance(&self, instance_id: &str) -> Result<Instance, EC2Error> {
         let response = self
             .client
             .describe_instances()
-            .set_filters(Some(vec![
-                Filter::builder()
-                    .set_name(Some("tag:application".into()))
-                    .set_values(Some(vec![GLOBAL_TAG_FILTER.into()]))
-                    .build(),
+            .instance_ids(instance_id)
+            .send()
+            .await?;
+
+        response
+            .reservations()
+            .iter()
+            .flat_map(|r| r.instances().to_owned())
+            .next()
+            .ok_or_else(|| EC2Error::new(format!("Could not find instance {instance_id}")))
+    }
+
+    /// States of `instance_ids` (comma separated) by instance id. Unlike
+    /// `get_instance`, ids EC2 no longer knows are left out, not an error.
+    pub async fn instance_states(
+        &self,
+        instance_ids: &str,
+    ) -> Result<HashMap<String, InstanceStateName>, EC2Error> {
+        let response = self
+            .client
+            .describe_instances()
+            .filters(
                 Filter::builder()
-                    .set_name(Some("instance-state-name".into()))
-                    .set_values(Some(statuses.into_iter().map(|s| s.to_string()).collect()))
+                    .name("instance-id")
+                    .set_values(Some(instance_ids.split(',').map(str::to_string).collect()))
                     .build(),
-            ]))
+            )
             .send()
             .await?;
 
-        let instances: Vec<_> = response
+        Ok(response
             .reservations()
             .iter()
-            .flat_map(|r| r.instances().to_owned())
+            .flat_map(|r| r.instances())
+            .filter_map(|i| {
+                let state = i.state().and_then(|s| s.name())?.clone();
+                Some((i.instance_id()?.to_string(), state))
+            })
+            .collect())
+    }
+
+    /// Names of the regions enabled for the account.
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
+    pub async fn describe_regions(&self) -> Result<Vec<String>, EC2Error> {
+        let response = self.client.describe_regions().send().await?;
+        let mut regions: Vec<String> = response
+            .regions()
+            .iter()
+            .filter_map(|r| r.region_name().map(str::to_string))
             .collect();
+        regions.sort();
+        Ok(regions)
+    }
 
-        Ok(instances)
+    /// Status checks and scheduled events of running instances, keyed by
+    /// instance id. Instances that aren't running have no entry.
+    pub async fn describe_instance_health(
+        &self,
+        instance_ids: Vec<String>,
+    ) -> Result<HashMap<String, InstanceHealth>, EC2Error> {
+        if instance_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let response = self
+            .client
+            .describe_instance_status()
+            .set_instance_ids(Some(instance_ids))
+            .send()
+            .await?;
+
+        Ok(response
+            .instance_statuses()
+            .iter()
+            .filter_map(|s| Some((s.instance_id()?.to_string(), s.into())))
+            .collect())
+    }
+
+    /// Snapshot every EBS volume attached to an instance.
+    ///
+    /// Returns the ids of the snapshots, which complete in the background.
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
+    pub async fn create_instance_snapshots(
+        &self,
+        instance_id: &str,
+        description: &str,
+    ) -> Result<Vec<String>, EC2Error> {
+        let response = self
+            .client
+            .create_snapshots()
+            .instance_specification(
+                InstanceSpecification::builder()
+                    .instance_id(instance_id)
+                    .build(),
+            )
+            .description(description)
+            .copy_tags_from_source(CopyTagsFromSource::Volume)
+            .tag_specifications(self.create_tag(ResourceType::Snapshot))
+            .send()
+            .await?;
+
+        Ok(response
+            .snapshots()
+            .iter()
+            .filter_map(|s| s.snapshot_id().map(str::to_string))
+            .collect())
     }
 
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
     pub async fn start_instances(&self, instance_id: &str) -> Result<(), EC2Error> {
         tracing::info!("Starting instance {instance_id}");
 
@@ -330,6 +1811,7 @@ impl EC2Impl {
         Ok(())
     }
 
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
     pub async fn stop_instances(&self, instance_ids: &str, wait: bool) -> Result<(), EC2Error> {
         tracing::info!("Stopping instance {instance_ids}");
 
@@ -359,6 +1841,22 @@ impl EC2Impl {
         Ok(())
     }
 
+    pub async fn wait_for_instance_running(
+        &self,
+        instance_ids: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), EC2Error> {
+        let mut waiter = self.client.wait_until_instance_running();
+        for id in instance_ids.split(",") {
+            waiter = waiter.instance_ids(id);
+        }
+        waiter
+            .wait(duration.unwrap_or(Duration::from_secs(90)))
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn wait_for_instance_stopped(
         &self,
         instance_ids: &str,
@@ -375,8 +1873,10 @@ impl EC2Impl {
         Ok(())
     }
 
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
     pub async fn delete_instances(&self, instance_ids: &str, wait: bool) -> Result<(), EC2Error> {
         tracing::info!("Deleting instance with id {:?}", instance_ids);
+        self.forget_inventory();
 
         self.stop_instances(instance_ids, wait).await?;
 
@@ -387,47 +1887,120 @@ impl EC2Impl {
         terminator.send().await?;
 
         if wait {
-            self.wait_for_instance_terminated(instance_ids).await?;
+            self.wait_for_instance_terminated(instance_ids, None)
+                .await?;
             tracing::info!("Terminated instance with ids {:?}", instance_ids);
         }
 
         Ok(())
     }
 
-    async fn wait_for_instance_terminated(&self, instance_ids: &str) -> Result<(), EC2Error> {
+    pub async fn wait_for_instance_terminated(
+        &self,
+        instance_ids: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), EC2Error> {
         let mut waiter = self.client.wait_until_instance_terminated();
         for id in instance_ids.split(",") {
             waiter = waiter.instance_ids(id);
         }
-        waiter.wait(Duration::from_secs(60)).await?;
+        waiter
+            .wait(duration.unwrap_or(Duration::from_secs(60)))
+            .await?;
         Ok(())
     }
 
-    /// Add new local IP to inbound security group.
-    ///
-    /// Local IPs can rotate or if you change to a different location.
-    async fn update_inbound_ip(&self, group_id: &str) -> Result<(), EC2Error> {
-        let check_ip = Util::do_get("https://checkip.amazonaws.com").await?;
+    /// Public addresses of this machine, its IPv4 and IPv6 ones as far as
+    /// it has them. Each family is asked for separately, as connections go
+    /// out over either depending on the destination.
+    pub async fn current_ips() -> Result<Vec<IpAddr>, EC2Error> {
+        let (v4, v6) = tokio::join!(
+            Self::current_ip(CHECK_IPV4_URL, Ipv4Addr::UNSPECIFIED.into()),
+            Self::current_ip(CHECK_IPV6_URL, Ipv6Addr::UNSPECIFIED.into()),
+        );
+        match (v4, v6) {
+            (Err(err), Err(v6_err)) => {
+                tracing::debug!("No IPv6 address either: {v6_err}");
+                Err(err)
+            }
+            (v4, v6) => Ok(v4.into_iter().chain(v6).collect()),
+        }
+    }
+
+    /// Public address of this machine as seen by `url`, reached from the
+    /// family of `local`.
+    async fn current_ip(url: &str, local: IpAddr) -> Result<IpAddr, EC2Error> {
+        let check_ip = Util::do_get_from(url, Some(local)).await?;
         tracing::info!("Current IP address = {}", check_ip);
 
-        let current_ip_address: Ipv4Addr = check_ip.trim().parse().map_err(|e| {
+        let ip: IpAddr = check_ip.trim().parse().map_err(|e| {
             EC2Error::new(format!(
                 "Failed to convert response {} to IP Address: {e:?}",
                 check_ip
             ))
         })?;
+        if ip.is_ipv4() != local.is_ipv4() {
+            return Err(EC2Error::new(format!("{url} answered with {ip}")));
+        }
+        Ok(ip)
+    }
+
+    /// Add new local IP to inbound security group.
+    ///
+    /// Local IPs can rotate or if you change to a different location, so
+    /// rules of IPs unused for `STALE_RULE_AGE` are revoked on the way,
+    /// before they fill the group.
+    async fn update_inbound_ip(&self, group_id: &str) -> Result<(), EC2Error> {
+        let ips = Self::current_ips().await?;
+        let current: Vec<String> = ips.iter().copied().map(host_cidr).collect();
+        let rules = self.describe_security_group_rules(group_id).await?;
+
+        let mut missing = vec![];
+        for (ip, cidr) in ips.iter().zip(&current) {
+            let Some(rule) = current_rule(&rules, self.ssh_port, cidr) else {
+                missing.push(*ip);
+                continue;
+            };
+            // Refreshed daily at most, it only needs to be days accurate.
+            let fresh = last_used(rule).is_some_and(|t| now().saturating_sub(t) < 24 * 60 * 60);
+            if let (false, Some(id)) = (fresh, rule.security_group_rule_id()) {
+                self.client
+                    .create_tags()
+                    .resources(id)
+                    .tags(
+                        Tag::builder()
+                            .key(LAST_USED_TAG)
+                            .value(now().to_string())
+                            .build(),
+                    )
+                    .send()
+                    .await?;
+            }
+        }
+        if !missing.is_empty() {
+            if let Err(err) = self
+                .authorize_security_group_ssh_ingress(group_id, missing)
+                .await
+            {
+                tracing::warn!("Most likely inbound rule already exists. Err = {err}");
+            }
+        }
 
-        if let Err(err) = self
-            .authorize_security_group_ssh_ingress(group_id, vec![current_ip_address])
+        match self
+            .prune_ssh_rules(group_id, &rules, &current, STALE_RULE_AGE, false)
             .await
         {
-            tracing::warn!("Most likely inbound rule already exists. Err = {err}");
-        };
-
+            Ok(revoked) if !revoked.is_empty() => {
+                tracing::info!("Revoked SSH access of unused IPs {}", revoked.join(", "))
+            }
+            Ok(_) => {}
+            Err(err) => tracing::warn!("Failed to revoke stale SSH rules: {err}"),
+        }
         Ok(())
     }
 
     /// Call this function to update local IP in inbound group.
+    #[tracing::instrument(skip(self), fields(phase = "api"))]
     pub async fn get_ssh_security_group(&self) -> Result<SecurityGroup, EC2Error> {
         let group = match self
             .create_security_group(
@@ -456,38 +2029,300 @@ impl EC2Impl {
     }
 }
 
-#[derive(Debug)]
-pub struct EC2Error(String);
+/// The EC2 calls behind the create, list and delete flows, so those can
+/// run against `mock::MockEc2` in tests, without AWS credentials.
+#[async_trait]
+pub trait Ec2Api: Send + Sync {
+    async fn get_ssh_security_group(&self) -> Result<SecurityGroup, EC2Error>;
+
+    async fn describe_image(&self, image_id: &str) -> Result<Image, EC2Error>;
+
+    async fn create_instances(
+        &self,
+        instance_name: &str,
+        image_id: &str,
+        instance_type: InstanceType,
+        key_pair: &KeyPairInfo,
+        security_groups: Vec<&SecurityGroup>,
+        opts: LaunchOptions,
+    ) -> Result<Vec<String>, EC2Error>;
+
+    async fn describe_instance_tagged(
+        &self,
+        statuses: Vec<InstanceStateName>,
+        tags: &[TagSelector],
+    ) -> Result<Vec<Instance>, EC2Error>;
+
+    async fn describe_instance_health(
+        &self,
+        instance_ids: Vec<String>,
+    ) -> Result<HashMap<String, InstanceHealth>, EC2Error>;
+
+    async fn delete_instances(&self, instance_ids: &str, wait: bool) -> Result<(), EC2Error>;
+}
+
+#[async_trait]
+impl Ec2Api for EC2Impl {
+    async fn get_ssh_security_group(&self) -> Result<SecurityGroup, EC2Error> {
+        EC2Impl::get_ssh_security_group(self).await
+    }
+
+    async fn describe_image(&self, image_id: &str) -> Result<Image, EC2Error> {
+        EC2Impl::describe_image(self, image_id).await
+    }
+
+    async fn create_instances(
+        &self,
+        instance_name: &str,
+        image_id: &str,
+        instance_type: InstanceType,
+        key_pair: &KeyPairInfo,
+        security_groups: Vec<&SecurityGroup>,
+        opts: LaunchOptions,
+    ) -> Result<Vec<String>, EC2Error> {
+        EC2Impl::create_instances(
+            self,
+            instance_name,
+            image_id,
+            instance_type,
+            key_pair,
+            security_groups,
+            opts,
+        )
+        .await
+    }
+
+    async fn describe_instance_tagged(
+        &self,
+        statuses: Vec<InstanceStateName>,
+        tags: &[TagSelector],
+    ) -> Result<Vec<Instance>, EC2Error> {
+        EC2Impl::describe_instance_tagged(self, statuses, tags).await
+    }
+
+    async fn describe_instance_health(
+        &self,
+        instance_ids: Vec<String>,
+    ) -> Result<HashMap<String, InstanceHealth>, EC2Error> {
+        EC2Impl::describe_instance_health(self, instance_ids).await
+    }
+
+    async fn delete_instances(&self, instance_ids: &str, wait: bool) -> Result<(), EC2Error> {
+        EC2Impl::delete_instances(self, instance_ids, wait).await
+    }
+}
+
+/// What to do about common launch errors, by error code (prefix).
+const LAUNCH_HINTS: &[(&str, &str)] = &[
+    (
+        "InstanceLimitExceeded",
+        "You are at the instance quota of this region. Delete unused instances or request a \
+         quota increase in the Service Quotas console.",
+    ),
+    (
+        "VcpuLimitExceeded",
+        "You are at the vCPU quota of this instance family. Pick a smaller instance type or \
+         request a quota increase in the Service Quotas console.",
+    ),
+    (
+        "InsufficientInstanceCapacity",
+        "AWS is out of capacity for this instance type in the availability zone. Retry later, \
+         or pick another instance type or region.",
+    ),
+    (
+        "Unsupported",
+        "The instance type isn't offered in this availability zone. Pick another instance \
+         type or region.",
+    ),
+    (
+        "InvalidAMIID",
+        "The AMI doesn't exist in this region. AMI ids are per region, check --region or \
+         launch from an alias instead.",
+    ),
+    (
+        "OptInRequired",
+        "The AMI requires a subscription. Accept its terms in the AWS Marketplace first.",
+    ),
+    (
+        "VPCIdNotSpecified",
+        "This region has no default VPC. Create a VPC for korasi with `korasi network init`.",
+    ),
+    (
+        "UnauthorizedOperation",
+        "Your credentials lack permission for this call. Check the IAM policy of the profile.",
+    ),
+];
+
+/// AWS error codes worth retrying, possibly elsewhere (eg. capacity errors
+/// in another availability zone).
+const RETRYABLE_CODES: &[&str] = &[
+    "RequestLimitExceeded",
+    "Throttling",
+    "ThrottlingException",
+    "InsufficientInstanceCapacity",
+    "InsufficientHostCapacity",
+    "InsufficientReservedInstanceCapacity",
+    "ServiceUnavailable",
+    "Unavailable",
+    "InternalError",
+    "InternalFailure",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum EC2Error {
+    /// Error returned by an AWS API. Requests that never got a response
+    /// (eg. missing credentials or no network) have no code.
+    #[error(
+        "{}: {}",
+        code.as_deref().unwrap_or("unknown code"),
+        message.as_deref().unwrap_or("missing reason (most likely profile credentials not set)")
+    )]
+    Service {
+        code: Option<String>,
+        message: Option<String>,
+        request_id: Option<String>,
+    },
+
+    /// Any other failure, eg. a resource that doesn't exist.
+    #[error("{0}")]
+    Other(String),
+}
+
 impl EC2Error {
     pub fn new(value: impl Into<String>) -> Self {
-        EC2Error(value.into())
+        EC2Error::Other(value.into())
     }
 
     pub fn _add_message(self, message: impl Into<String>) -> Self {
-        EC2Error(format!("{}: {}", message.into(), self.0))
+        EC2Error::Other(format!("{}: {}", message.into(), self))
+    }
+
+    /// AWS error code, eg. `InsufficientInstanceCapacity`.
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            EC2Error::Service { code, .. } => code.as_deref(),
+            EC2Error::Other(_) => None,
+        }
+    }
+
+    /// Request id to quote to AWS support.
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            EC2Error::Service { request_id, .. } => request_id.as_deref(),
+            EC2Error::Other(_) => None,
+        }
+    }
+
+    /// Guidance for common launch errors.
+    pub fn hint(&self) -> Option<&'static str> {
+        let code = self.code()?;
+        LAUNCH_HINTS
+            .iter()
+            .find(|(prefix, _)| code.starts_with(prefix))
+            .map(|(_, hint)| *hint)
+    }
+
+    /// Whether a launch may succeed in another availability zone.
+    pub fn is_capacity_error(&self) -> bool {
+        self.code().is_some_and(|c| {
+            c.starts_with("Insufficient") && c.ends_with("Capacity") || c == "Unsupported"
+        })
+    }
+
+    /// Whether the same request may succeed if tried again later.
+    pub fn is_retryable(&self) -> bool {
+        self.code().is_some_and(|c| RETRYABLE_CODES.contains(&c))
     }
 }
 
 impl<T: ProvideErrorMetadata> From<T> for EC2Error {
     fn from(value: T) -> Self {
-        EC2Error(format!(
-            "{}: {}",
-            value
-                .code()
-                .map(String::from)
-                .unwrap_or("unknown code".into()),
-            value
-                .message()
-                .map(String::from)
-                .unwrap_or("missing reason (most likely profile credentials not set)".into()),
-        ))
+        EC2Error::Service {
+            code: value.code().map(String::from),
+            message: value.message().map(String::from),
+            request_id: value.meta().request_id().map(String::from),
+        }
     }
 }
 
-impl std::error::Error for EC2Error {}
+#[cfg(test)]
+mod tests {
+    use aws_sdk_ec2::error::ErrorMetadata;
 
-impl std::fmt::Display for EC2Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+    use super::{EC2Error, TagSelector};
+
+    #[test]
+    fn service_error_metadata() {
+        let err: EC2Error = ErrorMetadata::builder()
+            .code("InsufficientInstanceCapacity")
+            .message("We currently do not have sufficient p4d.24xlarge capacity.")
+            .custom("aws_request_id", "0f9a1c2e")
+            .build()
+            .into();
+
+        pretty_assertions::assert_eq!(err.code(), Some("InsufficientInstanceCapacity"));
+        pretty_assertions::assert_eq!(err.request_id(), Some("0f9a1c2e"));
+        assert!(err.is_retryable());
+        pretty_assertions::assert_eq!(
+            err.to_string(),
+            "InsufficientInstanceCapacity: We currently do not have sufficient p4d.24xlarge capacity."
+        );
+
+        let err: EC2Error = ErrorMetadata::builder().build().into();
+        assert!(!err.is_retryable());
+        pretty_assertions::assert_eq!(
+            err.to_string(),
+            "unknown code: missing reason (most likely profile credentials not set)"
+        );
+        assert!(!EC2Error::new("Could not find volume vol-1").is_retryable());
+    }
+
+    #[test]
+    fn launch_error_hints() {
+        let error = |code: &str| -> EC2Error { ErrorMetadata::builder().code(code).build().into() };
+        let cases = [
+            ("VcpuLimitExceeded", Some("vCPU quota")),
+            ("InvalidAMIID.NotFound", Some("AMI ids are per region")),
+            ("InvalidAMIID.Malformed", Some("AMI ids are per region")),
+            ("InsufficientInstanceCapacity", Some("out of capacity")),
+            ("VPCIdNotSpecified", Some("korasi network init")),
+            ("RequestLimitExceeded", None),
+        ];
+
+        for (code, expected) in cases {
+            println!("code = {code}");
+            let hint = error(code).hint();
+            pretty_assertions::assert_eq!(
+                hint.map(|h| expected.is_some_and(|e| h.contains(e))),
+                expected.map(|_| true)
+            );
+        }
+        pretty_assertions::assert_eq!(EC2Error::new("no key pair").hint(), None);
+    }
+
+    #[test]
+    fn parse_tag_selector() {
+        let tag = |key: &str, value: &str| TagSelector {
+            key: key.into(),
+            value: value.into(),
+        };
+        let cases = [
+            ("team=ml", Ok(tag("team", "ml"))),
+            ("env=a=b", Ok(tag("env", "a=b"))),
+            ("empty=", Ok(tag("empty", ""))),
+            (
+                "team",
+                Err("invalid tag `team`, expected key=value".to_string()),
+            ),
+            (
+                "=ml",
+                Err("invalid tag `=ml`, expected key=value".to_string()),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            println!("input = {input}");
+            pretty_assertions::assert_eq!(input.parse::<TagSelector>(), expected);
+        }
     }
 }
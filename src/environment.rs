@@ -0,0 +1,192 @@
+//! Environment report of an instance (OS, hardware, toolchains), for
+//! reproducibility notes in papers and experiment logs.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{json::Value, ssh::Session};
+
+/// Prints `key=value` lines. Runs in a login shell so toolchains added to
+/// the PATH by profile scripts (eg. rustup) are found.
+const COLLECT_SCRIPT: &str = r#"
+. /etc/os-release 2>/dev/null && echo "os=$PRETTY_NAME"
+echo "kernel=$(uname -r)"
+echo "arch=$(uname -m)"
+echo "cpu=$(grep -m1 'model name' /proc/cpuinfo | cut -d: -f2- | sed 's/^ *//')"
+echo "cpus=$(nproc)"
+echo "memory_kb=$(awk '/MemTotal/ { print $2 }' /proc/meminfo)"
+if command -v nvidia-smi >/dev/null; then
+  nvidia-smi --query-gpu=name,driver_version --format=csv,noheader | sed 's/^/gpu=/'
+fi
+tool() { command -v "$2" >/dev/null && echo "tool.$1=$("${@:2}" 2>&1 | grep -m1 "${PATTERN:-.}")"; }
+tool rustc rustc --version
+tool cargo cargo --version
+tool python python3 --version
+tool gcc gcc --version
+tool docker docker --version
+PATTERN=release tool cuda nvcc --version
+echo "collected_at=$(date -u +%Y-%m-%dT%H:%M:%SZ)"
+"#;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EnvReport {
+    pub instance_id: Option<String>,
+    pub instance_type: Option<String>,
+    pub image_id: Option<String>,
+    pub os: Option<String>,
+    pub kernel: Option<String>,
+    pub arch: Option<String>,
+    pub cpu: Option<String>,
+    pub cpus: Option<u64>,
+    pub memory_kb: Option<u64>,
+    /// `name, driver version` of each GPU.
+    pub gpus: Vec<String>,
+    /// Version line of each installed toolchain, keyed by tool.
+    pub tools: BTreeMap<String, String>,
+    pub collected_at: Option<String>,
+}
+
+impl EnvReport {
+    /// Parse the output of `COLLECT_SCRIPT`. Empty values are left unset.
+    pub fn parse(output: &str) -> EnvReport {
+        let mut report = EnvReport::default();
+        for line in output.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            let text = Some(value.to_string());
+            match key {
+                "os" => report.os = text,
+                "kernel" => report.kernel = text,
+                "arch" => report.arch = text,
+                "cpu" => report.cpu = text,
+                "cpus" => report.cpus = value.parse().ok(),
+                "memory_kb" => report.memory_kb = value.parse().ok(),
+                "gpu" => report.gpus.push(value.into()),
+                "collected_at" => report.collected_at = text,
+                key => {
+                    if let Some(tool) = key.strip_prefix("tool.") {
+                        report.tools.insert(tool.into(), value.into());
+                    }
+                }
+            }
+        }
+        report
+    }
+
+    pub fn to_json(&self) -> Value {
+        let text = |v: &Option<String>| Value::from(v.clone());
+        Value::Object(vec![
+            ("instance_id".into(), text(&self.instance_id)),
+            ("instance_type".into(), text(&self.instance_type)),
+            ("image_id".into(), text(&self.image_id)),
+            ("os".into(), text(&self.os)),
+            ("kernel".into(), text(&self.kernel)),
+            ("arch".into(), text(&self.arch)),
+            ("cpu".into(), text(&self.cpu)),
+            ("cpus".into(), self.cpus.into()),
+            ("memory_kb".into(), self.memory_kb.into()),
+            (
+                "gpus".into(),
+                Value::Array(self.gpus.iter().map(|g| g.as_str().into()).collect()),
+            ),
+            (
+                "tools".into(),
+                Value::Object(
+                    self.tools
+                        .iter()
+                        .map(|(k, v)| (k.clone(), v.as_str().into()))
+                        .collect(),
+                ),
+            ),
+            ("collected_at".into(), text(&self.collected_at)),
+        ])
+    }
+}
+
+impl fmt::Display for EnvReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = |v: &Option<String>| v.clone().unwrap_or_else(|| "unknown".into());
+        writeln!(
+            f,
+            "instance   {} ({}, {})",
+            unknown(&self.instance_id),
+            unknown(&self.instance_type),
+            unknown(&self.image_id)
+        )?;
+        writeln!(f, "os         {}", unknown(&self.os))?;
+        writeln!(
+            f,
+            "kernel     {} ({})",
+            unknown(&self.kernel),
+            unknown(&self.arch)
+        )?;
+        writeln!(
+            f,
+            "cpu        {} x {}",
+            self.cpus.map_or("?".into(), |c| c.to_string()),
+            unknown(&self.cpu)
+        )?;
+        writeln!(
+            f,
+            "memory     {}",
+            self.memory_kb.map_or("unknown".into(), |kb| format!(
+                "{:.1} GiB",
+                kb as f64 / 1048576.0
+            ))
+        )?;
+        for gpu in &self.gpus {
+            writeln!(f, "gpu        {gpu}")?;
+        }
+        for (tool, version) in &self.tools {
+            writeln!(f, "{tool:<10} {version}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Collect the report over an open session.
+pub async fn collect(session: &Session) -> anyhow::Result<EnvReport> {
+    let command = format!("bash -lc {}", shell_escape::escape(COLLECT_SCRIPT.into()));
+    let output = session.exec_output(&command).await?;
+    if output.code != 0 && output.stdout.is_empty() {
+        anyhow::bail!(
+            "Failed to collect environment: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(EnvReport::parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EnvReport;
+
+    #[test]
+    fn parse_env_report() {
+        let report = EnvReport::parse(
+            "os=Ubuntu 22.04.4 LTS\n\
+             kernel=6.5.0-1017-aws\n\
+             arch=x86_64\n\
+             cpu=Intel(R) Xeon(R) Platinum 8259CL CPU @ 2.50GHz\n\
+             cpus=8\n\
+             memory_kb=32386500\n\
+             gpu=Tesla T4, 535.104.05\n\
+             tool.rustc=rustc 1.82.0 (f6e511eec 2024-10-15)\n\
+             tool.cuda=Cuda compilation tools, release 12.2, V12.2.140\n\
+             tool.docker=\n\
+             collected_at=2024-11-01T10:00:00Z\n",
+        );
+
+        pretty_assertions::assert_eq!(report.cpus, Some(8));
+        pretty_assertions::assert_eq!(report.gpus, vec!["Tesla T4, 535.104.05".to_string()]);
+        pretty_assertions::assert_eq!(
+            report.tools.keys().collect::<Vec<_>>(),
+            vec!["cuda", "rustc"]
+        );
+        pretty_assertions::assert_eq!(report.os.as_deref(), Some("Ubuntu 22.04.4 LTS"));
+    }
+}
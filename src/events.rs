@@ -0,0 +1,94 @@
+//! `--events ndjson`: remote command output as one JSON object per line,
+//! for programs driving korasi.
+//!
+//! ```text
+//! {"seq":0,"ts":"2024-11-01T10:00:00.120Z","instance":"gpu-box","event":"start","command":"make"}
+//! {"seq":1,"ts":"2024-11-01T10:00:00.480Z","instance":"gpu-box","event":"stdout","data":"ok\n"}
+//! {"seq":2,"ts":"2024-11-01T10:00:00.482Z","instance":"gpu-box","event":"exit","code":0}
+//! ```
+//!
+//! Sequence numbers are per command, so output of several instances can be
+//! told apart and put back in order by `instance` and `seq`. Commands that
+//! couldn't run at all, eg. as the connection failed, get an `exit` event
+//! with code 255 and an `error`. korasi exits with the highest code.
+
+use std::io::Write;
+
+use aws_sdk_ec2::primitives::{DateTime, DateTimeFormat};
+
+use crate::json::Value;
+
+/// Builds the events of one remote command.
+pub struct EventWriter {
+    instance: String,
+    seq: u64,
+}
+
+impl EventWriter {
+    pub fn new(instance: impl Into<String>) -> Self {
+        EventWriter {
+            instance: instance.into(),
+            seq: 0,
+        }
+    }
+
+    /// Serialize an event, stamped with the next sequence number and the
+    /// current time.
+    pub fn event(&mut self, event: &str, fields: Vec<(String, Value)>) -> String {
+        let ts = DateTime::from(std::time::SystemTime::now())
+            .fmt(DateTimeFormat::DateTime)
+            .unwrap_or_default();
+        self.event_at(&ts, event, fields)
+    }
+
+    fn event_at(&mut self, ts: &str, event: &str, fields: Vec<(String, Value)>) -> String {
+        let mut object = vec![
+            ("seq".to_string(), self.seq.into()),
+            ("ts".to_string(), ts.into()),
+            ("instance".to_string(), self.instance.as_str().into()),
+            ("event".to_string(), event.into()),
+        ];
+        object.extend(fields);
+        self.seq += 1;
+        Value::Object(object).to_string()
+    }
+
+    /// Print an event as its own line. The line is written at once so
+    /// events of concurrent commands don't interleave.
+    pub fn emit(&mut self, event: &str, fields: Vec<(String, Value)>) -> std::io::Result<()> {
+        let line = self.event(event, fields) + "\n";
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(line.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventWriter;
+
+    #[test]
+    fn sequence_events() {
+        let mut events = EventWriter::new("gpu-box");
+        let ts = "2024-11-01T10:00:00Z";
+        let cases = [
+            (
+                events.event_at(ts, "start", vec![("command".into(), "make".into())]),
+                r#"{"seq":0,"ts":"2024-11-01T10:00:00Z","instance":"gpu-box","event":"start","command":"make"}"#,
+            ),
+            (
+                events.event_at(ts, "stderr", vec![("data".into(), "warn: \"x\"\n".into())]),
+                r#"{"seq":1,"ts":"2024-11-01T10:00:00Z","instance":"gpu-box","event":"stderr","data":"warn: \"x\"\n"}"#,
+            ),
+            (
+                events.event_at(ts, "exit", vec![("code".into(), 2u64.into())]),
+                r#"{"seq":2,"ts":"2024-11-01T10:00:00Z","instance":"gpu-box","event":"exit","code":2}"#,
+            ),
+        ];
+
+        for (line, expected) in cases {
+            println!("expected = {expected}");
+            pretty_assertions::assert_eq!(line, expected);
+        }
+    }
+}
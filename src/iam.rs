@@ -0,0 +1,181 @@
+//! Explain `UnauthorizedOperation` errors. EC2 only says which action was
+//! denied in an encoded message, which STS decodes for principals allowed
+//! `sts:DecodeAuthorizationMessage`. Signed like [`crate::cloudwatch`].
+
+use aws_sigv4::http_request::{SignableBody, SigningSettings};
+use aws_types::SdkConfig as AwsSdkConfig;
+
+use crate::{ec2::EC2Error, json::string_fields, sigv4};
+
+/// Marks the encoded message at the end of `UnauthorizedOperation` errors.
+const ENCODED_PREFIX: &str = "Encoded authorization failure message:";
+
+/// The encoded authorization failure message of `err`, if any.
+pub fn encoded_message(err: &EC2Error) -> Option<&str> {
+    let EC2Error::Service {
+        code: Some(code),
+        message: Some(message),
+        ..
+    } = err
+    else {
+        return None;
+    };
+    if code != "UnauthorizedOperation" {
+        return None;
+    }
+    let (_, encoded) = message.split_once(ENCODED_PREFIX)?;
+    Some(encoded.trim()).filter(|e| !e.is_empty())
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// The denied action and resource of a decoded authorization message.
+pub fn denied(decoded: &str) -> Option<(String, String)> {
+    // Statements matched earlier in the message list actions too.
+    let context = &decoded[decoded.find("\"context\"")?..];
+    let action = string_fields(context, "action").into_iter().next()?;
+    let resource = string_fields(context, "resource")
+        .into_iter()
+        .next()
+        .unwrap_or("*".into());
+    Some((action, resource))
+}
+
+pub async fn decode_authorization_message(
+    config: &AwsSdkConfig,
+    encoded: &str,
+) -> Result<String, EC2Error> {
+    let url = format!("https://sts.{}.amazonaws.com/", sigv4::region(config));
+    // Messages run into kilobytes, too long for a query string.
+    let body = reqwest::Url::parse_with_params(
+        "http://localhost/",
+        [
+            ("Action", "DecodeAuthorizationMessage"),
+            ("Version", "2011-06-15"),
+            ("EncodedMessage", encoded),
+        ],
+    )
+    .map_err(|e| EC2Error::new(format!("Invalid STS request: {e}")))?
+    .query()
+    .unwrap_or_default()
+    .to_string();
+
+    let (headers, _) = sigv4::sign(
+        config,
+        "sts",
+        "POST",
+        &url,
+        SignableBody::Bytes(body.as_bytes()),
+        SigningSettings::default(),
+    )
+    .await?;
+    let mut req = reqwest::Client::new()
+        .post(&url)
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(body);
+    for (k, v) in headers {
+        req = req.header(k, v);
+    }
+    let xml = req
+        .send()
+        .await
+        .map_err(|e| EC2Error::new(format!("Could not reach STS: {e:?}")))?
+        .error_for_status()
+        .map_err(|e| EC2Error::new(format!("Failure status from STS: {e:?}")))?
+        .text()
+        .await
+        .map_err(|e| EC2Error::new(format!("Could not read STS response: {e:?}")))?;
+
+    let decoded = xml
+        .split_once("<DecodedMessage>")
+        .and_then(|(_, rest)| rest.split_once("</DecodedMessage>"))
+        .map(|(decoded, _)| unescape_xml(decoded))
+        .ok_or_else(|| EC2Error::new("No decoded message in the STS response"))?;
+    Ok(decoded)
+}
+
+/// What `err` was denied, or how to find out, for authorization failures.
+pub async fn explain(config: &AwsSdkConfig, err: &EC2Error) -> Option<String> {
+    let encoded = encoded_message(err)?;
+    let explanation = match decode_authorization_message(config, encoded).await {
+        Ok(decoded) => match denied(&decoded) {
+            Some((action, resource)) => {
+                format!("missing permission `{action}` on `{resource}`.")
+            }
+            None => format!("decoded authorization failure: {decoded}"),
+        },
+        Err(err) => {
+            tracing::debug!("Could not decode the authorization failure: {err}");
+            "the denied action is in the encoded message above. Decode it with \
+             `aws sts decode-authorization-message --encoded-message <message>`, \
+             or allow `sts:DecodeAuthorizationMessage` to have korasi do it."
+                .into()
+        }
+    };
+    Some(explanation)
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_ec2::error::ErrorMetadata;
+
+    use super::{denied, encoded_message, unescape_xml};
+    use crate::ec2::EC2Error;
+
+    #[test]
+    fn explain_authorization_failures() {
+        let error = |code: &str, message: &str| -> EC2Error {
+            ErrorMetadata::builder()
+                .code(code)
+                .message(message)
+                .build()
+                .into()
+        };
+        let cases = [
+            (
+                error(
+                    "UnauthorizedOperation",
+                    "You are not authorized to perform this operation. Encoded authorization failure message: AbC-123_x",
+                ),
+                Some("AbC-123_x"),
+            ),
+            (
+                error(
+                    "UnauthorizedOperation",
+                    "You are not authorized to perform this operation.",
+                ),
+                None,
+            ),
+            (
+                error(
+                    "InvalidParameterValue",
+                    "Encoded authorization failure message: AbC",
+                ),
+                None,
+            ),
+            (EC2Error::new("Encoded authorization failure message: AbC"), None),
+        ];
+        for (err, expected) in cases {
+            println!("err = {err}");
+            pretty_assertions::assert_eq!(encoded_message(&err), expected);
+        }
+
+        let decoded = unescape_xml(
+            r#"{&quot;allowed&quot;:false,&quot;matchedStatements&quot;:{&quot;items&quot;:[{&quot;statement&quot;:{&quot;action&quot;:&quot;ec2:Describe*&quot;}}]},&quot;context&quot;:{&quot;principal&quot;:{&quot;arn&quot;:&quot;arn:aws:iam::1:user/dev&quot;},&quot;action&quot;:&quot;ec2:RunInstances&quot;,&quot;resource&quot;:&quot;arn:aws:ec2:ap-southeast-1:1:instance/*&quot;}}"#,
+        );
+        pretty_assertions::assert_eq!(
+            denied(&decoded),
+            Some((
+                "ec2:RunInstances".into(),
+                "arn:aws:ec2:ap-southeast-1:1:instance/*".into()
+            ))
+        );
+        pretty_assertions::assert_eq!(denied(r#"{"allowed":false}"#), None);
+    }
+}
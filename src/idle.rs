@@ -0,0 +1,126 @@
+//! Reminders about instances left running idle, shown when opening a shell
+//! to another instance.
+
+use std::io::Write;
+
+use aws_sdk_ec2::types::{Instance, InstanceStateName};
+use termion::{event::Key, input::TermRead, raw::IntoRawMode};
+
+use crate::{
+    cloudwatch::CloudWatchImpl as CloudWatch, config::IdleConfig, ec2::EC2Impl as EC2,
+    pricing::on_demand_hourly, util::SelectOption,
+};
+
+pub struct IdleInstance {
+    pub instance: SelectOption,
+    pub instance_type: String,
+    /// Highest CPU utilization over the idle period.
+    pub max_cpu: f64,
+    pub hourly: Option<f64>,
+}
+
+/// Running instances, other than `exclude`, whose CPU stayed below the
+/// threshold for the configured period. Instances launched more recently
+/// than that are never idle.
+pub async fn find_idle(
+    ec2: &EC2,
+    cloudwatch: &CloudWatch,
+    exclude: &str,
+    config: &IdleConfig,
+) -> anyhow::Result<Vec<IdleInstance>> {
+    let since = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64
+        - (config.hours * 3600) as i64;
+
+    let mut idle = vec![];
+    for instance in ec2
+        .describe_instance(vec![InstanceStateName::Running])
+        .await?
+    {
+        let launched_before = instance.launch_time().is_some_and(|t| t.secs() <= since);
+        if instance.instance_id() == Some(exclude) || !launched_before {
+            continue;
+        }
+        let instance_id = instance.instance_id().unwrap_or_default().to_string();
+        // Missing CloudWatch permissions shouldn't get in the way of the shell.
+        let max_cpu = match cloudwatch.max_cpu(&instance_id, config.hours).await {
+            Ok(Some(cpu)) => cpu,
+            Ok(None) => continue,
+            Err(err) => {
+                tracing::debug!("Skipping idle check of {instance_id}: {err}");
+                continue;
+            }
+        };
+        if max_cpu < config.cpu_percent as f64 {
+            idle.push(idle_instance(instance, max_cpu));
+        }
+    }
+    Ok(idle)
+}
+
+fn idle_instance(instance: Instance, max_cpu: f64) -> IdleInstance {
+    let instance_type = instance
+        .instance_type()
+        .map(|t| t.to_string())
+        .unwrap_or_default();
+    IdleInstance {
+        hourly: on_demand_hourly(&instance_type),
+        instance_type,
+        max_cpu,
+        instance: instance.into(),
+    }
+}
+
+/// Print the idle instances with their cost, and stop them if the user
+/// presses `s`. Does nothing when stdin isn't a terminal.
+pub async fn remind_idle(
+    ec2: &EC2,
+    cloudwatch: &CloudWatch,
+    exclude: &str,
+    config: &IdleConfig,
+) -> anyhow::Result<()> {
+    if !termion::is_tty(&std::io::stdin()) {
+        return Ok(());
+    }
+    let idle = find_idle(ec2, cloudwatch, exclude, config).await?;
+    if idle.is_empty() {
+        return Ok(());
+    }
+
+    println!(
+        "Reminder: {} other instance(s) had CPU below {}% for the last {}h:",
+        idle.len(),
+        config.cpu_percent,
+        config.hours
+    );
+    for i in &idle {
+        println!(
+            "  {} ({}, {})  max CPU {:.1}%  {}",
+            i.instance.name,
+            i.instance_type,
+            i.instance.instance_id,
+            i.max_cpu,
+            i.hourly
+                .map_or("unknown cost".into(), |h| format!("${h:.3}/h"))
+        );
+    }
+    let total: f64 = idle.iter().filter_map(|i| i.hourly).sum();
+    print!("That's about ${total:.2}/h. Press [s] to stop them, any other key to continue. ");
+    std::io::stdout().flush()?;
+
+    let key = {
+        let _raw_term = std::io::stdout().into_raw_mode()?;
+        std::io::stdin().keys().next()
+    };
+    println!();
+    if matches!(key, Some(Ok(Key::Char('s' | 'S')))) {
+        let instance_ids = idle
+            .iter()
+            .map(|i| i.instance.instance_id.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        ec2.stop_instances(&instance_ids, false).await?;
+    }
+    Ok(())
+}
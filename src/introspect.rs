@@ -0,0 +1,164 @@
+//! `korasi introspect`: the installed version's subcommands, flags and
+//! capabilities as JSON, for wrappers that would otherwise parse `--help`.
+//!
+//! ```text
+//! {
+//!   "name": "korasi",
+//!   "version": "0.1.2",
+//!   "features": ["events-ndjson", ...],
+//!   "args": [{"name": "region", "long": "region", "short": "r", ...}],
+//!   "commands": [{"name": "run", "aliases": ["r"], "args": [...], "commands": []}, ...],
+//!   "plugins": [{"name": "corp-login", "path": "/opt/corp/bin/korasi-corp-login"}]
+//! }
+//! ```
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use clap::{Arg, ArgAction, Command};
+
+use crate::json::Value;
+
+/// Capabilities wrappers may check for, beyond the listed commands and
+/// flags.
+pub const FEATURES: &[&str] = &[
+    "events-ndjson",
+    "sync-rsync",
+    "plugins",
+    "config-schema",
+    "config-migrate",
+];
+
+fn describe_arg(arg: &Arg) -> Value {
+    let takes_value = matches!(arg.get_action(), ArgAction::Set | ArgAction::Append);
+    let strings = |v: Vec<String>| Value::Array(v.into_iter().map(Value::from).collect());
+    Value::Object(vec![
+        ("name".into(), arg.get_id().as_str().into()),
+        ("long".into(), arg.get_long().into()),
+        (
+            "short".into(),
+            arg.get_short().map(|c| c.to_string()).into(),
+        ),
+        ("positional".into(), arg.is_positional().into()),
+        ("takes_value".into(), takes_value.into()),
+        (
+            "multiple".into(),
+            matches!(arg.get_action(), ArgAction::Append).into(),
+        ),
+        ("required".into(), arg.is_required_set().into()),
+        (
+            "default".into(),
+            arg.get_default_values()
+                .first()
+                .map(|v| v.to_string_lossy().to_string())
+                .into(),
+        ),
+        (
+            "possible_values".into(),
+            strings(
+                arg.get_possible_values()
+                    .iter()
+                    .map(|v| v.get_name().to_string())
+                    .collect(),
+            ),
+        ),
+        ("help".into(), arg.get_help().map(|h| h.to_string()).into()),
+    ])
+}
+
+/// A command, its arguments and subcommands. `--help` and `--version`
+/// are left out, every command has them.
+pub fn describe_command(cmd: &Command) -> Value {
+    let args = cmd
+        .get_arguments()
+        .filter(|a| !matches!(a.get_action(), ArgAction::Help | ArgAction::Version))
+        .map(describe_arg)
+        .collect();
+    let commands = cmd
+        .get_subcommands()
+        .filter(|c| c.get_name() != "help")
+        .map(describe_command)
+        .collect();
+    Value::Object(vec![
+        ("name".into(), cmd.get_name().into()),
+        (
+            "about".into(),
+            cmd.get_about().map(|a| a.to_string()).into(),
+        ),
+        (
+            "aliases".into(),
+            Value::Array(cmd.get_all_aliases().map(Value::from).collect()),
+        ),
+        ("args".into(), Value::Array(args)),
+        ("commands".into(), Value::Array(commands)),
+    ])
+}
+
+/// The whole CLI, with the plugins found for it.
+pub fn introspect(cmd: &Command, plugins: &BTreeMap<String, PathBuf>) -> Value {
+    let Value::Object(described) = describe_command(cmd) else {
+        unreachable!("commands are described as objects");
+    };
+    let mut fields = vec![
+        ("name".into(), cmd.get_name().into()),
+        ("version".into(), env!("CARGO_PKG_VERSION").into()),
+        (
+            "features".into(),
+            Value::Array(FEATURES.iter().map(|f| Value::from(*f)).collect()),
+        ),
+    ];
+    // Everything but the name, which comes first.
+    fields.extend(described.into_iter().skip(1));
+    fields.push((
+        "plugins".into(),
+        Value::Array(
+            plugins
+                .iter()
+                .map(|(name, path)| {
+                    Value::Object(vec![
+                        ("name".into(), name.as_str().into()),
+                        ("path".into(), path.display().to_string().into()),
+                    ])
+                })
+                .collect(),
+        ),
+    ));
+    Value::Object(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, ArgAction, Command};
+
+    use super::describe_command;
+
+    #[test]
+    fn describe_cli() {
+        let cmd = Command::new("korasi").subcommand(
+            Command::new("run")
+                .about("Run a command")
+                .visible_alias("r")
+                .arg(
+                    Arg::new("user")
+                        .long("user")
+                        .short('u')
+                        .default_value("ubuntu"),
+                )
+                .arg(Arg::new("tag").long("tag").action(ArgAction::Append))
+                .arg(Arg::new("no_tty").long("no-tty").action(ArgAction::SetTrue))
+                .arg(Arg::new("command").num_args(1..)),
+        );
+
+        pretty_assertions::assert_eq!(
+            describe_command(&cmd).to_string(),
+            concat!(
+                r#"{"name":"korasi","about":null,"aliases":[],"args":[],"commands":["#,
+                r#"{"name":"run","about":"Run a command","aliases":["r"],"args":["#,
+                r#"{"name":"user","long":"user","short":"u","positional":false,"takes_value":true,"multiple":false,"required":false,"default":"ubuntu","possible_values":[],"help":null},"#,
+                r#"{"name":"tag","long":"tag","short":null,"positional":false,"takes_value":true,"multiple":true,"required":false,"default":null,"possible_values":[],"help":null},"#,
+                r#"{"name":"no_tty","long":"no-tty","short":null,"positional":false,"takes_value":false,"multiple":false,"required":false,"default":null,"possible_values":[],"help":null},"#,
+                r#"{"name":"command","long":null,"short":null,"positional":true,"takes_value":true,"multiple":false,"required":false,"default":null,"possible_values":[],"help":null}"#,
+                r#"],"commands":[]}]}"#
+            )
+        );
+    }
+}
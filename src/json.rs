@@ -0,0 +1,207 @@
+//! Minimal JSON writer for reports and exports, and just enough reading
+//! to pick fields out of AWS responses.
+
+use std::fmt::{self, Write};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// Keys keep their insertion order.
+    Object(Vec<(String, Value)>),
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Value::Null, Into::into)
+    }
+}
+
+/// Quote and escape a string literal.
+pub fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Value {
+    /// Serialize with two space indentation.
+    pub fn to_pretty(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, Some(0));
+        out
+    }
+
+    fn write(&self, out: &mut String, indent: Option<usize>) {
+        let newline = |out: &mut String, level: usize| {
+            if indent.is_some() {
+                out.push('\n');
+                out.push_str(&"  ".repeat(level));
+            }
+        };
+        let level = indent.unwrap_or(0);
+        let inner = indent.map(|i| i + 1);
+
+        match self {
+            Value::Null => out.push_str("null"),
+            Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Value::Number(n) if n.is_finite() => {
+                let _ = write!(out, "{n}");
+            }
+            Value::Number(_) => out.push_str("null"),
+            Value::String(s) => out.push_str(&quote(s)),
+            Value::Array(items) if items.is_empty() => out.push_str("[]"),
+            Value::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    newline(out, level + 1);
+                    item.write(out, inner);
+                }
+                newline(out, level);
+                out.push(']');
+            }
+            Value::Object(fields) if fields.is_empty() => out.push_str("{}"),
+            Value::Object(fields) => {
+                out.push('{');
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    newline(out, level + 1);
+                    out.push_str(&quote(key));
+                    out.push(':');
+                    if indent.is_some() {
+                        out.push(' ');
+                    }
+                    value.write(out, inner);
+                }
+                newline(out, level);
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// Compact serialization.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut out = String::new();
+        self.write(&mut out, None);
+        f.write_str(&out)
+    }
+}
+
+/// Values of the `"key": "..."` string fields anywhere in `json`, in
+/// order. Not a parser: keys are matched regardless of nesting.
+pub fn string_fields(json: &str, key: &str) -> Vec<String> {
+    let needle = format!("\"{key}\"");
+    json.match_indices(&needle)
+        .filter_map(|(i, _)| {
+            let rest = json[i + needle.len()..].trim_start().strip_prefix(':')?;
+            let rest = rest.trim_start().strip_prefix('"')?;
+            let mut value = String::new();
+            let mut chars = rest.chars();
+            loop {
+                match chars.next()? {
+                    '"' => return Some(value),
+                    '\\' => match chars.next()? {
+                        'n' => value.push('\n'),
+                        't' => value.push('\t'),
+                        c => value.push(c),
+                    },
+                    c => value.push(c),
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{string_fields, Value};
+
+    #[test]
+    fn write_json() {
+        let value = Value::Object(vec![
+            ("name".into(), "a \"quoted\"\nline".into()),
+            ("cpus".into(), 4u64.into()),
+            ("gpu".into(), Value::from(None::<String>)),
+            (
+                "tools".into(),
+                Value::Array(vec![true.into(), Value::Object(vec![])]),
+            ),
+        ]);
+
+        pretty_assertions::assert_eq!(
+            value.to_string(),
+            r#"{"name":"a \"quoted\"\nline","cpus":4,"gpu":null,"tools":[true,{}]}"#
+        );
+        pretty_assertions::assert_eq!(
+            value.to_pretty(),
+            r#"{
+  "name": "a \"quoted\"\nline",
+  "cpus": 4,
+  "gpu": null,
+  "tools": [
+    true,
+    {}
+  ]
+}"#
+        );
+    }
+
+    #[test]
+    fn read_string_fields() {
+        let body = r#"{"NextToken":"abc","Schedules":[{"Name":"korasi-stop","State":"ENABLED","Target":{"Arn":"arn"}},{"Name" : "korasi-\"start\""}]}"#;
+        pretty_assertions::assert_eq!(
+            string_fields(body, "Name"),
+            vec!["korasi-stop", "korasi-\"start\""]
+        );
+        pretty_assertions::assert_eq!(string_fields(body, "NextToken"), vec!["abc"]);
+        pretty_assertions::assert_eq!(string_fields(body, "Missing"), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,79 @@
+//! Secrets kept in the OS keychain, through the `security` tool on macOS
+//! and `secret-tool` (libsecret) elsewhere. Without either, nothing is
+//! remembered.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// Service secrets are filed under.
+const SERVICE: &str = "korasi";
+
+#[cfg(target_os = "macos")]
+const TOOL: &str = "security";
+#[cfg(not(target_os = "macos"))]
+const TOOL: &str = "secret-tool";
+
+/// Whether there is a keychain to remember secrets in.
+pub fn available() -> bool {
+    Command::new(TOOL)
+        .arg("--help")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+/// Secret stored for `account`, if any.
+pub fn get(account: &str) -> Option<String> {
+    let mut cmd = Command::new(TOOL);
+    if cfg!(target_os = "macos") {
+        cmd.args(["find-generic-password", "-s", SERVICE, "-a", account, "-w"]);
+    } else {
+        cmd.args(["lookup", "service", SERVICE, "account", account]);
+    }
+    let output = cmd.stderr(Stdio::null()).output().ok()?;
+    let secret = String::from_utf8(output.stdout).ok()?;
+    let secret = secret.trim_end_matches('\n');
+    (output.status.success() && !secret.is_empty()).then(|| secret.to_string())
+}
+
+/// Quote `arg` for the command line `security -i` reads.
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Store `secret` for `account`, replacing any earlier one.
+pub fn set(account: &str, secret: &str) -> anyhow::Result<()> {
+    // Both read from stdin, keeping the secret off the command line.
+    let mut cmd = Command::new(TOOL);
+    let input = if cfg!(target_os = "macos") {
+        cmd.arg("-i");
+        format!(
+            "add-generic-password -U -s {} -a {} -w {}\n",
+            quote(SERVICE),
+            quote(account),
+            quote(secret)
+        )
+    } else {
+        cmd.args(["store", "--label", &format!("{SERVICE}: {account}")])
+            .args(["service", SERVICE, "account", account]);
+        secret.to_string()
+    };
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(input.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        anyhow::bail!("{TOOL} failed to store the secret ({status})");
+    }
+    Ok(())
+}
@@ -1,30 +1,152 @@
+pub mod archive;
+pub mod bootlog;
+pub mod build;
+pub mod clone;
+pub mod cloudwatch;
+pub mod cluster;
+pub mod config;
+pub mod copy;
 pub mod create;
+pub mod detach;
+pub mod drift;
 pub mod ec2;
+pub mod environment;
+pub mod events;
+pub mod iam;
+pub mod idle;
+pub mod introspect;
+pub mod json;
+pub mod keychain;
+pub mod listing;
+pub mod lock;
+pub mod metrics;
+pub mod migrate;
+#[cfg(test)]
+pub mod mock;
 pub mod opt;
+pub mod patch;
+pub mod plugin;
+pub mod ports;
+pub mod pricing;
+pub mod progress;
+pub mod proxy;
+pub mod report;
+pub mod repro;
+pub mod s3;
+pub mod scheduler;
+pub mod scripts;
+pub mod show;
+pub mod sigv4;
+pub mod spot;
 pub mod ssh;
+pub mod state;
+pub mod stream;
+pub mod sync;
+pub mod telemetry;
+pub mod template;
+pub mod terminal;
+pub mod toml;
+pub mod units;
 pub mod util;
+pub mod verify;
+pub mod websocket;
+
+use std::collections::HashMap;
 
 use anyhow::Context;
 use aws_config::{
-    self, meta::region::RegionProviderChain, timeout::TimeoutConfig, BehaviorVersion,
+    self, meta::region::RegionProviderChain, retry::RetryConfig as AwsRetryConfig,
+    timeout::TimeoutConfig, BehaviorVersion,
+};
+use aws_sdk_ec2::{
+    primitives::DateTime,
+    types::{Instance, InstanceStateName, InstanceType, PlacementStrategy},
 };
-use aws_sdk_ec2::types::{InstanceStateName, InstanceType};
 use aws_types::{region::Region, SdkConfig as AwsSdkConfig};
-use inquire::{Select, Text};
+use clap::CommandFactory;
+use inquire::{MultiSelect, Text};
 use termion::raw::IntoRawMode;
 use tokio::time::Duration;
 
-use create::CreateCommand;
-use ec2::{EC2Impl as EC2, SSH_KEY_NAME, SSH_SECURITY_GROUP};
-use opt::{Commands, Opt};
-use ssh::Session;
-use util::{ids_to_str, multi_select_instances, select_instance, UtilImpl as Util};
+use bootlog::{print_console, wait_with_boot_log};
+use build::remote_build;
+use clone::{clone_instance, CloneOverrides};
+use cloudwatch::CloudWatchImpl;
+use cluster::{
+    cluster_nodes, hostfile, node_name, placement_group_name, write_file_command, CLUSTER_TAG,
+};
+use config::{expand_home, Config, RetryConfig};
+use copy::copy_between;
+use create::{
+    instance_name, parse_s3_uri, required_tags, zone_order, CreateCommand, RootVolume,
+    STAGED_USER_DATA_TAG,
+};
+use detach::{attach, detachable, timed, wait_for, PendingWait, WaitState};
+use drift::detect;
+use ec2::{
+    efa_security_group_name, EC2Error, EC2Impl as EC2, InstanceHealth, TagSelector,
+    GLOBAL_TAG_FILTER, SSH_KEY_NAME, SSH_SECURITY_GROUP,
+};
+use iam::{encoded_message, explain};
+use idle::remind_idle;
+use introspect::introspect;
+use listing::{print_cached, print_live, Row};
+use lock::{find_alias, locked_ami, preferred_arch, resolve_alias, update_lock};
+use metrics::serve;
+use migrate::migrate_instance;
+use opt::{
+    ClusterAction, Commands, EipAction, EventFormat, NetworkAction, Opt, PortsAction,
+    ProfileAction, RelayAction, ReproAction, ScheduleAction, SyncMode, TagAction,
+};
+use patch::patch_parallel;
+use plugin::{PluginEnv, PLUGIN_PREFIX};
+use ports::{
+    close_session_ports, format_permission, host_cidr, open_session_ports, relay_rules,
+    revoke_abandoned, PortSpec,
+};
+use pricing::hourly_prices;
+use progress::{format_bytes, format_duration};
+use proxy::proxy_command;
+use report::{render, Report};
+use repro::{git_source, launch_spec, Bundle, Manifest, STAGED_SETUP_PATH};
+use s3::S3Impl;
+use scheduler::{custom_schedule_name, schedule_name, Schedule, SchedulerImpl, SCHEDULE_PREFIX};
+use show::show;
+use spot::{watch, Hook};
+use ssh::{
+    exec_parallel, instance_output_path, parse_jump, read_public_key, shell_fallback, stderr_path,
+    stdin_is_piped, wait_for_port, wait_for_port_closed, ConnectOptions, OutputFiles,
+    ParallelOptions, RemoteExit, Session, CONNECTION_FAILED,
+};
+use state::{state_dir, Profile, Profiles, RunLog, Stats, DIRECT};
+use sync::{sync, Rsync};
+use util::{
+    active_instances, ids_to_str, instance_types, multi_select_instances, pick_instance,
+    private_key_path, resolve_user, select_address, select_image, select_instance, select_machine,
+    spend_summary, stop_on_exit, tagged_instances, AddressOption, SelectOption, UtilImpl as Util,
+};
+use verify::verify_instance;
+use websocket::{relay_url, relay_user_data, RELAY_NAME, RELAY_PORT, RELAY_TAG};
+
+/// How long `Reboot --wait-ssh` gives an instance to shut down.
+const REBOOT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Tags korasi finds and names its instances by.
+const RESERVED_TAGS: &[&str] = &["Name", "application"];
+
+/// How long `Create --wait` waits for status checks, and `--connect`
+/// then for SSH.
+const BOOT_TIMEOUT: Duration = Duration::from_secs(600);
 
 /// Loads an AWS config from default environments.
+///
+/// Throttled requests and transient errors are retried with exponential
+/// backoff following `retry`, or the SDK defaults when `None`.
 pub async fn load_config(
     region: Option<String>,
     profile_name: Option<String>,
     operation_timeout: Option<Duration>,
+    retry: Option<RetryConfig>,
 ) -> AwsSdkConfig {
     tracing::info!("loading config for the region {:?}", region);
 
@@ -45,6 +167,14 @@ pub async fn load_config(
         .region(reg_provider)
         .profile_name(profile_name.as_ref().unwrap_or(&"default".to_string()))
         .timeout_config(timeout_cfg);
+    if let Some(retry) = retry {
+        cfg = cfg.retry_config(
+            AwsRetryConfig::standard()
+                .with_max_attempts(retry.max_attempts.max(1))
+                .with_initial_backoff(Duration::from_millis(retry.initial_backoff_ms))
+                .with_max_backoff(Duration::from_secs(retry.max_backoff_secs)),
+        );
+    }
     if let Some(p) = profile_name {
         tracing::info!("loading the aws profile '{p}'");
         cfg = cfg.profile_name(p);
@@ -53,121 +183,1030 @@ pub async fn load_config(
     cfg.load().await
 }
 
+/// Print one line per instance, followed by its health when known.
+fn print_instances(instances: &[Instance], health: &HashMap<String, InstanceHealth>) {
+    for (i, instance) in instances.iter().enumerate() {
+        let tags = instance.tags();
+        let mut name = "";
+        for t in tags {
+            if t.key() == Some("Name") {
+                name = t.value().unwrap();
+            }
+        }
+
+        let mut host = "".to_string();
+        if let Some(dns) = instance.public_dns_name() {
+            if !dns.is_empty() {
+                host = dns.into();
+            }
+        }
+
+        tracing::info!(
+            "{}. {:?}, type = {}, state = {:?}, {:?}",
+            i + 1,
+            name,
+            instance.instance_type.as_ref().unwrap(),
+            instance.state().unwrap().name().unwrap(),
+            host,
+        );
+        if let Some(h) = instance.instance_id().and_then(|id| health.get(id)) {
+            if h.is_impaired() || !h.events.is_empty() {
+                tracing::warn!("   health = {h}");
+            } else {
+                tracing::info!("   health = {h}");
+            }
+        }
+    }
+}
+
+/// Rewrite deprecated keys of the config file in place, after backing it
+/// up to `<file>.bak`.
+fn migrate_config() -> anyhow::Result<()> {
+    let path = Config::path();
+    if !path.exists() {
+        println!("There is no {} to migrate.", config::CONFIG_FILE);
+        return Ok(());
+    }
+    let src = std::fs::read_to_string(path)?;
+    let (migrated, renamed) =
+        config::migrate(&src).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+    if renamed == 0 {
+        println!("{} is up to date.", path.display());
+        return Ok(());
+    }
+    let backup = format!("{}.bak", path.display());
+    std::fs::copy(path, &backup)?;
+    std::fs::write(path, migrated)?;
+    println!(
+        "Renamed {renamed} deprecated key(s) in {}, the original is at {backup}.",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Connect to `instance` the way its profile says worked last time, unless
+/// `connect` says otherwise, then remember how this connection went. A
+/// host key other than the remembered one is refused.
+async fn connect_profiled(
+    user: &str,
+    instance: &SelectOption,
+    ssh_key: String,
+    connect: &ConnectOptions,
+) -> anyhow::Result<Session> {
+    let profile = Profiles::load().get(&instance.instance_id).cloned();
+    let host = match (instance.address(connect), &profile) {
+        (Ok(host), _) => host,
+        (Err(_), Some(profile)) => profile.address.clone(),
+        (Err(err), None) => return Err(err),
+    };
+    // Settings given explicitly win over the remembered ones.
+    let connect = ConnectOptions {
+        port: connect.port.or(profile.as_ref().map(|p| p.port)),
+        relay: connect.relay.clone().or_else(|| {
+            profile
+                .as_ref()
+                .map(|p| p.transport.clone())
+                .filter(|t| t != DIRECT)
+        }),
+        ..connect.clone()
+    };
+    let plain_stdout = profile.as_ref().and_then(|p| p.plain_stdout);
+    let session = Session::connect_to(user, host.clone(), ssh_key, profile, &connect).await?;
+    if let Some(host_key) = session.host_key() {
+        Profiles::record(Profile {
+            instance_id: instance.instance_id.clone(),
+            user: user.into(),
+            port: connect.port(),
+            transport: connect.relay.unwrap_or(DIRECT.into()),
+            host_key,
+            address: host,
+            plain_stdout,
+        });
+    }
+    Ok(session)
+}
+
+/// Wait for `instance` to go down for its reboot, then to accept SSH
+/// connections again.
+async fn wait_rebooted(instance: &SelectOption, connect: &ConnectOptions) -> anyhow::Result<()> {
+    let host = instance.host()?;
+    let (port, relay) = (connect.port(), connect.relay.as_deref());
+    // The reboot is asynchronous, sshd may still be up for a while.
+    if !wait_for_port_closed(&host, port, relay, REBOOT_SHUTDOWN_TIMEOUT).await {
+        tracing::warn!(
+            "{} didn't go down, it may not have rebooted.",
+            instance.name
+        );
+    }
+    println!("Waiting for SSH on {host}...");
+    wait_for_port(&host, port, relay, BOOT_TIMEOUT).await?;
+    println!("{} is back up.", instance.name);
+    Ok(())
+}
+
+/// Open a bash shell over `session`, with the local terminal in raw mode.
+async fn interactive_shell(mut session: Session) -> anyhow::Result<()> {
+    let raw_term = std::io::stdout().into_raw_mode()?;
+    session.exec("bash").await?;
+    session.close().await?;
+    drop(raw_term);
+    Ok(())
+}
+
+/// The user CA key of korasi.toml, for the instances being launched to
+/// trust.
+fn user_ca_key(config: &Config) -> anyhow::Result<Option<String>> {
+    config
+        .ssh
+        .user_ca_key
+        .as_deref()
+        .map(|path| read_public_key(&expand_home(path)))
+        .transpose()
+}
+
+/// `tags` plus the ones korasi.toml requires, prompting for those
+/// missing.
+fn fill_required_tags(
+    config: &Config,
+    tags: &[TagSelector],
+    yes: bool,
+) -> anyhow::Result<Vec<TagSelector>> {
+    let (mut tags, missing) = required_tags(&config.tags, tags);
+    for key in missing {
+        if yes || !termion::is_tty(&std::io::stdin()) {
+            anyhow::bail!(
+                "The `{key}` tag is required by {}, set it with `--label {key}=VALUE`.",
+                Config::path().display()
+            );
+        }
+        let value = Text::new(&format!("Value of the required `{key}` tag:")).prompt()?;
+        if value.trim().is_empty() {
+            anyhow::bail!("Refusing to launch without the required `{key}` tag.");
+        }
+        tags.push(TagSelector {
+            key,
+            value: value.trim().into(),
+        });
+    }
+    Ok(tags)
+}
+
+/// The AMI an alias is locked to, for `machine`'s architecture unless
+/// `arch` is given.
+async fn alias_ami(
+    ec2: &EC2,
+    region: &str,
+    machine: &InstanceType,
+    alias: &str,
+    arch: Option<String>,
+    setup: &str,
+) -> anyhow::Result<String> {
+    let arch = match arch {
+        Some(arch) => arch,
+        None => preferred_arch(&ec2.supported_architectures(machine.clone()).await?)
+            .context("Machine type has no supported architectures")?,
+    };
+    locked_ami(ec2, region, alias, &arch, setup).await
+}
+
+/// Exit code to leave with after `run`, when a remote command failed, so
+/// scripts and CI see it like with ssh. Codes a process can't exit with
+/// are clamped to 255.
+pub fn exit_code(res: &anyhow::Result<()>) -> Option<i32> {
+    let RemoteExit(code) = res.as_ref().err()?.downcast_ref()?;
+    Some((*code).min(255) as i32)
+}
+
+/// Run the command of `opts`. Authorization failures are explained with
+/// the denied action, when STS lets us decode it.
 pub async fn run(opts: Opt) -> anyhow::Result<()> {
+    let (region, profile) = (opts.region.clone(), opts.profile.clone());
+    let res = run_command(opts).await;
+    if let Some(err) = res
+        .as_ref()
+        .err()
+        .and_then(|e| e.downcast_ref::<EC2Error>())
+    {
+        if encoded_message(err).is_some() {
+            let config = load_config(Some(region), Some(profile), None, None).await;
+            if let Some(explanation) = explain(&config, err).await {
+                eprintln!("hint: {explanation}");
+            }
+        }
+    }
+    res
+}
+
+#[tracing::instrument(skip_all, fields(command = ?opts.commands))]
+async fn run_command(opts: Opt) -> anyhow::Result<()> {
     let Opt {
         profile,
         region,
         ssh_key,
+        key_name,
+        import_key,
+        relay,
+        ssh_port: port_flag,
+        healthy_only,
         tag,
+        setup,
+        yes,
+        max_attempts,
+        events,
         ..
     } = opts;
 
-    let ssh_path = std::env::var("HOME")
-        .map(|h| {
-            if let Some(ssh_key) = ssh_key {
-                ssh_key
-            } else {
-                format!("{}/.ssh/{SSH_KEY_NAME}.pem", h)
+    if let Commands::MigrateConfig = opts.commands {
+        return migrate_config();
+    }
+    if healthy_only {
+        util::hide_unhealthy();
+    }
+
+    let mut config = Config::load()?;
+    if let Some(n) = max_attempts {
+        config.retry.max_attempts = n;
+    }
+    let key_name = key_name
+        .or_else(|| config.launch.key_name.clone())
+        .unwrap_or_else(|| SSH_KEY_NAME.into());
+    let mut connect_opts = ConnectOptions {
+        port: port_flag.or(config.ssh.port),
+        bastion: None,
+        relay,
+        certificate: config.ssh.certificate.as_deref().map(expand_home),
+    };
+    let ssh_path = match (ssh_key, &import_key) {
+        (Some(ssh_key), _) => ssh_key,
+        (None, Some(public_key)) => private_key_path(public_key)?,
+        (None, None) => std::env::var("HOME")
+            .map(|h| format!("{}/.ssh/{key_name}.pem", h))
+            .context("HOME is not set")?,
+    };
+    match &opts.commands {
+        Commands::Plugins => {
+            let plugins = plugin::list(&config.plugins);
+            if plugins.is_empty() {
+                println!("No plugins found.");
+            }
+            for (name, path) in plugins {
+                println!("{name:<20} {}", path.display());
+            }
+            return Ok(());
+        }
+        Commands::Introspect => {
+            let cmd = Opt::command().name("korasi");
+            println!(
+                "{}",
+                introspect(&cmd, &plugin::list(&config.plugins)).to_pretty()
+            );
+            return Ok(());
+        }
+        Commands::External(args) => {
+            let (name, args) = args.split_first().context("Missing plugin name")?;
+            let env = PluginEnv {
+                profile,
+                region,
+                tag,
+                ssh_key: ssh_path,
+                config: Config::path().to_path_buf(),
+                state_dir: state_dir(),
+                yes,
+            };
+            let code = plugin::run(name, args.to_vec(), &config.plugins, &env).await?;
+            if code != 0 {
+                anyhow::bail!("{PLUGIN_PREFIX}{name} exited with code {code}.");
             }
-        })
-        .unwrap();
+            return Ok(());
+        }
+        _ => {}
+    }
+    let setup = setup
+        .or(config.launch.setup.clone())
+        .unwrap_or("start_up.sh".into());
 
-    let shared_config = load_config(Some(region), Some(profile), None).await;
+    let shared_config = load_config(
+        Some(region.clone()),
+        Some(profile.clone()),
+        None,
+        Some(config.retry.clone()),
+    )
+    .await;
     let client = aws_sdk_ec2::Client::new(&shared_config);
-    let ec2 = EC2::new(client, tag);
+    let ec2 = EC2::new(client, tag.clone());
+    // Only what launches instances, or tears down, needs korasi's VPC.
+    let network = if matches!(
+        opts.commands,
+        Commands::Create { .. }
+            | Commands::Clone { .. }
+            | Commands::Repro { .. }
+            | Commands::Cluster { .. }
+            | Commands::Network { .. }
+            | Commands::Relay { .. }
+            | Commands::Obliterate
+    ) {
+        ec2.describe_network().await?
+    } else {
+        None
+    };
+    let ec2 = ec2
+        .with_network(network)
+        .with_key_name(key_name)
+        .with_ssh_port(connect_opts.port());
 
-    let info = Util::create_or_get_keypair(&ec2, ssh_path.clone()).await?;
+    let info = match &import_key {
+        Some(public_key) => Util::import_or_get_keypair(&ec2, public_key).await?,
+        None => Util::create_or_get_keypair(&ec2, ssh_path.clone()).await?,
+    };
     tracing::info!("Using SSH key at = {}", ssh_path);
 
+    if !matches!(opts.commands, Commands::Gc) {
+        match detach::reap(&ec2, &region).await {
+            Ok(pending) => {
+                for (deletion, left) in pending.iter().filter(|(d, _)| d.is_stuck()) {
+                    tracing::warn!(
+                        "{} still not terminated {} after deletion, see `korasi gc`.",
+                        left.join(","),
+                        format_duration(deletion.elapsed())
+                    );
+                }
+            }
+            Err(err) => tracing::warn!("Failed to check on detached deletions: {err}"),
+        }
+    }
+
     match opts.commands {
-        Commands::Create { ami_id } => {
-            let machine: InstanceType =
-                Select::new("Select the machine type:", InstanceType::values().to_vec())
-                    .prompt()
-                    .unwrap()
-                    .into();
+        Commands::Create {
+            ami_id,
+            ami_owner,
+            ami_arch,
+            ami_name,
+            disk_size,
+            volume_type,
+            iops,
+            throughput,
+            user_data_bucket,
+            iam_profile,
+            placement_group,
+            placement_strategy,
+            tags,
+            az,
+            efa,
+            instance_type,
+            user,
+            wait,
+            connect,
+            auto_stop,
+        } => {
+            let ec2 = ec2.with_tags(fill_required_tags(&config, &tags, yes)?);
+
+            let launch = &config.launch;
+            let user = user.or(launch.user.clone()).unwrap_or("ubuntu".into());
+            let machine = match instance_type.or(launch.instance_type.clone()) {
+                Some(t) => InstanceType::from(t.as_str()),
+                None => select_machine(&ec2, &shared_config, "Select the machine type:").await?,
+            };
+            let machine_type = machine.to_string();
+            let ami_id = match ami_id.or(launch.ami.clone()) {
+                Some(id) if id.starts_with("ami-") => id,
+                Some(alias) => alias_ami(&ec2, &region, &machine, &alias, ami_arch, &setup).await?,
+                None => {
+                    let architectures = match ami_arch {
+                        Some(arch) => vec![arch],
+                        None => ec2.supported_architectures(machine.clone()).await?,
+                    };
+                    let image = select_image(
+                        &ec2,
+                        "Select the image:",
+                        &ami_owner,
+                        architectures,
+                        &ami_name,
+                    )
+                    .await?;
+                    image.image_id().unwrap_or_default().to_string()
+                }
+            };
+            let mut security_groups = vec![];
+            if efa {
+                if !ec2.efa_supported(machine.clone()).await? {
+                    anyhow::bail!("{machine} doesn't support EFA, see `aws ec2 describe-instance-types --filters Name=network-info.efa-supported,Values=true`.");
+                }
+                let name = efa_security_group_name(placement_group.as_deref());
+                security_groups.push(ec2.get_efa_security_group(&name).await?);
+            }
+            if let Some(name) = &placement_group {
+                match ec2.describe_placement_group(name).await? {
+                    Some(group) => tracing::info!(
+                        "Using {} placement group {name}",
+                        group.strategy().map(|s| s.as_str()).unwrap_or("existing")
+                    ),
+                    None => {
+                        ec2.create_placement_group(
+                            name,
+                            PlacementStrategy::from(placement_strategy.as_str()),
+                        )
+                        .await?;
+                        println!("Created {placement_strategy} placement group {name}.");
+                    }
+                }
+            }
+            // The subnet of korasi's VPC is in a single zone.
+            let availability_zones = match ec2.network() {
+                Some(_) if az.is_some() => {
+                    anyhow::bail!("--az can't be used with the VPC of `korasi network init`.")
+                }
+                Some(_) => vec![],
+                None => zone_order(
+                    az.as_deref(),
+                    &launch.availability_zones,
+                    &ec2.describe_availability_zones().await?,
+                ),
+            };
             tracing::info!("Launching {machine} instance...");
-            CreateCommand
-                .launch(&ec2, machine, ami_id, info.unwrap(), "start_up.sh".into())
+            let instance_ids = CreateCommand {
+                root_volume: RootVolume {
+                    size: disk_size,
+                    volume_type: volume_type.map(|v| v.as_str().into()),
+                    iops,
+                    throughput,
+                },
+                user_data_store: user_data_bucket.map(|bucket| S3Impl::new(&shared_config, bucket)),
+                iam_profile,
+                auto_stop,
+                placement_group,
+                efa,
+                security_groups,
+                availability_zones,
+                user_ca_key: user_ca_key(&config)?,
+                ssh_port: connect_opts.port,
+            }
+            .launch(
+                &ec2,
+                machine,
+                ami_id,
+                info.context("No key pair to launch instances with")?,
+                setup,
+            )
+            .await
+            .inspect_err(|err| {
+                if let Some(hint) = err.hint() {
+                    eprintln!("hint: {hint}");
+                }
+            })?;
+
+            if wait || connect {
+                for (i, instance_id) in instance_ids.iter().enumerate() {
+                    println!("Waiting for {instance_id} to pass status checks...");
+                    // Detaching leaves this and all later instances to wait on.
+                    let remaining = instance_ids[i..].join(",");
+                    let ready = detachable(
+                        wait_with_boot_log(&ec2, instance_id, BOOT_TIMEOUT),
+                        PendingWait::new(WaitState::Ready, &region, &remaining),
+                        std::slice::from_ref(&machine_type),
+                    )
+                    .await?;
+                    if !ready {
+                        return Ok(());
+                    }
+                }
+            }
+
+            if !config.verify.is_empty() {
+                for instance_id in &instance_ids {
+                    verify_instance(
+                        &ec2,
+                        instance_id,
+                        &user,
+                        &ssh_path,
+                        &connect_opts,
+                        &config.verify,
+                    )
+                    .await?;
+                }
+            }
+
+            if connect {
+                let instance_id = instance_ids.first().context("No instance was launched")?;
+                let host = ec2
+                    .get_instance(instance_id)
+                    .await?
+                    .public_dns_name()
+                    .filter(|h| !h.is_empty())
+                    .context("Instance has no public DNS name to connect to")?
+                    .to_string();
+                println!("Waiting for SSH on {host}...");
+                wait_for_port(
+                    &host,
+                    connect_opts.port(),
+                    connect_opts.relay.as_deref(),
+                    BOOT_TIMEOUT,
+                )
                 .await?;
+                interactive_shell(Session::connect(&user, host, ssh_path, &connect_opts).await?)
+                    .await?;
+            }
         }
-        Commands::List => {
-            let res = ec2.describe_instance(vec![]).await.unwrap();
-            if res.is_empty() {
-                tracing::warn!("There are no active instances.");
-                return Ok(());
+        Commands::Clone {
+            instance,
+            name,
+            instance_type,
+            no_user_data,
+        } => {
+            let candidates: Vec<SelectOption> = ec2
+                .describe_instance(vec![])
+                .await?
+                .into_iter()
+                .map(SelectOption::from)
+                .collect();
+            let source = match instance {
+                Some(instance) => pick_instance(&candidates, &instance)?,
+                None => select_instance(&ec2, "Choose instance to clone:", vec![]).await?,
+            };
+            let overrides = CloneOverrides {
+                name,
+                instance_type: instance_type.map(|t| InstanceType::from(t.as_str())),
+                no_user_data,
+            };
+            let key_pair = info.context("No key pair to launch instances with")?;
+            let instance_ids = clone_instance(&ec2, &source.instance_id, &key_pair, overrides)
+                .await
+                .inspect_err(|err| {
+                    if let Some(hint) = err.hint() {
+                        eprintln!("hint: {hint}");
+                    }
+                })?;
+            for id in instance_ids {
+                println!("Launched {id}.");
             }
-            for (i, instance) in res.iter().enumerate() {
-                let tags = instance.tags();
-                let mut name = "";
-                for t in tags {
-                    if t.key() == Some("Name") {
-                        name = t.value().unwrap();
+        }
+        Commands::List { all_regions: false } => {
+            let tag = tag.as_deref().unwrap_or(GLOBAL_TAG_FILTER);
+            let (cached, printed) = print_cached(&region, tag);
+            let (res, health) = active_instances(&ec2).await?;
+            let types: Vec<String> = res
+                .iter()
+                .filter_map(|i| i.instance_type().map(|t| t.to_string()))
+                .collect();
+            let prices = hourly_prices(&shared_config, &types).await;
+            let rows = res.iter().map(|i| Row::new(i, &health, &prices)).collect();
+            print_live(&region, tag, cached, printed, rows);
+        }
+        Commands::List { all_regions: true } => {
+            let mut set = tokio::task::JoinSet::new();
+            for region in ec2.describe_regions().await? {
+                let profile = profile.clone();
+                let tag = tag.clone();
+                let retry = config.retry.clone();
+                set.spawn(async move {
+                    let config =
+                        load_config(Some(region.clone()), Some(profile), None, Some(retry)).await;
+                    let ec2 = EC2::new(aws_sdk_ec2::Client::new(&config), tag);
+                    let res = async {
+                        let instances = ec2.describe_instance(vec![]).await?;
+                        let health = ec2
+                            .describe_instance_health(
+                                instances
+                                    .iter()
+                                    .filter_map(|i| i.instance_id().map(str::to_string))
+                                    .collect(),
+                            )
+                            .await?;
+                        Ok::<_, EC2Error>((instances, health))
                     }
-                }
+                    .await;
+                    (region, res)
+                });
+            }
 
-                let mut host = "".to_string();
-                if let Some(dns) = instance.public_dns_name() {
-                    if !dns.is_empty() {
-                        host = dns.into();
+            let mut results = set.join_all().await;
+            results.sort_by(|(a, _), (b, _)| a.cmp(b));
+            let mut total = 0;
+            for (region, res) in results {
+                match res {
+                    Ok((instances, _)) if instances.is_empty() => {}
+                    Ok((instances, health)) => {
+                        total += instances.len();
+                        tracing::info!("{region}:");
+                        print_instances(&instances, &health);
                     }
+                    Err(err) => tracing::warn!("{region}: {err}"),
                 }
-
-                tracing::info!(
-                    "{}. {:?}, type = {}, state = {:?}, {:?}",
-                    i + 1,
-                    name,
-                    instance.instance_type.as_ref().unwrap(),
-                    instance.state().unwrap().name().unwrap(),
-                    host,
-                );
+            }
+            if total == 0 {
+                tracing::warn!("There are no active instances in any region.");
             }
         }
-        Commands::Delete { wait } => {
-            if let Ok(chosen) =
-                multi_select_instances(&ec2, "Choose the instance(s):", vec![]).await
-            {
-                let instance_ids = ids_to_str(chosen);
-                if instance_ids.is_empty() {
-                    tracing::warn!("Nothing is selected. Use [space] to select option.");
-                } else {
-                    ec2.delete_instances(&instance_ids, wait).await?;
+        Commands::Report { html, hours } => {
+            let res = ec2.inventory().await?.instances.clone();
+            let health = ec2
+                .describe_instance_health(
+                    res.iter()
+                        .filter_map(|i| i.instance_id().map(str::to_string))
+                        .collect(),
+                )
+                .await?;
+            let types: Vec<String> = res
+                .iter()
+                .filter_map(|i| i.instance_type().map(|t| t.to_string()))
+                .collect();
+            let prices = hourly_prices(&shared_config, &types).await;
+            let rows: Vec<Row> = res.iter().map(|i| Row::new(i, &health, &prices)).collect();
+
+            let cloudwatch = CloudWatchImpl::new(&shared_config);
+            let mut cpu = HashMap::new();
+            for row in &rows {
+                match cloudwatch.cpu_series(&row.instance_id, hours).await {
+                    Ok(series) => {
+                        cpu.insert(row.instance_id.clone(), series);
+                    }
+                    Err(err) => tracing::warn!("No CPU metrics for {}: {err}", row.name),
                 }
             }
+
+            let report = Report {
+                region: region.clone(),
+                generated_at: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)?
+                    .as_secs(),
+                rows,
+                cpu,
+                hours,
+                stats: Stats::load(),
+                runs: RunLog::load().0,
+            };
+            std::fs::write(&html, render(&report))
+                .with_context(|| format!("Failed to write {}", html.display()))?;
+            println!("Wrote {}.", html.display());
+        }
+        Commands::Show => {
+            let chosen = select_instance(&ec2, "Choose instance to show:", vec![]).await?;
+            show(&ec2, &chosen.instance_id).await?;
         }
-        Commands::Start => {
+        Commands::ProxyCommand { instance, port } => {
+            proxy_command(&ec2, &instance, port, &connect_opts).await?;
+        }
+        Commands::Console { follow } => {
+            let chosen = select_instance(&ec2, "Choose instance to read:", vec![]).await?;
+            print_console(&ec2, &chosen.instance_id, follow).await?;
+        }
+        Commands::Screenshot { output, wake_up } => {
+            let chosen = select_instance(&ec2, "Choose instance to screenshot:", vec![]).await?;
+            let image = ec2
+                .get_console_screenshot(&chosen.instance_id, wake_up)
+                .await?;
+            let path = output.unwrap_or_else(|| format!("{}-console.jpg", chosen.name).into());
+            std::fs::write(&path, &image)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            println!(
+                "Wrote {} ({}).",
+                path.display(),
+                format_bytes(image.len() as u64)
+            );
+        }
+        Commands::Delete { wait, detach } => {
             if let Ok(chosen) = multi_select_instances(
                 &ec2,
                 "Choose the instance(s):",
-                vec![InstanceStateName::Stopped],
+                vec![],
+                false,
+                (!yes).then_some("deleted"),
             )
             .await
             {
+                let types = instance_types(&chosen);
                 let instance_ids = ids_to_str(chosen);
                 if instance_ids.is_empty() {
                     tracing::warn!("Nothing is selected. Use [space] to select option.");
+                } else {
+                    ec2.delete_instances(&instance_ids, false).await?;
+                    // Their addresses go to other instances next.
+                    let mut profiles = Profiles::load();
+                    let before = profiles.0.len();
+                    for id in instance_ids.split(',') {
+                        profiles.forget(id);
+                    }
+                    if profiles.0.len() != before {
+                        profiles.save();
+                    }
+                    if detach {
+                        detach::record(PendingWait::new(
+                            WaitState::Terminated,
+                            &region,
+                            &instance_ids,
+                        ));
+                        println!("Deleting {instance_ids}. Check on it with `korasi gc`.");
+                    } else if wait {
+                        detachable(
+                            ec2.wait_for_instance_terminated(&instance_ids, None),
+                            PendingWait::new(WaitState::Terminated, &region, &instance_ids),
+                            &types,
+                        )
+                        .await?;
+                    }
+                }
+            }
+        }
+        Commands::Profile { action } => match action {
+            ProfileAction::List => {
+                let profiles = Profiles::load();
+                if profiles.0.is_empty() {
+                    println!("No connection profiles yet.");
+                }
+                for p in &profiles.0 {
+                    println!(
+                        "{}  {}@{}:{}  via {}  {}",
+                        p.instance_id, p.user, p.address, p.port, p.transport, p.host_key
+                    );
+                }
+            }
+            ProfileAction::Forget { instance } => {
+                let mut profiles = Profiles::load();
+                let instance_id = if profiles.get(&instance).is_some() {
+                    instance
+                } else {
+                    let candidates: Vec<SelectOption> = ec2
+                        .describe_instance(vec![])
+                        .await?
+                        .into_iter()
+                        .map(SelectOption::from)
+                        .collect();
+                    pick_instance(&candidates, &instance)?.instance_id
+                };
+                if profiles.forget(&instance_id) {
+                    profiles.save();
+                    println!("Forgot the connection profile of {instance_id}.");
+                } else {
+                    println!("{instance_id} has no connection profile.");
+                }
+            }
+        },
+        Commands::Gc => {
+            let pending = detach::reap(&ec2, &region).await?;
+            if pending.is_empty() {
+                println!("No deletions pending in {region}.");
+            }
+            for (deletion, left) in pending {
+                let status = if deletion.is_stuck() {
+                    "stuck"
+                } else {
+                    "pending"
+                };
+                println!(
+                    "{status:<8} {} (deleted {} ago)",
+                    left.join(","),
+                    format_duration(deletion.elapsed())
+                );
+            }
+        }
+        Commands::Start { tags, all_stopped } => {
+            let chosen = if all_stopped {
+                Ok(ec2
+                    .describe_instance(vec![InstanceStateName::Stopped])
+                    .await?
+                    .into_iter()
+                    .map(SelectOption::from)
+                    .collect())
+            } else if tags.is_empty() {
+                multi_select_instances(
+                    &ec2,
+                    "Choose the instance(s):",
+                    vec![InstanceStateName::Stopped],
+                    true,
+                    None,
+                )
+                .await
+            } else {
+                Ok(tagged_instances(&ec2, vec![InstanceStateName::Stopped], &tags).await?)
+            };
+            if let Ok(chosen) = chosen {
+                let summary = spend_summary(&chosen);
+                let instance_ids = ids_to_str(chosen);
+                if instance_ids.is_empty() {
+                    if all_stopped {
+                        println!("No stopped instances.");
+                    } else {
+                        tracing::warn!("Nothing is selected. Use [space] to select option.");
+                    }
                 } else {
                     ec2.start_instances(&instance_ids).await?;
+                    if all_stopped {
+                        println!("Started {summary}.");
+                    }
                 }
             }
         }
-        Commands::Stop { wait } => {
-            if let Ok(chosen) = multi_select_instances(
-                &ec2,
-                "Choose the instance(s):",
-                vec![InstanceStateName::Running],
-            )
-            .await
-            {
+        Commands::Stop {
+            wait,
+            tags,
+            all_running,
+        } => {
+            let chosen = if all_running {
+                Ok(ec2
+                    .describe_instance(vec![InstanceStateName::Running])
+                    .await?
+                    .into_iter()
+                    .map(SelectOption::from)
+                    .collect())
+            } else if tags.is_empty() {
+                multi_select_instances(
+                    &ec2,
+                    "Choose the instance(s):",
+                    vec![InstanceStateName::Running],
+                    false,
+                    (!yes).then_some("stopped"),
+                )
+                .await
+            } else {
+                Ok(tagged_instances(&ec2, vec![InstanceStateName::Running], &tags).await?)
+            };
+            if let Ok(chosen) = chosen {
+                let summary = spend_summary(&chosen);
+                let types = instance_types(&chosen);
                 let instance_ids = ids_to_str(chosen);
                 if instance_ids.is_empty() {
-                    tracing::warn!("Nothing is selected. Use [space] to select option.");
+                    if all_running {
+                        println!("No running instances.");
+                    } else {
+                        tracing::warn!("Nothing is selected. Use [space] to select option.");
+                    }
                 } else {
-                    ec2.stop_instances(&instance_ids, wait).await?;
+                    ec2.stop_instances(&instance_ids, false).await?;
+                    if all_running {
+                        println!("Stopped {summary}.");
+                    }
+                    if wait {
+                        detachable(
+                            ec2.wait_for_instance_stopped(&instance_ids, None),
+                            PendingWait::new(WaitState::Stopped, &region, &instance_ids),
+                            &types,
+                        )
+                        .await?;
+                    }
                 }
             }
         }
-        Commands::Upload { src, dst, user } => {
+        Commands::Reboot { tags, wait_ssh } => {
+            let chosen = if tags.is_empty() {
+                multi_select_instances(
+                    &ec2,
+                    "Choose the instance(s):",
+                    vec![InstanceStateName::Running],
+                    false,
+                    (!yes).then_some("rebooted"),
+                )
+                .await?
+            } else {
+                tagged_instances(&ec2, vec![InstanceStateName::Running], &tags).await?
+            };
+            if chosen.is_empty() {
+                tracing::warn!("Nothing is selected. Use [space] to select option.");
+                return Ok(());
+            }
+            for instance in &chosen {
+                ec2.reboot_instance(&instance.instance_id).await?;
+                println!("Rebooting {}.", instance.name);
+            }
+            if wait_ssh {
+                for instance in &chosen {
+                    wait_rebooted(instance, &connect_opts).await?;
+                }
+            }
+        }
+        Commands::Patch {
+            user,
+            tags,
+            no_reboot,
+        } => {
+            let chosen = if tags.is_empty() {
+                multi_select_instances(
+                    &ec2,
+                    "Choose the instance(s) to patch:",
+                    vec![InstanceStateName::Running],
+                    false,
+                    (!yes).then_some("patched"),
+                )
+                .await?
+            } else {
+                tagged_instances(&ec2, vec![InstanceStateName::Running], &tags).await?
+            };
+            if chosen.is_empty() {
+                tracing::warn!("Nothing is selected. Use [space] to select option.");
+                return Ok(());
+            }
+            // Instances picked together are assumed to share a distro.
+            let user = resolve_user(&ec2, user, config.launch.user.as_ref(), &chosen[0]).await;
+            // Refresh inbound IP.
+            ec2.get_ssh_security_group().await?;
+
+            let hosts = chosen
+                .iter()
+                .map(|c| {
+                    let host = c.address(&connect_opts).unwrap_or_default();
+                    (c.instance_id.clone(), host)
+                })
+                .collect();
+            let results = patch_parallel(hosts, &user, &ssh_path, &connect_opts).await;
+
+            let mut failed = 0;
+            for (instance_id, res) in results {
+                let Some(instance) = chosen.iter().find(|c| c.instance_id == instance_id) else {
+                    continue;
+                };
+                let name = &instance.name;
+                let res = match res {
+                    Ok(report) => {
+                        println!("{name}: {report}");
+                        if !report.reboot_required {
+                            continue;
+                        }
+                        if no_reboot {
+                            println!("{name}: not rebooting (--no-reboot).");
+                            continue;
+                        }
+                        println!("Rebooting {name}.");
+                        match ec2.reboot_instance(&instance_id).await {
+                            Ok(()) => wait_rebooted(instance, &connect_opts).await,
+                            Err(err) => Err(err.into()),
+                        }
+                    }
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = res {
+                    failed += 1;
+                    eprintln!("{name}: {err}");
+                }
+            }
+            if failed > 0 {
+                anyhow::bail!("Patching failed on {failed} of {} instances.", chosen.len());
+            }
+        }
+        Commands::Wait { attach: true, .. } => attach(&ec2, &region, &connect_opts).await?,
+        Commands::Wait {
+            state: Some(state),
+            instances,
+            tags,
+            timeout,
+            ..
+        } => {
+            let chosen = if instances.is_empty() && tags.is_empty() {
+                multi_select_instances(&ec2, "Choose the instance(s):", vec![], true, None).await?
+            } else {
+                // Include terminated instances, they may be waited for.
+                let every_state = InstanceStateName::values()
+                    .iter()
+                    .map(|s| InstanceStateName::from(*s))
+                    .collect();
+                let candidates: Vec<SelectOption> = ec2
+                    .describe_instance_tagged(every_state, &tags)
+                    .await?
+                    .into_iter()
+                    .map(SelectOption::from)
+                    .collect();
+                if instances.is_empty() {
+                    candidates
+                } else {
+                    instances
+                        .iter()
+                        .map(|name| pick_instance(&candidates, name))
+                        .collect::<anyhow::Result<_>>()?
+                }
+            };
+            let types = instance_types(&chosen);
+            let instance_ids = ids_to_str(chosen);
+            if instance_ids.is_empty() {
+                anyhow::bail!("No instances to wait for.");
+            }
+            let wait = wait_for(
+                &ec2,
+                state,
+                &instance_ids,
+                Duration::from_secs(timeout),
+                &connect_opts,
+            );
+            timed(state, &types, wait).await?;
+            println!("{instance_ids} {}.", state.as_str());
+        }
+        Commands::Wait { .. } => {
+            let waits: Vec<_> = detach::load()
+                .into_iter()
+                .filter(|w| w.region == region)
+                .collect();
+            if waits.is_empty() {
+                println!("No detached waits in {region}.");
+            }
+            for w in waits {
+                println!("{:<12} {}", w.state.as_str(), w.instance_ids);
+            }
+        }
+        Commands::Upload {
+            src,
+            dst,
+            user,
+            via,
+        } => {
+            if via.is_some() {
+                connect_opts.bastion = via;
+            }
             if let Ok(chosen) = select_instance(
                 &ec2,
                 "Choose running instance to upload files to:",
@@ -176,54 +1215,303 @@ pub async fn run(opts: Opt) -> anyhow::Result<()> {
             .await
             {
                 tracing::info!("Chosen instance: {} = {}", chosen.name, chosen.instance_id);
-                // Refresh inbound IP.
-                ec2.get_ssh_security_group().await?;
-                let session =
-                    Session::connect(&user, chosen.public_dns_name.unwrap(), ssh_path).await?;
-                session.upload(src, dst).await?;
+                let user = resolve_user(&ec2, user, config.launch.user.as_ref(), &chosen).await;
+                if connect_opts.bastion.is_none() {
+                    // Refresh inbound IP.
+                    ec2.get_ssh_security_group().await?;
+                }
+                let session = connect_profiled(&user, &chosen, ssh_path, &connect_opts).await?;
+                let uploaded = session.upload(src, dst, &config.upload.exclude).await?;
+                Stats::record(|s| s.transfer_bytes += uploaded);
             } else {
                 tracing::warn!("No active running instances to upload to.");
             }
         }
-        Commands::Run { command, user } => {
-            if command.is_empty() {
-                tracing::warn!("Please enter a command to run.");
-                return Ok(());
-            }
+        Commands::Cp { src, dst, user } => {
+            let running: Vec<SelectOption> = ec2
+                .describe_instance(vec![InstanceStateName::Running])
+                .await?
+                .into_iter()
+                .map(|i| i.into())
+                .collect();
+            let from = pick_instance(&running, &src.instance)?;
+            let to = pick_instance(&running, &dst.instance)?;
+            // Refresh inbound IP.
+            ec2.get_ssh_security_group().await?;
 
+            let mut src_session =
+                Session::connect(&user, from.host()?, ssh_path.clone(), &connect_opts).await?;
+            let mut dst_session =
+                Session::connect(&user, to.host()?, ssh_path, &connect_opts).await?;
+            let copied = copy_between(&src_session, &src.path, &dst_session, &dst.path).await?;
+            src_session.close().await?;
+            dst_session.close().await?;
+            Stats::record(|s| s.transfer_bytes += copied);
+            println!(
+                "Copied {} from {} to {}.",
+                format_bytes(copied),
+                from.name,
+                to.name
+            );
+        }
+        Commands::Sync {
+            src,
+            dst,
+            user,
+            checksum,
+            delete,
+            mode,
+        } => {
             let chosen = select_instance(
                 &ec2,
-                "Choose running instance to execute remote command:",
+                "Choose running instance to sync files to:",
                 vec![InstanceStateName::Running],
             )
-            .await
-            .unwrap();
+            .await?;
+            tracing::info!("Chosen instance: {} = {}", chosen.name, chosen.instance_id);
+            // Refresh inbound IP.
+            ec2.get_ssh_security_group().await?;
+
+            let host = chosen.host()?;
+            let mut session =
+                Session::connect(&user, host.clone(), ssh_path.clone(), &connect_opts).await?;
+            if mode == SyncMode::Rsync && connect_opts.relay.is_some() {
+                tracing::warn!("rsync can't go through the relay, syncing over SFTP instead.");
+            } else if mode == SyncMode::Rsync {
+                let code = Rsync {
+                    user: user.clone(),
+                    host,
+                    port: connect_opts.port(),
+                    bastion: connect_opts
+                        .bastion
+                        .as_deref()
+                        .map(|spec| parse_jump(spec, &user))
+                        .transpose()?,
+                    certificate: connect_opts.certificate.clone(),
+                    host_key: session.host_public_key(),
+                    bastion_host_key: session.bastion_public_key(),
+                    ssh_key: ssh_path,
+                    exclude: config.upload.exclude.clone(),
+                    checksum,
+                    delete,
+                }
+                .run(&session, src, dst)
+                .await?;
+                session.close().await?;
+                if code != 0 {
+                    anyhow::bail!("rsync failed with exit code {code}.");
+                }
+                return Ok(());
+            }
+            let summary =
+                sync(&session, src, dst, &config.upload.exclude, checksum, delete).await?;
+            session.close().await?;
+            Stats::record(|s| s.transfer_bytes += summary.bytes);
+            println!(
+                "Uploaded {} file(s) ({}), {} unchanged, {} deleted.",
+                summary.uploaded,
+                format_bytes(summary.bytes),
+                summary.unchanged,
+                summary.deleted
+            );
+        }
+        Commands::Run {
+            command,
+            user,
+            stop_on_exit: stop,
+            no_tty,
+            no_pty,
+            output_file,
+            quiet,
+            tags,
+            via,
+        } => {
+            if via.is_some() {
+                connect_opts.bastion = via;
+            }
+            if command.is_empty() {
+                tracing::warn!("Please enter a command to run.");
+                return Ok(());
+            }
+            let command = command
+                .into_iter()
+                // arguments are escaped manually since the SSH protocol doesn't support quoting
+                .map(|cmd_part| shell_escape::escape(cmd_part.into()))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            let mut chosen = if tags.is_empty() {
+                multi_select_instances(
+                    &ec2,
+                    "Choose running instance(s) to execute remote command:",
+                    vec![InstanceStateName::Running],
+                    true,
+                    None,
+                )
+                .await?
+            } else {
+                tagged_instances(&ec2, vec![InstanceStateName::Running], &tags).await?
+            };
+            if chosen.is_empty() {
+                tracing::warn!("Nothing is selected. Use [space] to select option.");
+                return Ok(());
+            }
+
+            // Instances picked together are assumed to share a distro.
+            let user = resolve_user(&ec2, user, config.launch.user.as_ref(), &chosen[0]).await;
+
+            if connect_opts.bastion.is_none() {
+                // Refresh inbound IP.
+                ec2.get_ssh_security_group().await?;
+            }
+
+            if events == EventFormat::Ndjson && output_file.is_some() {
+                anyhow::bail!("--output-file can't be combined with --events ndjson.");
+            }
+            if chosen.len() > 1 {
+                let instance_ids = ids_to_str(chosen.clone());
+                let hosts = chosen
+                    .into_iter()
+                    .map(|c| {
+                        let host = c.address(&connect_opts).unwrap_or_default();
+                        (c.name, host)
+                    })
+                    .collect();
+                let opts = ParallelOptions {
+                    connect: connect_opts.clone(),
+                    strip_ansi: no_tty,
+                    events: events == EventFormat::Ndjson,
+                    output_file: output_file.as_deref(),
+                    quiet,
+                };
+                let results = exec_parallel(hosts, &user, &ssh_path, &command, opts).await;
+
+                let mut failed = 0;
+                // The highest exit code, as the one to exit with.
+                let mut worst = 0;
+                for (name, res) in &results {
+                    let code = *res.as_ref().unwrap_or(&CONNECTION_FAILED);
+                    Stats::record_job(code);
+                    RunLog::record(name, &command, code);
+                    worst = worst.max(code);
+                    match res {
+                        Ok(0) => tracing::info!("{name}: exit code 0"),
+                        Ok(code) => {
+                            failed += 1;
+                            eprintln!("{name}: exit code {code}");
+                        }
+                        Err(err) => {
+                            failed += 1;
+                            eprintln!("{name}: {err}");
+                        }
+                    }
+                }
+                if let Some(path) = &output_file {
+                    for (name, _) in &results {
+                        let path = instance_output_path(path, name);
+                        eprintln!("{name}: output saved to {}", path.display());
+                    }
+                }
+                if stop {
+                    stop_on_exit(&ec2, &instance_ids, yes).await?;
+                }
+                if failed > 0 {
+                    eprintln!("Command failed on {failed} of {} instances.", results.len());
+                    return Err(RemoteExit(worst).into());
+                }
+                return Ok(());
+            }
+
+            let chosen = chosen.remove(0);
             tracing::info!(
                 "Chosen instance: name = {}, instance_id = {}",
                 chosen.name,
                 chosen.instance_id
             );
 
-            // Refresh inbound IP.
-            ec2.get_ssh_security_group().await?;
-
-            let mut session =
-                Session::connect(&user, chosen.public_dns_name.unwrap(), ssh_path).await?;
-            let _raw_term = std::io::stdout().into_raw_mode()?;
-            // TODO: On centos, nothing is printed to stdout (message is received on SDK client).
-            session
-                .exec(
-                    &command
-                        .into_iter()
-                        // arguments are escaped manually since the SSH protocol doesn't support quoting
-                        .map(|cmd_part| shell_escape::escape(cmd_part.into()))
-                        .collect::<Vec<_>>()
-                        .join(" "),
-                )
-                .await?;
+            let mut session = connect_profiled(&user, &chosen, ssh_path, &connect_opts).await?;
+            session.set_strip_ansi(no_tty);
+            let code = if let Some(path) = &output_file {
+                let mut files = OutputFiles::create(path, quiet).await?;
+                let code = session.exec_no_pty(&command, Some(&mut files)).await?;
+                eprintln!(
+                    "Wrote {} to {} and {}",
+                    format_bytes(files.written),
+                    path.display(),
+                    stderr_path(path).display()
+                );
+                code
+            } else if events == EventFormat::Ndjson {
+                session.exec_events(&command, &chosen.name).await?
+            } else if no_pty || stdin_is_piped() {
+                // Piped stdin, eg. `cat input.json | korasi run -- ./process`,
+                // is streamed until EOF, which a PTY would echo and not end on.
+                session.exec_no_pty(&command, None).await?
+            } else {
+                // Probed once per instance, it costs a remote exec.
+                let plain_stdout = match Profiles::load()
+                    .get(&chosen.instance_id)
+                    .and_then(|p| p.plain_stdout)
+                {
+                    Some(plain) => plain,
+                    None => {
+                        let plain = session.probe_stdout().await?;
+                        Profiles::record_plain_stdout(&chosen.instance_id, plain);
+                        plain
+                    }
+                };
+                let command = if plain_stdout {
+                    command.clone()
+                } else {
+                    tracing::warn!("No output from {}, running through bash -lc.", chosen.name);
+                    shell_fallback(&command)
+                };
+                let _raw_term = if no_tty {
+                    None
+                } else {
+                    Some(std::io::stdout().into_raw_mode()?)
+                };
+                session.exec(&command).await?
+            };
             session.close().await?;
+            Stats::record_job(code);
+            RunLog::record(&chosen.name, &command, code);
+            if stop {
+                stop_on_exit(&ec2, &chosen.instance_id, yes).await?;
+            }
+            if code != 0 {
+                return Err(RemoteExit(code).into());
+            }
         }
-        Commands::Shell { user } => {
+        Commands::Watch {
+            user,
+            on_interruption,
+        } => {
+            let chosen = select_instance(
+                &ec2,
+                "Choose spot instance to watch:",
+                vec![InstanceStateName::Running],
+            )
+            .await?;
+            let user = resolve_user(&ec2, user, config.launch.user.as_ref(), &chosen).await;
+            let hook = on_interruption.as_deref().map(|command| Hook {
+                command,
+                user: &user,
+                ssh_key: &ssh_path,
+                connect: &connect_opts,
+            });
+            watch(&ec2, &chosen.instance_id, hook).await?;
+        }
+        Commands::Shell {
+            user,
+            stop_on_exit: stop,
+            via,
+            open_port,
+            keep_open,
+        } => {
+            if via.is_some() {
+                connect_opts.bastion = via;
+            }
             let chosen = select_instance(
                 &ec2,
                 "Choose running instance to ssh:",
@@ -238,57 +1526,1035 @@ pub async fn run(opts: Opt) -> anyhow::Result<()> {
                     chosen.instance_id
                 );
 
-                // Refresh inbound IP.
-                ec2.get_ssh_security_group().await?;
+                remind_idle(
+                    &ec2,
+                    &CloudWatchImpl::new(&shared_config),
+                    &chosen.instance_id,
+                    &config.idle,
+                )
+                .await?;
+
+                let user = resolve_user(&ec2, user, config.launch.user.as_ref(), &chosen).await;
+                let mut opened = vec![];
+                if connect_opts.bastion.is_none() {
+                    // Refresh inbound IP.
+                    let group = ec2.get_ssh_security_group().await?;
+                    opened = open_session_ports(&ec2, &group, &open_port).await?;
+                }
 
+                let res: anyhow::Result<()> = async {
+                    let session = connect_profiled(&user, &chosen, ssh_path, &connect_opts).await?;
+                    interactive_shell(session).await
+                }
+                .await;
+                if !keep_open {
+                    close_session_ports(&ec2, &opened).await;
+                }
+                res?;
+                if stop {
+                    stop_on_exit(&ec2, &chosen.instance_id, yes).await?;
+                }
+            } else {
+                tracing::warn!("There are no active instances to SSH into.");
+            }
+        }
+        Commands::Forward {
+            user,
+            forward,
+            open_port,
+            keep_open,
+        } => {
+            let chosen = select_instance(
+                &ec2,
+                "Choose running instance to forward to:",
+                vec![InstanceStateName::Running],
+            )
+            .await?;
+            // Refresh inbound IP.
+            let group = ec2.get_ssh_security_group().await?;
+            let opened = open_session_ports(&ec2, &group, &open_port).await?;
+
+            let res: anyhow::Result<()> = async {
                 let mut session =
-                    Session::connect(&user, chosen.public_dns_name.unwrap(), ssh_path).await?;
-                let _raw_term = std::io::stdout().into_raw_mode()?;
+                    Session::connect(&user, chosen.host()?, ssh_path, &connect_opts).await?;
                 session
-                    .exec(
-                        &vec!["bash"]
-                            .into_iter()
-                            .map(|cmd_part| shell_escape::escape(cmd_part.into()))
-                            .collect::<Vec<_>>()
-                            .join(" "),
+                    .forward_local(
+                        forward.local_port,
+                        &forward.remote_host,
+                        forward.remote_port,
                     )
                     .await?;
-                session.close().await?;
+                session.close().await
+            }
+            .await;
+            if !keep_open {
+                close_session_ports(&ec2, &opened).await;
+            }
+            res?;
+        }
+        Commands::Build { user, args } => {
+            let chosen = select_instance(
+                &ec2,
+                "Choose running instance to build on:",
+                vec![InstanceStateName::Running],
+            )
+            .await?;
+            tracing::info!(
+                "Chosen instance: name = {}, instance_id = {}",
+                chosen.name,
+                chosen.instance_id
+            );
+
+            // Refresh inbound IP.
+            ec2.get_ssh_security_group().await?;
+
+            let mut session =
+                Session::connect(&user, chosen.host()?, ssh_path, &connect_opts).await?;
+            let code = remote_build(&session, &args, &config.upload.exclude).await?;
+            session.close().await?;
+            Stats::record_job(code);
+            RunLog::record(
+                &chosen.name,
+                &format!("cargo build {}", args.join(" ")),
+                code,
+            );
+            if code != 0 {
+                anyhow::bail!("Remote cargo build failed with exit code {code}.");
+            }
+        }
+        Commands::Env { user, json, output } => {
+            let chosen = select_instance(
+                &ec2,
+                "Choose running instance to report on:",
+                vec![InstanceStateName::Running],
+            )
+            .await?;
+            // Refresh inbound IP.
+            ec2.get_ssh_security_group().await?;
+
+            let mut session = Session::connect(
+                &user,
+                chosen.public_dns_name.clone().unwrap_or_default(),
+                ssh_path,
+                &connect_opts,
+            )
+            .await?;
+            let mut report = environment::collect(&session).await?;
+            session.close().await?;
+
+            let instance = ec2.get_instance(&chosen.instance_id).await?;
+            report.instance_id = Some(chosen.instance_id);
+            report.instance_type = instance.instance_type().map(|t| t.to_string());
+            report.image_id = instance.image_id().map(str::to_string);
+
+            if let Some(path) = output {
+                std::fs::write(&path, report.to_json().to_pretty() + "\n")
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+                println!("Saved environment report to {}", path.display());
+            }
+            if json {
+                println!("{}", report.to_json().to_pretty());
             } else {
-                tracing::warn!("There are no active instances to SSH into.");
+                print!("{report}");
             }
         }
-        Commands::Obliterate => {
-            let yes = Text::new("Do you want to obliterate all resources [y/n]?:").prompt()?;
-            if !(yes == "y" || yes == "Y") {
-                tracing::warn!("Aborting obliterate.");
+        Commands::Repro { action } => match action {
+            ReproAction::Bundle {
+                user,
+                output,
+                command,
+            } => {
+                let chosen = select_instance(
+                    &ec2,
+                    "Choose running instance to bundle:",
+                    vec![InstanceStateName::Running],
+                )
+                .await?;
+                // Refresh inbound IP.
+                ec2.get_ssh_security_group().await?;
+
+                let launch = launch_spec(&ec2, &chosen.instance_id, Some(region)).await?;
+                let mut setup = ec2.get_user_data(&chosen.instance_id).await?;
+
+                let mut session = Session::connect(
+                    &user,
+                    chosen.public_dns_name.clone().unwrap_or_default(),
+                    ssh_path,
+                    &connect_opts,
+                )
+                .await?;
+                // Scripts staged on S3 are only referenced by the user data.
+                if setup
+                    .as_deref()
+                    .is_some_and(|s| s.contains(STAGED_SETUP_PATH))
+                {
+                    let output = session
+                        .exec_output(&format!("sudo cat {STAGED_SETUP_PATH}"))
+                        .await?;
+                    if output.code == 0 {
+                        setup = Some(String::from_utf8_lossy(&output.stdout).into_owned());
+                    } else {
+                        tracing::warn!("Could not read the staged startup script, bundling the bootstrap instead.");
+                    }
+                }
+                let mut env = environment::collect(&session).await?;
+                session.close().await?;
+                env.instance_id = Some(chosen.instance_id.clone());
+                env.instance_type = Some(launch.instance_type.clone());
+                env.image_id = Some(launch.image_id.clone());
+
+                let (git_commit, git_remote, git_dirty) = git_source();
+                if git_dirty {
+                    eprintln!("Warning: uncommitted changes are not captured in the bundle.");
+                }
+                let bundle = Bundle {
+                    manifest: Manifest {
+                        launch,
+                        git_commit,
+                        git_remote,
+                        git_dirty,
+                        command: (!command.is_empty()).then(|| {
+                            command
+                                .iter()
+                                .map(|c| shell_escape::escape(c.into()))
+                                .collect::<Vec<_>>()
+                                .join(" ")
+                        }),
+                    },
+                    env: Some(env),
+                    setup,
+                    config: std::fs::read_to_string(config::CONFIG_FILE).ok(),
+                };
+
+                let path = output.unwrap_or_else(|| {
+                    format!("korasi-repro-{}.tar.gz", chosen.name.replace(':', "-")).into()
+                });
+                bundle.write(&path)?;
+                println!("Saved repro bundle to {}", path.display());
+            }
+            ReproAction::Apply { bundle, user } => {
+                let bundle = Bundle::read(&bundle)?;
+                let manifest = &bundle.manifest;
+                let launch = &manifest.launch;
+                if let Some(r) = launch.region.as_ref().filter(|r| **r != region) {
+                    tracing::warn!(
+                        "Bundle was created in {r}, but launching in {region}. AMI ids are regional, pass --region {r} if the launch fails."
+                    );
+                }
+
+                let instance_ids = CreateCommand {
+                    root_volume: launch.root_volume(),
+                    iam_profile: launch.iam_profile.clone(),
+                    ..Default::default()
+                }
+                .launch_script(
+                    &ec2,
+                    &instance_name(),
+                    launch.instance_type.as_str().into(),
+                    launch.image_id.clone(),
+                    info.context("No key pair to launch instances with")?,
+                    bundle.setup.clone(),
+                )
+                .await?;
+
+                if let Some(src) = &bundle.config {
+                    let bundled = Config::parse(src)
+                        .map_err(|e| anyhow::anyhow!("{}: {e}", config::CONFIG_FILE))?;
+                    if !bundled.verify.is_empty() {
+                        for instance_id in &instance_ids {
+                            verify_instance(
+                                &ec2,
+                                instance_id,
+                                &user,
+                                &ssh_path,
+                                &connect_opts,
+                                &bundled.verify,
+                            )
+                            .await?;
+                        }
+                    }
+                }
+
+                if let Some(commit) = &manifest.git_commit {
+                    println!(
+                        "Source: {} at commit {commit}{}",
+                        manifest.git_remote.as_deref().unwrap_or("local repo"),
+                        if manifest.git_dirty {
+                            " (had uncommitted changes)"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+                if let Some(command) = &manifest.command {
+                    println!("Run: korasi run -- {command}");
+                }
+            }
+        },
+        Commands::Ports { action } => {
+            let group = ec2.get_ssh_security_group().await?;
+            let group_id = group.group_id().unwrap_or_default().to_string();
+
+            match action {
+                PortsAction::List => {
+                    // Re-describe, the rules may have changed when refreshing the SSH ingress.
+                    let group = ec2
+                        .describe_security_group(SSH_SECURITY_GROUP)
+                        .await?
+                        .unwrap_or(group);
+                    for perm in group.ip_permissions() {
+                        println!("{}", format_permission(perm));
+                    }
+                }
+                PortsAction::Open { ports, cidr } => {
+                    let cidrs = match cidr {
+                        Some(c) => vec![c],
+                        None => EC2::current_ips()
+                            .await?
+                            .into_iter()
+                            .map(host_cidr)
+                            .collect(),
+                    };
+                    ec2.authorize_security_group_ingress(
+                        &group_id,
+                        ports.iter().map(|p| p.to_permission(&cidrs)).collect(),
+                    )
+                    .await?;
+                    for p in ports {
+                        println!("Opened {p} from {}", cidrs.join(", "));
+                    }
+                }
+                PortsAction::Prune { days, all } => {
+                    for r in revoke_abandoned(&ec2).await {
+                        println!("Revoked {} from {} left open by a session", r.port, r.cidr);
+                    }
+                    let current: Vec<String> = EC2::current_ips()
+                        .await?
+                        .into_iter()
+                        .map(host_cidr)
+                        .collect();
+                    let rules = ec2.describe_security_group_rules(&group_id).await?;
+                    let max_age = Duration::from_secs(days * 24 * 60 * 60);
+                    let revoked = ec2
+                        .prune_ssh_rules(&group_id, &rules, &current, max_age, all)
+                        .await?;
+                    if revoked.is_empty() {
+                        println!("No stale SSH rules.");
+                    }
+                    for cidr in revoked {
+                        println!("Revoked SSH from {cidr}");
+                    }
+                }
+                PortsAction::Close { ports, cidr } => {
+                    let group = ec2
+                        .describe_security_group(SSH_SECURITY_GROUP)
+                        .await?
+                        .unwrap_or(group);
+                    let mut revoke = vec![];
+                    for p in &ports {
+                        let cidrs: Vec<String> = match &cidr {
+                            Some(c) => vec![c.clone()],
+                            None => group
+                                .ip_permissions()
+                                .iter()
+                                .filter(|perm| p.matches(perm))
+                                .flat_map(|perm| {
+                                    perm.ip_ranges().iter().filter_map(|r| r.cidr_ip()).chain(
+                                        perm.ipv6_ranges().iter().filter_map(|r| r.cidr_ipv6()),
+                                    )
+                                })
+                                .map(str::to_string)
+                                .collect(),
+                        };
+                        if cidrs.is_empty() {
+                            tracing::warn!("No rule found for {p}.");
+                            continue;
+                        }
+                        println!("Closing {p} from {}", cidrs.join(", "));
+                        revoke.push(p.to_permission(&cidrs));
+                    }
+                    if !revoke.is_empty() {
+                        ec2.revoke_security_group_ingress(&group_id, revoke).await?;
+                    }
+                }
+            }
+        }
+        Commands::Tag { action } => {
+            let keys: Vec<&str> = match &action {
+                TagAction::List => vec![],
+                TagAction::Add { tags } => tags.iter().map(|t| t.key.as_str()).collect(),
+                TagAction::Remove { keys } => keys.iter().map(String::as_str).collect(),
+            };
+            if let Some(key) = keys.iter().find(|k| RESERVED_TAGS.contains(k)) {
+                anyhow::bail!("The `{key}` tag is managed by korasi and can't be changed.");
+            }
+            let chosen =
+                multi_select_instances(&ec2, "Choose the instance(s):", vec![], true, None).await?;
+            let instance_ids = ids_to_str(chosen);
+            if instance_ids.is_empty() {
+                tracing::warn!("Nothing is selected. Use [space] to select option.");
                 return Ok(());
             }
 
-            // Passing empty vec means all non-terminated instances are returned.
-            let instances = ec2.describe_instance(vec![]).await?;
-            let select_all = instances.into_iter().map(|i| i.into()).collect();
-            let instance_ids = ids_to_str(select_all);
+            match action {
+                TagAction::List => {
+                    let ids: Vec<&str> = instance_ids.split(',').collect();
+                    for instance in ec2.describe_instance(vec![]).await? {
+                        if !instance.instance_id().is_some_and(|id| ids.contains(&id)) {
+                            continue;
+                        }
+                        println!("{}", instance.instance_id().unwrap_or_default());
+                        for t in instance.tags() {
+                            println!(
+                                "  {}={}",
+                                t.key().unwrap_or_default(),
+                                t.value().unwrap_or_default()
+                            );
+                        }
+                    }
+                }
+                TagAction::Add { tags } => {
+                    ec2.add_tags(&instance_ids, &tags).await?;
+                    println!("Tagged {instance_ids}.");
+                }
+                TagAction::Remove { keys } => {
+                    ec2.remove_tags(&instance_ids, &keys).await?;
+                    println!("Removed {} from {instance_ids}.", keys.join(", "));
+                }
+            }
+        }
+        Commands::Network {
+            action: NetworkAction::Init,
+        } => {
+            let existing = ec2.network().cloned();
+            let resumed = existing.is_some();
+            let network = ec2.create_network(existing).await?;
+            println!(
+                "{} VPC {} with public subnet {} in {region}.",
+                if resumed { "Checked" } else { "Created" },
+                network.vpc_id,
+                network.subnet_id.unwrap_or_default()
+            );
+        }
+        Commands::Cluster {
+            action:
+                ClusterAction::Create {
+                    nodes,
+                    name,
+                    ami_id,
+                    instance_type,
+                    efa,
+                    slots,
+                    hostfile: hostfile_path,
+                    remote_hostfile,
+                    tags,
+                    user,
+                },
+        } => {
+            let cluster = name.unwrap_or_else(|| instance_name().replace(':', "-"));
+            let mut tags = fill_required_tags(&config, &tags, yes)?;
+            tags.push(TagSelector {
+                key: CLUSTER_TAG.into(),
+                value: cluster.clone(),
+            });
+            let ec2 = ec2.with_tags(tags);
 
-            let grp = ec2.describe_security_group(SSH_SECURITY_GROUP).await?;
-            let grp_id = grp.as_ref().unwrap().group_id().unwrap();
+            let launch = &config.launch;
+            let user = user.or(launch.user.clone()).unwrap_or("ubuntu".into());
+            let machine = match instance_type.or(launch.instance_type.clone()) {
+                Some(t) => InstanceType::from(t.as_str()),
+                None => select_machine(&ec2, &shared_config, "Select the machine type:").await?,
+            };
+            let ami_id = match ami_id.or(launch.ami.clone()) {
+                Some(id) if id.starts_with("ami-") => id,
+                Some(alias) => alias_ami(&ec2, &region, &machine, &alias, None, &setup).await?,
+                None => anyhow::bail!(
+                    "Pass `--ami-id` or set `[launch] ami` in korasi.toml to launch a cluster."
+                ),
+            };
+            if efa && !ec2.efa_supported(machine.clone()).await? {
+                anyhow::bail!("{machine} doesn't support EFA.");
+            }
+            let placement_group = placement_group_name(&cluster);
+            // MPI ranks talk to each other on arbitrary ports.
+            let name = efa_security_group_name(Some(&placement_group));
+            let security_groups = vec![ec2.get_efa_security_group(&name).await?];
+            if ec2
+                .describe_placement_group(&placement_group)
+                .await?
+                .is_none()
+            {
+                ec2.create_placement_group(&placement_group, PlacementStrategy::Cluster)
+                    .await?;
+            }
 
-            let key_pairs = ec2.list_key_pair(SSH_KEY_NAME).await?;
-            let key_pair_ids: Vec<_> = key_pairs.iter().map(|k| k.key_pair_id().unwrap()).collect();
+            let create = CreateCommand {
+                placement_group: Some(placement_group.clone()),
+                efa,
+                security_groups,
+                user_ca_key: user_ca_key(&config)?,
+                ..Default::default()
+            };
+            let info = info.context("No key pair to launch instances with")?;
+            let mut instance_ids = vec![];
+            for i in 1..=nodes {
+                let name = node_name(&cluster, i);
+                let vars = HashMap::from([
+                    ("instance_name", name.clone()),
+                    ("instance_type", machine.to_string()),
+                ]);
+                let script = scripts::load_setup(&setup, &vars)?;
+                println!("Launching {name} ({machine}) in {placement_group}...");
+                let launched = create
+                    .launch_script(
+                        &ec2,
+                        &name,
+                        machine.clone(),
+                        ami_id.clone(),
+                        info.clone(),
+                        script,
+                    )
+                    .await;
+                match launched {
+                    Ok(ids) => instance_ids.extend(ids),
+                    Err(err) => {
+                        if let Some(hint) = err.hint() {
+                            eprintln!("hint: {hint}");
+                        }
+                        // A partial cluster is of no use, and still billed.
+                        if !instance_ids.is_empty() {
+                            let ids = instance_ids.join(",");
+                            eprintln!("Terminating the nodes already launched: {ids}");
+                            if let Err(err) = ec2.delete_instances(&ids, false).await {
+                                eprintln!(
+                                    "Failed to terminate {ids}, delete them with `korasi delete`: {err}"
+                                );
+                            }
+                        }
+                        return Err(err.into());
+                    }
+                }
+            }
 
-            tracing::info!("instance_ids = {:?}", instance_ids);
-            tracing::info!("grp_id = {:?}", grp_id);
-            tracing::info!("key pairs = {:?}", key_pair_ids);
+            println!("Waiting for {nodes} nodes to run...");
+            ec2.wait_for_instance_running(&instance_ids.join(","), Some(BOOT_TIMEOUT))
+                .await?;
+            let nodes = cluster_nodes(&ec2, &instance_ids).await?;
+            let contents = hostfile(&nodes, slots);
+            std::fs::write(&hostfile_path, &contents)?;
+            println!("Wrote {}.", hostfile_path.display());
 
-            ec2.delete_instances(&instance_ids, true).await?;
-            ec2.delete_security_group(grp_id).await?;
-            for id in key_pair_ids {
+            for node in &nodes {
+                wait_for_port(
+                    &node.public_dns_name,
+                    connect_opts.port(),
+                    connect_opts.relay.as_deref(),
+                    BOOT_TIMEOUT,
+                )
+                .await?;
+            }
+            let hosts = nodes
+                .iter()
+                .map(|n| (n.name.clone(), n.public_dns_name.clone()))
+                .collect();
+            let command = write_file_command(&remote_hostfile, &contents);
+            let opts = ParallelOptions {
+                connect: connect_opts.clone(),
+                ..Default::default()
+            };
+            let results = exec_parallel(hosts, &user, &ssh_path, &command, opts).await;
+            let failed: Vec<String> = results
+                .into_iter()
+                .filter_map(|(name, res)| match res {
+                    Ok(0) => None,
+                    Ok(code) => Some(format!("{name} (exit code {code})")),
+                    Err(err) => Some(format!("{name} ({err})")),
+                })
+                .collect();
+            if !failed.is_empty() {
+                anyhow::bail!(
+                    "Could not write ~/{remote_hostfile} on {}.",
+                    failed.join(", ")
+                );
+            }
+            println!(
+                "Cluster {cluster} is up: {} nodes, hostfile at ~/{remote_hostfile} on each.",
+                nodes.len()
+            );
+        }
+        Commands::Schedule { action } => {
+            let scheduler = SchedulerImpl::new(&shared_config);
+            match action {
+                ScheduleAction::Add {
+                    power,
+                    cron,
+                    tags,
+                    timezone,
+                    role_arn,
+                    name,
+                } => {
+                    let instance_ids: Vec<String> = ec2
+                        .describe_instance_tagged(vec![], &tags)
+                        .await?
+                        .iter()
+                        .filter_map(|i| i.instance_id().map(str::to_string))
+                        .collect();
+                    if instance_ids.is_empty() {
+                        anyhow::bail!("No instances are tagged with all of the given tags.");
+                    }
+                    let name = match name {
+                        Some(name) => custom_schedule_name(&name),
+                        None => schedule_name(power, &tags),
+                    };
+                    let updated = scheduler
+                        .put_schedule(&Schedule {
+                            name: name.clone(),
+                            power,
+                            cron: cron.clone(),
+                            timezone,
+                            role_arn,
+                            instance_ids: instance_ids.clone(),
+                        })
+                        .await?;
+                    println!(
+                        "{} {name} to {} {} at cron({cron}).",
+                        if updated { "Updated" } else { "Scheduled" },
+                        power.as_str(),
+                        instance_ids.join(",")
+                    );
+                    println!("Instances created later are not covered, add the schedule again to include them.");
+                }
+                ScheduleAction::List => {
+                    let names = scheduler.list_schedules().await?;
+                    if names.is_empty() {
+                        println!("There are no korasi schedules.");
+                    }
+                    for name in names {
+                        let info = scheduler.get_schedule(&name).await?;
+                        println!(
+                            "{}: {} ({}), {}",
+                            info.name,
+                            info.expression,
+                            info.state.to_lowercase(),
+                            info.description
+                        );
+                    }
+                }
+                ScheduleAction::Delete { names } => {
+                    for name in names {
+                        let name = if name.starts_with(SCHEDULE_PREFIX) {
+                            name
+                        } else {
+                            format!("{SCHEDULE_PREFIX}{name}")
+                        };
+                        scheduler.delete_schedule(&name).await?;
+                        println!("Deleted {name}.");
+                    }
+                }
+            }
+        }
+        Commands::Relay { action } => {
+            let relay_tag = TagSelector {
+                key: RELAY_TAG.into(),
+                value: "true".into(),
+            };
+            let relays = ec2
+                .describe_instance_tagged(vec![], std::slice::from_ref(&relay_tag))
+                .await?;
+            match action {
+                RelayAction::Deploy { instance_type } => {
+                    if let Some(instance) = relays.first() {
+                        let id = instance.instance_id().unwrap_or_default();
+                        match instance.public_dns_name().filter(|h| !h.is_empty()) {
+                            Some(host) => println!(
+                                "Relay {id} is up, connect through it with `--relay {}`.",
+                                relay_url(host)
+                            ),
+                            None => println!(
+                                "Relay {id} is {}, start it with `korasi start`.",
+                                instance.state().and_then(|s| s.name()).map_or(
+                                    "not running".to_string(),
+                                    InstanceStateName::to_string
+                                )
+                            ),
+                        }
+                        return Ok(());
+                    }
+
+                    let ec2 = ec2.with_tags(vec![relay_tag]);
+                    let machine = InstanceType::from(instance_type.as_str());
+                    let arch = preferred_arch(&ec2.supported_architectures(machine.clone()).await?)
+                        .context("Machine type has no supported architectures")?;
+                    let image_id = resolve_alias(&ec2, find_alias("al2023")?, &arch).await?;
+
+                    let group = ec2.get_ssh_security_group().await?;
+                    let group_id = group.group_id().context("Security group has no id")?;
+                    // The relay is a member of the group it connects to.
+                    ec2.authorize_security_group_self_ingress(group_id, Some(connect_opts.port()))
+                        .await?;
+                    let relay_port = PortSpec {
+                        from: RELAY_PORT as i32,
+                        to: RELAY_PORT as i32,
+                        protocol: "tcp".into(),
+                    };
+                    let cidrs: Vec<String> = EC2::current_ips()
+                        .await?
+                        .into_iter()
+                        .map(host_cidr)
+                        .collect();
+                    match ec2
+                        .authorize_security_group_ingress(
+                            group_id,
+                            vec![relay_port.to_permission(&cidrs)],
+                        )
+                        .await
+                    {
+                        Err(err) if err.code() == Some("InvalidPermission.Duplicate") => {}
+                        res => res?,
+                    }
+
+                    let info = info.context("No key pair to launch instances with")?;
+                    println!("Launching {RELAY_NAME} ({machine})...");
+                    let ids = CreateCommand::default()
+                        .launch_script(
+                            &ec2,
+                            RELAY_NAME,
+                            machine,
+                            image_id,
+                            info,
+                            Some(relay_user_data(connect_opts.port())),
+                        )
+                        .await?
+                        .join(",");
+                    ec2.wait_for_instance_running(&ids, Some(BOOT_TIMEOUT))
+                        .await?;
+                    let host = ec2
+                        .get_instance(&ids)
+                        .await?
+                        .public_dns_name()
+                        .filter(|h| !h.is_empty())
+                        .map(str::to_string)
+                        .with_context(|| format!("Relay {ids} has no public DNS name"))?;
+                    println!(
+                        "Relay {ids} is running, connect through it with `--relay {}` once it \
+                         has booted.",
+                        relay_url(&host)
+                    );
+                }
+                RelayAction::Delete => {
+                    if relays.is_empty() {
+                        println!("No relay instance.");
+                    }
+                    for instance in relays {
+                        let id = instance.instance_id().unwrap_or_default();
+                        ec2.delete_instances(id, false).await?;
+                        println!("Deleted relay {id}.");
+                    }
+                    // Close what `relay deploy` opened.
+                    if let Some(group) = ec2.describe_security_group(SSH_SECURITY_GROUP).await? {
+                        let group_id = group.group_id().context("Security group has no id")?;
+                        let rules = ec2.describe_security_group_rules(group_id).await?;
+                        let opened = relay_rules(&rules, group_id, connect_opts.port(), RELAY_PORT);
+                        if !opened.is_empty() {
+                            ec2.revoke_security_group_rules(group_id, &opened).await?;
+                        }
+                    }
+                }
+            }
+        }
+        Commands::Eip { action } => {
+            let associate = |allocation_id: String| {
+                let ec2 = &ec2;
+                async move {
+                    let chosen = select_instance(
+                        ec2,
+                        "Choose the instance to associate with:",
+                        vec![InstanceStateName::Running, InstanceStateName::Stopped],
+                    )
+                    .await?;
+                    ec2.associate_address(&allocation_id, &chosen.instance_id)
+                        .await?;
+                    println!("Associated {allocation_id} with {}", chosen.name);
+                    anyhow::Ok(())
+                }
+            };
+
+            match action {
+                EipAction::List => {
+                    for address in ec2.describe_addresses().await? {
+                        println!("{}", AddressOption(address));
+                    }
+                }
+                EipAction::Allocate { associate: assoc } => {
+                    let address = ec2.allocate_address().await?;
+                    println!("Allocated {}", AddressOption(address.clone()));
+                    if assoc {
+                        associate(address.allocation_id().unwrap_or_default().into()).await?;
+                    }
+                }
+                EipAction::Associate => {
+                    let address = select_address(&ec2, "Choose the elastic IP:").await?;
+                    associate(address.allocation_id().unwrap_or_default().into()).await?;
+                }
+                EipAction::Release => {
+                    let address = select_address(&ec2, "Choose the elastic IP to release:").await?;
+                    ec2.release_address(&address).await?;
+                    println!("Released {}", AddressOption(address));
+                }
+            }
+        }
+        Commands::Migrate { no_snapshot, all } => {
+            let instances = ec2
+                .describe_instance(vec![InstanceStateName::Running])
+                .await?;
+            let health = ec2
+                .describe_instance_health(
+                    instances
+                        .iter()
+                        .filter_map(|i| i.instance_id().map(str::to_string))
+                        .collect(),
+                )
+                .await?;
+
+            let options: Vec<SelectOption> = instances
+                .into_iter()
+                .filter(|i| {
+                    all || i
+                        .instance_id()
+                        .and_then(|id| health.get(id))
+                        .is_some_and(|h| !h.events.is_empty())
+                })
+                .map(|i| i.into())
+                .collect();
+            if options.is_empty() {
+                tracing::warn!("No running instances have scheduled events.");
+                return Ok(());
+            }
+            for opt in &options {
+                if let Some(h) = health.get(&opt.instance_id) {
+                    println!("{} ({}): {h}", opt.name, opt.instance_id);
+                }
+            }
+
+            let chosen = MultiSelect::new("Choose the instance(s) to migrate:", options)
+                .with_vim_mode(true)
+                .prompt()?;
+            if chosen.is_empty() {
+                tracing::warn!("Nothing is selected. Use [space] to select option.");
+                return Ok(());
+            }
+            for c in chosen {
+                migrate_instance(&ec2, &c.instance_id, !no_snapshot).await?;
+            }
+        }
+        Commands::Drift { instances } => {
+            let report = detect(&ec2, &config, &region, &setup, &instances).await?;
+            if report.is_empty() {
+                anyhow::bail!("No instances to check.");
+            }
+            let mut drifted = 0;
+            for (name, drifts) in report {
+                if drifts.is_empty() {
+                    println!("{name}: no drift");
+                    continue;
+                }
+                drifted += 1;
+                println!("{name}:");
+                for drift in drifts {
+                    println!("  {drift}");
+                }
+            }
+            if drifted > 0 {
+                anyhow::bail!(
+                    "{drifted} instance(s) drifted from {}.",
+                    Config::path().display()
+                );
+            }
+        }
+        Commands::UpdateLock => update_lock(&ec2, &region).await?,
+        Commands::MigrateConfig
+        | Commands::Introspect
+        | Commands::Plugins
+        | Commands::External(_) => {
+            unreachable!("handled before connecting to AWS")
+        }
+        Commands::Serve { listen } => serve(&ec2, &listen).await?,
+        Commands::Obliterate => {
+            if config.safety.protect_obliterate {
+                anyhow::bail!(
+                    "Obliterate is disabled by `protect_obliterate` in {}.",
+                    Config::path().display()
+                );
+            }
+            let inventory = ec2.inventory().await?;
+            // With a minimum age, resources of others who just launched
+            // theirs in a shared account are left alone.
+            let min_age = config.safety.obliterate_min_age;
+            let cutoff =
+                min_age.map(|age| DateTime::from(std::time::SystemTime::now()).secs() - age as i64);
+            let old_enough = |created: Option<&DateTime>| match cutoff {
+                Some(cutoff) => created.is_some_and(|t| t.secs() <= cutoff),
+                None => true,
+            };
+            let (instances, kept): (Vec<_>, Vec<_>) = inventory
+                .instances
+                .iter()
+                .cloned()
+                .partition(|i| old_enough(i.launch_time()));
+            // Startup scripts staged on S3, unless a kept instance (eg. a
+            // clone) still boots from one.
+            let staged_uri = |i: &Instance| {
+                i.tags()
+                    .iter()
+                    .find(|t| t.key() == Some(STAGED_USER_DATA_TAG))
+                    .and_then(|t| t.value())
+                    .map(str::to_string)
+            };
+            let mut staged: Vec<String> = instances
+                .iter()
+                .filter_map(staged_uri)
+                .filter(|uri| !kept.iter().any(|i| staged_uri(i).as_ref() == Some(uri)))
+                .collect();
+            staged.sort();
+            staged.dedup();
+            let instances: Vec<SelectOption> =
+                instances.into_iter().map(SelectOption::from).collect();
+            // Shared resources stay for as long as any instance does.
+            let shared = kept.is_empty();
+            let groups = if shared {
+                inventory.security_groups.clone()
+            } else {
+                vec![]
+            };
+            let key_pairs: Vec<_> = inventory
+                .key_pairs
+                .iter()
+                .filter(|k| shared && old_enough(k.create_time()))
+                .cloned()
+                .collect();
+            let addresses: Vec<_> = inventory
+                .addresses
+                .iter()
+                .filter(|a| {
+                    !kept
+                        .iter()
+                        .any(|i| i.instance_id().is_some() && i.instance_id() == a.instance_id())
+                })
+                .cloned()
+                .collect();
+            let placement_groups = if shared {
+                inventory.placement_groups.clone()
+            } else {
+                vec![]
+            };
+            let network = inventory.network.clone().filter(|_| shared);
+
+            println!("The following resources will be destroyed:");
+            for i in &instances {
+                println!("  instance       {} ({})", i.instance_id, i.name);
+            }
+            for a in &addresses {
+                println!("  elastic IP     {}", AddressOption(a.clone()));
+            }
+            for g in &groups {
+                println!(
+                    "  security group {} ({})",
+                    g.group_id().unwrap_or_default(),
+                    g.group_name().unwrap_or_default()
+                );
+            }
+            for g in placement_groups.iter().filter_map(|g| g.group_name()) {
+                println!("  placement group {g}");
+            }
+            if let Some(network) = &network {
+                println!("  VPC            {}", network.vpc_id);
+            }
+            for uri in &staged {
+                println!("  startup script {uri}");
+            }
+            for k in &key_pairs {
+                println!(
+                    "  key pair       {} ({})",
+                    k.key_pair_id().unwrap_or_default(),
+                    k.key_name().unwrap_or_default()
+                );
+            }
+            // An imported key is the user's own, and stays. So does one
+            // whose key pair isn't being deleted, it may still be in use.
+            let local_key = import_key.is_none()
+                && key_pairs
+                    .iter()
+                    .any(|k| k.key_name() == Some(ec2.key_name()))
+                && std::path::Path::new(&ssh_path).exists();
+            if local_key {
+                println!("  local key      {ssh_path}");
+            }
+            if !kept.is_empty() {
+                println!(
+                    "Keeping {} instance(s) younger than {}, along with the resources they share.",
+                    kept.len(),
+                    format_duration(Duration::from_secs(min_age.unwrap_or_default()))
+                );
+            }
+
+            if config.safety.configured {
+                // Not skipped by `--yes`, the tag has to be typed.
+                let tag = ec2.project_tag();
+                if !termion::is_tty(&std::io::stdin()) {
+                    anyhow::bail!("Obliterate needs the project tag `{tag}` typed to confirm.");
+                }
+                let answer =
+                    Text::new(&format!("Type the project tag `{tag}` to confirm:")).prompt()?;
+                if answer.trim() != tag {
+                    tracing::warn!("Aborting obliterate.");
+                    return Ok(());
+                }
+            } else if !yes {
+                let answer =
+                    Text::new("Do you want to obliterate all resources [y/n]?:").prompt()?;
+                if !(answer == "y" || answer == "Y") {
+                    tracing::warn!("Aborting obliterate.");
+                    return Ok(());
+                }
+            }
+
+            // Elastic IPs outlive terminated instances (and keep being billed).
+            for a in &addresses {
+                ec2.release_address(a).await?;
+            }
+            let instance_ids = ids_to_str(instances);
+            if !instance_ids.is_empty() {
+                ec2.delete_instances(&instance_ids, true).await?;
+            }
+            // S3 permissions may differ from EC2 ones, so this only warns.
+            for uri in &staged {
+                let Some((bucket, key)) = parse_s3_uri(uri) else {
+                    continue;
+                };
+                if let Err(err) = S3Impl::new(&shared_config, bucket).delete_object(key).await {
+                    eprintln!("Failed to delete {uri}: {err}");
+                }
+            }
+            for id in groups.iter().filter_map(|g| g.group_id()) {
+                ec2.delete_security_group(id).await?;
+            }
+            // Only empty groups can be deleted, hence after the instances.
+            for g in placement_groups.iter().filter_map(|g| g.group_name()) {
+                ec2.delete_placement_group(g).await?;
+            }
+            // Last, once nothing is left in it.
+            if let Some(network) = &network {
+                ec2.delete_network(network).await?;
+            }
+            for id in key_pairs.iter().filter_map(|k| k.key_pair_id()) {
                 ec2.delete_key_pair(id).await?;
             }
 
             // Remove SSH key. PK is useless when key pair is deleted.
-            std::fs::remove_file(&ssh_path)
-                .with_context(|| format!("Failed to remove pk file at {ssh_path}."))?;
+            if local_key {
+                std::fs::remove_file(&ssh_path)
+                    .with_context(|| format!("Failed to remove pk file at {ssh_path}."))?;
+            }
         }
     };
 
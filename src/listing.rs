@@ -0,0 +1,415 @@
+//! `korasi list` from a local cache of the last listing, so rows show up
+//! before `describe_instances` answers. Once it does, the rows are redrawn
+//! in place with what changed.
+
+use std::{collections::HashMap, io::Write, path::PathBuf};
+
+use aws_sdk_ec2::types::Instance;
+
+use crate::{
+    ec2::InstanceHealth,
+    progress::format_duration,
+    state::{save_state, state_dir},
+    toml::{self, quote, Value},
+};
+
+/// What `list` shows of an instance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub instance_id: String,
+    pub name: String,
+    pub instance_type: String,
+    pub state: String,
+    pub host: String,
+    /// Status checks and scheduled events, when worth showing.
+    pub health: String,
+    /// On-demand USD/hour, if known.
+    pub hourly: Option<f64>,
+    /// Unix time the instance was last started.
+    pub launched: Option<i64>,
+}
+
+impl Row {
+    pub fn new(
+        instance: &Instance,
+        health: &HashMap<String, InstanceHealth>,
+        prices: &HashMap<String, f64>,
+    ) -> Self {
+        let name = instance
+            .tags()
+            .iter()
+            .find(|t| t.key() == Some("Name"))
+            .and_then(|t| t.value())
+            .unwrap_or_default();
+        let instance_id = instance.instance_id().unwrap_or_default();
+        let instance_type = instance
+            .instance_type()
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        Row {
+            instance_id: instance_id.into(),
+            name: name.into(),
+            hourly: prices.get(&instance_type).copied(),
+            instance_type,
+            state: instance
+                .state()
+                .and_then(|s| s.name())
+                .map(|s| s.to_string())
+                .unwrap_or_default(),
+            host: instance.public_dns_name().unwrap_or_default().into(),
+            health: health
+                .get(instance_id)
+                .filter(|h| h.is_impaired() || !h.events.is_empty())
+                .map(|h| h.to_string())
+                .unwrap_or_default(),
+            launched: instance.launch_time().map(|t| t.secs()),
+        }
+    }
+
+    /// USD spent since the instance was last started, while running.
+    pub fn cost(&self, now: u64) -> Option<f64> {
+        if self.state != "running" {
+            return None;
+        }
+        let hours = (now as i64 - self.launched?).max(0) as f64 / 3600.0;
+        Some(self.hourly? * hours)
+    }
+}
+
+/// Hourly rate and spend since launch of the running `rows`, eg.
+/// `Total: 2 running, $0.106/h, $4.51 since launch`. Instances of unknown
+/// price are left out of the amounts.
+pub fn fleet_total(rows: &[Row], now: u64) -> Option<String> {
+    let running: Vec<_> = rows.iter().filter(|r| r.state == "running").collect();
+    if running.is_empty() {
+        return None;
+    }
+    let hourly = running
+        .iter()
+        .filter_map(|r| r.hourly)
+        .fold(0.0, |a, b| a + b);
+    let spent = running
+        .iter()
+        .filter_map(|r| r.cost(now))
+        .fold(0.0, |a, b| a + b);
+    Some(format!(
+        "Total: {} running, ${hourly:.3}/h, ${spent:.2} since launch",
+        running.len()
+    ))
+}
+
+/// How a row compares to the cached listing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    /// Not checked against AWS yet.
+    Stale,
+    Same,
+    Updated,
+    New,
+    Gone,
+}
+
+impl Change {
+    fn marker(&self) -> &'static str {
+        match self {
+            Change::Stale => " (stale)",
+            Change::Same => "",
+            Change::Updated => " (updated)",
+            Change::New => " (new)",
+            Change::Gone => " (gone)",
+        }
+    }
+}
+
+pub fn format_row(i: usize, row: &Row, change: Change) -> String {
+    let mut line = format!(
+        "{}. {:?}, type = {}, state = {}, {:?}{}",
+        i + 1,
+        row.name,
+        row.instance_type,
+        row.state,
+        row.host,
+        change.marker()
+    );
+    if let Some(hourly) = row.hourly {
+        line.push_str(&format!(", ${hourly:.3}/h"));
+    }
+    if let Some(cost) = row.cost(now()) {
+        line.push_str(&format!(", ${cost:.2} since launch"));
+    }
+    if !row.health.is_empty() {
+        line.push_str(&format!("\n   health = {}", row.health));
+    }
+    line
+}
+
+/// `live` rows marked against `cached`, followed by the cached rows that
+/// are gone.
+pub fn diff(cached: &[Row], live: &[Row]) -> Vec<(Row, Change)> {
+    let mut rows: Vec<(Row, Change)> = live
+        .iter()
+        .map(|row| {
+            let change = match cached.iter().find(|c| c.instance_id == row.instance_id) {
+                None => Change::New,
+                Some(c) if c == row => Change::Same,
+                Some(_) => Change::Updated,
+            };
+            (row.clone(), change)
+        })
+        .collect();
+    rows.extend(
+        cached
+            .iter()
+            .filter(|c| !live.iter().any(|r| r.instance_id == c.instance_id))
+            .map(|c| (c.clone(), Change::Gone)),
+    );
+    rows
+}
+
+/// Cached listing of a region and tag.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Cache {
+    /// Unix time of the listing.
+    pub updated: u64,
+    pub rows: Vec<Row>,
+}
+
+impl Cache {
+    fn path(region: &str, tag: &str) -> PathBuf {
+        state_dir()
+            .join("cache")
+            .join(format!("list-{region}-{tag}.toml"))
+    }
+
+    pub fn load(region: &str, tag: &str) -> Option<Cache> {
+        let src = std::fs::read_to_string(Self::path(region, tag)).ok()?;
+        Some(Self::parse(&src))
+    }
+
+    /// Malformed rows are dropped, the next listing replaces them anyway.
+    pub fn parse(src: &str) -> Cache {
+        let Ok(table) = toml::parse(src) else {
+            return Cache::default();
+        };
+        let updated = match table.get("updated").map(|i| &i.value) {
+            Some(Value::Integer(i)) => (*i).max(0) as u64,
+            _ => 0,
+        };
+        let rows = match table.get("row").map(|i| &i.value) {
+            Some(Value::Array(rows)) => rows
+                .iter()
+                .filter_map(|r| {
+                    let Value::Table(t) = r else {
+                        return None;
+                    };
+                    let string = |key: &str| match t.get(key).map(|i| &i.value) {
+                        Some(Value::String(s)) => Some(s.clone()),
+                        _ => None,
+                    };
+                    Some(Row {
+                        instance_id: string("instance_id")?,
+                        name: string("name")?,
+                        instance_type: string("instance_type")?,
+                        state: string("state")?,
+                        host: string("host")?,
+                        health: string("health").unwrap_or_default(),
+                        hourly: match t.get("hourly").map(|i| &i.value) {
+                            Some(Value::Float(f)) => Some(*f),
+                            Some(Value::Integer(i)) => Some(*i as f64),
+                            _ => None,
+                        },
+                        launched: match t.get("launched").map(|i| &i.value) {
+                            Some(Value::Integer(i)) => Some(*i),
+                            _ => None,
+                        },
+                    })
+                })
+                .collect(),
+            _ => vec![],
+        };
+        Cache { updated, rows }
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut out = format!("updated = {}\n", self.updated);
+        for row in &self.rows {
+            out.push_str(&format!(
+                "\n[[row]]\ninstance_id = {}\nname = {}\ninstance_type = {}\nstate = {}\nhost = {}\nhealth = {}\n",
+                quote(&row.instance_id),
+                quote(&row.name),
+                quote(&row.instance_type),
+                quote(&row.state),
+                quote(&row.host),
+                quote(&row.health),
+            ));
+            if let Some(hourly) = row.hourly {
+                out.push_str(&format!("hourly = {hourly:?}\n"));
+            }
+            if let Some(launched) = row.launched {
+                out.push_str(&format!("launched = {launched}\n"));
+            }
+        }
+        out
+    }
+
+    /// Failing to cache is only logged, like other local state.
+    pub fn save(&self, region: &str, tag: &str) {
+        let path = Self::path(region, tag);
+        let res = save_state(&path, &self.to_toml());
+        if let Err(err) = res {
+            tracing::warn!("Failed to cache the listing: {err}");
+        }
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Print the cached rows, if any and stdout is a terminal. Returns the
+/// cache and the number of lines printed, for `print_live` to redraw.
+pub fn print_cached(region: &str, tag: &str) -> (Option<Cache>, usize) {
+    if !termion::is_tty(&std::io::stdout()) {
+        return (None, 0);
+    }
+    let Some(cache) = Cache::load(region, tag) else {
+        return (None, 0);
+    };
+    let age = std::time::Duration::from_secs(now().saturating_sub(cache.updated));
+    let mut lines = vec![format!("As of {} ago, refreshing...", format_duration(age))];
+    lines.extend(
+        cache
+            .rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| format_row(i, row, Change::Stale)),
+    );
+    let text = lines.join("\n");
+    println!("{text}");
+    (Some(cache), text.lines().count())
+}
+
+/// Print `live` rows, replacing the `printed` lines of the cached listing,
+/// and cache them.
+pub fn print_live(region: &str, tag: &str, cached: Option<Cache>, printed: usize, live: Vec<Row>) {
+    let rows = match &cached {
+        Some(cache) => diff(&cache.rows, &live),
+        None => live.iter().map(|r| (r.clone(), Change::Same)).collect(),
+    };
+    let mut stdout = std::io::stdout();
+    if printed > 0 {
+        let _ = write!(
+            stdout,
+            "{}\r{}",
+            termion::cursor::Up(printed as u16),
+            termion::clear::AfterCursor
+        );
+    }
+    if rows.is_empty() {
+        println!("There are no active instances.");
+    }
+    for (i, (row, change)) in rows.iter().enumerate() {
+        println!("{}", format_row(i, row, *change));
+    }
+    if let Some(total) = fleet_total(&live, now()) {
+        println!("{total}");
+    }
+    let _ = stdout.flush();
+
+    Cache {
+        updated: now(),
+        rows: live,
+    }
+    .save(region, tag);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, fleet_total, Cache, Change, Row};
+
+    fn row(id: &str, state: &str) -> Row {
+        Row {
+            instance_id: id.into(),
+            name: format!("name \"{id}\""),
+            instance_type: "t3.micro".into(),
+            state: state.into(),
+            host: String::new(),
+            health: String::new(),
+            hourly: Some(0.0104),
+            launched: Some(1_700_000_000),
+        }
+    }
+
+    #[test]
+    fn diff_cached_rows() {
+        let cached = vec![row("i-1", "running"), row("i-2", "running")];
+        let live = vec![row("i-1", "running"), row("i-3", "pending")];
+        let cases = [
+            (vec![], vec![]),
+            (
+                live.clone(),
+                vec![
+                    (row("i-1", "running"), Change::Same),
+                    (row("i-3", "pending"), Change::New),
+                    (row("i-2", "running"), Change::Gone),
+                ],
+            ),
+            (
+                vec![row("i-1", "stopping"), row("i-2", "running")],
+                vec![
+                    (row("i-1", "stopping"), Change::Updated),
+                    (row("i-2", "running"), Change::Same),
+                ],
+            ),
+        ];
+
+        for (live, expected) in cases {
+            println!("live = {live:?}");
+            let cached = if live.is_empty() {
+                vec![]
+            } else {
+                cached.clone()
+            };
+            pretty_assertions::assert_eq!(diff(&cached, &live), expected);
+        }
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let cache = Cache {
+            updated: 1_700_000_000,
+            rows: vec![row("i-1", "running"), row("i-2", "stopped")],
+        };
+        pretty_assertions::assert_eq!(Cache::parse(&cache.to_toml()), cache);
+    }
+
+    #[test]
+    fn spend_since_launch() {
+        let unpriced = Row {
+            hourly: None,
+            ..row("i-3", "running")
+        };
+        let cases = [
+            (vec![], None),
+            (vec![row("i-1", "stopped")], None),
+            (
+                vec![row("i-1", "running"), row("i-2", "stopped")],
+                Some("Total: 1 running, $0.010/h, $0.10 since launch"),
+            ),
+            (
+                vec![row("i-1", "running"), row("i-2", "running"), unpriced],
+                Some("Total: 3 running, $0.021/h, $0.21 since launch"),
+            ),
+        ];
+
+        // 10 hours after launch.
+        let now = 1_700_036_000;
+        for (rows, expected) in cases {
+            println!("rows = {rows:?}");
+            pretty_assertions::assert_eq!(fleet_total(&rows, now).as_deref(), expected);
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! `korasi.lock`: pins what AMI aliases (eg. `al2023`) resolved to, along
+//! with a digest of each setup recipe, so every run of a project launches
+//! the same environment until `korasi update-lock` is run.
+//!
+//! ```toml
+//! [ami."us-east-1"."al2023"]
+//! x86_64 = "ami-0123456789abcdef0"
+//!
+//! [recipes]
+//! "preset:rust-dev" = "9f86d081884c7d65..."
+//! ```
+//!
+//! Packages installed by a recipe come from the distro mirrors at boot, so
+//! the recipe digest only tells that the script itself is unchanged. Pin
+//! package versions inside the script to make installs repeatable.
+
+use std::{collections::BTreeMap, path::Path};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::ConfigError,
+    ec2::{EC2Error, EC2Impl as EC2},
+    json::quote,
+    scripts::{PRESETS, PRESET_PREFIX},
+    toml::{self, Item, ParseError, Value},
+};
+
+pub const LOCK_FILE: &str = "korasi.lock";
+
+pub struct AmiAlias {
+    pub name: &'static str,
+    pub owner: &'static str,
+    pub name_pattern: &'static str,
+}
+
+pub const AMI_ALIASES: &[AmiAlias] = &[
+    AmiAlias {
+        name: "al2023",
+        owner: "amazon",
+        name_pattern: "al2023-ami-2023.*-kernel-*",
+    },
+    AmiAlias {
+        name: "al2",
+        owner: "amazon",
+        name_pattern: "amzn2-ami-hvm-*-gp2",
+    },
+    AmiAlias {
+        name: "ubuntu-22.04",
+        owner: "099720109477",
+        name_pattern: "ubuntu/images/hvm-ssd/ubuntu-jammy-22.04-*-server-*",
+    },
+    AmiAlias {
+        name: "ubuntu-24.04",
+        owner: "099720109477",
+        name_pattern: "ubuntu/images/hvm-ssd-gp3/ubuntu-noble-24.04-*-server-*",
+    },
+    AmiAlias {
+        name: "debian-12",
+        owner: "136693071363",
+        name_pattern: "debian-12-*",
+    },
+];
+
+pub fn find_alias(name: &str) -> Result<&'static AmiAlias, EC2Error> {
+    AMI_ALIASES.iter().find(|a| a.name == name).ok_or_else(|| {
+        EC2Error::new(format!(
+            "Unknown AMI alias `{name}`, expected an ami-* id or one of: {}",
+            AMI_ALIASES
+                .iter()
+                .map(|a| a.name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    })
+}
+
+/// Architecture to resolve an alias for, preferring x86_64 when the
+/// machine type supports several (eg. i386 and x86_64).
+pub fn preferred_arch(architectures: &[String]) -> Option<String> {
+    ["x86_64", "arm64"]
+        .iter()
+        .find(|a| architectures.iter().any(|s| s == *a))
+        .map(|a| a.to_string())
+        .or_else(|| architectures.first().cloned())
+}
+
+/// Newest image matching the alias.
+pub async fn resolve_alias(ec2: &EC2, alias: &AmiAlias, arch: &str) -> Result<String, EC2Error> {
+    let images = ec2
+        .describe_images(alias.owner, vec![arch.into()], alias.name_pattern)
+        .await?;
+    images
+        .first()
+        .and_then(|i| i.image_id())
+        .map(str::to_string)
+        .ok_or_else(|| EC2Error::new(format!("No {arch} image found for alias `{}`", alias.name)))
+}
+
+/// SHA-256 of the unrendered setup script, or `None` when there is none.
+pub fn recipe_digest(setup: &str) -> Option<String> {
+    let source = match setup.strip_prefix(PRESET_PREFIX) {
+        Some(name) => PRESETS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, s)| s.to_string()),
+        None => std::fs::read_to_string(setup).ok(),
+    }?;
+    Some(hex::encode(Sha256::digest(source.as_bytes())))
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Lockfile {
+    /// Image id by region, alias, then architecture. Images are regional,
+    /// so each region gets its own pins.
+    pub amis: BTreeMap<String, BTreeMap<String, BTreeMap<String, String>>>,
+    /// Script digest by `--setup` value.
+    pub recipes: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    /// Load `korasi.lock` from the current directory, if there is one.
+    pub fn load() -> Result<Option<Lockfile>, ConfigError> {
+        let path = Path::new(LOCK_FILE);
+        let Ok(src) = std::fs::read_to_string(path) else {
+            return Ok(None);
+        };
+        Self::parse(&src).map(Some).map_err(|e| ConfigError {
+            path: path.to_path_buf(),
+            line: e.line,
+            message: e.message,
+        })
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        std::fs::write(LOCK_FILE, self.to_toml())
+    }
+
+    pub fn ami(&self, region: &str, alias: &str, arch: &str) -> Option<&str> {
+        self.amis
+            .get(region)?
+            .get(alias)?
+            .get(arch)
+            .map(String::as_str)
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut out = String::from("# Generated by korasi. Refresh with `korasi update-lock`.\n");
+        for (region, aliases) in &self.amis {
+            for (alias, images) in aliases {
+                out.push_str(&format!("\n[ami.{}.{}]\n", quote(region), quote(alias)));
+                for (arch, image_id) in images {
+                    out.push_str(&format!("{arch} = {}\n", quote(image_id)));
+                }
+            }
+        }
+        if !self.recipes.is_empty() {
+            out.push_str("\n[recipes]\n");
+            for (setup, digest) in &self.recipes {
+                out.push_str(&format!("{} = {}\n", quote(setup), quote(digest)));
+            }
+        }
+        out
+    }
+
+    pub fn parse(src: &str) -> Result<Lockfile, ParseError> {
+        let root = toml::parse(src)?;
+        let table = |item: &Item, section: &str| match &item.value {
+            Value::Table(t) => Ok(t.clone()),
+            other => Err(ParseError::new(
+                item.line,
+                format!(
+                    "expected [{section}] to be a table, found {}",
+                    other.type_name()
+                ),
+            )),
+        };
+        let strings = |item: &Item, section: &str| {
+            table(item, section)?
+                .iter()
+                .map(|(key, item)| match &item.value {
+                    Value::String(s) => Ok((key.clone(), s.clone())),
+                    other => Err(ParseError::new(
+                        item.line,
+                        format!(
+                            "expected a string for `{key}` in [{section}], found {}",
+                            other.type_name()
+                        ),
+                    )),
+                })
+                .collect::<Result<BTreeMap<_, _>, _>>()
+        };
+
+        let mut lock = Lockfile::default();
+        if let Some(item) = root.get("ami") {
+            for (region, aliases) in table(item, "ami")?.iter() {
+                let section = format!("ami.{region}");
+                let pins = lock.amis.entry(region.clone()).or_default();
+                for (alias, images) in table(aliases, &section)?.iter() {
+                    let section = format!("{section}.{alias}");
+                    pins.insert(alias.clone(), strings(images, &section)?);
+                }
+            }
+        }
+        if let Some(item) = root.get("recipes") {
+            lock.recipes = strings(item, "recipes")?;
+        }
+        Ok(lock)
+    }
+}
+
+/// Image id for `alias`, taken from `korasi.lock` when pinned. Otherwise the
+/// alias is resolved and pinned along with the digest of the `setup` recipe.
+///
+/// Fails when the recipe changed since it was locked, as the instance
+/// would no longer match the environment teammates get.
+pub async fn locked_ami(
+    ec2: &EC2,
+    region: &str,
+    alias: &str,
+    arch: &str,
+    setup: &str,
+) -> anyhow::Result<String> {
+    let alias = find_alias(alias)?;
+    let mut lock = Lockfile::load()?.unwrap_or_default();
+    let mut changed = false;
+
+    let image_id = match lock.ami(region, alias.name, arch) {
+        Some(image_id) => {
+            println!(
+                "Using {image_id} for {} ({arch}) from {LOCK_FILE}",
+                alias.name
+            );
+            image_id.to_string()
+        }
+        None => {
+            let image_id = resolve_alias(ec2, alias, arch).await?;
+            println!(
+                "Pinned {} ({arch}) to {image_id} in {LOCK_FILE}",
+                alias.name
+            );
+            lock.amis
+                .entry(region.into())
+                .or_default()
+                .entry(alias.name.into())
+                .or_default()
+                .insert(arch.into(), image_id.clone());
+            changed = true;
+            image_id
+        }
+    };
+
+    if let Some(digest) = recipe_digest(setup) {
+        match lock.recipes.get(setup) {
+            Some(locked) if *locked != digest => anyhow::bail!(
+                "{setup} changed since it was locked in {LOCK_FILE}. \
+                 Run `korasi update-lock` to accept the new version."
+            ),
+            Some(_) => {}
+            None => {
+                lock.recipes.insert(setup.into(), digest);
+                changed = true;
+            }
+        }
+    }
+
+    if changed {
+        lock.save()?;
+    }
+    Ok(image_id)
+}
+
+/// Re-resolve every pinned alias and re-hash every recipe, printing what
+/// changed.
+pub async fn update_lock(ec2: &EC2, region: &str) -> anyhow::Result<()> {
+    let Some(mut lock) = Lockfile::load()? else {
+        anyhow::bail!("There is no {LOCK_FILE} in the current directory.");
+    };
+
+    let mut changes = 0;
+    match lock.amis.get_mut(region) {
+        Some(aliases) => {
+            for (name, images) in aliases.iter_mut() {
+                let alias = find_alias(name)?;
+                for (arch, image_id) in images.iter_mut() {
+                    let latest = resolve_alias(ec2, alias, arch).await?;
+                    if latest != *image_id {
+                        println!("{name} ({arch}): {image_id} -> {latest}");
+                        *image_id = latest;
+                        changes += 1;
+                    }
+                }
+            }
+        }
+        None => println!("No images are pinned for {region}."),
+    }
+
+    for (setup, digest) in lock.recipes.iter_mut() {
+        match recipe_digest(setup) {
+            Some(latest) if latest != *digest => {
+                println!("{setup}: {:.12} -> {latest:.12}", digest.as_str());
+                *digest = latest;
+                changes += 1;
+            }
+            Some(_) => {}
+            None => println!("{setup}: not found, keeping the locked digest"),
+        }
+    }
+
+    if changes == 0 {
+        println!("{LOCK_FILE} is up to date.");
+    } else {
+        lock.save()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lockfile;
+
+    #[test]
+    fn lockfile_round_trip() {
+        let mut lock = Lockfile::default();
+        let us_east = lock.amis.entry("us-east-1".into()).or_default();
+        us_east.entry("al2023".into()).or_default().extend([
+            ("arm64".to_string(), "ami-0aaaaaaaaaaaaaaa1".to_string()),
+            ("x86_64".to_string(), "ami-0bbbbbbbbbbbbbbb2".to_string()),
+        ]);
+        us_east
+            .entry("ubuntu-22.04".into())
+            .or_default()
+            .insert("x86_64".into(), "ami-0ccccccccccccccc3".into());
+        lock.recipes
+            .insert("preset:rust-dev".into(), "9f86d081884c7d65".into());
+        lock.recipes
+            .insert("start_up.sh".into(), "2c26b46b68ffc68f".into());
+
+        pretty_assertions::assert_eq!(Lockfile::parse(&lock.to_toml()).unwrap(), lock);
+        pretty_assertions::assert_eq!(
+            lock.ami("us-east-1", "ubuntu-22.04", "x86_64"),
+            Some("ami-0ccccccccccccccc3")
+        );
+        assert!(Lockfile::parse("[recipes]\nminimal = 1").is_err());
+    }
+}
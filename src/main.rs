@@ -1,14 +1,17 @@
 use clap::Parser;
 
-use korasi_cli::{opt::Opt, run};
+use korasi_cli::{exit_code, opt::Opt, run, telemetry};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opts = Opt::parse();
 
-    if opts.debug {
-        tracing_subscriber::fmt().init();
-    }
+    let telemetry = telemetry::init(opts.debug, opts.timings);
 
-    run(opts).await
+    let res = run(opts).await;
+    telemetry.finish().await;
+    if let Some(code) = exit_code(&res) {
+        std::process::exit(code);
+    }
+    res
 }
@@ -0,0 +1,194 @@
+//! Prometheus exporter behind `korasi serve`.
+//!
+//! Instances are described on every scrape, so the numbers are as fresh as
+//! the scrape interval. Counters come from the stats recorded by other
+//! korasi invocations, see [`Stats`].
+
+use std::{collections::BTreeMap, fmt::Write, time::SystemTime};
+
+use aws_sdk_ec2::types::{Instance, InstanceStateName};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{ec2::EC2Impl as EC2, pricing::on_demand_hourly, state::Stats};
+
+/// Render the metrics in the Prometheus text exposition format.
+///
+/// `now` is seconds since the epoch, used to estimate how long running
+/// instances have been up.
+pub fn render(instances: &[Instance], stats: &Stats, now: i64) -> String {
+    let mut by_state = BTreeMap::new();
+    for state in [
+        InstanceStateName::Pending,
+        InstanceStateName::Running,
+        InstanceStateName::Stopping,
+        InstanceStateName::Stopped,
+    ] {
+        by_state.insert(state.as_str().to_string(), 0);
+    }
+
+    let mut cost = 0.0;
+    let mut unpriced = 0;
+    for i in instances {
+        let state = i
+            .state()
+            .and_then(|s| s.name())
+            .map_or("unknown", |n| n.as_str());
+        *by_state.entry(state.to_string()).or_insert(0) += 1;
+
+        if state != InstanceStateName::Running.as_str() {
+            continue;
+        }
+        let price = i.instance_type().and_then(|t| on_demand_hourly(t.as_str()));
+        match (price, i.launch_time()) {
+            (Some(price), Some(launched)) => {
+                let hours = (now - launched.secs()).max(0) as f64 / 3600.0;
+                cost += price * hours;
+            }
+            _ => unpriced += 1,
+        }
+    }
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    };
+
+    metric(
+        "korasi_instances",
+        "gauge",
+        "Instances managed by korasi, by state.",
+        by_state
+            .into_iter()
+            .map(|(state, n)| (format!("{{state=\"{state}\"}}"), n.to_string()))
+            .collect(),
+    );
+    metric(
+        "korasi_cost_estimate_dollars",
+        "gauge",
+        "Estimated on-demand cost of running instances since they were last started.",
+        vec![(String::new(), format!("{cost:.4}"))],
+    );
+    metric(
+        "korasi_unpriced_instances",
+        "gauge",
+        "Running instances left out of the cost estimate for lack of a price.",
+        vec![(String::new(), unpriced.to_string())],
+    );
+    metric(
+        "korasi_transfer_bytes_total",
+        "counter",
+        "Bytes uploaded to instances.",
+        vec![(String::new(), stats.transfer_bytes.to_string())],
+    );
+    metric(
+        "korasi_jobs_total",
+        "counter",
+        "Remote commands run, by result.",
+        vec![
+            (
+                "{result=\"success\"}".into(),
+                stats.jobs_succeeded.to_string(),
+            ),
+            ("{result=\"failure\"}".into(), stats.jobs_failed.to_string()),
+        ],
+    );
+
+    out
+}
+
+async fn handle(ec2: &EC2, mut stream: TcpStream) -> anyhow::Result<()> {
+    let mut buf = vec![0; 4096];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, body) = if path == "/metrics" {
+        // Scrapes within `INVENTORY_TTL` of each other share one answer.
+        let instances = &ec2.inventory().await?.instances;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs() as i64;
+        ("200 OK", render(instances, &Stats::load(), now))
+    } else {
+        ("404 Not Found", "Not found, try /metrics\n".to_string())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Serve `/metrics` on `addr` until interrupted.
+pub async fn serve(ec2: &EC2, addr: &str) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        if let Err(err) = handle(ec2, stream).await {
+            tracing::error!("Failed to serve {peer}: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_ec2::{
+        primitives::DateTime,
+        types::{Instance, InstanceState, InstanceStateName, InstanceType},
+    };
+
+    use super::render;
+    use crate::state::Stats;
+
+    #[test]
+    fn render_metrics() {
+        let instance = |state: InstanceStateName, instance_type: InstanceType| {
+            Instance::builder()
+                .state(InstanceState::builder().name(state).build())
+                .instance_type(instance_type)
+                .launch_time(DateTime::from_secs(0))
+                .build()
+        };
+        let instances = [
+            instance(InstanceStateName::Running, InstanceType::T3Micro),
+            instance(InstanceStateName::Running, InstanceType::X2iednMetal),
+            instance(InstanceStateName::Stopped, InstanceType::T3Micro),
+        ];
+        let stats = Stats {
+            transfer_bytes: 2048,
+            jobs_succeeded: 5,
+            jobs_failed: 1,
+        };
+
+        let out = render(&instances, &stats, 36_000);
+        for expected in [
+            "korasi_instances{state=\"running\"} 2",
+            "korasi_instances{state=\"stopped\"} 1",
+            "korasi_instances{state=\"pending\"} 0",
+            "korasi_cost_estimate_dollars 0.1040",
+            "korasi_unpriced_instances 1",
+            "korasi_transfer_bytes_total 2048",
+            "korasi_jobs_total{result=\"failure\"} 1",
+        ] {
+            assert!(
+                out.lines().any(|l| l == expected),
+                "missing {expected:?} in\n{out}"
+            );
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Move instances off hardware scheduled for retirement or maintenance.
+//!
+//! Stopping and starting an EBS-backed instance places it on a new host,
+//! which clears any scheduled event tied to the old one. EBS volumes (and
+//! so the workspace) survive the move; a snapshot of every volume is taken
+//! first in case they don't.
+
+use std::time::Duration;
+
+use aws_sdk_ec2::types::DeviceType;
+
+use crate::ec2::{EC2Error, EC2Impl as EC2};
+
+/// Stopping can take longer than the default 90s on large instances.
+const STOP_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Time for the instance to pass status checks on the new host.
+const READY_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Snapshot, stop and start an instance.
+///
+/// Returns the new public DNS name, since the old one is released on stop.
+pub async fn migrate_instance(
+    ec2: &EC2,
+    instance_id: &str,
+    snapshot: bool,
+) -> Result<Option<String>, EC2Error> {
+    let instance = ec2.get_instance(instance_id).await?;
+    if instance.root_device_type() == Some(&DeviceType::InstanceStore) {
+        return Err(EC2Error::new(format!(
+            "{instance_id} is instance store backed and cannot be stopped, relaunch it instead"
+        )));
+    }
+    let old_host = instance.public_dns_name().unwrap_or_default().to_string();
+
+    if snapshot {
+        let snapshot_ids = ec2
+            .create_instance_snapshots(
+                instance_id,
+                &format!("korasi migrate backup of {instance_id}"),
+            )
+            .await?;
+        println!("{instance_id}: snapshots {}", snapshot_ids.join(", "));
+    }
+
+    println!("{instance_id}: stopping...");
+    ec2.stop_instances(instance_id, false).await?;
+    ec2.wait_for_instance_stopped(instance_id, Some(STOP_TIMEOUT))
+        .await?;
+
+    println!("{instance_id}: starting on new hardware...");
+    ec2.start_instances(instance_id).await?;
+    ec2.wait_for_instance_ready(instance_id, Some(READY_TIMEOUT))
+        .await?;
+
+    let new_host = ec2
+        .get_instance(instance_id)
+        .await?
+        .public_dns_name()
+        .filter(|h| !h.is_empty())
+        .map(str::to_string);
+    println!(
+        "{instance_id}: migrated, public DNS {} -> {}",
+        if old_host.is_empty() {
+            "(none)"
+        } else {
+            &old_host
+        },
+        new_host.as_deref().unwrap_or("(none)")
+    );
+
+    Ok(new_host)
+}
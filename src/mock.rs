@@ -0,0 +1,195 @@
+//! In-memory `Ec2Api` for testing the create, list and delete flows
+//! offline.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use aws_sdk_ec2::error::ErrorMetadata;
+use aws_sdk_ec2::types::{
+    Image, Instance, InstanceState, InstanceStateName, InstanceType, KeyPairInfo, SecurityGroup,
+    SummaryStatus, Tag,
+};
+
+use crate::ec2::{EC2Error, Ec2Api, InstanceHealth, LaunchOptions, TagSelector, GLOBAL_TAG_FILTER};
+
+/// Instances launched by `create_instances` start out running, except in
+/// `full_zones`. Every call is recorded in `calls`, in order.
+#[derive(Debug, Default)]
+pub struct MockEc2 {
+    pub images: Vec<Image>,
+    /// Availability zones out of capacity.
+    pub full_zones: Vec<String>,
+    pub instances: Mutex<Vec<Instance>>,
+    pub launches: Mutex<Vec<LaunchOptions>>,
+    pub calls: Mutex<Vec<String>>,
+}
+
+impl MockEc2 {
+    pub fn with_instances(instances: Vec<Instance>) -> Self {
+        MockEc2 {
+            instances: Mutex::new(instances),
+            ..MockEc2::default()
+        }
+    }
+
+    fn record(&self, call: String) {
+        self.calls.lock().unwrap().push(call);
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+/// A korasi instance named `name` with extra `tags`.
+pub fn instance(id: &str, name: &str, state: InstanceStateName, tags: &[(&str, &str)]) -> Instance {
+    let mut builder = Instance::builder()
+        .instance_id(id)
+        .instance_type(InstanceType::T3Micro)
+        .state(InstanceState::builder().name(state).build())
+        .public_dns_name(format!("{id}.compute.amazonaws.com"))
+        .tags(Tag::builder().key("Name").value(name).build())
+        .tags(
+            Tag::builder()
+                .key("application")
+                .value(GLOBAL_TAG_FILTER)
+                .build(),
+        );
+    for (key, value) in tags {
+        builder = builder.tags(Tag::builder().key(*key).value(*value).build());
+    }
+    builder.build()
+}
+
+fn has_tag(instance: &Instance, key: &str, value: &str) -> bool {
+    instance
+        .tags()
+        .iter()
+        .any(|t| t.key() == Some(key) && t.value() == Some(value))
+}
+
+fn state(instance: &Instance) -> Option<&InstanceStateName> {
+    instance.state().and_then(|s| s.name())
+}
+
+#[async_trait]
+impl Ec2Api for MockEc2 {
+    async fn get_ssh_security_group(&self) -> Result<SecurityGroup, EC2Error> {
+        self.record("get_ssh_security_group".into());
+        Ok(SecurityGroup::builder()
+            .group_id("sg-mock")
+            .group_name("allow-ssh")
+            .build())
+    }
+
+    async fn describe_image(&self, image_id: &str) -> Result<Image, EC2Error> {
+        self.record(format!("describe_image {image_id}"));
+        self.images
+            .iter()
+            .find(|i| i.image_id() == Some(image_id))
+            .cloned()
+            .ok_or_else(|| EC2Error::new(format!("Could not find image with id {image_id}")))
+    }
+
+    async fn create_instances(
+        &self,
+        instance_name: &str,
+        image_id: &str,
+        instance_type: InstanceType,
+        _key_pair: &KeyPairInfo,
+        security_groups: Vec<&SecurityGroup>,
+        opts: LaunchOptions,
+    ) -> Result<Vec<String>, EC2Error> {
+        let groups: Vec<_> = security_groups
+            .iter()
+            .filter_map(|g| g.group_id())
+            .collect();
+        self.record(format!(
+            "create_instances {image_id} {instance_type} {}",
+            groups.join(",")
+        ));
+        if let Some(zone) = opts
+            .availability_zone
+            .as_ref()
+            .filter(|z| self.full_zones.contains(z))
+        {
+            return Err(ErrorMetadata::builder()
+                .code("InsufficientInstanceCapacity")
+                .message(format!("No capacity in {zone}"))
+                .build()
+                .into());
+        }
+        let mut instances = self.instances.lock().unwrap();
+        let id = format!("i-{:04}", instances.len());
+        let mut launched = instance(&id, instance_name, InstanceStateName::Running, &[]);
+        launched.image_id = Some(image_id.into());
+        launched.instance_type = Some(instance_type);
+        instances.push(launched);
+        self.launches.lock().unwrap().push(opts);
+        Ok(vec![id])
+    }
+
+    async fn describe_instance_tagged(
+        &self,
+        statuses: Vec<InstanceStateName>,
+        tags: &[TagSelector],
+    ) -> Result<Vec<Instance>, EC2Error> {
+        self.record("describe_instance_tagged".into());
+        Ok(self
+            .instances
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|i| has_tag(i, "application", GLOBAL_TAG_FILTER))
+            .filter(|i| match state(i) {
+                Some(s) if statuses.is_empty() => *s != InstanceStateName::Terminated,
+                Some(s) => statuses.contains(s),
+                None => false,
+            })
+            .filter(|i| tags.iter().all(|t| has_tag(i, &t.key, &t.value)))
+            .cloned()
+            .collect())
+    }
+
+    async fn describe_instance_health(
+        &self,
+        instance_ids: Vec<String>,
+    ) -> Result<HashMap<String, InstanceHealth>, EC2Error> {
+        self.record(format!(
+            "describe_instance_health {}",
+            instance_ids.join(",")
+        ));
+        Ok(self
+            .instances
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|i| state(i) == Some(&InstanceStateName::Running))
+            .filter_map(|i| i.instance_id())
+            .filter(|id| instance_ids.iter().any(|i| i == id))
+            .map(|id| {
+                let health = InstanceHealth {
+                    system_status: Some(SummaryStatus::Ok),
+                    instance_status: Some(SummaryStatus::Ok),
+                    events: vec![],
+                };
+                (id.to_string(), health)
+            })
+            .collect())
+    }
+
+    async fn delete_instances(&self, instance_ids: &str, wait: bool) -> Result<(), EC2Error> {
+        self.record(format!("delete_instances {instance_ids} wait={wait}"));
+        let ids: Vec<&str> = instance_ids.split(',').collect();
+        for i in self.instances.lock().unwrap().iter_mut() {
+            if i.instance_id().is_some_and(|id| ids.contains(&id)) {
+                i.state = Some(
+                    InstanceState::builder()
+                        .name(InstanceStateName::Terminated)
+                        .build(),
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,16 @@
-use clap::{Parser, Subcommand};
+use aws_sdk_ec2::types::{InstanceType, PlacementStrategy, VolumeType};
+use clap::{builder::PossibleValuesParser, Parser, Subcommand, ValueEnum};
 
-use crate::ec2::GLOBAL_TAG_FILTER;
+use crate::{
+    copy::CopyTarget,
+    detach::WaitState,
+    ec2::{TagSelector, GLOBAL_TAG_FILTER},
+    ports::PortSpec,
+    scheduler::{parse_cron, Power},
+    ssh::{ForwardSpec, SSH_PORT},
+    units::{parse_disk_size, parse_duration, parse_throughput},
+    websocket::RELAY_INSTANCE_TYPE,
+};
 
 #[derive(Debug, Parser)]
 #[command(version, arg_required_else_help = true)]
@@ -21,9 +31,19 @@ pub struct Opt {
     #[structopt(short, default_value_t = false)]
     pub debug: bool,
 
+    /// Print where the time went (API calls, instance boot, SSH handshakes,
+    /// remote commands, transfers) when the command ends.
+    #[structopt(long, default_value_t = false)]
+    pub timings: bool,
+
     /// Specify path to launch script.
-    #[structopt(long, default_value = "start_up.sh")]
-    pub setup: String,
+    ///
+    /// Use `preset:<name>` to pick a script bundled with korasi instead:
+    /// minimal, rust-dev, python-ml, cuda or docker.
+    ///
+    /// Defaults to `[launch] setup` in korasi.toml, then start_up.sh.
+    #[structopt(long)]
+    pub setup: Option<String>,
 
     /// Path to SSH private key (default Ed25519).
     /// Default path is set to $HOME/.ssh/{pk}.
@@ -32,40 +52,397 @@ pub struct Opt {
     #[structopt(short, long)]
     pub ssh_key: Option<String>,
 
+    /// Import this OpenSSH public key (eg. ~/.ssh/id_ed25519.pub) as the
+    /// key pair instead of creating one. The private key is looked for
+    /// next to it, unless `--ssh-key` is given.
+    #[structopt(long, value_name = "PATH")]
+    pub import_key: Option<String>,
+
+    /// Name of the EC2 key pair to create or reuse, overriding
+    /// `[launch] key_name` in korasi.toml. Defaults to `ec2-ssh-key`.
+    #[structopt(long)]
+    pub key_name: Option<String>,
+
+    /// Reach instances through this WebSocket relay, eg.
+    /// `wss://relay.example.com/ssh`, where outbound port 22 is blocked.
+    /// `korasi relay deploy` provisions one.
+    #[structopt(long, value_name = "URL")]
+    pub relay: Option<String>,
+
+    /// Port sshd listens on, for AMIs not using 22. Also the port let in
+    /// by the security group.
+    #[structopt(long, value_name = "PORT")]
+    pub ssh_port: Option<u16>,
+
+    /// Leave instances failing their status checks or without a public
+    /// address out of the instance pickers, rather than flagging them.
+    #[structopt(long, default_value_t = false)]
+    pub healthy_only: bool,
+
+    /// Assume yes to every confirmation prompt, for use in scripts.
+    #[structopt(short = 'y', long, default_value_t = false)]
+    pub yes: bool,
+
+    /// Attempts per AWS request when throttled or on transient errors,
+    /// overriding `[retry] max_attempts` in korasi.toml. 1 disables retries.
+    #[structopt(long)]
+    pub max_attempts: Option<u32>,
+
+    /// How `run` reports remote output. `ndjson` prints one JSON event per
+    /// line, keeping stdout and stderr apart, for use by other programs.
+    #[structopt(long, value_enum, default_value_t = EventFormat::Text)]
+    pub events: EventFormat,
+
     #[command(subcommand)]
     pub commands: Commands,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum EventFormat {
+    Text,
+    Ndjson,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SyncMode {
+    Sftp,
+    Rsync,
+}
+
+// Parsed once per run, boxing `Create` wouldn't save anything.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// Create new instance, and print out host.
     ///
     /// If not machine_type is specified, allow user to
     /// choose machine_type from list of options.
-    Create { ami_id: String },
+    Create {
+        /// AMI to launch. When omitted, pick from the images matching the
+        /// `--ami-*` filters, newest first.
+        ///
+        /// Also accepts an alias (al2023, al2, ubuntu-22.04, ubuntu-24.04
+        /// or debian-12), which is pinned in `korasi.lock` the first time
+        /// it is resolved.
+        ami_id: Option<String>,
+
+        /// Owner of the images to pick from (account id or alias).
+        ///
+        /// Defaults to Canonical, matching the default `--user`.
+        #[arg(long, default_value = "099720109477")]
+        ami_owner: String,
+
+        /// Image architecture, eg. x86_64 or arm64.
+        ///
+        /// Defaults to the architectures supported by the machine type.
+        #[arg(long)]
+        ami_arch: Option<String>,
+
+        /// Image name pattern, `*` matches any characters.
+        #[arg(long, default_value = "ubuntu/images/*")]
+        ami_name: String,
+
+        /// Size of the root EBS volume, eg. 200GiB or 1TiB. Plain numbers
+        /// are in GiB.
+        ///
+        /// Defaults to the size of the AMI's root snapshot.
+        #[arg(long, value_parser = parse_disk_size)]
+        disk_size: Option<i32>,
+
+        /// Type of the root EBS volume.
+        #[arg(long, value_parser = PossibleValuesParser::new(VolumeType::values()))]
+        volume_type: Option<String>,
+
+        /// Provisioned IOPS of the root volume (gp3, io1, io2).
+        #[arg(long)]
+        iops: Option<i32>,
+
+        /// Provisioned throughput of the root volume (gp3 only), eg.
+        /// 250MiB/s. Plain numbers are in MiB/s.
+        #[arg(long, value_parser = parse_throughput)]
+        throughput: Option<i32>,
+
+        /// S3 bucket used to stage startup scripts over the 16 KB user data limit.
+        ///
+        /// The instance downloads the script with the role of `--iam-profile`,
+        /// which is required along and must allow `s3:GetObject` on it.
+        /// `obliterate` deletes staged scripts.
+        #[arg(long)]
+        user_data_bucket: Option<String>,
+
+        /// IAM instance profile (name or ARN) to attach, so the instance
+        /// can reach S3/ECR without credentials in the startup script.
+        #[arg(long)]
+        iam_profile: Option<String>,
+
+        /// Launch into this placement group, created if it doesn't exist.
+        /// A cluster group packs instances close together for low network
+        /// latency between them, eg. for MPI jobs.
+        #[arg(long)]
+        placement_group: Option<String>,
+
+        /// Strategy of the placement group, when it is created.
+        #[arg(
+            long,
+            default_value = "cluster",
+            requires = "placement_group",
+            value_parser = PossibleValuesParser::new(PlacementStrategy::values())
+        )]
+        placement_strategy: String,
+
+        /// Tag the instance, eg. with a value for a `[tags]` key of
+        /// korasi.toml. Repeat for several tags.
+        #[arg(long = "label", value_name = "KEY=VALUE")]
+        tags: Vec<TagSelector>,
+
+        /// Availability zone to launch in, eg. ap-southeast-1a. When it is
+        /// out of capacity, the zones of `[launch] availability_zones` in
+        /// korasi.toml (or else all of the region's) are tried in turn.
+        /// Without either, AWS picks the zone first.
+        #[arg(long)]
+        az: Option<String>,
+
+        /// Attach an Elastic Fabric Adapter, for low latency MPI/NCCL
+        /// traffic between instances. The instance type must support EFA.
+        /// The instance joins a security group open to traffic from the
+        /// other EFA instances of its placement group.
+        #[arg(long, default_value_t = false)]
+        efa: bool,
+
+        /// Instance type, eg. t3.large. Defaults to `[launch]
+        /// instance_type` in korasi.toml, otherwise it is picked from a list.
+        #[arg(long, value_parser = PossibleValuesParser::new(InstanceType::values()))]
+        instance_type: Option<String>,
+
+        /// Specify user for OS distro, defaults to `[launch] user` in
+        /// korasi.toml, then ubuntu.
+        ///
+        /// Used to run the `[verify]` checks from korasi.toml, if any.
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Wait until the instance passes its status checks, showing its
+        /// console output (kernel and cloud-init, including the setup
+        /// script) meanwhile.
+        #[arg(long)]
+        wait: bool,
+
+        /// Like `--wait`, then wait until the instance accepts SSH
+        /// connections and open a shell on it.
+        #[arg(long)]
+        connect: bool,
+
+        /// Stop the instance once it has had no SSH connection and low CPU
+        /// for this long, eg. `1h` or `30m`. Checked on the instance every
+        /// minute, shown by `show`.
+        #[arg(long, value_parser = parse_duration, value_name = "DURATION")]
+        auto_stop: Option<std::time::Duration>,
+    },
+
+    /// Launch a copy of an instance, with the same AMI, type, security
+    /// groups, IAM profile, user data and volume sizes.
+    ///
+    /// Volume contents aren't copied: the copy boots from the AMI.
+    Clone {
+        /// Instance to copy, by name or id. Picked from a list when omitted.
+        instance: Option<String>,
+
+        /// Name of the copy, defaults to `<source name>-clone`.
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Launch the copy as another instance type.
+        #[arg(long, value_parser = PossibleValuesParser::new(InstanceType::values()))]
+        instance_type: Option<String>,
+
+        /// Don't run the source's startup script on the copy.
+        #[arg(long, default_value_t = false)]
+        no_user_data: bool,
+    },
 
     /// List all instances created by this tool, which is under
     /// the same tag.
     #[clap(alias = "ls")]
-    List,
+    List {
+        /// List instances in every enabled region, grouped by region,
+        /// instead of only `--region`.
+        #[arg(long, default_value_t = false)]
+        all_regions: bool,
+    },
+
+    /// Show details of an instance: AMI, key, security groups, addresses,
+    /// subnet, uptime, volumes and status checks.
+    Show,
+
+    /// Write a self-contained HTML report of the instances, their cost
+    /// and CPU, and the latest runs, to share with people who don't use
+    /// korasi.
+    Report {
+        /// Where to write the report.
+        #[arg(long, value_name = "PATH")]
+        html: std::path::PathBuf,
+
+        /// Hours of CPU utilization to chart.
+        #[arg(long, default_value_t = 24)]
+        hours: u64,
+    },
+
+    /// Print the serial console output of an instance, eg. to debug the
+    /// setup script or a boot failure.
+    ///
+    /// EC2 only keeps the last 64 KB, refreshed every few seconds.
+    Console {
+        /// Keep polling for new output until Ctrl-C.
+        #[arg(long, short, default_value_t = false)]
+        follow: bool,
+    },
+
+    /// Save a screenshot of an instance's console, eg. to see a kernel
+    /// panic or GRUB prompt on an instance that never reaches SSH.
+    ///
+    /// EC2 returns a JPEG, of the screen as last drawn.
+    Screenshot {
+        /// Where to save it. Defaults to `<name>-console.jpg` in the
+        /// current directory.
+        #[arg(long, short, value_name = "PATH")]
+        output: Option<std::path::PathBuf>,
+
+        /// Wake the screen first if it went to sleep.
+        #[arg(long, default_value_t = false)]
+        wake_up: bool,
+    },
+
+    /// Pipe stdin/stdout to the SSH port of an instance, for use as a
+    /// `ProxyCommand` in `~/.ssh/config`:
+    ///
+    ///   Host korasi-*
+    ///       ProxyCommand korasi proxy-command %n --port %p
+    ///
+    /// Stopped instances are started and the current IP is let in first,
+    /// so `ssh`, `scp` and `rsync` work as is. Don't combine with `-d`,
+    /// logs would go to stdout.
+    ProxyCommand {
+        /// Name or id of the instance.
+        instance: String,
+
+        #[arg(long, default_value_t = SSH_PORT)]
+        port: u16,
+    },
 
     /// Delete 1 or more instances, where all options are displayed
     /// using a multi-select input.
     Delete {
         #[arg(long, short, default_value_t = false)]
         wait: bool,
+
+        /// Return right after requesting the deletion. Later commands and
+        /// `korasi gc` check that the instances did terminate.
+        #[arg(long, default_value_t = false, conflicts_with = "wait")]
+        detach: bool,
+    },
+
+    /// Manage the connection profiles remembered per instance: user, port,
+    /// transport, host key and address.
+    Profile {
+        #[command(subcommand)]
+        action: ProfileAction,
     },
 
+    /// Check on the deletions left by `delete --detach`, forgetting the
+    /// finished ones and reporting instances stuck shutting down.
+    Gc,
+
     /// Start 1 or more instances.
     ///
     /// Starting a stopped instance without an EIP will
     /// result in a new IP being assigned.
-    Start,
+    Start {
+        /// Start every stopped instance with this tag instead of picking
+        /// them. Repeat to require several tags.
+        #[arg(long = "with-tag", value_name = "KEY=VALUE")]
+        tags: Vec<TagSelector>,
+
+        /// Start every stopped instance, without prompting.
+        #[arg(long, default_value_t = false, conflicts_with = "tags")]
+        all_stopped: bool,
+    },
 
     /// Stop 1 or more instances.
     Stop {
         #[arg(long, short)]
         wait: bool,
+
+        /// Stop every running instance with this tag, without prompting,
+        /// eg. from cron. Repeat to require several tags.
+        #[arg(long = "with-tag", value_name = "KEY=VALUE")]
+        tags: Vec<TagSelector>,
+
+        /// Stop every running instance, without prompting, eg. for an
+        /// end-of-day alias. Prints the hourly spend halted.
+        #[arg(long, default_value_t = false, conflicts_with = "tags")]
+        all_running: bool,
+    },
+
+    /// Wait for instances to reach a state, eg. to sequence scripts
+    /// around korasi. Fails when `--timeout` runs out.
+    ///
+    /// Without `--state`, list the waits left in the background by
+    /// pressing Enter during `create --wait`, `stop --wait` or `delete
+    /// --wait`.
+    Wait {
+        /// Resume the waits left in the background, one after the other.
+        #[arg(long, default_value_t = false, conflicts_with = "state")]
+        attach: bool,
+
+        /// State to wait for. `ready` means passing status checks.
+        #[arg(long, value_enum)]
+        state: Option<WaitState>,
+
+        /// Instances to wait for, by name or id. Picked from a list when
+        /// neither these nor `--with-tag` are given.
+        #[arg(requires = "state")]
+        instances: Vec<String>,
+
+        /// Wait for every instance with this tag. Repeat to require
+        /// several tags.
+        #[arg(long = "with-tag", value_name = "KEY=VALUE", requires = "state")]
+        tags: Vec<TagSelector>,
+
+        /// Seconds to wait, per step, before giving up.
+        #[arg(long, default_value_t = 600)]
+        timeout: u64,
+    },
+
+    /// Reboot 1 or more running instances. Unlike stop/start, the public
+    /// DNS name is kept.
+    Reboot {
+        /// Reboot every running instance with this tag instead of picking
+        /// them. Repeat to require several tags.
+        #[arg(long = "with-tag", value_name = "KEY=VALUE")]
+        tags: Vec<TagSelector>,
+
+        /// Wait until the instances accept SSH connections again.
+        #[arg(long, default_value_t = false)]
+        wait_ssh: bool,
+    },
+
+    /// Install OS updates on 1 or more running instances with their
+    /// package manager (dnf, yum or apt), then reboot those that need it,
+    /// eg. for a new kernel.
+    Patch {
+        /// Specify user for OS distro. Defaults to `[launch] user` in
+        /// korasi.toml, otherwise it is detected from the instance's AMI.
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Patch every running instance with this tag instead of picking
+        /// them. Repeat to require several tags.
+        #[arg(long = "with-tag", value_name = "KEY=VALUE")]
+        tags: Vec<TagSelector>,
+
+        /// Don't reboot, even when updates need it to take effect.
+        #[arg(long, default_value_t = false)]
+        no_reboot: bool,
     },
 
     /// Upload local file(s) or directory to remote target instance directory.
@@ -89,13 +466,75 @@ pub enum Commands {
         #[arg(index = 2)]
         dst: Option<String>,
 
+        /// Specify user for OS distro. Defaults to `[launch] user` in
+        /// korasi.toml, otherwise it is detected from the instance's AMI.
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Jump through this bastion, as `[user@]host[:port]`, to reach
+        /// instances by private IP, eg. in private subnets.
+        #[arg(long, value_name = "BASTION")]
+        via: Option<String>,
+    },
+
+    /// Copy a file or directory from one running instance to another, eg.
+    /// checkpoints from a training node to an inference node.
+    ///
+    /// Data is relayed through this machine over SFTP.
+    Cp {
+        /// Source as `<instance>:<path>`, where the instance is a name or id.
+        src: CopyTarget,
+
+        /// Destination as `<instance>:<path>`. When the path is an existing
+        /// directory, the source is copied into it.
+        dst: CopyTarget,
+
+        /// Specify user for OS distro.
+        #[arg(short, long, default_value = "ubuntu")]
+        user: String,
+    },
+
+    /// Upload only the local files that changed since the last sync.
+    ///
+    /// Files are compared by size and modification time, or by checksum
+    /// with `--checksum`. Paths are laid out as with `upload`.
+    Sync {
+        /// Local file or directory, defaults to the working directory.
+        #[arg(index = 1)]
+        src: Option<String>,
+
+        /// Remote directory to sync into, defaults to $HOME.
+        #[arg(index = 2)]
+        dst: Option<String>,
+
         /// Specify user for OS distro.
         #[arg(short, long, default_value = "ubuntu")]
         user: String,
+
+        /// Compare same sized files by SHA-256 rather than modification
+        /// time. Slower, as every file is hashed on both ends.
+        #[arg(long, default_value_t = false)]
+        checksum: bool,
+
+        /// Remove remote files that no longer exist locally.
+        #[arg(long, default_value_t = false)]
+        delete: bool,
+
+        /// Transfer over korasi's SFTP session, or hand over to rsync (over
+        /// the system ssh) when it is installed on both ends. Through the
+        /// WebSocket relay, files always go over SFTP.
+        #[arg(long, value_enum, default_value_t = SyncMode::Sftp)]
+        mode: SyncMode,
     },
 
-    /// Executes a given command on remote instance.
-    /// Warn: output is not printed on centos distro (there may be more).
+    /// Executes a given command on remote instance(s).
+    ///
+    /// When several instances are selected, the command runs on all of
+    /// them concurrently without a PTY, and each line of output is
+    /// prefixed with the instance name.
+    ///
+    /// korasi exits with the command's exit code, or the highest one
+    /// across instances, so it can gate CI pipelines.
     ///
     /// Only run commands that are non-blocking. Commands like
     /// opening `vi` does not working at the moment.
@@ -103,9 +542,54 @@ pub enum Commands {
     /// TODO: run cmd from target directory.
     #[clap(alias = "r")]
     Run {
-        /// Specify user for OS distro.
-        #[arg(short, long, default_value = "ubuntu")]
-        user: String,
+        /// Specify user for OS distro. Defaults to `[launch] user` in
+        /// korasi.toml, otherwise it is detected from the instance's AMI.
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Stop the instance once the command finishes. There is a short grace
+        /// period to keep it running, skipped by `--yes`.
+        #[arg(long, default_value_t = false)]
+        stop_on_exit: bool,
+
+        /// Output isn't going to a terminal (eg. redirected to a log), so
+        /// strip ANSI escapes and turn carriage returns into newlines.
+        #[arg(long, default_value_t = false)]
+        no_tty: bool,
+
+        /// Run without a PTY, eg. for binary output: stdout is passed
+        /// through byte for byte and stderr kept apart, so
+        /// `korasi run --no-pty -- cat data.bin > local.bin` works.
+        ///
+        /// The local terminal stays as is, so interactive programs won't.
+        /// Implied when stdin is piped or a file, eg. `korasi run < input.json`.
+        #[arg(long, default_value_t = false, conflicts_with = "no_tty")]
+        no_pty: bool,
+
+        /// Copy the command's stdout verbatim to this local file, and its
+        /// stderr to `<PATH>.stderr`, while still showing both unless
+        /// `--quiet`, eg. to keep benchmark logs. The command runs without
+        /// a PTY.
+        ///
+        /// With several instances, each gets its own files, named after
+        /// it: `bench.log` becomes `bench.<name>.log`.
+        #[arg(long, value_name = "PATH")]
+        output_file: Option<std::path::PathBuf>,
+
+        /// Only write the output to `--output-file`, eg. binary or very
+        /// large output, not to the terminal.
+        #[arg(long, default_value_t = false, requires = "output_file")]
+        quiet: bool,
+
+        /// Run on every running instance with this tag instead of picking
+        /// them. Repeat to require several tags.
+        #[arg(long = "with-tag", value_name = "KEY=VALUE")]
+        tags: Vec<TagSelector>,
+
+        /// Jump through this bastion, as `[user@]host[:port]`, to reach
+        /// instances by private IP, eg. in private subnets.
+        #[arg(long, value_name = "BASTION")]
+        via: Option<String>,
 
         #[arg(allow_hyphen_values = true)]
         command: Vec<String>,
@@ -116,14 +600,477 @@ pub enum Commands {
     /// Executes default `bash` shell.
     #[clap(alias = "sh")]
     Shell {
+        /// Specify user for OS distro. Defaults to `[launch] user` in
+        /// korasi.toml, otherwise it is detected from the instance's AMI.
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Stop the instance once the session finishes. There is a short grace
+        /// period to keep it running, skipped by `--yes`.
+        #[arg(long, default_value_t = false)]
+        stop_on_exit: bool,
+
+        /// Jump through this bastion, as `[user@]host[:port]`, to reach
+        /// instances by private IP, eg. in private subnets.
+        #[arg(long, value_name = "BASTION")]
+        via: Option<String>,
+
+        /// Open these ports to your IP for the session, eg. `8888,6006`,
+        /// and close them when it ends. Ports already open stay as they are.
+        /// Instances behind a bastion aren't reachable on them, so it can't
+        /// be combined with `--via`.
+        #[arg(
+            long,
+            value_delimiter = ',',
+            value_name = "PORTS",
+            conflicts_with = "via"
+        )]
+        open_port: Vec<PortSpec>,
+
+        /// Leave the `--open-port` rules in place after the session.
+        #[arg(long, default_value_t = false, requires = "open_port")]
+        keep_open: bool,
+    },
+
+    /// Watch a spot instance until it gets an interruption notice, two
+    /// minutes before it is reclaimed, and warn.
+    Watch {
+        /// Specify user for OS distro. Defaults to `[launch] user` in
+        /// korasi.toml, otherwise it is detected from the instance's AMI.
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Command to run on the instance on notice, eg. to save a
+        /// checkpoint.
+        #[arg(long, value_name = "COMMAND")]
+        on_interruption: Option<String>,
+    },
+
+    /// Forward a local port to the instance until Ctrl-C, eg. to reach
+    /// Jupyter or a database running there.
+    Forward {
         /// Specify user for OS distro.
         #[arg(short, long, default_value = "ubuntu")]
         user: String,
+
+        /// `port`, `local_port:remote_port` or
+        /// `local_port:remote_host:remote_port`, where the remote host is
+        /// resolved on the instance.
+        forward: ForwardSpec,
+
+        /// Open these ports to your IP for the session, eg. `8888,6006`,
+        /// and close them when it ends. Ports already open stay as they are.
+        #[arg(long, value_delimiter = ',', value_name = "PORTS")]
+        open_port: Vec<PortSpec>,
+
+        /// Leave the `--open-port` rules in place after the session.
+        #[arg(long, default_value_t = false, requires = "open_port")]
+        keep_open: bool,
+    },
+
+    /// Build the crate in the current directory on a remote instance.
+    ///
+    /// The crate is uploaded, `cargo build` runs remotely with the given
+    /// flags, and artifacts are downloaded into `target/korasi/`.
+    Build {
+        /// Specify user for OS distro.
+        #[arg(short, long, default_value = "ubuntu")]
+        user: String,
+
+        /// Flags passed through to `cargo build`, eg. `--release`.
+        #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
+        args: Vec<String>,
+    },
+
+    /// Report the environment of an instance: OS, kernel, CPU, memory,
+    /// GPUs and toolchain versions (rustc, python, cuda, docker).
+    Env {
+        /// Specify user for OS distro.
+        #[arg(short, long, default_value = "ubuntu")]
+        user: String,
+
+        /// Print the report as JSON.
+        #[arg(long, default_value_t = false)]
+        json: bool,
+
+        /// Save the report as JSON to this file.
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+    },
+
+    /// Package or re-create an experiment environment.
+    Repro {
+        #[command(subcommand)]
+        action: ReproAction,
+    },
+
+    /// Manage extra ingress rules on the korasi security group, eg. to
+    /// reach a notebook or web server on the instances.
+    Ports {
+        #[command(subcommand)]
+        action: PortsAction,
+    },
+
+    /// Manage the tags of instances, eg. cost centers or experiment names.
+    Tag {
+        #[command(subcommand)]
+        action: TagAction,
+    },
+
+    /// Start or stop tagged instances on a cron schedule, eg. stop every
+    /// dev box at 7pm on weekdays. Runs on EventBridge Scheduler, so
+    /// korasi doesn't have to.
+    Schedule {
+        #[command(subcommand)]
+        action: ScheduleAction,
+    },
+
+    /// Manage the VPC korasi launches into, for regions without a
+    /// default VPC.
+    Network {
+        #[command(subcommand)]
+        action: NetworkAction,
+    },
+
+    /// Launch and wire up groups of instances for MPI jobs.
+    Cluster {
+        #[command(subcommand)]
+        action: ClusterAction,
+    },
+
+    /// Manage elastic IPs, which keep an instance's address (and public
+    /// DNS name) fixed across stop/start.
+    Eip {
+        #[command(subcommand)]
+        action: EipAction,
+    },
+
+    /// Manage the WebSocket relay instance, for networks where outbound
+    /// port 22 is blocked but 443 is allowed. See `--relay`.
+    Relay {
+        #[command(subcommand)]
+        action: RelayAction,
+    },
+
+    /// Move instances with scheduled retirement/maintenance events to new
+    /// hardware.
+    ///
+    /// Volumes are snapshotted, then the instance is stopped and started,
+    /// which places it on a new host. The public DNS name changes.
+    Migrate {
+        /// Skip the backup snapshot of attached volumes.
+        #[arg(long, default_value_t = false)]
+        no_snapshot: bool,
+
+        /// Offer every running instance, not only those with scheduled events.
+        #[arg(long, default_value_t = false)]
+        all: bool,
+    },
+
+    /// Compare instances against korasi.toml (instance type, AMI, open
+    /// ports, volumes and setup recipe) and print how they drifted from it.
+    ///
+    /// Exits with an error when any instance drifted.
+    Drift {
+        /// Instances to check, by name or id. Defaults to all of them.
+        instances: Vec<String>,
+    },
+
+    /// Re-resolve the AMI aliases pinned in `korasi.lock` to their newest
+    /// images, and accept changes to the locked setup recipes.
+    UpdateLock,
+
+    /// Rewrite deprecated keys of `korasi.toml` (or `.korasi.toml`) to
+    /// their current names. The original is kept as `<file>.bak`.
+    MigrateConfig,
+
+    /// Run as a daemon exposing Prometheus metrics on `/metrics`.
+    ///
+    /// Exports instance counts by state, an estimated cost of running
+    /// instances, bytes uploaded and remote command results.
+    Serve {
+        /// Address to listen on.
+        #[arg(long, default_value = "127.0.0.1:9185")]
+        listen: String,
     },
 
     /// Terminate all resources deployed by tool.
     /// Does not remove AWS iAM permissions.
     ///
+    /// The resources are listed before asking for confirmation, which
+    /// `--yes` skips.
+    ///
+    /// For shared accounts, `[safety] obliterate_min_age` in korasi.toml
+    /// leaves younger instances (and what they share) alone and has the
+    /// project tag typed to confirm. `protect_obliterate` refuses outright.
+    ///
     /// Yugi: "I have assembled all the 5 pieces of Exodia. Exodia obliterate!"
     Obliterate,
+
+    /// Describe the subcommands, flags and capabilities of this version
+    /// as JSON, for wrappers and plugins.
+    Introspect,
+
+    /// List the external subcommands: `korasi-<name>` executables on PATH
+    /// and those registered under `[plugins]` in korasi.toml.
+    Plugins,
+
+    /// `korasi <name> [args]` runs plugin `korasi-<name>`.
+    #[command(external_subcommand)]
+    External(Vec<String>),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ProfileAction {
+    /// List the remembered profiles.
+    #[clap(alias = "ls")]
+    List,
+
+    /// Forget the profile of an instance, eg. after its host key changed.
+    Forget {
+        /// Instance name or ID.
+        instance: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PortsAction {
+    /// List the ingress rules of the security group.
+    #[clap(alias = "ls")]
+    List,
+
+    /// Open ports, eg. `8080,9000/udp,8000-8100`.
+    Open {
+        #[arg(value_delimiter = ',', required = true)]
+        ports: Vec<PortSpec>,
+
+        /// Source CIDR allowed in. Defaults to your current IP.
+        #[arg(long)]
+        cidr: Option<String>,
+    },
+
+    /// Revoke rules for the given ports.
+    Close {
+        #[arg(value_delimiter = ',', required = true)]
+        ports: Vec<PortSpec>,
+
+        /// Only revoke this source CIDR. Defaults to every source.
+        #[arg(long)]
+        cidr: Option<String>,
+    },
+
+    /// Revoke SSH access of your former IPs. Also done on the way by
+    /// every command, for IPs unused for 30 days.
+    ///
+    /// Rules `--open-port` left behind by sessions that were killed are
+    /// revoked too.
+    Prune {
+        /// Revoke IPs unused for this many days.
+        #[arg(long, default_value_t = 30)]
+        days: u64,
+
+        /// Revoke every single IP but the current one, including rules
+        /// added by older versions or by hand.
+        #[arg(long, default_value_t = false)]
+        all: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TagAction {
+    /// List the tags of the chosen instances.
+    #[clap(alias = "ls")]
+    List,
+
+    /// Add tags to the chosen instances, overwriting existing values.
+    ///
+    /// `Name` and `application` are managed by korasi and can't be set.
+    Add {
+        /// Tags as `key=value`.
+        #[arg(required = true, value_name = "KEY=VALUE")]
+        tags: Vec<TagSelector>,
+    },
+
+    /// Remove tags from the chosen instances.
+    ///
+    /// `Name` and `application` are managed by korasi and can't be removed.
+    Remove {
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum NetworkAction {
+    /// Create a tagged VPC with a public subnet, internet gateway and
+    /// route table. Instances and security groups are created in it from
+    /// then on, `obliterate` tears it down. Run again to finish or repair
+    /// a network left incomplete.
+    Init,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ClusterAction {
+    /// Launch nodes into one cluster placement group, wait for all of
+    /// them, then write an MPI hostfile of their private IPs locally and
+    /// onto every node.
+    ///
+    /// Nodes are named `<name>-1` to `<name>-N` and may reach each other
+    /// on any port.
+    Create {
+        /// Number of nodes.
+        #[arg(short, long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+        nodes: u32,
+
+        /// Name of the cluster, random when omitted.
+        #[arg(long)]
+        name: Option<String>,
+
+        /// AMI id or alias to launch, defaults to `[launch] ami` in
+        /// korasi.toml.
+        #[arg(long)]
+        ami_id: Option<String>,
+
+        /// Instance type of every node, defaults to `[launch]
+        /// instance_type` in korasi.toml, otherwise it is picked from a list.
+        #[arg(long, value_parser = PossibleValuesParser::new(InstanceType::values()))]
+        instance_type: Option<String>,
+
+        /// Attach an Elastic Fabric Adapter to every node.
+        #[arg(long, default_value_t = false)]
+        efa: bool,
+
+        /// Processes per node in the hostfile, defaults to MPI's choice
+        /// (the number of cores).
+        #[arg(long)]
+        slots: Option<u32>,
+
+        /// Where to write the hostfile locally.
+        #[arg(long, default_value = "hostfile")]
+        hostfile: std::path::PathBuf,
+
+        /// Where to write the hostfile on the nodes, relative to the home
+        /// directory.
+        #[arg(long, default_value = "hostfile")]
+        remote_hostfile: String,
+
+        /// Tag the nodes, eg. with a value for a `[tags]` key of
+        /// korasi.toml. Repeat for several tags.
+        #[arg(long = "label", value_name = "KEY=VALUE")]
+        tags: Vec<TagSelector>,
+
+        /// Specify user for OS distro, defaults to `[launch] user` in
+        /// korasi.toml, then ubuntu.
+        #[arg(short, long)]
+        user: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ScheduleAction {
+    /// Schedule starting or stopping the instances with the given tags.
+    ///
+    /// Instances are resolved now, those created later are not covered.
+    Add {
+        #[arg(value_enum)]
+        power: Power,
+
+        /// Scheduler cron expression (UTC unless `--timezone`), eg.
+        /// `0 19 ? * MON-FRI *`.
+        #[arg(long, value_parser = parse_cron)]
+        cron: String,
+
+        /// Instances with this tag. Repeat to require several tags.
+        #[arg(long = "with-tag", required = true, value_name = "KEY=VALUE")]
+        tags: Vec<TagSelector>,
+
+        /// IANA timezone of the cron expression, eg. `Asia/Singapore`.
+        #[arg(long)]
+        timezone: Option<String>,
+
+        /// ARN of the IAM role the scheduler assumes. It must trust
+        /// `scheduler.amazonaws.com` and allow `ec2:StartInstances` and
+        /// `ec2:StopInstances`.
+        #[arg(long)]
+        role_arn: String,
+
+        /// Name of the schedule, defaults to the action and tags. Prefixed
+        /// with `korasi-`. Adding a schedule of an existing name updates it.
+        #[arg(long)]
+        name: Option<String>,
+    },
+
+    /// List the korasi schedules.
+    #[clap(alias = "ls")]
+    List,
+
+    /// Delete korasi schedules by name.
+    #[clap(alias = "rm")]
+    Delete {
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum EipAction {
+    /// List the elastic IPs allocated by this tool.
+    #[clap(alias = "ls")]
+    List,
+
+    /// Allocate a new elastic IP.
+    Allocate {
+        /// Associate it with an instance right away.
+        #[arg(long, default_value_t = false)]
+        associate: bool,
+    },
+
+    /// Associate an elastic IP with an instance.
+    Associate,
+
+    /// Release an elastic IP, disassociating it first.
+    Release,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RelayAction {
+    /// Launch a relay instance listening on port 443, let this machine in
+    /// and print its `--relay` URL. Prints the URL of the running one
+    /// instead, if any.
+    Deploy {
+        /// Instance type of the relay.
+        #[arg(long, default_value = RELAY_INSTANCE_TYPE, value_parser = PossibleValuesParser::new(InstanceType::values()))]
+        instance_type: String,
+    },
+
+    /// Terminate the relay instance.
+    Delete,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ReproAction {
+    /// Save the launch spec, environment report, startup script, git
+    /// commit and run command of an instance into a `.tar.gz`.
+    Bundle {
+        /// Specify user for OS distro.
+        #[arg(short, long, default_value = "ubuntu")]
+        user: String,
+
+        /// Archive to write. Defaults to `korasi-repro-<instance name>.tar.gz`.
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+
+        /// Command that runs the experiment, recorded for later.
+        #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
+        command: Vec<String>,
+    },
+
+    /// Launch an instance from a bundle and run its `[verify]` checks.
+    Apply {
+        bundle: std::path::PathBuf,
+
+        /// Specify user for OS distro.
+        #[arg(short, long, default_value = "ubuntu")]
+        user: String,
+    },
 }
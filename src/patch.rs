@@ -0,0 +1,143 @@
+//! OS updates for long-lived instances, through the distro's package
+//! manager (dnf, yum or apt), for `korasi patch`.
+
+use crate::ssh::{on_each_host, ConnectOptions, Session};
+
+/// Installs every available update, then prints a summary line for
+/// `PatchReport::parse`. Package manager output goes to stderr.
+const PATCH_SCRIPT: &str = r#"set -e
+if pm=$(command -v dnf || command -v yum); then
+  manager=$(basename "$pm")
+  updates=$("$pm" -q check-update | grep -c '^[[:alnum:]]' || true)
+  sudo "$pm" -y -q upgrade >&2
+  if "$pm" needs-restarting --help >/dev/null 2>&1; then
+    sudo "$pm" needs-restarting -r >/dev/null 2>&1 && reboot=no || reboot=yes
+  else
+    latest=$(rpm -q kernel --qf '%{VERSION}-%{RELEASE}.%{ARCH}\n' | sort -V | tail -n 1)
+    [ "$latest" = "$(uname -r)" ] && reboot=no || reboot=yes
+  fi
+elif command -v apt-get >/dev/null; then
+  manager=apt
+  sudo DEBIAN_FRONTEND=noninteractive apt-get -q update >&2
+  updates=$(apt-get -s upgrade | grep -c '^Inst ' || true)
+  sudo DEBIAN_FRONTEND=noninteractive apt-get -y -q -o Dpkg::Options::=--force-confold upgrade >&2
+  [ -f /var/run/reboot-required ] && reboot=yes || reboot=no
+else
+  echo "No supported package manager (dnf, yum or apt-get)" >&2
+  exit 1
+fi
+echo "korasi-patch manager=$manager updated=$updates reboot=$reboot"
+"#;
+
+/// Prefix of the summary line printed by `PATCH_SCRIPT`.
+const SUMMARY_PREFIX: &str = "korasi-patch ";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchReport {
+    pub manager: String,
+    /// Number of packages upgraded.
+    pub updated: usize,
+    /// Whether the updates only take effect after a reboot, eg. a kernel.
+    pub reboot_required: bool,
+}
+
+impl PatchReport {
+    /// Report from the output of `PATCH_SCRIPT`.
+    pub fn parse(stdout: &str) -> Option<PatchReport> {
+        let line = stdout
+            .lines()
+            .rev()
+            .find_map(|l| l.trim().strip_prefix(SUMMARY_PREFIX))?;
+        let field = |key: &str| {
+            line.split_whitespace()
+                .find_map(|kv| kv.strip_prefix(key)?.strip_prefix('='))
+        };
+        Some(PatchReport {
+            manager: field("manager")?.into(),
+            updated: field("updated")?.parse().ok()?,
+            reboot_required: field("reboot")? == "yes",
+        })
+    }
+}
+
+impl std::fmt::Display for PatchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} package(s) updated with {}",
+            self.updated, self.manager
+        )?;
+        if self.reboot_required {
+            write!(f, ", reboot required")?;
+        }
+        Ok(())
+    }
+}
+
+/// Install the available updates on the instance of `session`.
+pub async fn patch(session: &Session) -> anyhow::Result<PatchReport> {
+    let output = session.exec_output(PATCH_SCRIPT).await?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if output.code != 0 {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let tail: Vec<&str> = stderr.lines().rev().take(5).collect();
+        anyhow::bail!(
+            "Updating failed with exit code {}:\n{}",
+            output.code,
+            tail.into_iter().rev().collect::<Vec<_>>().join("\n")
+        );
+    }
+    PatchReport::parse(&stdout).ok_or_else(|| anyhow::anyhow!("Updates ran without a summary"))
+}
+
+/// Patch every `(instance_id, host)` concurrently, returning the results in
+/// the same order.
+pub async fn patch_parallel(
+    hosts: Vec<(String, String)>,
+    user: &str,
+    ssh_key: &str,
+    connect: &ConnectOptions,
+) -> Vec<(String, anyhow::Result<PatchReport>)> {
+    on_each_host(hosts, |_, host| {
+        let user = user.to_string();
+        let ssh_key = ssh_key.to_string();
+        let connect = connect.clone();
+        async move {
+            let mut session = Session::connect(&user, host, ssh_key, &connect).await?;
+            let report = patch(&session).await?;
+            session.close().await?;
+            Ok(report)
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PatchReport;
+
+    #[test]
+    fn parse_patch_summary() {
+        let cases = [
+            (
+                "korasi-patch manager=dnf updated=12 reboot=yes\n",
+                Some(("dnf", 12, true)),
+            ),
+            (
+                "Reading package lists...\nkorasi-patch manager=apt updated=0 reboot=no",
+                Some(("apt", 0, false)),
+            ),
+            ("korasi-patch manager=apt updated= reboot=no", None),
+            ("nothing to see", None),
+        ];
+        for (stdout, expected) in cases {
+            println!("stdout = {stdout:?}");
+            let expected = expected.map(|(manager, updated, reboot_required)| PatchReport {
+                manager: manager.into(),
+                updated,
+                reboot_required,
+            });
+            pretty_assertions::assert_eq!(PatchReport::parse(stdout), expected);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! External subcommands, git style: `korasi corp-login` runs the
+//! `korasi-corp-login` executable, looked up in `[plugins]` of korasi.toml
+//! first and then on `PATH`.
+//!
+//! ```toml
+//! [plugins]
+//! corp-login = "/opt/corp/bin/korasi-corp-login"
+//! ```
+//!
+//! Plugins get the remaining arguments as is, and korasi's settings
+//! through the environment:
+//!
+//! | Variable             | Value                                        |
+//! |----------------------|----------------------------------------------|
+//! | `KORASI_BIN`         | Path of the korasi executable, to call back. |
+//! | `KORASI_PROFILE`     | `--profile`                                  |
+//! | `KORASI_REGION`      | `--region`                                   |
+//! | `KORASI_TAG`         | `--tag`, the tag of korasi's resources.      |
+//! | `KORASI_SSH_KEY`     | Path of the SSH private key.                 |
+//! | `KORASI_CONFIG`      | Path of korasi.toml, which may not exist.    |
+//! | `KORASI_STATE_DIR`   | Directory of korasi's local state.           |
+//! | `KORASI_YES`         | `1` with `--yes`, otherwise `0`.             |
+
+use std::{
+    collections::BTreeMap,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+pub const PLUGIN_PREFIX: &str = "korasi-";
+
+/// Settings passed on to plugins.
+#[derive(Debug, Clone)]
+pub struct PluginEnv {
+    pub profile: String,
+    pub region: String,
+    pub tag: Option<String>,
+    pub ssh_key: String,
+    pub config: PathBuf,
+    pub state_dir: PathBuf,
+    pub yes: bool,
+}
+
+impl PluginEnv {
+    pub fn vars(&self) -> Vec<(&'static str, String)> {
+        let bin = std::env::current_exe()
+            .map(|p| p.display().to_string())
+            .unwrap_or("korasi".into());
+        vec![
+            ("KORASI_BIN", bin),
+            ("KORASI_PROFILE", self.profile.clone()),
+            ("KORASI_REGION", self.region.clone()),
+            ("KORASI_TAG", self.tag.clone().unwrap_or_default()),
+            ("KORASI_SSH_KEY", self.ssh_key.clone()),
+            ("KORASI_CONFIG", self.config.display().to_string()),
+            ("KORASI_STATE_DIR", self.state_dir.display().to_string()),
+            ("KORASI_YES", if self.yes { "1" } else { "0" }.into()),
+        ]
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Plugins found in `dirs` (eg. `PATH`) and the `registry` from
+/// korasi.toml, by name. The registry and earlier directories win.
+pub fn discover(
+    dirs: impl IntoIterator<Item = PathBuf>,
+    registry: &BTreeMap<String, String>,
+) -> BTreeMap<String, PathBuf> {
+    let mut plugins = BTreeMap::new();
+    for dir in dirs {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let Some(name) = file_name.strip_prefix(PLUGIN_PREFIX) else {
+                continue;
+            };
+            if !name.is_empty() && is_executable(&entry.path()) {
+                plugins.entry(name.to_string()).or_insert(entry.path());
+            }
+        }
+    }
+    for (name, path) in registry {
+        plugins.insert(name.clone(), PathBuf::from(path));
+    }
+    plugins
+}
+
+fn path_dirs() -> Vec<PathBuf> {
+    std::env::var_os("PATH")
+        .map(|p| std::env::split_paths(&p).collect())
+        .unwrap_or_default()
+}
+
+/// All plugins on `PATH` and in the registry.
+pub fn list(registry: &BTreeMap<String, String>) -> BTreeMap<String, PathBuf> {
+    discover(path_dirs(), registry)
+}
+
+/// Run plugin `name` with `args`, returning its exit code.
+pub async fn run(
+    name: &str,
+    args: Vec<String>,
+    registry: &BTreeMap<String, String>,
+    env: &PluginEnv,
+) -> anyhow::Result<i32> {
+    let Some(path) = list(registry).remove(name) else {
+        anyhow::bail!(
+            "Unknown command `{name}`, and no {PLUGIN_PREFIX}{name} plugin on PATH. See `korasi --help`."
+        );
+    };
+    tracing::info!("Running plugin {}", path.display());
+    let vars = env.vars();
+    // The runtime is single threaded, don't block it while the plugin runs.
+    let status = tokio::task::spawn_blocking(move || {
+        std::process::Command::new(&path)
+            .args(&args)
+            .envs(vars)
+            .status()
+    })
+    .await??;
+    Ok(status.code().unwrap_or(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, os::unix::fs::PermissionsExt, path::PathBuf};
+
+    use super::discover;
+
+    #[test]
+    fn discover_plugins() {
+        let root = std::env::temp_dir().join(format!("korasi-plugins-{}", std::process::id()));
+        let (first, second) = (root.join("a"), root.join("b"));
+        let write = |path: PathBuf, mode: u32| {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "#!/bin/sh\n").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        };
+        write(first.join("korasi-corp-login"), 0o755);
+        write(second.join("korasi-corp-login"), 0o755);
+        write(second.join("korasi-notes.txt"), 0o644);
+        write(second.join("korasi-report"), 0o755);
+        write(second.join("korasi-"), 0o755);
+
+        let registry = BTreeMap::from([("report".to_string(), "/opt/corp/report".to_string())]);
+        let plugins = discover(vec![first.clone(), second, root.join("missing")], &registry);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        pretty_assertions::assert_eq!(
+            plugins,
+            BTreeMap::from([
+                ("corp-login".to_string(), first.join("korasi-corp-login")),
+                ("report".to_string(), PathBuf::from("/opt/corp/report")),
+            ])
+        );
+    }
+}
@@ -0,0 +1,502 @@
+//! Extra ingress rules on the korasi security group, managed by `Ports`.
+
+use std::{fmt, net::IpAddr, str::FromStr, time::Duration};
+
+use aws_sdk_ec2::types::{IpPermission, IpRange, Ipv6Range, SecurityGroup, SecurityGroupRule};
+
+use crate::{
+    ec2::{EC2Error, EC2Impl as EC2},
+    state::{SessionRule, SessionRules},
+};
+
+/// Tag of the SSH rules korasi adds: when (unix seconds) their IP was last
+/// this machine's.
+pub const LAST_USED_TAG: &str = "korasi:last-used";
+
+/// SSH rules of IPs unused for this long are revoked.
+pub const STALE_RULE_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Single address CIDR of `ip`, eg. `1.2.3.4/32` or `2001:db8::1/128`.
+pub fn host_cidr(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => format!("{ip}/32"),
+        IpAddr::V6(ip) => format!("{ip}/128"),
+    }
+}
+
+fn rule_cidr(rule: &SecurityGroupRule) -> Option<&str> {
+    rule.cidr_ipv4().or(rule.cidr_ipv6())
+}
+
+fn is_ssh_ingress(rule: &SecurityGroupRule, port: u16) -> bool {
+    rule.is_egress() == Some(false)
+        && rule.ip_protocol() == Some("tcp")
+        && rule.from_port() == Some(port as i32)
+        && rule.to_port() == Some(port as i32)
+}
+
+/// Rules `relay deploy` adds to the SSH group `group_id`: `relay_port`
+/// from the IPs it was deployed from, and SSH `port` from the group
+/// itself, for the relay to forward to.
+pub fn relay_rules<'a>(
+    rules: &'a [SecurityGroupRule],
+    group_id: &str,
+    port: u16,
+    relay_port: u16,
+) -> Vec<&'a SecurityGroupRule> {
+    rules
+        .iter()
+        .filter(|r| {
+            let from_group = r
+                .referenced_group_info()
+                .is_some_and(|g| g.group_id() == Some(group_id));
+            is_ssh_ingress(r, relay_port) || (is_ssh_ingress(r, port) && from_group)
+        })
+        .collect()
+}
+
+/// When the IP of `rule` was last used, for rules korasi tagged.
+pub fn last_used(rule: &SecurityGroupRule) -> Option<u64> {
+    rule.tags()
+        .iter()
+        .find(|t| t.key() == Some(LAST_USED_TAG))
+        .and_then(|t| t.value())
+        .and_then(|v| v.parse().ok())
+}
+
+/// The ingress rule of `current` (a CIDR) on SSH `port`, if any.
+pub fn current_rule<'a>(
+    rules: &'a [SecurityGroupRule],
+    port: u16,
+    current: &str,
+) -> Option<&'a SecurityGroupRule> {
+    rules
+        .iter()
+        .find(|r| is_ssh_ingress(r, port) && rule_cidr(r) == Some(current))
+}
+
+/// Ingress rules on SSH `port` to revoke, other than those of the
+/// `current` CIDRs: those korasi added for IPs unused for `max_age`, or
+/// with `all`, every single address one.
+pub fn stale_rules<'a>(
+    rules: &'a [SecurityGroupRule],
+    port: u16,
+    current: &[String],
+    now: u64,
+    max_age: Duration,
+    all: bool,
+) -> Vec<&'a SecurityGroupRule> {
+    rules
+        .iter()
+        .filter(|r| is_ssh_ingress(r, port) && !current.iter().any(|c| rule_cidr(r) == Some(c)))
+        .filter(|r| {
+            if all {
+                rule_cidr(r).is_some_and(|c| c.ends_with("/32") || c.ends_with("/128"))
+            } else {
+                last_used(r).is_some_and(|t| now.saturating_sub(t) > max_age.as_secs())
+            }
+        })
+        .collect()
+}
+
+/// A port or port range with its protocol, eg. `8080`, `9000/udp` or
+/// `8000-8100/tcp`. The protocol defaults to TCP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortSpec {
+    pub from: i32,
+    pub to: i32,
+    pub protocol: String,
+}
+
+impl FromStr for PortSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (range, protocol) = s.split_once('/').unwrap_or((s, "tcp"));
+        let protocol = protocol.to_ascii_lowercase();
+        if !matches!(protocol.as_str(), "tcp" | "udp") {
+            return Err(format!("unsupported protocol `{protocol}`, use tcp or udp"));
+        }
+
+        let port = |p: &str| match p.trim().parse::<u16>() {
+            Ok(p) if p > 0 => Ok(p as i32),
+            _ => Err(format!("invalid port `{p}`")),
+        };
+        let (from, to) = match range.split_once('-') {
+            Some((from, to)) => (port(from)?, port(to)?),
+            None => (port(range)?, port(range)?),
+        };
+        if from > to {
+            return Err(format!("invalid port range `{range}`"));
+        }
+
+        Ok(PortSpec { from, to, protocol })
+    }
+}
+
+impl fmt::Display for PortSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.from == self.to {
+            write!(f, "{}/{}", self.from, self.protocol)
+        } else {
+            write!(f, "{}-{}/{}", self.from, self.to, self.protocol)
+        }
+    }
+}
+
+impl PortSpec {
+    pub fn to_permission(&self, cidrs: &[String]) -> IpPermission {
+        IpPermission::builder()
+            .ip_protocol(&self.protocol)
+            .from_port(self.from)
+            .to_port(self.to)
+            .set_ip_ranges(Some(
+                cidrs
+                    .iter()
+                    .filter(|c| !c.contains(':'))
+                    .map(|c| IpRange::builder().cidr_ip(c).build())
+                    .collect(),
+            ))
+            .set_ipv6_ranges(Some(
+                cidrs
+                    .iter()
+                    .filter(|c| c.contains(':'))
+                    .map(|c| Ipv6Range::builder().cidr_ipv6(c).build())
+                    .collect(),
+            ))
+            .build()
+    }
+
+    pub fn matches(&self, perm: &IpPermission) -> bool {
+        perm.ip_protocol() == Some(self.protocol.as_str())
+            && perm.from_port() == Some(self.from)
+            && perm.to_port() == Some(self.to)
+    }
+}
+
+/// Whether `group` already lets `cidr` in on `port`.
+fn is_open(group: &SecurityGroup, port: &PortSpec, cidr: &str) -> bool {
+    group
+        .ip_permissions()
+        .iter()
+        .filter(|perm| port.matches(perm))
+        .any(|perm| {
+            perm.ip_ranges().iter().any(|r| r.cidr_ip() == Some(cidr))
+                || perm
+                    .ipv6_ranges()
+                    .iter()
+                    .any(|r| r.cidr_ipv6() == Some(cidr))
+        })
+}
+
+/// When the process `pid` started, in an OS specific format, to tell it
+/// apart from a later process reusing the pid.
+fn process_start(pid: u32) -> Option<String> {
+    if cfg!(target_os = "linux") {
+        // Field 22, counted after the command name, which may hold spaces.
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+        let (_, fields) = stat.rsplit_once(')')?;
+        fields.split_whitespace().nth(19).map(str::to_string)
+    } else {
+        let output = std::process::Command::new("ps")
+            .args(["-o", "lstart=", "-p", &pid.to_string()])
+            .output()
+            .ok()?;
+        let started = String::from_utf8(output.stdout).ok()?;
+        let started = started.trim();
+        (output.status.success() && !started.is_empty()).then(|| started.to_string())
+    }
+}
+
+/// Whether the process `pid` that started at `started` still runs. Those
+/// of other users count too, signalling them is only refused.
+fn is_running(pid: u32, started: Option<&str>) -> bool {
+    let alive = unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+        || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    alive && started.is_none_or(|s| process_start(pid).as_deref() == Some(s))
+}
+
+/// Open `ports` of `group` to this machine's IP for the length of a
+/// session. Ports already open to it are left out, so closing the session
+/// doesn't revoke rules it didn't add.
+///
+/// The rules are recorded in `SessionRules` until `close_session_ports`,
+/// and those of sessions that never got to close are revoked first.
+pub async fn open_session_ports(
+    ec2: &EC2,
+    group: &SecurityGroup,
+    ports: &[PortSpec],
+) -> Result<Vec<SessionRule>, EC2Error> {
+    revoke_abandoned(ec2).await;
+    let group_id = group.group_id().unwrap_or_default();
+    let cidrs: Vec<String> = EC2::current_ips()
+        .await?
+        .into_iter()
+        .map(host_cidr)
+        .collect();
+    let missing: Vec<(&PortSpec, &String)> = ports
+        .iter()
+        .flat_map(|p| cidrs.iter().map(move |c| (p, c)))
+        .filter(|(p, c)| !is_open(group, p, c))
+        .collect();
+    if missing.is_empty() {
+        return Ok(vec![]);
+    }
+
+    ec2.authorize_security_group_ingress(
+        group_id,
+        missing
+            .iter()
+            .map(|(p, c)| p.to_permission(std::slice::from_ref(c)))
+            .collect(),
+    )
+    .await?;
+    let rules: Vec<SessionRule> = missing
+        .iter()
+        .map(|(p, c)| SessionRule {
+            group_id: group_id.into(),
+            port: p.to_string(),
+            cidr: (*c).clone(),
+            pid: std::process::id(),
+            started: process_start(std::process::id()),
+        })
+        .collect();
+    SessionRules::record(&rules);
+    for r in &rules {
+        eprintln!("Opened {} from {} for this session.", r.port, r.cidr);
+    }
+    Ok(rules)
+}
+
+/// Revoke `rules` opened by `open_session_ports`. Failures are only
+/// logged, so the end of a session still goes on.
+pub async fn close_session_ports(ec2: &EC2, rules: &[SessionRule]) {
+    for r in rules {
+        let Ok(port) = r.port.parse::<PortSpec>() else {
+            continue;
+        };
+        let permission = port.to_permission(std::slice::from_ref(&r.cidr));
+        match ec2
+            .revoke_security_group_ingress(&r.group_id, vec![permission])
+            .await
+        {
+            Ok(()) => eprintln!("Closed {} from {}.", r.port, r.cidr),
+            Err(err) => tracing::warn!("Failed to close {} from {}: {err}", r.port, r.cidr),
+        }
+    }
+    SessionRules::forget(rules);
+}
+
+/// Revoke the rules of sessions whose process is gone (eg. killed), and
+/// return them.
+pub async fn revoke_abandoned(ec2: &EC2) -> Vec<SessionRule> {
+    let abandoned: Vec<SessionRule> = SessionRules::load()
+        .0
+        .into_iter()
+        .filter(|r| !is_running(r.pid, r.started.as_deref()))
+        .collect();
+    if !abandoned.is_empty() {
+        close_session_ports(ec2, &abandoned).await;
+    }
+    abandoned
+}
+
+/// One line per rule, eg. `22/tcp from 1.2.3.4/32, 5.6.7.8/32`.
+pub fn format_permission(perm: &IpPermission) -> String {
+    let ports = match (perm.ip_protocol(), perm.from_port(), perm.to_port()) {
+        (Some("-1"), ..) => "all traffic".to_string(),
+        (Some(proto), Some(from), Some(to)) => PortSpec {
+            from,
+            to,
+            protocol: proto.into(),
+        }
+        .to_string(),
+        (proto, ..) => proto.unwrap_or("unknown").to_string(),
+    };
+    let sources = perm
+        .ip_ranges()
+        .iter()
+        .filter_map(|r| r.cidr_ip())
+        .chain(perm.ipv6_ranges().iter().filter_map(|r| r.cidr_ipv6()))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!("{ports} from {sources}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use aws_sdk_ec2::types::{ReferencedSecurityGroup, SecurityGroup, SecurityGroupRule, Tag};
+
+    use super::{
+        format_permission, host_cidr, is_open, is_running, process_start, relay_rules, stale_rules,
+        PortSpec, LAST_USED_TAG,
+    };
+    use crate::ssh::SSH_PORT;
+
+    #[test]
+    fn parse_port_spec() {
+        let spec = |from, to, protocol: &str| PortSpec {
+            from,
+            to,
+            protocol: protocol.into(),
+        };
+        let cases = [
+            ("8080", Ok(spec(8080, 8080, "tcp"))),
+            ("9000/udp", Ok(spec(9000, 9000, "udp"))),
+            ("8000-8100/TCP", Ok(spec(8000, 8100, "tcp"))),
+            ("0", Err("invalid port `0`".to_string())),
+            ("70000", Err("invalid port `70000`".to_string())),
+            ("90-80", Err("invalid port range `90-80`".to_string())),
+            (
+                "53/icmp",
+                Err("unsupported protocol `icmp`, use tcp or udp".to_string()),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            println!("input = {input}");
+            pretty_assertions::assert_eq!(input.parse::<PortSpec>(), expected);
+        }
+    }
+
+    #[test]
+    fn stale_ssh_rules() {
+        let rule = |cidr: &str, port: i32, last_used: Option<u64>| {
+            SecurityGroupRule::builder()
+                .is_egress(false)
+                .ip_protocol("tcp")
+                .from_port(port)
+                .to_port(port)
+                .cidr_ipv4(cidr)
+                .set_tags(last_used.map(|t| {
+                    vec![Tag::builder()
+                        .key(LAST_USED_TAG)
+                        .value(t.to_string())
+                        .build()]
+                }))
+                .build()
+        };
+        let day = 24 * 60 * 60;
+        let now = 100 * day;
+        let rules = [
+            rule("1.1.1.1/32", 22, Some(now - day)),
+            rule("2.2.2.2/32", 22, Some(now - 40 * day)),
+            rule("3.3.3.3/32", 22, None),
+            rule("4.4.4.4/32", 8888, Some(now - 40 * day)),
+            rule("10.0.0.0/16", 22, None),
+            rule("5.5.5.5/32", 22, Some(now - 90 * day)),
+            SecurityGroupRule::builder()
+                .is_egress(false)
+                .ip_protocol("tcp")
+                .from_port(22)
+                .to_port(22)
+                .cidr_ipv6("2001:db8::1/128")
+                .build(),
+        ];
+        let cases = [
+            (false, vec!["2.2.2.2/32"]),
+            (
+                true,
+                vec!["1.1.1.1/32", "2.2.2.2/32", "3.3.3.3/32", "2001:db8::1/128"],
+            ),
+        ];
+        for (all, expected) in cases {
+            println!("all = {all}");
+            let stale: Vec<_> = stale_rules(
+                &rules,
+                SSH_PORT,
+                &["5.5.5.5/32".into(), "2001:db8::5/128".into()],
+                now,
+                Duration::from_secs(30 * day),
+                all,
+            )
+            .iter()
+            .filter_map(|r| r.cidr_ipv4().or(r.cidr_ipv6()))
+            .collect();
+            pretty_assertions::assert_eq!(stale, expected);
+        }
+
+        let cidrs = [
+            host_cidr("1.2.3.4".parse().unwrap()),
+            host_cidr("2001:db8::1".parse().unwrap()),
+        ];
+        let ssh = PortSpec {
+            from: 22,
+            to: 22,
+            protocol: "tcp".into(),
+        };
+        pretty_assertions::assert_eq!(
+            format_permission(&ssh.to_permission(&cidrs)),
+            "22/tcp from 1.2.3.4/32, 2001:db8::1/128"
+        );
+    }
+
+    #[test]
+    fn relay_ingress_rules() {
+        let rule = |id: &str, port: i32, group: Option<&str>| {
+            SecurityGroupRule::builder()
+                .security_group_rule_id(id)
+                .is_egress(false)
+                .ip_protocol("tcp")
+                .from_port(port)
+                .to_port(port)
+                .set_cidr_ipv4(group.is_none().then(|| "1.1.1.1/32".into()))
+                .set_referenced_group_info(
+                    group.map(|g| ReferencedSecurityGroup::builder().group_id(g).build()),
+                )
+                .build()
+        };
+        let rules = [
+            rule("sgr-ssh", 22, None),
+            rule("sgr-relay", 443, None),
+            rule("sgr-self", 22, Some("sg-1")),
+            rule("sgr-other", 22, Some("sg-2")),
+            rule("sgr-jupyter", 8888, Some("sg-1")),
+        ];
+        let ids: Vec<_> = relay_rules(&rules, "sg-1", SSH_PORT, 443)
+            .iter()
+            .filter_map(|r| r.security_group_rule_id())
+            .collect();
+        pretty_assertions::assert_eq!(ids, vec!["sgr-relay", "sgr-self"]);
+    }
+
+    #[test]
+    fn ports_open_to_cidr() {
+        let jupyter: PortSpec = "8888".parse().unwrap();
+        let group = SecurityGroup::builder()
+            .ip_permissions(jupyter.to_permission(&["1.2.3.4/32".into()]))
+            .build();
+        let cases = [
+            ("8888", "1.2.3.4/32", true),
+            ("8888", "5.6.7.8/32", false),
+            ("8888/udp", "1.2.3.4/32", false),
+            ("8000-8888", "1.2.3.4/32", false),
+        ];
+        for (port, cidr, expected) in cases {
+            println!("port = {port}, cidr = {cidr}");
+            pretty_assertions::assert_eq!(is_open(&group, &port.parse().unwrap(), cidr), expected);
+        }
+    }
+
+    #[test]
+    fn running_sessions() {
+        let pid = std::process::id();
+        let started = process_start(pid);
+        assert!(started.is_some());
+        let cases = [
+            ("this process", pid, started.as_deref(), true),
+            ("start unknown", pid, None, true),
+            ("pid reused", pid, Some("0"), false),
+            // Owned by root, signalling it is refused for other users.
+            ("init", 1, None, true),
+            ("gone", u32::MAX / 2, None, false),
+        ];
+        for (label, pid, started, expected) in cases {
+            println!("{label}");
+            pretty_assertions::assert_eq!(is_running(pid, started), expected);
+        }
+    }
+}
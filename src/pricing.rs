@@ -0,0 +1,407 @@
+//! Rough on-demand prices for cost estimates.
+//!
+//! A small table of common instance types is bundled. Prices are us-east-1
+//! Linux on-demand rates in USD per hour; other regions are typically
+//! within 10-30%. Where accuracy matters (`list`, picking a machine type),
+//! [`hourly_prices`] asks the AWS Price List API for the region and caches
+//! the answers under `~/.korasi/prices`, falling back to the table.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use aws_sigv4::http_request::{SignableBody, SigningSettings};
+use aws_types::{region::Region, SdkConfig as AwsSdkConfig};
+
+use crate::{
+    ec2::EC2Error,
+    json::Value as Json,
+    sigv4,
+    state::{save_state, state_dir},
+    toml::{self, quote, Value},
+};
+
+/// The Price List API is only served from a few regions, prices of every
+/// region are available from each.
+const PRICING_REGION: &str = "us-east-1";
+
+/// Prices rarely change, refresh cached ones monthly.
+const MAX_PRICE_AGE: u64 = 30 * 24 * 3600;
+
+/// Types the Price List API has no price for are asked about again daily,
+/// in case they were just launched.
+const MAX_MISS_AGE: u64 = 24 * 3600;
+
+/// Keep `list` snappy when the Price List API is unreachable: prices not
+/// fetched by then come from the bundled table.
+const PRICING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Price List API requests in flight at once. Prices of every instance
+/// type take a few runs to be cached, rather than a burst of requests.
+const MAX_CONCURRENT_FETCHES: usize = 16;
+
+/// Sorted by instance type.
+const ON_DEMAND_HOURLY: &[(&str, f64)] = &[
+    ("c5.2xlarge", 0.34),
+    ("c5.4xlarge", 0.68),
+    ("c5.large", 0.085),
+    ("c5.xlarge", 0.17),
+    ("c6i.large", 0.085),
+    ("c6i.xlarge", 0.17),
+    ("c7g.large", 0.0725),
+    ("c7g.xlarge", 0.145),
+    ("g4dn.2xlarge", 0.752),
+    ("g4dn.xlarge", 0.526),
+    ("g5.2xlarge", 1.212),
+    ("g5.xlarge", 1.006),
+    ("m5.2xlarge", 0.384),
+    ("m5.4xlarge", 0.768),
+    ("m5.large", 0.096),
+    ("m5.xlarge", 0.192),
+    ("m6i.2xlarge", 0.384),
+    ("m6i.large", 0.096),
+    ("m6i.xlarge", 0.192),
+    ("m7g.large", 0.0816),
+    ("m7g.xlarge", 0.1632),
+    ("p3.2xlarge", 3.06),
+    ("p4d.24xlarge", 32.7726),
+    ("r5.large", 0.126),
+    ("r5.xlarge", 0.252),
+    ("t2.large", 0.0928),
+    ("t2.medium", 0.0464),
+    ("t2.micro", 0.0116),
+    ("t2.small", 0.023),
+    ("t3.2xlarge", 0.3328),
+    ("t3.large", 0.0832),
+    ("t3.medium", 0.0416),
+    ("t3.micro", 0.0104),
+    ("t3.small", 0.0208),
+    ("t3.xlarge", 0.1664),
+    ("t4g.large", 0.0672),
+    ("t4g.medium", 0.0336),
+    ("t4g.micro", 0.0084),
+    ("t4g.small", 0.0168),
+];
+
+/// Approximate on-demand USD/hour, if the instance type is known.
+pub fn on_demand_hourly(instance_type: &str) -> Option<f64> {
+    ON_DEMAND_HOURLY
+        .binary_search_by(|(t, _)| (*t).cmp(instance_type))
+        .ok()
+        .map(|i| ON_DEMAND_HOURLY[i].1)
+}
+
+/// A price fetched for a region.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedPrice {
+    pub instance_type: String,
+    /// `None` when the Price List API has no price for it.
+    pub hourly: Option<f64>,
+    /// Unix time it was fetched.
+    pub updated: u64,
+}
+
+impl CachedPrice {
+    fn is_fresh(&self) -> bool {
+        let max_age = if self.hourly.is_some() {
+            MAX_PRICE_AGE
+        } else {
+            MAX_MISS_AGE
+        };
+        now().saturating_sub(self.updated) < max_age
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn cache_path(region: &str) -> PathBuf {
+    state_dir().join("prices").join(format!("{region}.toml"))
+}
+
+/// Prices in `src`, skipping malformed entries.
+pub fn parse_prices(src: &str) -> Vec<CachedPrice> {
+    let Ok(table) = toml::parse(src) else {
+        return vec![];
+    };
+    let Some(Value::Array(prices)) = table.get("price").map(|i| &i.value) else {
+        return vec![];
+    };
+    prices
+        .iter()
+        .filter_map(|p| {
+            let Value::Table(t) = p else {
+                return None;
+            };
+            let value = |key: &str| t.get(key).map(|i| &i.value);
+            Some(CachedPrice {
+                instance_type: match value("instance_type")? {
+                    Value::String(s) => s.clone(),
+                    _ => return None,
+                },
+                hourly: match value("hourly") {
+                    Some(Value::Float(f)) => Some(*f),
+                    Some(Value::Integer(i)) => Some(*i as f64),
+                    None => None,
+                    _ => return None,
+                },
+                updated: match value("updated") {
+                    Some(Value::Integer(i)) => (*i).max(0) as u64,
+                    _ => 0,
+                },
+            })
+        })
+        .collect()
+}
+
+pub fn prices_to_toml(prices: &[CachedPrice]) -> String {
+    prices
+        .iter()
+        .map(|p| {
+            let hourly = p
+                .hourly
+                .map(|h| format!("hourly = {h:?}\n"))
+                .unwrap_or_default();
+            format!(
+                "[[price]]\ninstance_type = {}\n{hourly}updated = {}\n",
+                quote(&p.instance_type),
+                p.updated
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// On-demand USD/hour from a `GetProducts` response. Each product comes
+/// as an escaped JSON string, with `OnDemand` and `Reserved` terms.
+fn parse_on_demand_usd(body: &str) -> Option<f64> {
+    let on_demand = &body[body.find("OnDemand")?..];
+    let usd = &on_demand[on_demand.find("USD")? + 3..];
+    let start = usd.find(|c: char| c.is_ascii_digit())?;
+    let price = &usd[start..];
+    let end = price
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(price.len());
+    price[..end].parse().ok()
+}
+
+/// Linux, shared tenancy on-demand price of `instance_type` in `region`,
+/// `None` when the Price List API has none.
+async fn fetch_price(
+    config: &AwsSdkConfig,
+    region: &str,
+    instance_type: &str,
+) -> Result<Option<f64>, EC2Error> {
+    let filter = |field: &str, value: &str| {
+        Json::Object(vec![
+            ("Type".into(), "TERM_MATCH".into()),
+            ("Field".into(), field.into()),
+            ("Value".into(), value.into()),
+        ])
+    };
+    let body = Json::Object(vec![
+        ("ServiceCode".into(), "AmazonEC2".into()),
+        (
+            "Filters".into(),
+            Json::Array(vec![
+                filter("regionCode", region),
+                filter("instanceType", instance_type),
+                filter("operatingSystem", "Linux"),
+                filter("tenancy", "Shared"),
+                filter("preInstalledSw", "NA"),
+                filter("capacitystatus", "Used"),
+            ]),
+        ),
+        ("MaxResults".into(), 1u64.into()),
+    ])
+    .to_string();
+
+    let url = format!("https://api.pricing.{PRICING_REGION}.amazonaws.com/");
+    let config = config
+        .to_builder()
+        .region(Region::new(PRICING_REGION))
+        .build();
+    let (headers, _) = sigv4::sign(
+        &config,
+        "pricing",
+        "POST",
+        &url,
+        SignableBody::Bytes(body.as_bytes()),
+        SigningSettings::default(),
+    )
+    .await?;
+    let mut req = reqwest::Client::new()
+        .post(&url)
+        .timeout(PRICING_TIMEOUT)
+        .header("content-type", "application/x-amz-json-1.1")
+        .header("x-amz-target", "AWSPriceListService.GetProducts")
+        .body(body);
+    for (k, v) in headers {
+        req = req.header(k, v);
+    }
+    let res = req
+        .send()
+        .await
+        .map_err(|e| EC2Error::new(format!("Could not query the Price List API: {e:?}")))?
+        .error_for_status()
+        .map_err(|e| EC2Error::new(format!("Failure status from the Price List API: {e:?}")))?
+        .text()
+        .await
+        .map_err(|e| EC2Error::new(format!("Could not read the Price List API response: {e:?}")))?;
+    Ok(parse_on_demand_usd(&res))
+}
+
+/// On-demand USD/hour of `instance_types` in the region of `config`, from
+/// the cache, the Price List API or the bundled table, in that order.
+/// Types without any known price are left out.
+///
+/// Prices missing from the cache are fetched concurrently, all within
+/// `PRICING_TIMEOUT`. Those not fetched by then are tried next time.
+pub async fn hourly_prices(
+    config: &AwsSdkConfig,
+    instance_types: &[String],
+) -> HashMap<String, f64> {
+    let region = sigv4::region(config);
+    let path = cache_path(&region);
+    let mut cached = std::fs::read_to_string(&path)
+        .map(|src| parse_prices(&src))
+        .unwrap_or_default();
+
+    let mut prices = HashMap::new();
+    let mut missing = vec![];
+    for instance_type in instance_types {
+        if prices.contains_key(instance_type) || missing.contains(instance_type) {
+            continue;
+        }
+        let fresh = cached
+            .iter()
+            .find(|p| &p.instance_type == instance_type)
+            .filter(|p| p.is_fresh());
+        match fresh {
+            Some(CachedPrice {
+                hourly: Some(hourly),
+                ..
+            }) => {
+                prices.insert(instance_type.clone(), *hourly);
+            }
+            Some(_) => {}
+            None => missing.push(instance_type.clone()),
+        }
+    }
+
+    let queue = Arc::new(Mutex::new(missing.clone()));
+    let fetched = Arc::new(Mutex::new(vec![]));
+    let mut set = tokio::task::JoinSet::new();
+    for _ in 0..missing.len().min(MAX_CONCURRENT_FETCHES) {
+        let (queue, fetched) = (queue.clone(), fetched.clone());
+        let (config, region) = (config.clone(), region.clone());
+        set.spawn(async move {
+            loop {
+                let Some(instance_type) = queue.lock().unwrap().pop() else {
+                    break;
+                };
+                match fetch_price(&config, &region, &instance_type).await {
+                    Ok(hourly) => fetched.lock().unwrap().push((instance_type, hourly)),
+                    Err(err) => tracing::debug!("{err}, using the bundled price"),
+                }
+            }
+        });
+    }
+    let all = async {
+        while let Some(joined) = set.join_next().await {
+            if let Err(err) = joined {
+                tracing::debug!("Price task failed: {err}");
+            }
+        }
+    };
+    if tokio::time::timeout(PRICING_TIMEOUT, all).await.is_err() {
+        tracing::debug!("Price List API timed out, using bundled prices");
+    }
+    set.abort_all();
+    let fetched = std::mem::take(&mut *fetched.lock().unwrap());
+
+    for instance_type in &missing {
+        let hourly = fetched
+            .iter()
+            .find(|(t, _)| t == instance_type)
+            .and_then(|(_, hourly)| *hourly)
+            .or_else(|| on_demand_hourly(instance_type));
+        if let Some(hourly) = hourly {
+            prices.insert(instance_type.clone(), hourly);
+        }
+    }
+    // Types without a price are cached too, so they aren't asked about on
+    // every run.
+    for (instance_type, hourly) in &fetched {
+        cached.retain(|p| &p.instance_type != instance_type);
+        cached.push(CachedPrice {
+            instance_type: instance_type.clone(),
+            hourly: *hourly,
+            updated: now(),
+        });
+    }
+
+    if !fetched.is_empty() {
+        // Like other local state, failing to save is only logged.
+        let res = save_state(&path, &prices_to_toml(&cached));
+        if let Err(err) = res {
+            tracing::warn!("Failed to cache prices: {err}");
+        }
+    }
+    prices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        on_demand_hourly, parse_on_demand_usd, parse_prices, prices_to_toml, CachedPrice,
+        ON_DEMAND_HOURLY,
+    };
+
+    #[test]
+    fn lookup_price() {
+        assert!(ON_DEMAND_HOURLY.windows(2).all(|w| w[0].0 < w[1].0));
+
+        let cases = [
+            ("t3.micro", Some(0.0104)),
+            ("g5.xlarge", Some(1.006)),
+            ("x9.huge", None),
+        ];
+        for (instance_type, expected) in cases {
+            println!("instance_type = {instance_type}");
+            pretty_assertions::assert_eq!(on_demand_hourly(instance_type), expected);
+        }
+    }
+    #[test]
+    fn cached_prices() {
+        let prices = vec![
+            CachedPrice {
+                instance_type: "t3.micro".into(),
+                hourly: Some(0.0132),
+                updated: 1_700_000_000,
+            },
+            CachedPrice {
+                instance_type: "p4d.24xlarge".into(),
+                hourly: Some(40.0),
+                updated: 1_700_000_100,
+            },
+            CachedPrice {
+                instance_type: "x9.huge".into(),
+                hourly: None,
+                updated: 1_700_000_200,
+            },
+        ];
+        pretty_assertions::assert_eq!(parse_prices(&prices_to_toml(&prices)), prices);
+
+        let body = r#"{"FormatVersion":"aws_v1","PriceList":["{\"product\":{\"sku\":\"X\"},\"terms\":{\"OnDemand\":{\"X.JRTCKXETXF\":{\"priceDimensions\":{\"X.JRTCKXETXF.6YS6EN2CT7\":{\"unit\":\"Hrs\",\"pricePerUnit\":{\"USD\":\"0.0132000000\"}}}}},\"Reserved\":{}}}"]}"#;
+        pretty_assertions::assert_eq!(parse_on_demand_usd(body), Some(0.0132));
+        pretty_assertions::assert_eq!(parse_on_demand_usd(r#"{"PriceList":[]}"#), None);
+    }
+}
@@ -0,0 +1,253 @@
+//! Progress reporting for SFTP uploads and for waits on instances.
+
+use std::{
+    future::Future,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// Redraw interval on a terminal.
+const TTY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval between progress lines when stderr is not a terminal, eg.
+/// redirected to a CI log.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Human readable size, eg. `1.5 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Duration as `1h 02m`, `3m 05s` or `12s`.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s:02}s"),
+        (h, m, _) => format!("{h}h {m:02}m"),
+    }
+}
+
+/// Single status line, eg.
+/// `1.0 GiB / 4.0 GiB (25%), 3/12 files, 10.0 MiB/s, ETA 5m 07s`.
+pub fn format_status(
+    bytes: u64,
+    total_bytes: u64,
+    files: usize,
+    total_files: usize,
+    elapsed: Duration,
+) -> String {
+    let percent = (bytes * 100).checked_div(total_bytes).unwrap_or(100);
+    let mut status = format!(
+        "{} / {} ({percent}%), {files}/{total_files} files",
+        format_bytes(bytes),
+        format_bytes(total_bytes)
+    );
+
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 && bytes > 0 {
+        let rate = bytes as f64 / secs;
+        let eta = Duration::from_secs_f64(total_bytes.saturating_sub(bytes) as f64 / rate);
+        status.push_str(&format!(
+            ", {}/s, ETA {}",
+            format_bytes(rate as u64),
+            format_duration(eta)
+        ));
+    }
+    status
+}
+
+/// Tracks bytes and files transferred and reports them on stderr.
+pub struct Progress {
+    total_bytes: u64,
+    total_files: usize,
+    bytes: u64,
+    files: usize,
+    started: Instant,
+    last_draw: Instant,
+    tty: bool,
+}
+
+impl Progress {
+    pub fn new(total_files: usize, total_bytes: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            total_bytes,
+            total_files,
+            bytes: 0,
+            files: 0,
+            started: now,
+            last_draw: now,
+            tty: termion::is_tty(&std::io::stderr()),
+        }
+    }
+
+    pub fn add_bytes(&mut self, n: u64) {
+        self.bytes += n;
+        self.draw();
+    }
+
+    pub fn file_done(&mut self) {
+        self.files += 1;
+        self.draw();
+    }
+
+    fn draw(&mut self) {
+        let interval = if self.tty { TTY_INTERVAL } else { LOG_INTERVAL };
+        if self.last_draw.elapsed() < interval {
+            return;
+        }
+        self.last_draw = Instant::now();
+
+        let status = format_status(
+            self.bytes,
+            self.total_bytes,
+            self.files,
+            self.total_files,
+            self.started.elapsed(),
+        );
+        let mut stderr = std::io::stderr();
+        if self.tty {
+            let _ = write!(stderr, "\r{}{status}", termion::clear::CurrentLine);
+        } else {
+            let _ = writeln!(stderr, "{status}");
+        }
+        let _ = stderr.flush();
+    }
+
+    /// Replace the status line with a summary.
+    pub fn finish(&mut self) {
+        if self.tty {
+            let _ = write!(std::io::stderr(), "\r{}", termion::clear::CurrentLine);
+        }
+        eprintln!(
+            "Uploaded {} files ({}) in {}",
+            self.files,
+            format_bytes(self.bytes),
+            format_duration(self.started.elapsed())
+        );
+    }
+}
+
+/// Width of the bar drawn by `format_wait`.
+const BAR_WIDTH: usize = 20;
+
+/// Status of a wait expected to take `expected`, eg.
+/// `[#####---------------] 25s / ~1m 40s, ETA 1m 15s`.
+pub fn format_wait(elapsed: Duration, expected: Option<Duration>) -> String {
+    let Some(expected) = expected.filter(|e| !e.is_zero()) else {
+        return format!("{} elapsed", format_duration(elapsed));
+    };
+    let ratio = (elapsed.as_secs_f64() / expected.as_secs_f64()).min(1.0);
+    let filled = (ratio * BAR_WIDTH as f64) as usize;
+    let bar = format!("[{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled));
+    let times = format!(
+        "{} / ~{}",
+        format_duration(elapsed),
+        format_duration(expected)
+    );
+    match expected.checked_sub(elapsed) {
+        Some(left) if !left.is_zero() => format!("{bar} {times}, ETA {}", format_duration(left)),
+        _ => format!("{bar} {times}, taking longer than usual"),
+    }
+}
+
+/// Await `fut`, redrawing `format_wait` on stderr every second when it is
+/// a terminal.
+pub async fn with_eta<T>(expected: Option<Duration>, fut: impl Future<Output = T>) -> T {
+    if !termion::is_tty(&std::io::stderr()) {
+        return fut.await;
+    }
+    let started = Instant::now();
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    tokio::pin!(fut);
+    let res = loop {
+        tokio::select! {
+            res = &mut fut => break res,
+            _ = tick.tick() => {
+                let mut stderr = std::io::stderr();
+                let status = format_wait(started.elapsed(), expected);
+                let _ = write!(stderr, "\r{}{status}", termion::clear::CurrentLine);
+                let _ = stderr.flush();
+            }
+        }
+    };
+    eprint!("\r{}", termion::clear::CurrentLine);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{format_status, format_wait};
+
+    #[test]
+    fn progress_status() {
+        let cases = [
+            (0, 0, 0, 0, 0, "0 B / 0 B (100%), 0/0 files"),
+            (
+                512,
+                2048,
+                1,
+                4,
+                1,
+                "512 B / 2.0 KiB (25%), 1/4 files, 512 B/s, ETA 3s",
+            ),
+            (
+                1 << 30,
+                4 << 30,
+                3,
+                12,
+                100,
+                "1.0 GiB / 4.0 GiB (25%), 3/12 files, 10.2 MiB/s, ETA 5m 00s",
+            ),
+        ];
+
+        for (bytes, total_bytes, files, total_files, secs, expected) in cases {
+            pretty_assertions::assert_eq!(
+                format_status(
+                    bytes,
+                    total_bytes,
+                    files,
+                    total_files,
+                    Duration::from_secs(secs)
+                ),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn wait_status() {
+        let secs = Duration::from_secs;
+        let cases = [
+            (secs(25), None, "25s elapsed"),
+            (
+                secs(25),
+                Some(secs(100)),
+                "[#####---------------] 25s / ~1m 40s, ETA 1m 15s",
+            ),
+            (
+                secs(130),
+                Some(secs(100)),
+                "[####################] 2m 10s / ~1m 40s, taking longer than usual",
+            ),
+        ];
+
+        for (elapsed, expected, status) in cases {
+            pretty_assertions::assert_eq!(format_wait(elapsed, expected), status);
+        }
+    }
+}
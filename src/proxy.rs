@@ -0,0 +1,153 @@
+//! `korasi proxy-command`, to reach instances with the system `ssh` (and
+//! `scp`, `rsync`, ...) through an `~/.ssh/config` entry like:
+//!
+//! ```text
+//! Host korasi-*
+//!     User ubuntu
+//!     IdentityFile ~/.ssh/korasi.pem
+//!     ProxyCommand korasi proxy-command %n --port %p
+//! ```
+//!
+//! korasi starts the instance if need be and lets this machine's IP in,
+//! then pipes stdin/stdout to its SSH port. stdout carries the connection,
+//! so nothing else may be printed to it.
+
+use std::time::Duration;
+
+use aws_sdk_ec2::types::InstanceStateName;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    copy::find_instance,
+    ec2::EC2Impl as EC2,
+    ssh::{wait_for_port, ConnectOptions},
+    util::SelectOption,
+    websocket,
+};
+
+/// How long a stopped instance gets to boot and open its SSH port.
+const START_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Copy `input` to `stream` and `stream` to `output` until the remote end
+/// closes the connection. Closing `input` only shuts down the write half,
+/// the remote may still have something to say.
+pub async fn pipe<S, R, W>(stream: S, mut input: R, mut output: W) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let upload = async {
+        tokio::io::copy(&mut input, &mut writer).await?;
+        writer.shutdown().await
+    };
+    let download = async {
+        let mut buf = vec![0; 32 * 1024];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return output.flush().await;
+            }
+            output.write_all(&buf[..n]).await?;
+            // ssh waits on every packet, don't hold any back.
+            output.flush().await?;
+        }
+    };
+    tokio::pin!(upload, download);
+    tokio::select! {
+        res = &mut download => res,
+        res = &mut upload => {
+            res?;
+            download.await
+        }
+    }
+}
+
+/// Make `name` (or instance id) reachable on `port`, through the relay of
+/// `connect` if any, and pipe stdin/stdout to it.
+pub async fn proxy_command(
+    ec2: &EC2,
+    name: &str,
+    port: u16,
+    connect: &ConnectOptions,
+) -> anyhow::Result<()> {
+    let candidates: Vec<SelectOption> = ec2
+        .describe_instance(vec![])
+        .await?
+        .into_iter()
+        .map(SelectOption::from)
+        .collect();
+    let instance = find_instance(&candidates, name)?;
+    let id = instance.instance_id.clone();
+
+    match instance.state() {
+        Some(InstanceStateName::Running) => {}
+        Some(InstanceStateName::Stopping) => {
+            eprintln!("korasi: waiting for {name} to stop before starting it...");
+            ec2.wait_for_instance_stopped(&id, Some(START_TIMEOUT))
+                .await?;
+            ec2.start_instances(&id).await?;
+        }
+        Some(InstanceStateName::Stopped) => {
+            eprintln!("korasi: starting {name}...");
+            ec2.start_instances(&id).await?;
+        }
+        _ => {}
+    }
+    ec2.wait_for_instance_running(&id, Some(START_TIMEOUT))
+        .await?;
+    // Lets the current IP in, it may have changed since the last session.
+    ec2.get_ssh_security_group().await?;
+
+    // The public DNS name changes across stop/start.
+    let host = ec2
+        .get_instance(&id)
+        .await?
+        .public_dns_name()
+        .filter(|h| !h.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow::anyhow!("{name} has no public DNS name"))?;
+    wait_for_port(&host, port, connect.relay.as_deref(), START_TIMEOUT).await?;
+
+    if let Some(relay) = &connect.relay {
+        let stream = websocket::connect(relay, &host, port).await?;
+        pipe(stream, tokio::io::stdin(), tokio::io::stdout()).await?;
+        return Ok(());
+    }
+    let stream = TcpStream::connect((host.as_str(), port)).await?;
+    stream.set_nodelay(true)?;
+    pipe(stream, tokio::io::stdin(), tokio::io::stdout()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::pipe;
+
+    #[tokio::test]
+    async fn pipe_until_remote_closes() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Echo in upper case until the client is done writing, then say bye.
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = String::new();
+            socket.read_to_string(&mut received).await.unwrap();
+            let reply = format!("{}bye\n", received.to_uppercase());
+            socket.write_all(reply.as_bytes()).await.unwrap();
+        });
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut output = vec![];
+        pipe(stream, &b"hello\n"[..], &mut output).await.unwrap();
+        server.await.unwrap();
+
+        pretty_assertions::assert_eq!(String::from_utf8(output).unwrap(), "HELLO\nbye\n");
+    }
+}
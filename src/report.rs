@@ -0,0 +1,188 @@
+//! `korasi report --html`: a single, self-contained HTML page of the
+//! instances, their cost and CPU, and the latest runs, to share with
+//! people who don't use the CLI. No scripts or external assets, charts
+//! are inline SVG.
+
+use std::collections::HashMap;
+
+use aws_sdk_ec2::primitives::{DateTime, DateTimeFormat};
+
+use crate::{
+    listing::{fleet_total, Row},
+    progress::format_bytes,
+    state::{Run, Stats},
+};
+
+const STYLE: &str = "body{font-family:system-ui,sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;margin-bottom:2em}\
+th,td{border-bottom:1px solid #ddd;padding:.3em .8em;text-align:left}\
+th{background:#f4f4f4}.fail{color:#b00}.muted{color:#888}\
+svg polyline{fill:none;stroke:#2a6fdb;stroke-width:1.5}";
+
+const CHART_WIDTH: f64 = 240.0;
+const CHART_HEIGHT: f64 = 40.0;
+
+/// Everything shown in a report.
+#[derive(Debug, Default, Clone)]
+pub struct Report {
+    pub region: String,
+    /// Unix time the report was made at.
+    pub generated_at: u64,
+    pub rows: Vec<Row>,
+    /// Hourly average CPU percent by instance id, oldest first.
+    pub cpu: HashMap<String, Vec<f64>>,
+    /// Hours covered by `cpu`.
+    pub hours: u64,
+    pub stats: Stats,
+    pub runs: Vec<Run>,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn timestamp(secs: u64) -> String {
+    DateTime::from_secs(secs as i64)
+        .fmt(DateTimeFormat::DateTime)
+        .unwrap_or_default()
+}
+
+/// Line chart of CPU percentages, on a fixed 0-100% scale so charts of
+/// different instances compare.
+fn sparkline(values: &[f64]) -> String {
+    if values.is_empty() {
+        return "<span class=\"muted\">no data</span>".into();
+    }
+    let step = CHART_WIDTH / (values.len().max(2) - 1) as f64;
+    let points: Vec<String> = values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let y = CHART_HEIGHT - v.clamp(0.0, 100.0) / 100.0 * CHART_HEIGHT;
+            format!("{:.1},{y:.1}", i as f64 * step)
+        })
+        .collect();
+    let peak = values.iter().cloned().fold(0.0, f64::max);
+    format!(
+        "<svg width=\"{CHART_WIDTH}\" height=\"{CHART_HEIGHT}\" viewBox=\"0 0 {CHART_WIDTH} {CHART_HEIGHT}\"><polyline points=\"{}\"/></svg> <span class=\"muted\">peak {peak:.0}%</span>",
+        points.join(" ")
+    )
+}
+
+pub fn render(report: &Report) -> String {
+    let now = report.generated_at;
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>korasi report, {region}</title><style>{STYLE}</style></head><body>\n<h1>korasi report</h1>\n<p class=\"muted\">{region}, generated {}</p>\n",
+        timestamp(now),
+        region = escape(&report.region),
+    );
+
+    html.push_str("<h2>Instances</h2>\n");
+    if report.rows.is_empty() {
+        html.push_str("<p>No instances.</p>\n");
+    } else {
+        html.push_str(&format!(
+            "<table><tr><th>Name</th><th>Id</th><th>Type</th><th>State</th><th>Health</th><th>$/h</th><th>Since launch</th><th>CPU, last {}h</th></tr>\n",
+            report.hours
+        ));
+        for row in &report.rows {
+            let money =
+                |v: Option<f64>, digits: usize| v.map_or("-".into(), |v| format!("${v:.digits$}"));
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                escape(&row.name),
+                escape(&row.instance_id),
+                escape(&row.instance_type),
+                escape(&row.state),
+                escape(&row.health),
+                money(row.hourly, 3),
+                money(row.cost(now), 2),
+                sparkline(report.cpu.get(&row.instance_id).map_or(&[][..], Vec::as_slice)),
+            ));
+        }
+        html.push_str("</table>\n");
+    }
+    if let Some(total) = fleet_total(&report.rows, now) {
+        html.push_str(&format!("<p><strong>{}</strong></p>\n", escape(&total)));
+    }
+
+    html.push_str("<h2>Recent runs</h2>\n");
+    if report.runs.is_empty() {
+        html.push_str("<p>No runs recorded.</p>\n");
+    } else {
+        html.push_str("<table><tr><th>Finished</th><th>Instance</th><th>Program</th><th>Exit code</th></tr>\n");
+        for run in report.runs.iter().rev() {
+            let class = if run.code == 0 { "" } else { " class=\"fail\"" };
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td><code>{}</code></td><td{class}>{}</td></tr>\n",
+                timestamp(run.at),
+                escape(&run.instance),
+                escape(&run.program),
+                run.code
+            ));
+        }
+        html.push_str("</table>\n");
+    }
+    let stats = &report.stats;
+    html.push_str(&format!(
+        "<p class=\"muted\">All time: {} jobs succeeded, {} failed, {} uploaded.</p>\n</body></html>\n",
+        stats.jobs_succeeded,
+        stats.jobs_failed,
+        format_bytes(stats.transfer_bytes)
+    ));
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{render, Report};
+    use crate::{listing::Row, state::Run};
+
+    #[test]
+    fn render_html_report() {
+        let row = Row {
+            instance_id: "i-1".into(),
+            name: "<script>".into(),
+            instance_type: "t3.micro".into(),
+            state: "running".into(),
+            host: String::new(),
+            health: String::new(),
+            hourly: Some(0.0104),
+            launched: Some(0),
+        };
+        let report = Report {
+            region: "ap-southeast-1".into(),
+            generated_at: 7200,
+            rows: vec![row],
+            cpu: HashMap::from([("i-1".to_string(), vec![0.0, 50.0, 100.0])]),
+            hours: 24,
+            runs: vec![Run {
+                at: 3600,
+                instance: "happy:otter".into(),
+                program: "make&".into(),
+                code: 2,
+            }],
+            ..Default::default()
+        };
+        let html = render(&report);
+
+        let cases = [
+            "<td>&lt;script&gt;</td>",
+            "<td>$0.010</td><td>$0.02</td>",
+            "points=\"0.0,40.0 120.0,20.0 240.0,0.0\"",
+            "peak 100%",
+            "<code>make&amp;</code></td><td class=\"fail\">2</td>",
+            "Total: 1 running, $0.010/h, $0.02 since launch",
+        ];
+        for expected in cases {
+            println!("expected = {expected}");
+            assert!(html.contains(expected));
+        }
+        assert!(!html.contains("<script>"));
+    }
+}
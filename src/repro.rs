@@ -0,0 +1,294 @@
+//! Reproducibility bundles: everything needed to reconstruct an experiment
+//! environment months later, in a single `.tar.gz`.
+//!
+//! | File           | Contents                                         |
+//! |----------------|--------------------------------------------------|
+//! | `manifest.toml`| launch spec, git commit and the command to run   |
+//! | `env.json`     | environment report, see [`crate::environment`]   |
+//! | `setup.sh`     | startup script the instance was launched with    |
+//! | `korasi.toml`  | project config (`[verify]` checks), if present   |
+
+use std::{path::Path, process::Command};
+
+use aws_sdk_ec2::types::{Instance, VolumeType};
+
+use crate::{
+    archive::{read_tar_gz, write_tar_gz},
+    config::CONFIG_FILE,
+    create::RootVolume,
+    ec2::EC2Impl as EC2,
+    environment::EnvReport,
+    json::quote,
+    toml::{self, ParseError, Table, Value},
+};
+
+pub const MANIFEST_FILE: &str = "manifest.toml";
+pub const ENV_FILE: &str = "env.json";
+pub const SETUP_FILE: &str = "setup.sh";
+
+/// Where the S3 bootstrap (see `create::bootstrap_user_data`) saves the
+/// real startup script on the instance.
+pub const STAGED_SETUP_PATH: &str = "/var/lib/korasi-user-data.sh";
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LaunchSpec {
+    pub instance_type: String,
+    pub image_id: String,
+    pub region: Option<String>,
+    /// Instance profile ARN.
+    pub iam_profile: Option<String>,
+    pub root_volume_size: Option<i32>,
+    pub root_volume_type: Option<String>,
+    pub root_volume_iops: Option<i32>,
+    pub root_volume_throughput: Option<i32>,
+}
+
+impl LaunchSpec {
+    pub fn from_instance(instance: &Instance, region: Option<String>) -> Self {
+        LaunchSpec {
+            instance_type: instance
+                .instance_type()
+                .map(|t| t.to_string())
+                .unwrap_or_default(),
+            image_id: instance.image_id().unwrap_or_default().into(),
+            region,
+            iam_profile: instance
+                .iam_instance_profile()
+                .and_then(|p| p.arn())
+                .map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    pub fn root_volume(&self) -> RootVolume {
+        RootVolume {
+            size: self.root_volume_size,
+            volume_type: self.root_volume_type.as_deref().map(VolumeType::from),
+            iops: self.root_volume_iops,
+            throughput: self.root_volume_throughput,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Manifest {
+    pub launch: LaunchSpec,
+    pub git_commit: Option<String>,
+    pub git_remote: Option<String>,
+    /// Whether the working tree had uncommitted changes.
+    pub git_dirty: bool,
+    pub command: Option<String>,
+}
+
+impl Manifest {
+    pub fn to_toml(&self) -> String {
+        let l = &self.launch;
+        let int = |v: Option<i32>| v.map(|v| v.to_string());
+        let text = |v: &Option<String>| v.as_deref().map(quote);
+        let sections = [
+            (
+                "launch",
+                vec![
+                    ("instance_type", Some(quote(&l.instance_type))),
+                    ("image_id", Some(quote(&l.image_id))),
+                    ("region", text(&l.region)),
+                    ("iam_profile", text(&l.iam_profile)),
+                    ("root_volume_size", int(l.root_volume_size)),
+                    ("root_volume_type", text(&l.root_volume_type)),
+                    ("root_volume_iops", int(l.root_volume_iops)),
+                    ("root_volume_throughput", int(l.root_volume_throughput)),
+                ],
+            ),
+            (
+                "source",
+                vec![
+                    ("git_commit", text(&self.git_commit)),
+                    ("git_remote", text(&self.git_remote)),
+                    ("git_dirty", Some(self.git_dirty.to_string())),
+                ],
+            ),
+            ("run", vec![("command", text(&self.command))]),
+        ];
+
+        let mut out = String::new();
+        for (name, fields) in sections {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("[{name}]\n"));
+            for (key, value) in fields {
+                if let Some(v) = value {
+                    out.push_str(&format!("{key} = {v}\n"));
+                }
+            }
+        }
+        out
+    }
+
+    pub fn parse(src: &str) -> Result<Manifest, ParseError> {
+        let root = toml::parse(src)?;
+        let empty = Table::default();
+        let table = |key: &str| match root.get(key).map(|i| &i.value) {
+            Some(Value::Table(t)) => t,
+            _ => &empty,
+        };
+        let string = |t: &Table, key: &str| match t.get(key).map(|i| &i.value) {
+            Some(Value::String(s)) => Some(s.clone()),
+            _ => None,
+        };
+        let int = |t: &Table, key: &str| match t.get(key).map(|i| &i.value) {
+            Some(Value::Integer(i)) => i32::try_from(*i).ok(),
+            _ => None,
+        };
+
+        let launch = table("launch");
+        let required = |key: &str| {
+            string(launch, key)
+                .ok_or_else(|| ParseError::new(1, format!("missing `{key}` in [launch]")))
+        };
+        let source = table("source");
+
+        Ok(Manifest {
+            launch: LaunchSpec {
+                instance_type: required("instance_type")?,
+                image_id: required("image_id")?,
+                region: string(launch, "region"),
+                iam_profile: string(launch, "iam_profile"),
+                root_volume_size: int(launch, "root_volume_size"),
+                root_volume_type: string(launch, "root_volume_type"),
+                root_volume_iops: int(launch, "root_volume_iops"),
+                root_volume_throughput: int(launch, "root_volume_throughput"),
+            },
+            git_commit: string(source, "git_commit"),
+            git_remote: string(source, "git_remote"),
+            git_dirty: matches!(
+                source.get("git_dirty").map(|i| &i.value),
+                Some(Value::Boolean(true))
+            ),
+            command: string(table("run"), "command"),
+        })
+    }
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Commit, remote and dirty state of the git repo in the working directory.
+pub fn git_source() -> (Option<String>, Option<String>, bool) {
+    let commit = git(&["rev-parse", "HEAD"]);
+    let remote = git(&["remote", "get-url", "origin"]);
+    let dirty = git(&["status", "--porcelain"]).is_some_and(|s| !s.is_empty());
+    (commit, remote, dirty)
+}
+
+/// Launch spec of a running instance, including its root volume.
+pub async fn launch_spec(
+    ec2: &EC2,
+    instance_id: &str,
+    region: Option<String>,
+) -> anyhow::Result<LaunchSpec> {
+    let instance = ec2.get_instance(instance_id).await?;
+    let mut spec = LaunchSpec::from_instance(&instance, region);
+
+    let root_volume_id = instance
+        .block_device_mappings()
+        .iter()
+        .find(|m| m.device_name() == instance.root_device_name())
+        .and_then(|m| m.ebs())
+        .and_then(|e| e.volume_id());
+    if let Some(volume_id) = root_volume_id {
+        let volume = ec2.describe_volume(volume_id).await?;
+        spec.root_volume_size = volume.size();
+        spec.root_volume_type = volume.volume_type().map(|t| t.to_string());
+        spec.root_volume_iops = volume.iops();
+        spec.root_volume_throughput = volume.throughput();
+    }
+    Ok(spec)
+}
+
+pub struct Bundle {
+    pub manifest: Manifest,
+    pub env: Option<EnvReport>,
+    pub setup: Option<String>,
+    /// Raw `korasi.toml`.
+    pub config: Option<String>,
+}
+
+impl Bundle {
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let mut files = vec![(
+            MANIFEST_FILE.to_string(),
+            self.manifest.to_toml().into_bytes(),
+        )];
+        if let Some(env) = &self.env {
+            files.push((
+                ENV_FILE.into(),
+                (env.to_json().to_pretty() + "\n").into_bytes(),
+            ));
+        }
+        if let Some(setup) = &self.setup {
+            files.push((SETUP_FILE.into(), setup.clone().into_bytes()));
+        }
+        if let Some(config) = &self.config {
+            files.push((CONFIG_FILE.into(), config.clone().into_bytes()));
+        }
+
+        write_tar_gz(std::fs::File::create(path)?, &files)?;
+        Ok(())
+    }
+
+    /// Reads the bundle back. The environment report is informational and
+    /// isn't parsed.
+    pub fn read(path: &Path) -> anyhow::Result<Bundle> {
+        let files = read_tar_gz(std::fs::File::open(path)?)?;
+        let file = |name: &str| {
+            files
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, data)| String::from_utf8_lossy(data).into_owned())
+        };
+
+        let manifest = file(MANIFEST_FILE)
+            .ok_or_else(|| anyhow::anyhow!("{} has no {MANIFEST_FILE}", path.display()))?;
+        Ok(Bundle {
+            manifest: Manifest::parse(&manifest)
+                .map_err(|e| anyhow::anyhow!("{MANIFEST_FILE}: {e}"))?,
+            env: None,
+            setup: file(SETUP_FILE),
+            config: file(CONFIG_FILE),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LaunchSpec, Manifest};
+
+    #[test]
+    fn manifest_round_trip() {
+        let manifest = Manifest {
+            launch: LaunchSpec {
+                instance_type: "g5.xlarge".into(),
+                image_id: "ami-0123456789abcdef0".into(),
+                region: Some("us-east-1".into()),
+                iam_profile: None,
+                root_volume_size: Some(200),
+                root_volume_type: Some("gp3".into()),
+                root_volume_iops: Some(3000),
+                root_volume_throughput: Some(125),
+            },
+            git_commit: Some("4f2a9c1".into()),
+            git_remote: Some("git@github.com:me/exp.git".into()),
+            git_dirty: true,
+            command: Some("python train.py --lr \"1e-3\"".into()),
+        };
+
+        pretty_assertions::assert_eq!(Manifest::parse(&manifest.to_toml()).unwrap(), manifest);
+        assert!(Manifest::parse("[launch]\nimage_id = \"ami-1\"").is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! Minimal S3 client used to stage artifacts that are too large to
+//! pass to EC2 directly (eg. user data over the 16 KB limit).
+//!
+//! Requests are signed with SigV4 using the credentials of the loaded
+//! AWS profile, so only the handful of calls korasi needs are supported.
+
+use aws_sigv4::http_request::{PayloadChecksumKind, SignableBody, SigningSettings};
+use aws_types::SdkConfig as AwsSdkConfig;
+
+use crate::{ec2::EC2Error, sigv4};
+
+#[derive(Clone)]
+pub struct S3Impl {
+    config: AwsSdkConfig,
+
+    /// Bucket where objects are staged.
+    pub bucket: String,
+}
+
+impl S3Impl {
+    pub fn new(config: &AwsSdkConfig, bucket: impl Into<String>) -> Self {
+        S3Impl {
+            config: config.clone(),
+            bucket: bucket.into(),
+        }
+    }
+
+    pub fn region(&self) -> String {
+        sigv4::region(&self.config)
+    }
+
+    /// Virtual-hosted style url of an object. Keys are expected to be url safe.
+    fn object_url(&self, key: &str) -> String {
+        format!(
+            "https://{}.s3.{}.amazonaws.com/{key}",
+            self.bucket,
+            self.region()
+        )
+    }
+
+    async fn sign(
+        &self,
+        method: &str,
+        url: &str,
+        body: SignableBody<'_>,
+        settings: SigningSettings,
+    ) -> Result<(Vec<(String, String)>, Vec<(String, String)>), EC2Error> {
+        sigv4::sign(&self.config, "s3", method, url, body, settings).await
+    }
+
+    pub async fn put_object(&self, key: &str, body: Vec<u8>) -> Result<(), EC2Error> {
+        tracing::info!("Uploading s3://{}/{key}", self.bucket);
+        let url = self.object_url(key);
+
+        let mut settings = SigningSettings::default();
+        settings.payload_checksum_kind = PayloadChecksumKind::XAmzSha256;
+        let (headers, _) = self
+            .sign("PUT", &url, SignableBody::Bytes(&body), settings)
+            .await?;
+
+        let mut req = reqwest::Client::new().put(&url).body(body);
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+        req.send()
+            .await
+            .map_err(|e| EC2Error::new(format!("Could not upload to {url}: {e:?}")))?
+            .error_for_status()
+            .map_err(|e| EC2Error::new(format!("Failure status from {url}: {e:?}")))?;
+        Ok(())
+    }
+
+    pub async fn delete_object(&self, key: &str) -> Result<(), EC2Error> {
+        tracing::info!("Deleting s3://{}/{key}", self.bucket);
+        let url = self.object_url(key);
+
+        let mut settings = SigningSettings::default();
+        settings.payload_checksum_kind = PayloadChecksumKind::XAmzSha256;
+        let (headers, _) = self
+            .sign("DELETE", &url, SignableBody::Bytes(&[]), settings)
+            .await?;
+
+        let mut req = reqwest::Client::new().delete(&url);
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+        req.send()
+            .await
+            .map_err(|e| EC2Error::new(format!("Could not delete {url}: {e:?}")))?
+            .error_for_status()
+            .map_err(|e| EC2Error::new(format!("Failure status from {url}: {e:?}")))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,308 @@
+//! Minimal EventBridge Scheduler client, for `korasi schedule`. Schedules
+//! call `ec2:StartInstances`/`ec2:StopInstances` directly (a universal
+//! target), so nothing has to run for them to fire. Signed like
+//! [`crate::s3`].
+
+use aws_sigv4::http_request::{SignableBody, SigningSettings};
+use aws_types::SdkConfig as AwsSdkConfig;
+use clap::ValueEnum;
+
+use crate::{
+    ec2::{EC2Error, TagSelector},
+    json::{string_fields, Value},
+    sigv4,
+};
+
+/// Every schedule made by korasi is named with this prefix, to list them
+/// apart from others in the default group.
+pub const SCHEDULE_PREFIX: &str = "korasi-";
+
+/// Schedule names are at most 64 characters.
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Power {
+    Start,
+    Stop,
+}
+
+impl Power {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Power::Start => "start",
+            Power::Stop => "stop",
+        }
+    }
+
+    fn target_arn(&self) -> &'static str {
+        match self {
+            Power::Start => "arn:aws:scheduler:::aws-sdk:ec2:startInstances",
+            Power::Stop => "arn:aws:scheduler:::aws-sdk:ec2:stopInstances",
+        }
+    }
+}
+
+/// A schedule to create.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub name: String,
+    pub power: Power,
+    /// The 6 fields of a scheduler cron expression, eg. `0 19 ? * MON-FRI *`.
+    pub cron: String,
+    pub timezone: Option<String>,
+    /// Role assumed by the scheduler, allowed to start/stop instances.
+    pub role_arn: String,
+    pub instance_ids: Vec<String>,
+}
+
+/// A korasi schedule as returned by `GetSchedule`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleInfo {
+    pub name: String,
+    pub expression: String,
+    pub description: String,
+    pub state: String,
+}
+
+/// Check a cron expression has the 6 fields the scheduler expects (minutes,
+/// hours, day of month, month, day of week, year).
+pub fn parse_cron(s: &str) -> Result<String, String> {
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    if fields.len() != 6 {
+        return Err(format!(
+            "expected 6 cron fields (minutes hours day-of-month month day-of-week year), eg. `0 19 ? * MON-FRI *`, got {}",
+            fields.len()
+        ));
+    }
+    Ok(fields.join(" "))
+}
+
+/// Default name of a schedule, from what it does and to which instances.
+pub fn schedule_name(power: Power, tags: &[TagSelector]) -> String {
+    let mut name = power.as_str().to_string();
+    for tag in tags {
+        name.push('-');
+        name.push_str(&tag.to_string());
+    }
+    custom_schedule_name(&name)
+}
+
+/// `name` made a valid korasi schedule name: prefixed with
+/// `SCHEDULE_PREFIX`, with the characters schedule names can't have
+/// replaced and cut to their length limit, as it goes in the URL path.
+pub fn custom_schedule_name(name: &str) -> String {
+    let name = if name.starts_with(SCHEDULE_PREFIX) {
+        name.to_string()
+    } else {
+        format!("{SCHEDULE_PREFIX}{name}")
+    };
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '-'
+            }
+        })
+        .take(MAX_NAME_LEN)
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct SchedulerImpl {
+    config: AwsSdkConfig,
+}
+
+impl SchedulerImpl {
+    pub fn new(config: &AwsSdkConfig) -> Self {
+        SchedulerImpl {
+            config: config.clone(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!(
+            "https://scheduler.{}.amazonaws.com{path}",
+            sigv4::region(&self.config)
+        )
+    }
+
+    async fn call(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<String>,
+    ) -> Result<String, EC2Error> {
+        let bytes = body.clone().unwrap_or_default().into_bytes();
+        let (headers, _) = sigv4::sign(
+            &self.config,
+            "scheduler",
+            method,
+            url,
+            SignableBody::Bytes(&bytes),
+            SigningSettings::default(),
+        )
+        .await?;
+        let method = reqwest::Method::from_bytes(method.as_bytes())
+            .map_err(|e| EC2Error::new(format!("Invalid method {method}: {e}")))?;
+        let mut req = reqwest::Client::new().request(method, url);
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+        if let Some(body) = body {
+            req = req.header("content-type", "application/json").body(body);
+        }
+        let res = req
+            .send()
+            .await
+            .map_err(|e| EC2Error::new(format!("Could not reach EventBridge Scheduler: {e:?}")))?;
+        let status = res.status();
+        let text = res
+            .text()
+            .await
+            .map_err(|e| EC2Error::new(format!("Could not read scheduler response: {e:?}")))?;
+        if !status.is_success() {
+            let message = string_fields(&text, "Message")
+                .into_iter()
+                .next()
+                .unwrap_or(text);
+            return Err(EC2Error::new(format!(
+                "EventBridge Scheduler returned {status}: {message}"
+            )));
+        }
+        Ok(text)
+    }
+
+    /// Create `schedule`, or update the one of the same name. Returns
+    /// whether it already existed.
+    pub async fn put_schedule(&self, schedule: &Schedule) -> Result<bool, EC2Error> {
+        let input = Value::Object(vec![(
+            "InstanceIds".into(),
+            Value::Array(
+                schedule
+                    .instance_ids
+                    .iter()
+                    .map(|id| id.as_str().into())
+                    .collect(),
+            ),
+        )]);
+        let mut fields = vec![
+            (
+                "ScheduleExpression".into(),
+                format!("cron({})", schedule.cron).into(),
+            ),
+            (
+                "Description".into(),
+                format!(
+                    "{} {}",
+                    schedule.power.as_str(),
+                    schedule.instance_ids.join(",")
+                )
+                .into(),
+            ),
+            (
+                "FlexibleTimeWindow".into(),
+                Value::Object(vec![("Mode".into(), "OFF".into())]),
+            ),
+            (
+                "Target".into(),
+                Value::Object(vec![
+                    ("Arn".into(), schedule.power.target_arn().into()),
+                    ("RoleArn".into(), schedule.role_arn.as_str().into()),
+                    ("Input".into(), input.to_string().into()),
+                ]),
+            ),
+        ];
+        if let Some(tz) = &schedule.timezone {
+            fields.push(("ScheduleExpressionTimezone".into(), tz.as_str().into()));
+        }
+        let exists = self.list_schedules().await?.contains(&schedule.name);
+        // CreateSchedule fails with a conflict on an existing name.
+        let method = if exists { "PUT" } else { "POST" };
+        let url = self.url(&format!("/schedules/{}", schedule.name));
+        self.call(method, &url, Some(Value::Object(fields).to_string()))
+            .await?;
+        Ok(exists)
+    }
+
+    /// Names of the korasi schedules.
+    pub async fn list_schedules(&self) -> Result<Vec<String>, EC2Error> {
+        let mut names = vec![];
+        let mut next_token: Option<String> = None;
+        loop {
+            let mut url = reqwest::Url::parse(&self.url("/schedules"))
+                .map_err(|e| EC2Error::new(format!("Invalid scheduler url: {e}")))?;
+            url.query_pairs_mut()
+                .append_pair("NamePrefix", SCHEDULE_PREFIX);
+            if let Some(token) = &next_token {
+                url.query_pairs_mut().append_pair("NextToken", token);
+            }
+            let body = self.call("GET", url.as_str(), None).await?;
+            // `Name` only appears at the top level of schedule summaries.
+            names.extend(string_fields(&body, "Name"));
+            next_token = string_fields(&body, "NextToken").into_iter().next();
+            if next_token.is_none() {
+                return Ok(names);
+            }
+        }
+    }
+
+    pub async fn get_schedule(&self, name: &str) -> Result<ScheduleInfo, EC2Error> {
+        let body = self
+            .call("GET", &self.url(&format!("/schedules/{name}")), None)
+            .await?;
+        let field = |key: &str| {
+            string_fields(&body, key)
+                .into_iter()
+                .next()
+                .unwrap_or_default()
+        };
+        Ok(ScheduleInfo {
+            name: name.into(),
+            expression: field("ScheduleExpression"),
+            description: field("Description"),
+            state: field("State"),
+        })
+    }
+
+    pub async fn delete_schedule(&self, name: &str) -> Result<(), EC2Error> {
+        self.call("DELETE", &self.url(&format!("/schedules/{name}")), None)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{custom_schedule_name, parse_cron, schedule_name, Power};
+
+    #[test]
+    fn schedule_requests() {
+        let cases = [
+            ("0 19 ? * MON-FRI *", true),
+            (" 0  7 ? *  MON-FRI * ", true),
+            ("0 19 * * *", false),
+        ];
+        for (cron, ok) in cases {
+            println!("cron = {cron:?}");
+            pretty_assertions::assert_eq!(parse_cron(cron).is_ok(), ok);
+        }
+
+        pretty_assertions::assert_eq!(
+            schedule_name(Power::Stop, &["team=ml infra".parse().unwrap()]),
+            "korasi-stop-team-ml-infra"
+        );
+        pretty_assertions::assert_eq!(schedule_name(Power::Start, &[]), "korasi-start");
+
+        let cases = [
+            ("nightly", "korasi-nightly".to_string()),
+            ("korasi-nightly", "korasi-nightly".into()),
+            ("../stop?all", "korasi-..-stop-all".into()),
+            (&"a".repeat(80), format!("korasi-{}", "a".repeat(57))),
+        ];
+        for (name, expected) in cases {
+            println!("name = {name:?}");
+            pretty_assertions::assert_eq!(custom_schedule_name(name), expected);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Startup scripts bundled with the crate.
+//!
+//! Selected with `--setup preset:<name>` so a first launch doesn't need a
+//! hand written `start_up.sh`.
+
+use std::collections::HashMap;
+
+use crate::{ec2::EC2Error, template::render};
+
+pub const PRESET_PREFIX: &str = "preset:";
+
+const COMMON: &str = include_str!("../scripts/common.sh");
+
+pub const PRESETS: &[(&str, &str)] = &[
+    ("minimal", include_str!("../scripts/minimal.sh")),
+    ("rust-dev", include_str!("../scripts/rust-dev.sh")),
+    ("python-ml", include_str!("../scripts/python-ml.sh")),
+    ("cuda", include_str!("../scripts/cuda.sh")),
+    ("docker", include_str!("../scripts/docker.sh")),
+];
+
+pub fn preset_names() -> Vec<&'static str> {
+    PRESETS.iter().map(|(name, _)| *name).collect()
+}
+
+/// Render the bundled preset `name` with `vars`.
+pub fn render_preset(name: &str, vars: &HashMap<&str, String>) -> Result<String, EC2Error> {
+    let (_, preset) = PRESETS.iter().find(|(n, _)| *n == name).ok_or_else(|| {
+        EC2Error::new(format!(
+            "Unknown preset `{name}`, expected one of: {}",
+            preset_names().join(", ")
+        ))
+    })?;
+
+    let mut vars = vars.clone();
+    vars.insert("common", render(COMMON, &vars)?);
+    render(preset, &vars)
+}
+
+/// Resolve a `--setup` value into the script contents.
+///
+/// `preset:<name>` selects a bundled script, anything else is read as a
+/// local file path. A missing local file yields `None`, ie. no user data.
+pub fn load_setup(setup: &str, vars: &HashMap<&str, String>) -> Result<Option<String>, EC2Error> {
+    match setup.strip_prefix(PRESET_PREFIX) {
+        Some(name) => render_preset(name, vars).map(Some),
+        None => Ok(std::fs::read_to_string(setup).ok()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{render_preset, PRESETS};
+
+    #[test]
+    fn render_all_presets() {
+        let vars = HashMap::from([
+            ("instance_name", "foo".to_string()),
+            ("instance_type", "t2.micro".to_string()),
+        ]);
+
+        for (name, _) in PRESETS {
+            let script = render_preset(name, &vars).unwrap();
+            assert!(script.starts_with("#!/bin/bash\n"), "{name} has no shebang");
+            assert!(script.contains("pkg_install()"), "{name} has no helpers");
+            assert!(!script.contains("{{"), "{name} is not fully rendered");
+        }
+        assert!(render_preset("unknown", &vars).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Detailed view of a single instance, for debugging what `List` is too
+//! terse to show.
+
+use std::time::Duration;
+
+use aws_sdk_ec2::types::{Instance, InstanceStateName, Volume};
+
+use crate::{
+    create::AUTO_STOP_TAG,
+    ec2::{EC2Error, EC2Impl as EC2, InstanceHealth},
+    progress::format_duration,
+};
+
+/// `(label, value)` rows describing the instance. Uptime is relative to
+/// `now` (unix seconds).
+pub fn instance_details(
+    instance: &Instance,
+    health: Option<&InstanceHealth>,
+    volumes: &[Volume],
+    now: i64,
+) -> Vec<(&'static str, String)> {
+    let or_none = |v: Option<&str>| v.filter(|s| !s.is_empty()).unwrap_or("-").to_string();
+    let state = instance.state().and_then(|s| s.name());
+
+    let mut rows = vec![
+        (
+            "Name",
+            or_none(
+                instance
+                    .tags()
+                    .iter()
+                    .find(|t| t.key() == Some("Name"))
+                    .and_then(|t| t.value()),
+            ),
+        ),
+        ("Instance id", or_none(instance.instance_id())),
+        (
+            "Type",
+            or_none(instance.instance_type().map(|t| t.as_str())),
+        ),
+        ("State", or_none(state.map(|s| s.as_str()))),
+        ("AMI", or_none(instance.image_id())),
+        ("Key name", or_none(instance.key_name())),
+        (
+            "Security groups",
+            instance
+                .security_groups()
+                .iter()
+                .map(|g| {
+                    format!(
+                        "{} ({})",
+                        g.group_name().unwrap_or("-"),
+                        g.group_id().unwrap_or("-")
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        ("Public IP", or_none(instance.public_ip_address())),
+        ("Public DNS", or_none(instance.public_dns_name())),
+        ("Private IP", or_none(instance.private_ip_address())),
+        ("Subnet", or_none(instance.subnet_id())),
+        ("VPC", or_none(instance.vpc_id())),
+        (
+            "Availability zone",
+            or_none(instance.placement().and_then(|p| p.availability_zone())),
+        ),
+        (
+            "Auto-stop",
+            instance
+                .tags()
+                .iter()
+                .find(|t| t.key() == Some(AUTO_STOP_TAG))
+                .and_then(|t| t.value())
+                .map_or("-".into(), |idle| format!("after {idle} idle")),
+        ),
+    ];
+
+    let launched = instance.launch_time().map(|t| t.secs());
+    rows.push((
+        "Launch time",
+        or_none(instance.launch_time().map(|t| t.to_string()).as_deref()),
+    ));
+    let uptime = match (state, launched) {
+        (Some(InstanceStateName::Running), Some(t)) => {
+            format_duration(Duration::from_secs((now - t).max(0) as u64))
+        }
+        _ => "-".into(),
+    };
+    rows.push(("Uptime", uptime));
+
+    let volume_rows = instance.block_device_mappings().iter().map(|m| {
+        let device = m.device_name().unwrap_or("-");
+        let volume_id = m.ebs().and_then(|e| e.volume_id()).unwrap_or("-");
+        let volume = volumes.iter().find(|v| v.volume_id() == Some(volume_id));
+        match volume {
+            Some(v) => format!(
+                "{device} {volume_id} ({} GiB {})",
+                v.size().unwrap_or_default(),
+                v.volume_type().map_or("-", |t| t.as_str())
+            ),
+            None => format!("{device} {volume_id}"),
+        }
+    });
+    rows.push(("Volumes", volume_rows.collect::<Vec<_>>().join(", ")));
+    rows.push((
+        "Status checks",
+        health.map_or("-".into(), |h| h.to_string()),
+    ));
+    rows
+}
+
+/// Print the details of an instance, looking up its volumes and status
+/// checks.
+pub async fn show(ec2: &EC2, instance_id: &str) -> Result<(), EC2Error> {
+    let (inventory, health) = tokio::try_join!(
+        ec2.inventory(),
+        ec2.describe_instance_health(vec![instance_id.to_string()]),
+    )?;
+    let instance = match inventory
+        .instances
+        .iter()
+        .find(|i| i.instance_id() == Some(instance_id))
+    {
+        Some(instance) => instance.clone(),
+        None => ec2.get_instance(instance_id).await?,
+    };
+
+    let mut volumes = vec![];
+    for volume_id in instance
+        .block_device_mappings()
+        .iter()
+        .filter_map(|m| m.ebs().and_then(|e| e.volume_id()))
+    {
+        match inventory
+            .volumes
+            .iter()
+            .find(|v| v.volume_id() == Some(volume_id))
+        {
+            Some(volume) => volumes.push(volume.clone()),
+            None => volumes.push(ec2.describe_volume(volume_id).await?),
+        }
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let rows = instance_details(&instance, health.get(instance_id), &volumes, now);
+    let width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    for (label, value) in rows {
+        println!("{label:<width$}  {value}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_ec2::{
+        primitives::DateTime,
+        types::{
+            EbsInstanceBlockDevice, GroupIdentifier, Instance, InstanceBlockDeviceMapping,
+            InstanceState, InstanceStateName, InstanceType, SummaryStatus, Tag, Volume, VolumeType,
+        },
+    };
+
+    use super::instance_details;
+    use crate::ec2::InstanceHealth;
+
+    #[test]
+    fn describe_instance_details() {
+        let instance = Instance::builder()
+            .instance_id("i-0123")
+            .instance_type(InstanceType::T3Micro)
+            .state(
+                InstanceState::builder()
+                    .name(InstanceStateName::Running)
+                    .build(),
+            )
+            .image_id("ami-0abc")
+            .key_name("ec2-ssh-key")
+            .security_groups(
+                GroupIdentifier::builder()
+                    .group_name("allow-ssh")
+                    .group_id("sg-1")
+                    .build(),
+            )
+            .public_ip_address("203.0.113.7")
+            .private_ip_address("10.0.0.5")
+            .subnet_id("subnet-1")
+            .launch_time(DateTime::from_secs(1_700_000_000))
+            .tags(Tag::builder().key("Name").value("dev").build())
+            .block_device_mappings(
+                InstanceBlockDeviceMapping::builder()
+                    .device_name("/dev/sda1")
+                    .ebs(EbsInstanceBlockDevice::builder().volume_id("vol-1").build())
+                    .build(),
+            )
+            .build();
+        let volume = Volume::builder()
+            .volume_id("vol-1")
+            .size(30)
+            .volume_type(VolumeType::Gp3)
+            .build();
+        let health = InstanceHealth {
+            system_status: Some(SummaryStatus::Ok),
+            instance_status: Some(SummaryStatus::Ok),
+            events: vec![],
+        };
+
+        let rows = instance_details(&instance, Some(&health), &[volume], 1_700_005_400);
+        let get = |label| rows.iter().find(|(l, _)| *l == label).unwrap().1.as_str();
+        let cases = [
+            ("Name", "dev"),
+            ("Type", "t3.micro"),
+            ("Security groups", "allow-ssh (sg-1)"),
+            ("Public DNS", "-"),
+            ("Uptime", "1h 30m"),
+            ("Volumes", "/dev/sda1 vol-1 (30 GiB gp3)"),
+            ("Status checks", "ok"),
+            ("Auto-stop", "-"),
+        ];
+
+        for (label, expected) in cases {
+            println!("label = {label}");
+            pretty_assertions::assert_eq!(get(label), expected);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! SigV4 signing for the AWS APIs korasi calls without an SDK client
+//! (eg. [`crate::s3`], [`crate::cloudwatch`] and [`crate::iam`]).
+
+use std::time::SystemTime;
+
+use aws_credential_types::provider::ProvideCredentials;
+use aws_sigv4::{
+    http_request::{sign as sign_request, SignableBody, SignableRequest, SigningSettings},
+    sign::v4,
+};
+use aws_types::SdkConfig as AwsSdkConfig;
+
+use crate::ec2::EC2Error;
+
+/// Region of the loaded config, defaulting to us-east-1.
+pub fn region(config: &AwsSdkConfig) -> String {
+    config
+        .region()
+        .map(|r| r.to_string())
+        .unwrap_or("us-east-1".into())
+}
+
+/// Signs the request for `service` with the credentials of `config`, and
+/// returns the headers and query params to apply.
+pub async fn sign(
+    config: &AwsSdkConfig,
+    service: &str,
+    method: &str,
+    url: &str,
+    body: SignableBody<'_>,
+    settings: SigningSettings,
+) -> Result<(Vec<(String, String)>, Vec<(String, String)>), EC2Error> {
+    let credentials = config
+        .credentials_provider()
+        .ok_or_else(|| EC2Error::new(format!("No credentials provider configured for {service}")))?
+        .provide_credentials()
+        .await
+        .map_err(|e| EC2Error::new(format!("Failed to load credentials: {e}")))?;
+    let identity = credentials.into();
+    let region = region(config);
+    let params = v4::SigningParams::builder()
+        .identity(&identity)
+        .region(&region)
+        .name(service)
+        .time(SystemTime::now())
+        .settings(settings)
+        .build()
+        .map_err(|e| EC2Error::new(format!("Invalid signing params: {e}")))?
+        .into();
+    let request = SignableRequest::new(method, url, std::iter::empty(), body)
+        .map_err(|e| EC2Error::new(format!("Failed to sign {url}: {e}")))?;
+    let (instructions, _) = sign_request(request, &params)
+        .map_err(|e| EC2Error::new(format!("Failed to sign {url}: {e}")))?
+        .into_parts();
+
+    let headers = instructions
+        .headers()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let query = instructions
+        .params()
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    Ok((headers, query))
+}
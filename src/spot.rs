@@ -0,0 +1,107 @@
+//! Watch spot instances for interruption notices.
+//!
+//! EC2 marks the spot request about two minutes before reclaiming the
+//! instance (`marked-for-termination`, `marked-for-stop` or
+//! `marked-for-hibernation`), which is the last chance to checkpoint.
+
+use std::time::Duration;
+
+use crate::{
+    ec2::EC2Impl as EC2,
+    ssh::{ConnectOptions, Session},
+};
+
+/// Notices come two minutes ahead, polling often leaves most of them for
+/// the hook.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What happens to the instance, if `code` (a spot request status code) is
+/// an interruption notice.
+pub fn interruption(code: &str) -> Option<&'static str> {
+    match code {
+        "marked-for-termination" => Some("terminated"),
+        "marked-for-stop" => Some("stopped"),
+        "marked-for-hibernation" => Some("hibernated"),
+        _ => None,
+    }
+}
+
+/// Remote command run on an interruption notice.
+pub struct Hook<'a> {
+    pub command: &'a str,
+    pub user: &'a str,
+    pub ssh_key: &'a str,
+    pub connect: &'a ConnectOptions,
+}
+
+/// Poll the spot request of `instance_id` until it is interrupted, then
+/// warn and run `hook`, if any.
+pub async fn watch(ec2: &EC2, instance_id: &str, hook: Option<Hook<'_>>) -> anyhow::Result<()> {
+    let instance = ec2.get_instance(instance_id).await?;
+    let Some(request_id) = instance.spot_instance_request_id() else {
+        anyhow::bail!("{instance_id} is not a spot instance.");
+    };
+    let host = instance.public_dns_name().unwrap_or_default().to_string();
+    println!("Watching {instance_id} ({request_id}) for interruption notices...");
+
+    let mut last_code = String::new();
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        poll.tick().await;
+        let status = match ec2.describe_spot_request_status(request_id).await {
+            Ok(status) => status,
+            // A throttled poll shouldn't end the watch.
+            Err(err) if err.is_retryable() => {
+                tracing::debug!("Polling {request_id} failed: {err}");
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let Some((code, message)) = status else {
+            anyhow::bail!("Spot request {request_id} has no status.");
+        };
+        if code != last_code {
+            tracing::info!("Spot request {request_id}: {code} ({message})");
+            last_code = code.clone();
+        }
+        if let Some(outcome) = interruption(&code) {
+            eprintln!(
+                "\x07WARNING: {instance_id} will be {outcome} in about two minutes. {message}"
+            );
+            if let Some(hook) = hook {
+                println!("Running `{}` on {instance_id}...", hook.command);
+                let mut session =
+                    Session::connect(hook.user, host, hook.ssh_key.into(), hook.connect).await?;
+                let code = session
+                    .exec_prefixed(hook.command, instance_id, None)
+                    .await?;
+                session.close().await?;
+                if code != 0 {
+                    anyhow::bail!("Interruption hook exited with code {code}.");
+                }
+            }
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::interruption;
+
+    #[test]
+    fn classify_spot_status() {
+        let cases = [
+            ("fulfilled", None),
+            ("marked-for-termination", Some("terminated")),
+            ("marked-for-stop", Some("stopped")),
+            ("marked-for-hibernation", Some("hibernated")),
+            ("instance-terminated-by-price", None),
+        ];
+
+        for (code, expected) in cases {
+            println!("code = {code}");
+            pretty_assertions::assert_eq!(interruption(code), expected);
+        }
+    }
+}
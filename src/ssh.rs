@@ -1,19 +1,233 @@
-use std::{fs::File, io::Read, path::Path, sync::Arc};
+use std::{
+    fs::File,
+    future::Future,
+    io::{Read, Write},
+    os::{fd::AsFd, unix::fs::FileTypeExt},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
+use anyhow::Context;
 use async_trait::async_trait;
+use inquire::{Confirm, Password, PasswordDisplayMode};
 use russh::{
     client::{self, Msg},
-    keys::{decode_secret_key, PrivateKey, PublicKey},
-    Channel, ChannelId, ChannelMsg, Disconnect,
+    keys::{
+        agent::client::AgentClient, decode_secret_key, Algorithm, Certificate, HashAlg, PrivateKey,
+        PublicKey,
+    },
+    Channel, ChannelId, ChannelMsg, Disconnect, Pty, Sig,
 };
 use russh_sftp::{client::SftpSession, protocol::OpenFlags};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    signal::unix::{signal, SignalKind},
+};
 
-use crate::util::{biject_paths, calc_prefix};
+use crate::{
+    events::EventWriter,
+    keychain,
+    progress::Progress,
+    state::{bastion_profile_id, Profile, Profiles, DIRECT},
+    stream::OutputGuard,
+    terminal::local_modes,
+    util::{biject_paths, calc_prefix},
+    websocket,
+};
 
 pub const SSH_PORT: u16 = 22;
 
-pub struct ClientSSH;
+/// Passphrases entered this run by key path, so parallel sessions ask once.
+static PASSPHRASES: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// How to reach instances over SSH, from the `[ssh]` table of korasi.toml
+/// and the connection flags.
+#[derive(Debug, Default, Clone)]
+pub struct ConnectOptions {
+    /// Port sshd listens on when not `SSH_PORT`, for AMIs whose sshd
+    /// listens elsewhere.
+    pub port: Option<u16>,
+    /// Bastion to jump through like `ssh -J`, as `[user@]host[:port]`, so
+    /// instances need no public address or open port 22.
+    pub bastion: Option<String>,
+    /// WebSocket relay to connect through, see `websocket`.
+    pub relay: Option<String>,
+    /// User certificate to present, rather than `<key>-cert.pub`.
+    pub certificate: Option<PathBuf>,
+}
+
+impl ConnectOptions {
+    /// Port sshd listens on.
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(SSH_PORT)
+    }
+}
+
+/// User, host and port of the bastion `spec`, defaulting to `user` and
+/// `SSH_PORT`.
+pub fn parse_jump(spec: &str, user: &str) -> anyhow::Result<(String, String, u16)> {
+    let (jump_user, rest) = spec.split_once('@').unwrap_or((user, spec));
+    let (host, port) = match rest.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .with_context(|| format!("Invalid port in bastion {spec}"))?,
+        ),
+        None => (rest, SSH_PORT),
+    };
+    if jump_user.is_empty() || host.is_empty() {
+        anyhow::bail!("Bastion {spec} should look like [user@]host[:port]");
+    }
+    Ok((jump_user.into(), host.into(), port))
+}
+
+/// The OpenSSH public key at `path`, re-encoded after parsing it.
+pub fn read_public_key(path: &Path) -> anyhow::Result<String> {
+    let key = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let key = PublicKey::from_openssh(key.trim())
+        .with_context(|| format!("{} is not an OpenSSH public key", path.display()))?;
+    Ok(key.to_openssh()?)
+}
+
+/// User certificate to present with `ssh_key`: `path` (from korasi.toml),
+/// else `<key>-cert.pub` if there is one.
+fn certificate(ssh_key: &str, path: Option<&Path>) -> anyhow::Result<Option<Certificate>> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let path = PathBuf::from(format!("{ssh_key}-cert.pub"));
+            if !path.exists() {
+                return Ok(None);
+            }
+            path
+        }
+    };
+    let cert = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let cert = Certificate::from_openssh(cert.trim())
+        .with_context(|| format!("{} is not an OpenSSH certificate", path.display()))?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    if cert.valid_before() < now {
+        tracing::warn!("Certificate {} has expired", path.display());
+    }
+    Ok(Some(cert))
+}
+
+/// Whether `key` is held by a FIDO2 security key, so signing with it takes
+/// the hardware (through `ssh-agent`) rather than a private key file.
+pub fn is_security_key(key: &PublicKey) -> bool {
+    matches!(
+        key.algorithm(),
+        Algorithm::SkEd25519 | Algorithm::SkEcdsaSha2NistP256
+    )
+}
+
+/// Check that `host:port` takes connections, through `relay` if any.
+async fn probe(host: &str, port: u16, relay: Option<&str>) -> anyhow::Result<()> {
+    match relay {
+        Some(relay) => websocket::connect(relay, host, port).await.map(drop),
+        None => Ok(tokio::net::TcpStream::connect((host, port))
+            .await
+            .map(drop)?),
+    }
+}
+
+/// How long to keep reading output once a command reported its exit status.
+const EXIT_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// How long `Session::probe_stdout` waits for its output.
+const STDOUT_PROBE_GRACE: std::time::Duration = std::time::Duration::from_secs(3);
+const STDOUT_PROBE: &str = "korasi-stdout-probe";
+
+/// Files are written in chunks so progress can be reported mid-file.
+const UPLOAD_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Tunnel given as `port`, `local_port:remote_port` or
+/// `local_port:remote_host:remote_port`, like `ssh -L`. The remote host is
+/// resolved on the instance and defaults to `localhost`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardSpec {
+    pub local_port: u16,
+    pub remote_host: String,
+    pub remote_port: u16,
+}
+
+impl std::str::FromStr for ForwardSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let port = |p: &str| match p.parse::<u16>() {
+            Ok(p) if p > 0 => Ok(p),
+            _ => Err(format!("invalid port `{p}`")),
+        };
+        let parts: Vec<&str> = s.split(':').collect();
+        let (local, host, remote) = match parts[..] {
+            [port] => (port, "localhost", port),
+            [local, remote] => (local, "localhost", remote),
+            [local, host, remote] if !host.is_empty() => (local, host, remote),
+            _ => {
+                return Err(format!(
+                    "invalid forward `{s}`, expected [local:][host:]port"
+                ))
+            }
+        };
+        Ok(ForwardSpec {
+            local_port: port(local)?,
+            remote_host: host.into(),
+            remote_port: port(remote)?,
+        })
+    }
+}
+
+/// Buffers partial lines of a remote stream and prepends `prefix` to every
+/// complete line, so output of concurrent sessions can be told apart.
+pub struct LinePrefixer {
+    prefix: String,
+    buf: Vec<u8>,
+}
+
+impl LinePrefixer {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            buf: vec![],
+        }
+    }
+
+    /// Returns the prefixed complete lines found so far.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<u8> {
+        self.buf.extend_from_slice(data);
+        let mut out = vec![];
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            out.extend_from_slice(self.prefix.as_bytes());
+            out.extend(self.buf.drain(..=pos));
+        }
+        out
+    }
+
+    /// Flushes a trailing line that is not newline terminated.
+    pub fn finish(&mut self) -> Vec<u8> {
+        if self.buf.is_empty() {
+            return vec![];
+        }
+        let mut out = self.prefix.as_bytes().to_vec();
+        out.append(&mut self.buf);
+        out.push(b'\n');
+        out
+    }
+}
+
+#[derive(Default)]
+pub struct ClientSSH {
+    /// Profile pinning the host key the server must present, when known.
+    pinned: Option<Profile>,
+    /// Host key the server presented.
+    host_key: Arc<Mutex<Option<PublicKey>>>,
+}
 
 #[async_trait]
 impl client::Handler for ClientSSH {
@@ -24,6 +238,21 @@ impl client::Handler for ClientSSH {
         server_public_key: &PublicKey,
     ) -> Result<bool, Self::Error> {
         tracing::debug!("check_server_key: {:?}", server_public_key);
+        let fingerprint = server_public_key.fingerprint(HashAlg::Sha256).to_string();
+        if let Some(Profile {
+            instance_id,
+            host_key,
+            ..
+        }) = &self.pinned
+        {
+            if *host_key != fingerprint {
+                anyhow::bail!(
+                    "Host key of {instance_id} changed from {host_key} to {fingerprint}. If it \
+                     was rebuilt, forget its profile with `korasi profile forget {instance_id}`."
+                );
+            }
+        }
+        *self.host_key.lock().unwrap_or_else(|e| e.into_inner()) = Some(server_public_key.clone());
         Ok(true)
     }
 
@@ -40,6 +269,365 @@ impl client::Handler for ClientSSH {
 
 pub struct Session {
     session: client::Handle<ClientSSH>,
+    /// Session with the bastion the connection goes through, kept open
+    /// for as long as this one.
+    bastion: Option<client::Handle<ClientSSH>>,
+    /// The bastion's host key.
+    bastion_host_key: Option<PublicKey>,
+    /// The server's host key.
+    host_key: Option<PublicKey>,
+    /// Local terminal modes, captured before the terminal goes raw.
+    terminal_modes: Vec<(Pty, u32)>,
+    /// Strip ANSI escapes from output, for output that isn't shown on a
+    /// terminal (eg. redirected to a log).
+    strip_ansi: bool,
+}
+
+/// Type of the file local stdin is, if it can be told.
+fn stdin_type() -> Option<std::fs::FileType> {
+    let fd = std::io::stdin().as_fd().try_clone_to_owned().ok()?;
+    File::from(fd).metadata().ok().map(|m| m.file_type())
+}
+
+/// Whether stdin is piped or redirected from a file, rather than a
+/// terminal or nothing at all.
+pub fn stdin_is_piped() -> bool {
+    stdin_type().is_some_and(|t| t.is_fifo() || t.is_file())
+}
+
+/// Local stdin to forward to a remote command.
+///
+/// Regular files can't be polled, so only they are read on a blocking
+/// thread by `tokio::io::stdin`; a read there always returns, unlike one
+/// on a pipe, which would keep the runtime from shutting down after the
+/// channel closes.
+fn local_stdin() -> anyhow::Result<Box<dyn AsyncRead + Unpin + Send>> {
+    if stdin_type().is_some_and(|t| t.is_file()) {
+        Ok(Box::new(tokio::io::stdin()))
+    } else {
+        Ok(Box::new(tokio_fd::AsyncFd::try_from(0)?))
+    }
+}
+
+/// Which output of a remote command a chunk comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Takes the output of a remote command from `read_channel`, chunk by
+/// chunk as it arrives.
+trait OutputSink: Send {
+    fn write(
+        &mut self,
+        stream: Stream,
+        data: &[u8],
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// Prints the output of a remote command locally.
+struct Printer<'a> {
+    stdout: Box<dyn AsyncWrite + Unpin + Send>,
+    stderr: Box<dyn AsyncWrite + Unpin + Send>,
+    /// For stdout and stderr, printed as is without one, eg. binary data.
+    guards: [Option<OutputGuard>; 2],
+    /// Prefix lines of stdout and stderr, see `exec_prefixed`.
+    prefixers: Option<[LinePrefixer; 2]>,
+    /// Also copied here unchanged, or only here when they are quiet.
+    files: Option<&'a mut OutputFiles>,
+}
+
+impl OutputSink for Printer<'_> {
+    async fn write(&mut self, stream: Stream, data: &[u8]) -> anyhow::Result<()> {
+        if let Some(files) = self.files.as_mut() {
+            files.write(stream, data).await?;
+            if files.quiet {
+                return Ok(());
+            }
+        }
+        let i = stream as usize;
+        let mut text = match &mut self.guards[i] {
+            Some(guard) => guard.feed(data).into_bytes(),
+            None => data.to_vec(),
+        };
+        if let Some(prefixers) = &mut self.prefixers {
+            text = prefixers[i].feed(&text);
+        }
+        let writer = match stream {
+            Stream::Stdout => &mut self.stdout,
+            Stream::Stderr => &mut self.stderr,
+        };
+        writer.write_all(&text).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+}
+
+impl Printer<'_> {
+    /// Print what the guards and prefixers held back, and flush `files`.
+    async fn finish(mut self) -> anyhow::Result<()> {
+        for (i, writer) in [&mut self.stdout, &mut self.stderr].into_iter().enumerate() {
+            let mut text = match &mut self.guards[i] {
+                Some(guard) => guard.finish().into_bytes(),
+                None => vec![],
+            };
+            if let Some(prefixers) = &mut self.prefixers {
+                text = prefixers[i].feed(&text);
+                text.extend(prefixers[i].finish());
+            }
+            writer.write_all(&text).await?;
+        }
+        if let Some(files) = self.files {
+            files.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Emits the output of a remote command as `stdout`/`stderr` events.
+struct EventSink {
+    events: EventWriter,
+    /// For stdout and stderr.
+    guards: [OutputGuard; 2],
+}
+
+impl OutputSink for EventSink {
+    async fn write(&mut self, stream: Stream, data: &[u8]) -> anyhow::Result<()> {
+        let text = self.guards[stream as usize].feed(data);
+        if !text.is_empty() {
+            self.events
+                .emit(stream.as_str(), vec![("data".into(), text.into())])?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for Output {
+    async fn write(&mut self, stream: Stream, data: &[u8]) -> anyhow::Result<()> {
+        match stream {
+            Stream::Stdout => self.stdout.extend_from_slice(data),
+            Stream::Stderr => self.stderr.extend_from_slice(data),
+        }
+        Ok(())
+    }
+}
+
+/// Read `channel` until it closes, since data may still arrive after the
+/// exit status, passing its output to `sink`. Returns the exit status.
+/// `stdin` is forwarded to the channel until it ends.
+///
+/// On a `pty`, the local terminal's resizes are forwarded too, and reading
+/// stops `EXIT_DRAIN_TIMEOUT` after the exit status, as some servers (eg.
+/// CentOS) send it before flushing the PTY.
+async fn read_channel(
+    channel: &mut Channel<Msg>,
+    stdin: Option<&mut (dyn AsyncRead + Unpin + Send)>,
+    pty: bool,
+    sink: &mut impl OutputSink,
+) -> anyhow::Result<u32> {
+    let mut stdin_closed = stdin.is_none();
+    let mut no_stdin = tokio::io::empty();
+    let stdin = stdin.unwrap_or(&mut no_stdin);
+    let mut resized = if pty {
+        Some(signal(SignalKind::window_change())?)
+    } else {
+        None
+    };
+    let mut code = None;
+    let mut buf = vec![0; 1024];
+    let drain = tokio::time::sleep(EXIT_DRAIN_TIMEOUT);
+    tokio::pin!(drain);
+
+    loop {
+        tokio::select! {
+            r = stdin.read(&mut buf), if !stdin_closed => {
+                match r {
+                    Ok(0) => {
+                        stdin_closed = true;
+                        channel.eof().await?;
+                    }
+                    Ok(n) => channel.data(&buf[..n]).await?,
+                    Err(e) => return Err(e.into()),
+                }
+            },
+            // Keep the remote PTY the size of the local terminal, so full
+            // screen programs (vim, htop) redraw correctly.
+            Some(()) = async { resized.as_mut()?.recv().await }, if resized.is_some() => {
+                if let Ok((w, h)) = termion::terminal_size() {
+                    channel.window_change(w as u32, h as u32, 0, 0).await?;
+                }
+            },
+            msg = channel.wait() => {
+                let exit = match msg {
+                    Some(ChannelMsg::Data { ref data }) => {
+                        sink.write(Stream::Stdout, data).await?;
+                        continue;
+                    }
+                    Some(ChannelMsg::ExtendedData { ref data, ext: _ }) => {
+                        sink.write(Stream::Stderr, data).await?;
+                        continue;
+                    }
+                    Some(ChannelMsg::ExitStatus { exit_status }) => exit_status,
+                    // A process killed by a signal (eg. OOM-killed) has
+                    // no exit status.
+                    Some(ChannelMsg::ExitSignal { ref signal_name, .. }) => {
+                        signal_exit_code(signal_name)
+                    }
+                    Some(_) => continue,
+                    None => break,
+                };
+                code = Some(exit);
+                if pty {
+                    if !stdin_closed {
+                        stdin_closed = true;
+                        channel.eof().await?;
+                    }
+                    drain
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + EXIT_DRAIN_TIMEOUT);
+                }
+            },
+            _ = &mut drain, if pty && code.is_some() => break,
+        }
+    }
+    code.ok_or_else(|| anyhow::anyhow!("program did not exit cleanly"))
+}
+
+/// Exit code of a remote command killed by `signal`, 128 plus its number
+/// as shells report it. Numbers are the Linux ones of the instance, not
+/// the local ones, and the exit code is 255 for names Linux doesn't have.
+fn signal_exit_code(signal: &Sig) -> u32 {
+    let number = match signal {
+        Sig::HUP => 1,
+        Sig::INT => 2,
+        Sig::QUIT => 3,
+        Sig::ILL => 4,
+        Sig::ABRT => 6,
+        Sig::FPE => 8,
+        Sig::KILL => 9,
+        Sig::USR1 => 10,
+        Sig::SEGV => 11,
+        Sig::PIPE => 13,
+        Sig::ALRM => 14,
+        Sig::TERM => 15,
+        Sig::Custom(name) => match name.strip_prefix("SIG").unwrap_or(name) {
+            "TRAP" => 5,
+            "BUS" => 7,
+            "USR2" => 12,
+            "STKFLT" => 16,
+            "CHLD" => 17,
+            "CONT" => 18,
+            "STOP" => 19,
+            "TSTP" => 20,
+            "TTIN" => 21,
+            "TTOU" => 22,
+            "URG" => 23,
+            "XCPU" => 24,
+            "XFSZ" => 25,
+            "VTALRM" => 26,
+            "PROF" => 27,
+            "WINCH" => 28,
+            "IO" | "POLL" => 29,
+            "PWR" => 30,
+            "SYS" => 31,
+            _ => return 255,
+        },
+    };
+    128 + number
+}
+
+/// Exit code ssh reports when the connection itself failed.
+pub const CONNECTION_FAILED: u32 = 255;
+
+/// A remote command exited with a non-zero code, which korasi exits with
+/// in turn, like ssh.
+#[derive(Debug, thiserror::Error)]
+#[error("Remote command exited with code {0}")]
+pub struct RemoteExit(pub u32);
+
+/// Local copies of the output of a remote command, for `run --output-file`:
+/// stdout goes verbatim to the given path and stderr next to it, see
+/// `stderr_path`.
+pub struct OutputFiles {
+    stdout: tokio::fs::File,
+    stderr: tokio::fs::File,
+    /// Bytes written to both.
+    pub written: u64,
+    /// Only write the files, not the terminal, eg. for binary output.
+    quiet: bool,
+}
+
+impl OutputFiles {
+    pub async fn create(path: &Path, quiet: bool) -> anyhow::Result<Self> {
+        let create = |path: PathBuf| async move {
+            tokio::fs::File::create(&path)
+                .await
+                .with_context(|| format!("Failed to create {}", path.display()))
+        };
+        Ok(OutputFiles {
+            stdout: create(path.to_path_buf()).await?,
+            stderr: create(stderr_path(path)).await?,
+            written: 0,
+            quiet,
+        })
+    }
+
+    async fn write(&mut self, stream: Stream, data: &[u8]) -> std::io::Result<()> {
+        self.written += data.len() as u64;
+        match stream {
+            Stream::Stdout => self.stdout.write_all(data).await,
+            Stream::Stderr => self.stderr.write_all(data).await,
+        }
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.stdout.flush().await?;
+        self.stderr.flush().await
+    }
+}
+
+/// Where stderr is copied when stdout goes to `path`: `path` with
+/// `.stderr` appended.
+pub fn stderr_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".stderr");
+    path.into()
+}
+
+/// The output file of instance `name` when several run a command, eg.
+/// `bench.log` becomes `bench.gpu-box.log`.
+pub fn instance_output_path(path: &Path, name: &str) -> PathBuf {
+    let name = name.replace(['/', '\\'], "_");
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => path.with_file_name(format!(
+            "{}.{name}.{}",
+            stem.to_string_lossy(),
+            ext.to_string_lossy()
+        )),
+        _ => {
+            let mut path = path.as_os_str().to_owned();
+            path.push(format!(".{name}"));
+            path.into()
+        }
+    }
+}
+
+/// Captured result of a remote command, akin to `std::process::Output`.
+#[derive(Debug, Default)]
+pub struct Output {
+    pub code: u32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
 }
 
 impl Session {
@@ -61,39 +649,260 @@ impl Session {
         Ok(decode_secret_key(&secret, password)?)
     }
 
+    /// Load the key at `path`, asking for its passphrase if it is encrypted.
+    /// The passphrase is looked up in the OS keychain first, and can be
+    /// remembered there.
+    pub fn load_key(path: &str) -> anyhow::Result<PrivateKey> {
+        // Held while prompting, so concurrent sessions wait for the answer.
+        let mut passphrases = PASSPHRASES.lock().unwrap_or_else(|e| e.into_inner());
+        let entered = passphrases
+            .iter()
+            .find(|(p, _)| p == path)
+            .map(|(_, passphrase)| passphrase.as_str());
+        match Self::load_secret_key(path, entered) {
+            Err(err) if matches!(err.downcast_ref(), Some(russh::keys::Error::KeyIsEncrypted)) => {}
+            res => return res,
+        }
+
+        if let Some(passphrase) = keychain::get(path) {
+            if let Ok(key) = Self::load_secret_key(path, Some(&passphrase)) {
+                passphrases.push((path.into(), passphrase));
+                return Ok(key);
+            }
+            tracing::warn!("Passphrase of {path} in the keychain is out of date");
+        }
+        if !termion::is_tty(&std::io::stdin()) {
+            anyhow::bail!("{path} is encrypted, add it to ssh-agent or run interactively.");
+        }
+        let passphrase = Password::new(&format!("Passphrase for {path}:"))
+            .with_display_mode(PasswordDisplayMode::Hidden)
+            .without_confirmation()
+            .prompt()?;
+        let key = Self::load_secret_key(path, Some(&passphrase))
+            .with_context(|| format!("Could not decrypt {path}, wrong passphrase?"))?;
+        if keychain::available()
+            && Confirm::new("Remember the passphrase in the keychain?")
+                .with_default(false)
+                .prompt()
+                .unwrap_or(false)
+        {
+            if let Err(err) = keychain::set(path, &passphrase) {
+                tracing::warn!("Failed to save the passphrase: {err}");
+            }
+        }
+        passphrases.push((path.into(), passphrase));
+        Ok(key)
+    }
+
+    /// Authenticate with `public_key` held by `ssh-agent`, which has the
+    /// user touch their security key.
+    async fn authenticate_with_agent(
+        session: &mut client::Handle<ClientSSH>,
+        user: &str,
+        ssh_key: &str,
+        public_key: PublicKey,
+    ) -> anyhow::Result<()> {
+        let mut agent = AgentClient::connect_env()
+            .await
+            .context("Security keys need a running ssh-agent (SSH_AUTH_SOCK)")?;
+        let identities = agent.request_identities().await?;
+        if !identities
+            .iter()
+            .any(|k| k.key_data() == public_key.key_data())
+        {
+            anyhow::bail!("{ssh_key} is not in ssh-agent, add it with `ssh-add {ssh_key}`.");
+        }
+        eprintln!("Touch your security key to connect...");
+        if !session
+            .authenticate_publickey_with(user, public_key, &mut agent)
+            .await?
+        {
+            anyhow::bail!("The instance refused security key {ssh_key}.");
+        }
+        Ok(())
+    }
+
     /// Connect to remote instance via SSH.
     ///
     /// The public DNS name is the emphemeral host address generated when
     /// an EC2 instance starts.
+    #[tracing::instrument(skip(ssh_key, opts), fields(phase = "handshake"))]
     pub async fn connect(
         user: &str,
         public_dns_name: String,
         ssh_key: String,
+        opts: &ConnectOptions,
     ) -> anyhow::Result<Self> {
+        Self::connect_to(user, public_dns_name, ssh_key, None, opts).await
+    }
+
+    /// Like `connect`, refusing servers whose host key isn't the one
+    /// `pinned` by the profile of the instance, when there is one.
+    pub async fn connect_to(
+        user: &str,
+        public_dns_name: String,
+        ssh_key: String,
+        pinned: Option<Profile>,
+        opts: &ConnectOptions,
+    ) -> anyhow::Result<Self> {
+        let (port, relay) = (opts.port(), opts.relay.as_deref());
+        let certificate = opts.certificate.as_deref();
         let config = russh::client::Config {
             inactivity_timeout: Some(std::time::Duration::from_secs(1200)), // 20 min.
             ..<_>::default()
         };
-        let mut session =
-            russh::client::connect(Arc::new(config), (public_dns_name, SSH_PORT), ClientSSH {})
+        let config = Arc::new(config);
+        let host_key = Arc::new(Mutex::new(None));
+        let handler = ClientSSH {
+            pinned,
+            host_key: host_key.clone(),
+        };
+        let (bastion, session) = match &opts.bastion {
+            Some(spec) => {
+                let (jump_user, jump_host, jump_port) = parse_jump(spec, user)?;
+                // The bastion sees every session, its key is pinned too.
+                let profile_id = bastion_profile_id(&jump_host, jump_port);
+                let jump_key = Arc::new(Mutex::new(None));
+                let jump_handler = ClientSSH {
+                    pinned: Profiles::load().get(&profile_id).cloned(),
+                    host_key: jump_key.clone(),
+                };
+                let mut jump = Self::open(
+                    config.clone(),
+                    jump_host.clone(),
+                    jump_port,
+                    jump_handler,
+                    relay,
+                )
                 .await
-                .expect("Failed to establish SSH connection with remote instance.");
-        let key_pair = Self::load_secret_key(ssh_key, None).unwrap();
+                .with_context(|| format!("Failed to connect to bastion {spec}"))?;
+                Self::authenticate(&mut jump, &jump_user, &ssh_key, certificate).await?;
+                let jump_key = jump_key.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                if let Some(key) = &jump_key {
+                    Profiles::record(Profile {
+                        instance_id: profile_id,
+                        user: jump_user,
+                        port: jump_port,
+                        transport: relay.unwrap_or(DIRECT).into(),
+                        host_key: key.fingerprint(HashAlg::Sha256).to_string(),
+                        address: jump_host,
+                        plain_stdout: None,
+                    });
+                }
+                let channel = jump
+                    .channel_open_direct_tcpip(public_dns_name.clone(), port as u32, "127.0.0.1", 0)
+                    .await
+                    .with_context(|| {
+                        format!("Bastion {spec} could not reach {public_dns_name}:{port}")
+                    })?;
+                let session =
+                    russh::client::connect_stream(config, channel.into_stream(), handler).await;
+                (Some((jump, jump_key)), session)
+            }
+            None => (
+                None,
+                Self::open(config, public_dns_name, port, handler, relay).await,
+            ),
+        };
+        let mut session =
+            session.context("Failed to establish SSH connection with remote instance.")?;
+        let host_key = host_key.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        Self::authenticate(&mut session, user, &ssh_key, certificate).await?;
 
-        session
-            .authenticate_publickey(user, Arc::new(key_pair))
-            .await?;
+        let (bastion, bastion_host_key) = bastion.unzip();
+        Ok(Self {
+            session,
+            bastion,
+            bastion_host_key: bastion_host_key.flatten(),
+            host_key,
+            terminal_modes: local_modes(),
+            strip_ansi: false,
+        })
+    }
 
-        Ok(Self { session })
+    /// Start an SSH connection with `host:port`, through `relay` if any.
+    async fn open(
+        config: Arc<client::Config>,
+        host: String,
+        port: u16,
+        handler: ClientSSH,
+        relay: Option<&str>,
+    ) -> anyhow::Result<client::Handle<ClientSSH>> {
+        Ok(match relay {
+            Some(relay) => {
+                let stream = websocket::connect(relay, &host, port).await?;
+                russh::client::connect_stream(config, stream, handler).await?
+            }
+            None => russh::client::connect(config, (host, port), handler).await?,
+        })
     }
 
-    /// Executes a remote command using SSH.
-    pub async fn exec(&self, command: &str) -> anyhow::Result<u32> {
-        let mut channel = self.channel_open_session().await?;
+    /// Authenticate as `user` with `ssh_key`, its certificate (or the one
+    /// at `certificate`) or, for security keys, ssh-agent.
+    async fn authenticate(
+        session: &mut client::Handle<ClientSSH>,
+        user: &str,
+        ssh_key: &str,
+        certificate: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        match Self::load_key(ssh_key) {
+            Ok(key_pair) if !is_security_key(key_pair.public_key()) => {
+                match self::certificate(ssh_key, certificate)? {
+                    Some(cert) => {
+                        session
+                            .authenticate_openssh_cert(user, Arc::new(key_pair), cert)
+                            .await?
+                    }
+                    None => {
+                        session
+                            .authenticate_publickey(user, Arc::new(key_pair))
+                            .await?
+                    }
+                };
+            }
+            key_pair => {
+                // Security key files only hold a handle, the agent signs.
+                let public_key = match key_pair {
+                    Ok(key_pair) => key_pair.public_key().clone(),
+                    Err(err) => std::fs::read_to_string(format!("{ssh_key}.pub"))
+                        .ok()
+                        .and_then(|s| PublicKey::from_openssh(s.trim()).ok())
+                        .filter(is_security_key)
+                        .ok_or(err)?,
+                };
+                Self::authenticate_with_agent(session, user, ssh_key, public_key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// SHA256 fingerprint of the server's host key.
+    pub fn host_key(&self) -> Option<String> {
+        self.host_key
+            .as_ref()
+            .map(|key| key.fingerprint(HashAlg::Sha256).to_string())
+    }
+
+    /// The server's host key in OpenSSH format, as in `known_hosts`.
+    pub fn host_public_key(&self) -> Option<String> {
+        self.host_key.as_ref().and_then(|key| key.to_openssh().ok())
+    }
+
+    /// Like `host_public_key`, for the bastion the connection goes through.
+    pub fn bastion_public_key(&self) -> Option<String> {
+        self.bastion_host_key
+            .as_ref()
+            .and_then(|key| key.to_openssh().ok())
+    }
 
-        // No terminal resizing after the connection is established.
-        let (w, h) = termion::terminal_size()?;
-        // Request an interactive PTY from the server.
+    pub fn set_strip_ansi(&mut self, strip_ansi: bool) {
+        self.strip_ansi = strip_ansi;
+    }
+
+    /// Request an interactive PTY the size of the local terminal.
+    async fn request_pty(&self, channel: &Channel<Msg>) -> anyhow::Result<()> {
+        // Later resizes are sent by `exec` as window-change requests.
+        let (w, h) = termion::terminal_size().unwrap_or((80, 24));
         channel
             .request_pty(
                 false,
@@ -102,62 +911,166 @@ impl Session {
                 h as u32,
                 0,
                 0,
-                &[], // ideally you want to pass the actual terminal modes here
+                &self.terminal_modes,
             )
             .await?;
+        Ok(())
+    }
 
+    /// Executes a remote command using SSH.
+    #[tracing::instrument(skip(self), fields(phase = "exec"))]
+    pub async fn exec(&self, command: &str) -> anyhow::Result<u32> {
+        let mut channel = self.channel_open_session().await?;
+        self.request_pty(&channel).await?;
         channel.exec(true, command).await?;
 
         let mut stdin = tokio_fd::AsyncFd::try_from(0)?;
-        let mut stdout = tokio_fd::AsyncFd::try_from(1)?;
-        let mut stderr = tokio_fd::AsyncFd::try_from(2)?;
+        let mut printer = Printer {
+            stdout: Box::new(tokio_fd::AsyncFd::try_from(1)?),
+            stderr: Box::new(tokio_fd::AsyncFd::try_from(2)?),
+            guards: [
+                Some(OutputGuard::new(self.strip_ansi)),
+                Some(OutputGuard::new(self.strip_ansi)),
+            ],
+            prefixers: None,
+            files: None,
+        };
+        let code = read_channel(&mut channel, Some(&mut stdin), true, &mut printer).await;
+        printer.finish().await?;
+        code
+    }
 
-        let code;
-        let mut buf = vec![0; 1024];
-        let mut stdin_closed = false;
+    /// Executes a remote command without a PTY, so its stdout stays byte
+    /// for byte what it wrote (eg. binary data) and apart from stderr.
+    /// Stdin is still forwarded. With `files`, both streams are also
+    /// copied there, or only there when they are quiet.
+    #[tracing::instrument(skip(self, files), fields(phase = "exec"))]
+    pub async fn exec_no_pty(
+        &self,
+        command: &str,
+        files: Option<&mut OutputFiles>,
+    ) -> anyhow::Result<u32> {
+        let mut channel = self.channel_open_session().await?;
+        channel.exec(true, command).await?;
 
-        loop {
-            tokio::select! {
-                r = stdin.read(&mut buf), if !stdin_closed => {
-                    match r {
-                        Ok(0) => {
-                            stdin_closed = true;
-                            channel.eof().await?;
-                        },
-                        // Send it to the server
-                        Ok(n) => channel.data(&buf[..n]).await?,
-                        Err(e) => return Err(e.into()),
-                    };
-                },
-                Some(msg) = channel.wait() => {
-                    match msg {
-                        // Write data to the terminal
-                        ChannelMsg::Data { ref data } => {
-                            stdout.write_all(data).await?;
-                            stdout.flush().await?;
-                        }
-                        ChannelMsg::ExitStatus { exit_status } => {
-                            code = Some(exit_status);
-                            if !stdin_closed {
-                                channel.eof().await?;
-                            }
-                            break;
-                        }
-                        // Get std error from remote command.
-                        ChannelMsg::ExtendedData { ref data, ext: _ } => {
-                            stderr.write_all(data).await?;
-                            stderr.flush().await?;
-                        }
-                        _ => {}
+        let mut stdin = local_stdin()?;
+        let mut printer = Printer {
+            stdout: Box::new(tokio::io::stdout()),
+            stderr: Box::new(tokio::io::stderr()),
+            guards: [None, Some(OutputGuard::new(false))],
+            prefixers: None,
+            files,
+        };
+        let code = read_channel(&mut channel, Some(&mut *stdin), false, &mut printer).await;
+        printer.finish().await?;
+        code
+    }
+
+    /// Whether output of commands run on a PTY reaches us, by running a
+    /// harmless `echo` and waiting for it for a short grace period.
+    ///
+    /// Some images print nothing for commands not run through a login
+    /// shell, see `shell_fallback`.
+    pub async fn probe_stdout(&self) -> anyhow::Result<bool> {
+        let mut channel = self.channel_open_session().await?;
+        self.request_pty(&channel).await?;
+        channel.exec(true, format!("echo {STDOUT_PROBE}")).await?;
+
+        let mut output = vec![];
+        let received = tokio::time::timeout(STDOUT_PROBE_GRACE, async {
+            while let Some(msg) = channel.wait().await {
+                if let ChannelMsg::Data { ref data } = msg {
+                    output.extend_from_slice(data);
+                    if String::from_utf8_lossy(&output).contains(STDOUT_PROBE) {
+                        return true;
                     }
-                },
+                }
+            }
+            false
+        })
+        .await
+        .unwrap_or(false);
+        let _ = channel.close().await;
+        Ok(received)
+    }
+
+    /// Executes a remote command without a PTY or stdin, prefixing every
+    /// line of output with `[prefix]`. With `files`, the output is also
+    /// copied there unprefixed, or only there when they are quiet.
+    ///
+    /// Meant for running the same command on several instances at once.
+    #[tracing::instrument(skip(self, files), fields(phase = "exec"))]
+    pub async fn exec_prefixed(
+        &self,
+        command: &str,
+        prefix: &str,
+        files: Option<&mut OutputFiles>,
+    ) -> anyhow::Result<u32> {
+        let mut channel = self.channel_open_session().await?;
+        channel.exec(true, command).await?;
+
+        let mut printer = Printer {
+            stdout: Box::new(tokio::io::stdout()),
+            stderr: Box::new(tokio::io::stderr()),
+            guards: [
+                Some(OutputGuard::new(self.strip_ansi)),
+                Some(OutputGuard::new(self.strip_ansi)),
+            ],
+            prefixers: Some([
+                LinePrefixer::new(format!("[{prefix}] ")),
+                LinePrefixer::new(format!("[{prefix}] ")),
+            ]),
+            files,
+        };
+        let code = read_channel(&mut channel, None, false, &mut printer).await;
+        printer.finish().await?;
+        code
+    }
+
+    /// Executes a remote command without a PTY or stdin, printing its
+    /// output as `stdout`/`stderr` events between `start` and `exit` ones.
+    #[tracing::instrument(skip(self), fields(phase = "exec"))]
+    pub async fn exec_events(&self, command: &str, instance: &str) -> anyhow::Result<u32> {
+        let mut channel = self.channel_open_session().await?;
+        channel.exec(true, command).await?;
+
+        let mut events = EventWriter::new(instance);
+        events.emit("start", vec![("command".into(), command.into())])?;
+        let mut sink = EventSink {
+            events,
+            guards: [
+                OutputGuard::new(self.strip_ansi),
+                OutputGuard::new(self.strip_ansi),
+            ],
+        };
+        let code = read_channel(&mut channel, None, false, &mut sink).await;
+        let EventSink { mut events, guards } = sink;
+        for (stream, mut guard) in [Stream::Stdout, Stream::Stderr].into_iter().zip(guards) {
+            let text = guard.finish();
+            if !text.is_empty() {
+                events.emit(stream.as_str(), vec![("data".into(), text.into())])?;
             }
         }
 
-        Ok(code.expect("program did not exit cleanly"))
+        let code = code?;
+        events.emit("exit", vec![("code".into(), (code as u64).into())])?;
+        Ok(code)
+    }
+
+    /// Executes a remote command without a PTY and collects its output.
+    #[tracing::instrument(skip(self), fields(phase = "exec"))]
+    pub async fn exec_output(&self, command: &str) -> anyhow::Result<Output> {
+        let mut channel = self.channel_open_session().await?;
+        channel.exec(true, command).await?;
+
+        let mut output = Output::default();
+        output.code = read_channel(&mut channel, None, false, &mut output).await?;
+        Ok(output)
     }
 
-    async fn open_sftp_session(&self) -> Result<SftpSession, russh_sftp::client::error::Error> {
+    pub(crate) async fn open_sftp_session(
+        &self,
+    ) -> Result<SftpSession, russh_sftp::client::error::Error> {
         let channel = self.session.channel_open_session().await.unwrap();
         channel.request_subsystem(true, "sftp").await.unwrap();
 
@@ -167,9 +1080,18 @@ impl Session {
     /// Upload files within `src` to `dst` directory using SFTP.
     /// If `dst` is not specified, files will uploaded to $HOME/{cwd}.
     /// The {cwd} folder will be created by default in this use case.
+    /// Files matching the `exclude` globs are skipped.
     ///
     /// Panics if dst is not a directory.
-    pub async fn upload(&self, src: Option<String>, dst: Option<String>) -> anyhow::Result<()> {
+    ///
+    /// Returns the number of bytes uploaded.
+    #[tracing::instrument(skip(self), fields(phase = "transfer"))]
+    pub async fn upload(
+        &self,
+        src: Option<String>,
+        dst: Option<String>,
+        exclude: &[String],
+    ) -> anyhow::Result<u64> {
         let src_path = match std::fs::canonicalize(src.unwrap_or(".".into())) {
             Ok(pth) => pth,
             // Bail early if the src path is fked.
@@ -187,7 +1109,7 @@ impl Session {
                 }
                 Err(err) => {
                     tracing::error!("Error remote metadata = {err}");
-                    return Ok(());
+                    return Ok(0);
                 }
             }
         }
@@ -199,35 +1121,41 @@ impl Session {
             .expect("Failed to canonicalize remote dst.");
 
         // The .gitignore at src_path will be respected.
-        for result in biject_paths(
+        let entries = biject_paths(
             src_path.to_str().unwrap(),
             prefix.to_str().unwrap_or(""),
             &dst_abs_path,
-        ) {
+            exclude,
+        )?;
+        let (total_files, total_bytes) = entries
+            .iter()
+            .filter_map(|e| e.as_ref().ok())
+            .filter(|(_, _, is_dir)| !is_dir)
+            .fold((0, 0), |(files, bytes), (local_pth, _, _)| {
+                let size = std::fs::metadata(local_pth).map_or(0, |m| m.len());
+                (files + 1, bytes + size)
+            });
+        let mut progress = Progress::new(total_files, total_bytes);
+
+        let mut uploaded = 0;
+        for result in entries {
             match result {
                 Ok((local_pth, combined, is_dir)) => {
                     if is_dir {
                         let _ = sftp.create_dir(combined.to_str().unwrap().to_owned()).await;
                     } else {
-                        let open_remote_file = sftp
-                            .open_with_flags(
-                                combined.to_str().unwrap(),
-                                OpenFlags::CREATE | OpenFlags::TRUNCATE | OpenFlags::WRITE,
-                            )
-                            .await;
-                        if open_remote_file.is_err() {
-                            tracing::warn!("Failed to open file = {:?}", combined,);
-                        }
-
-                        // Overwrite remote file contents with local file contents.
-                        if let Ok(mut remote_file) = open_remote_file {
-                            let mut local_file = File::open(local_pth).unwrap();
-                            let mut buffer = Vec::new();
-                            local_file.read_to_end(&mut buffer).unwrap();
-                            remote_file.write_all(buffer.as_slice()).await.unwrap();
-                            let _ = remote_file.sync_all().await;
-                            remote_file.shutdown().await.unwrap();
+                        match write_remote_file(
+                            &sftp,
+                            &local_pth,
+                            combined.to_str().unwrap(),
+                            &mut progress,
+                        )
+                        .await
+                        {
+                            Ok(n) => uploaded += n,
+                            Err(err) => tracing::warn!("Failed to upload {:?}: {err}", combined),
                         }
+                        progress.file_done();
                     }
                 }
                 Err(err) => tracing::error!("ERROR: {}", err),
@@ -235,7 +1163,104 @@ impl Session {
         }
 
         sftp.close().await?;
+        progress.finish();
+
+        Ok(uploaded)
+    }
+
+    /// Download regular files directly within remote directory `src` into
+    /// local directory `dst`, keeping their permissions. Files for which
+    /// `keep` returns false are skipped.
+    ///
+    /// Returns the local paths of downloaded files.
+    #[tracing::instrument(skip(self, keep), fields(phase = "transfer"))]
+    pub async fn download_files(
+        &self,
+        src: &str,
+        dst: &Path,
+        keep: impl Fn(&str) -> bool,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let sftp = self.open_sftp_session().await?;
+        std::fs::create_dir_all(dst)?;
+
+        let mut downloaded = vec![];
+        for entry in sftp.read_dir(src).await? {
+            let name = entry.file_name();
+            if !entry.file_type().is_file() || !keep(&name) {
+                continue;
+            }
+
+            let local_pth = dst.join(&name);
+            tracing::info!("Downloading {src}/{name} to {:?}", local_pth);
+            let mut remote_file = sftp.open(format!("{src}/{name}")).await?;
+            let mut local_file = File::create(&local_pth)?;
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let n = remote_file.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                local_file.write_all(&buf[..n])?;
+            }
 
+            #[cfg(unix)]
+            if let Some(mode) = entry.metadata().permissions {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(
+                    &local_pth,
+                    std::fs::Permissions::from_mode(mode & 0o777),
+                )?;
+            }
+            downloaded.push(local_pth);
+        }
+
+        sftp.close().await?;
+        Ok(downloaded)
+    }
+
+    /// Forwards connections on `127.0.0.1:local_port` to
+    /// `remote_host:remote_port` as seen from the instance, until Ctrl-C.
+    pub async fn forward_local(
+        &self,
+        local_port: u16,
+        remote_host: &str,
+        remote_port: u16,
+    ) -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", local_port)).await?;
+        println!("Forwarding 127.0.0.1:{local_port} -> {remote_host}:{remote_port}. Press Ctrl-C to stop.");
+
+        loop {
+            let (mut stream, peer) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = tokio::signal::ctrl_c() => break,
+            };
+            tracing::info!("Forwarding connection from {peer}");
+
+            let channel = match self
+                .session
+                .channel_open_direct_tcpip(
+                    remote_host,
+                    remote_port as u32,
+                    peer.ip().to_string(),
+                    peer.port() as u32,
+                )
+                .await
+            {
+                Ok(channel) => channel,
+                // The remote end refusing one connection shouldn't tear down
+                // the tunnel.
+                Err(err) => {
+                    eprintln!("Failed to open {remote_host}:{remote_port} on the instance: {err}");
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                let mut remote = channel.into_stream();
+                if let Err(err) = tokio::io::copy_bidirectional(&mut stream, &mut remote).await {
+                    tracing::debug!("Connection from {peer} closed: {err}");
+                }
+            });
+        }
         Ok(())
     }
 
@@ -244,6 +1269,388 @@ impl Session {
         self.session
             .disconnect(Disconnect::ByApplication, "", "English")
             .await?;
+        if let Some(bastion) = &self.bastion {
+            bastion
+                .disconnect(Disconnect::ByApplication, "", "English")
+                .await?;
+        }
         Ok(())
     }
 }
+
+/// Run `command` in a login shell with its output sent to the PTY
+/// explicitly, for hosts where `Session::probe_stdout` got nothing back.
+pub fn shell_fallback(command: &str) -> String {
+    format!(
+        "bash -lc {} </dev/tty >/dev/tty 2>&1",
+        shell_escape::escape(command.into())
+    )
+}
+
+/// Poll until `host` accepts TCP connections on `port`, through `relay`
+/// if any, backing off from 1 up to 10 seconds between attempts. Status
+/// checks pass before sshd is always listening, so this is what makes a
+/// first connect reliable.
+pub async fn wait_for_port(
+    host: &str,
+    port: u16,
+    relay: Option<&str>,
+    timeout: std::time::Duration,
+) -> anyhow::Result<()> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut delay = std::time::Duration::from_secs(1);
+    loop {
+        let attempt = tokio::time::timeout(delay, probe(host, port, relay)).await;
+        match attempt {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(err)) => tracing::debug!("{host}:{port} not reachable yet: {err}"),
+            Err(_) => tracing::debug!("{host}:{port} timed out"),
+        }
+        if tokio::time::Instant::now() + delay > deadline {
+            anyhow::bail!("{host}:{port} still not reachable after {timeout:?}");
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(std::time::Duration::from_secs(10));
+    }
+}
+
+/// Poll every second until `host` stops accepting connections on `port`
+/// (through `relay` if any), eg. while a rebooting instance shuts down.
+/// Returns false if it is still reachable after `timeout`.
+pub async fn wait_for_port_closed(
+    host: &str,
+    port: u16,
+    relay: Option<&str>,
+    timeout: std::time::Duration,
+) -> bool {
+    let delay = std::time::Duration::from_secs(1);
+    let deadline = tokio::time::Instant::now() + timeout;
+    while tokio::time::Instant::now() < deadline {
+        let attempt = tokio::time::timeout(delay, probe(host, port, relay)).await;
+        if !matches!(attempt, Ok(Ok(_))) {
+            return true;
+        }
+        tokio::time::sleep(delay).await;
+    }
+    false
+}
+
+/// Overwrite remote file `remote` (creating it if needed) with the contents
+/// of `local`, in chunks reported to `progress`.
+///
+/// Returns the number of bytes written.
+pub(crate) async fn write_remote_file(
+    sftp: &SftpSession,
+    local: &Path,
+    remote: &str,
+    progress: &mut Progress,
+) -> anyhow::Result<u64> {
+    let mut remote_file = sftp
+        .open_with_flags(
+            remote,
+            OpenFlags::CREATE | OpenFlags::TRUNCATE | OpenFlags::WRITE,
+        )
+        .await?;
+    let mut local_file = File::open(local)?;
+    let mut buffer = vec![0; UPLOAD_CHUNK_SIZE];
+    let mut written = 0;
+    loop {
+        let n = local_file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        remote_file.write_all(&buffer[..n]).await?;
+        written += n as u64;
+        progress.add_bytes(n as u64);
+    }
+    let _ = remote_file.sync_all().await;
+    remote_file.shutdown().await?;
+    Ok(written)
+}
+
+/// Runs `task(name, host)` for every `(name, host)` concurrently, returning
+/// the results in the same order. A task that panics yields an `Err`.
+pub async fn on_each_host<T, F, Fut>(
+    hosts: Vec<(String, String)>,
+    task: F,
+) -> Vec<(String, anyhow::Result<T>)>
+where
+    F: Fn(String, String) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let handles: Vec<_> = hosts
+        .into_iter()
+        .map(|(name, host)| {
+            let handle = tokio::spawn(task(name.clone(), host));
+            (name, handle)
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(handles.len());
+    for (name, handle) in handles {
+        let res = handle
+            .await
+            .unwrap_or_else(|err| Err(anyhow::anyhow!("Task panicked: {err}")));
+        results.push((name, res));
+    }
+    results
+}
+
+/// How `exec_parallel` runs and shows a command.
+#[derive(Debug, Default, Clone)]
+pub struct ParallelOptions<'a> {
+    /// How to reach the hosts.
+    pub connect: ConnectOptions,
+    /// Strip ANSI escapes from the output.
+    pub strip_ansi: bool,
+    /// Print events instead of prefixed lines.
+    pub events: bool,
+    /// Copy the output of each host to its own file, see
+    /// `instance_output_path`.
+    pub output_file: Option<&'a Path>,
+    /// Only copy the output to `output_file`.
+    pub quiet: bool,
+}
+
+/// Runs `command` concurrently on every `(name, public_dns_name)` host,
+/// as `opts` says.
+///
+/// Returns the exit code (or connection error) of each host, in the
+/// same order as `hosts`. A panicked task counts as a connection error.
+pub async fn exec_parallel(
+    hosts: Vec<(String, String)>,
+    user: &str,
+    ssh_key: &str,
+    command: &str,
+    opts: ParallelOptions<'_>,
+) -> Vec<(String, anyhow::Result<u32>)> {
+    let ParallelOptions {
+        connect,
+        strip_ansi,
+        events,
+        output_file,
+        quiet,
+    } = opts;
+    on_each_host(hosts, |name, host| {
+        let user = user.to_string();
+        let ssh_key = ssh_key.to_string();
+        let command = command.to_string();
+        let output_file = output_file.map(|path| instance_output_path(path, &name));
+        let connect = connect.clone();
+        async move {
+            let res: anyhow::Result<u32> = async {
+                let mut files = match &output_file {
+                    Some(path) => Some(OutputFiles::create(path, quiet).await?),
+                    None => None,
+                };
+                let mut session = Session::connect(&user, host, ssh_key, &connect).await?;
+                session.set_strip_ansi(strip_ansi);
+                let code = if events {
+                    session.exec_events(&command, &name).await?
+                } else {
+                    session
+                        .exec_prefixed(&command, &name, files.as_mut())
+                        .await?
+                };
+                session.close().await?;
+                Ok(code)
+            }
+            .await;
+            if let (true, Err(err)) = (events, &res) {
+                // Failed before the command could exit, eg. to connect.
+                let fields = vec![
+                    ("code".into(), (CONNECTION_FAILED as u64).into()),
+                    ("error".into(), err.to_string().as_str().into()),
+                ];
+                if let Err(err) = EventWriter::new(name.as_str()).emit("exit", fields) {
+                    tracing::warn!("Failed to write event: {err}");
+                }
+            }
+            res
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use russh::{keys::PublicKey, Sig};
+
+    use super::{
+        instance_output_path, is_security_key, on_each_host, parse_jump, shell_fallback,
+        signal_exit_code, stderr_path, wait_for_port, wait_for_port_closed, ForwardSpec,
+        LinePrefixer,
+    };
+
+    #[test]
+    fn prefix_split_lines() {
+        let mut p = LinePrefixer::new("[a] ");
+
+        pretty_assertions::assert_eq!(p.feed(b"hel"), b"".to_vec());
+        pretty_assertions::assert_eq!(p.feed(b"lo\nwor"), b"[a] hello\n".to_vec());
+        pretty_assertions::assert_eq!(p.feed(b"ld\n\n"), b"[a] world\n[a] \n".to_vec());
+        pretty_assertions::assert_eq!(p.feed(b"tail"), b"".to_vec());
+        pretty_assertions::assert_eq!(p.finish(), b"[a] tail\n".to_vec());
+        pretty_assertions::assert_eq!(p.finish(), b"".to_vec());
+    }
+
+    #[test]
+    fn parse_forward_spec() {
+        let spec = |local_port, remote_host: &str, remote_port| ForwardSpec {
+            local_port,
+            remote_host: remote_host.into(),
+            remote_port,
+        };
+        let cases = [
+            ("8888", Ok(spec(8888, "localhost", 8888))),
+            ("15432:5432", Ok(spec(15432, "localhost", 5432))),
+            ("9000:db.internal:5432", Ok(spec(9000, "db.internal", 5432))),
+            ("0", Err("invalid port `0`".to_string())),
+            (
+                "1:2:3:4",
+                Err("invalid forward `1:2:3:4`, expected [local:][host:]port".to_string()),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            println!("input = {input}");
+            pretty_assertions::assert_eq!(input.parse::<ForwardSpec>(), expected);
+        }
+    }
+
+    #[test]
+    fn wrap_shell_fallback() {
+        pretty_assertions::assert_eq!(
+            shell_fallback("echo 'hi' && ls"),
+            r#"bash -lc 'echo '\''hi'\'' && ls' </dev/tty >/dev/tty 2>&1"#
+        );
+    }
+
+    #[tokio::test]
+    async fn wait_for_listening_port() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(
+            wait_for_port("127.0.0.1", port, None, Duration::from_secs(1))
+                .await
+                .is_ok()
+        );
+
+        assert!(!wait_for_port_closed("127.0.0.1", port, None, Duration::from_millis(500)).await);
+
+        drop(listener);
+        assert!(
+            wait_for_port("127.0.0.1", port, None, Duration::from_millis(500))
+                .await
+                .is_err()
+        );
+        assert!(wait_for_port_closed("127.0.0.1", port, None, Duration::from_secs(1)).await);
+    }
+
+    #[test]
+    fn jump_hosts() {
+        let cases = [
+            (
+                "bastion.example.com",
+                Some(("ubuntu", "bastion.example.com", 22)),
+            ),
+            (
+                "ec2-user@10.0.0.5:2222",
+                Some(("ec2-user", "10.0.0.5", 2222)),
+            ),
+            ("jump@bastion", Some(("jump", "bastion", 22))),
+            ("bastion:ssh", None),
+            ("@bastion", None),
+        ];
+        for (spec, expected) in cases {
+            println!("spec = {spec}");
+            let expected = expected.map(|(u, h, p)| (u.to_string(), h.to_string(), p));
+            pretty_assertions::assert_eq!(parse_jump(spec, "ubuntu").ok(), expected);
+        }
+    }
+
+    #[test]
+    fn security_keys() {
+        let cases = [
+            ("sk-ssh-ed25519@openssh.com AAAAGnNrLXNzaC1lZDI1NTE5QG9wZW5zc2guY29tAAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4fAAAABHNzaDo= yubikey", true),
+            ("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f", false),
+        ];
+        for (key, expected) in cases {
+            println!("key = {key}");
+            let key = PublicKey::from_openssh(key).unwrap();
+            pretty_assertions::assert_eq!(is_security_key(&key), expected);
+        }
+    }
+
+    #[test]
+    fn output_file_paths() {
+        let cases = [
+            ("bench.log", "gpu-box", "bench.gpu-box.log"),
+            (
+                "logs/bench.log",
+                "happy:otter",
+                "logs/bench.happy:otter.log",
+            ),
+            ("out", "gpu-box", "out.gpu-box"),
+            ("out", "a/b", "out.a_b"),
+        ];
+        for (path, name, expected) in cases {
+            println!("path = {path}, name = {name}");
+            pretty_assertions::assert_eq!(
+                instance_output_path(Path::new(path), name),
+                Path::new(expected)
+            );
+        }
+        pretty_assertions::assert_eq!(
+            stderr_path(Path::new("bench.log")),
+            Path::new("bench.log.stderr")
+        );
+    }
+
+    #[test]
+    fn exit_code_of_signals() {
+        let cases = [
+            (Sig::KILL, 137),
+            (Sig::TERM, 143),
+            (Sig::SEGV, 139),
+            (Sig::USR1, 138),
+            (Sig::Custom("BUS".into()), 135),
+            (Sig::Custom("USR2".into()), 140),
+            (Sig::Custom("NOPE".into()), 255),
+        ];
+        for (signal, expected) in cases {
+            println!("signal = {signal:?}");
+            pretty_assertions::assert_eq!(signal_exit_code(&signal), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn panicked_host_keeps_its_place() {
+        let hosts = ["a", "b", "c"]
+            .map(|name| (name.to_string(), format!("{name}.host")))
+            .to_vec();
+        let results = on_each_host(hosts, |name, host| async move {
+            if name == "b" {
+                panic!("boom");
+            }
+            Ok(host)
+        })
+        .await;
+
+        let results: Vec<_> = results
+            .into_iter()
+            .map(|(name, res)| (name, res.map_err(|_| ())))
+            .collect();
+        pretty_assertions::assert_eq!(
+            results,
+            vec![
+                ("a".to_string(), Ok("a.host".to_string())),
+                ("b".to_string(), Err(())),
+                ("c".to_string(), Ok("c.host".to_string())),
+            ]
+        );
+    }
+}
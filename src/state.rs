@@ -0,0 +1,741 @@
+//! Local state kept across invocations under `~/.korasi`.
+
+use std::{
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use crate::toml::{self, quote, Value};
+
+/// Usage counters, exported by `korasi serve`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    /// Bytes sent to instances by `Upload` and `Build`.
+    pub transfer_bytes: u64,
+    /// Remote commands (`Run`, `Build`) that exited 0.
+    pub jobs_succeeded: u64,
+    /// Remote commands that exited non-zero or failed to run.
+    pub jobs_failed: u64,
+}
+
+pub fn state_dir() -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_else(|_| ".".into());
+    PathBuf::from(home).join(".korasi")
+}
+
+/// Save `contents` to the state file at `path`, under `state_dir`. The
+/// file is replaced with a rename, so readers never see half of it.
+pub fn save_state(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}", std::process::id()));
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)
+}
+
+impl Stats {
+    fn path() -> PathBuf {
+        state_dir().join("stats.toml")
+    }
+
+    /// Current counters, zeroed if there are none yet.
+    pub fn load() -> Stats {
+        std::fs::read_to_string(Self::path())
+            .map(|src| Self::parse(&src))
+            .unwrap_or_default()
+    }
+
+    /// Unknown keys and malformed values are ignored, the counters are
+    /// best effort.
+    pub fn parse(src: &str) -> Stats {
+        let Ok(table) = toml::parse(src) else {
+            return Stats::default();
+        };
+        let get = |key: &str| match table.get(key).map(|i| &i.value) {
+            Some(Value::Integer(i)) => (*i).max(0) as u64,
+            _ => 0,
+        };
+        Stats {
+            transfer_bytes: get("transfer_bytes"),
+            jobs_succeeded: get("jobs_succeeded"),
+            jobs_failed: get("jobs_failed"),
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        format!(
+            "transfer_bytes = {}\njobs_succeeded = {}\njobs_failed = {}\n",
+            self.transfer_bytes, self.jobs_succeeded, self.jobs_failed
+        )
+    }
+
+    /// Apply `f` to the stored counters and save them.
+    ///
+    /// Failing to record stats should never fail a command, so errors are
+    /// only logged.
+    pub fn record(f: impl FnOnce(&mut Stats)) {
+        let mut stats = Self::load();
+        f(&mut stats);
+        let res = save_state(&Self::path(), &stats.to_toml());
+        if let Err(err) = res {
+            tracing::warn!("Failed to save stats: {err}");
+        }
+    }
+
+    pub fn record_job(code: u32) {
+        Self::record(|s| {
+            if code == 0 {
+                s.jobs_succeeded += 1;
+            } else {
+                s.jobs_failed += 1;
+            }
+        });
+    }
+}
+
+/// How long waits for an instance type to reach a state took so far.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timing {
+    /// The state waited for, eg. `ready` or `stopped`.
+    pub phase: String,
+    pub instance_type: String,
+    pub samples: u64,
+    pub mean_secs: u64,
+}
+
+/// Durations of past waits, used to estimate the next ones.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Timings(pub Vec<Timing>);
+
+impl Timings {
+    fn path() -> PathBuf {
+        state_dir().join("timings.toml")
+    }
+
+    pub fn load() -> Timings {
+        std::fs::read_to_string(Self::path())
+            .map(|src| Self::parse(&src))
+            .unwrap_or_default()
+    }
+
+    /// Malformed entries are skipped, like malformed stats.
+    pub fn parse(src: &str) -> Timings {
+        let Ok(table) = toml::parse(src) else {
+            return Timings::default();
+        };
+        let Some(Value::Array(timings)) = table.get("timing").map(|i| &i.value) else {
+            return Timings::default();
+        };
+        let parse_one = |t: &toml::Table| {
+            let get = |key: &str| t.get(key).map(|i| &i.value);
+            match (
+                get("phase"),
+                get("instance_type"),
+                get("samples"),
+                get("mean_secs"),
+            ) {
+                (
+                    Some(Value::String(phase)),
+                    Some(Value::String(instance_type)),
+                    Some(Value::Integer(samples)),
+                    Some(Value::Integer(mean_secs)),
+                ) => Some(Timing {
+                    phase: phase.clone(),
+                    instance_type: instance_type.clone(),
+                    samples: (*samples).max(0) as u64,
+                    mean_secs: (*mean_secs).max(0) as u64,
+                }),
+                _ => None,
+            }
+        };
+        Timings(
+            timings
+                .iter()
+                .filter_map(|t| match t {
+                    Value::Table(t) => parse_one(t),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    pub fn to_toml(&self) -> String {
+        self.0
+            .iter()
+            .map(|t| {
+                format!(
+                    "[[timing]]\nphase = {}\ninstance_type = {}\nsamples = {}\nmean_secs = {}\n",
+                    quote(&t.phase),
+                    quote(&t.instance_type),
+                    t.samples,
+                    t.mean_secs
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Typical duration of `phase` for `instance_type`, if it was timed before.
+    pub fn expected(&self, phase: &str, instance_type: &str) -> Option<std::time::Duration> {
+        self.0
+            .iter()
+            .find(|t| t.phase == phase && t.instance_type == instance_type)
+            .map(|t| std::time::Duration::from_secs(t.mean_secs))
+    }
+
+    /// Fold `took` into the mean of `phase` for `instance_type`.
+    pub fn add(&mut self, phase: &str, instance_type: &str, took: std::time::Duration) {
+        let secs = took.as_secs();
+        match self
+            .0
+            .iter_mut()
+            .find(|t| t.phase == phase && t.instance_type == instance_type)
+        {
+            Some(t) => {
+                t.mean_secs = (t.mean_secs * t.samples + secs) / (t.samples + 1);
+                t.samples += 1;
+            }
+            None => self.0.push(Timing {
+                phase: phase.into(),
+                instance_type: instance_type.into(),
+                samples: 1,
+                mean_secs: secs,
+            }),
+        }
+    }
+
+    /// Record a wait. Like stats, failing to save is only logged.
+    pub fn record(phase: &str, instance_type: &str, took: std::time::Duration) {
+        let mut timings = Self::load();
+        timings.add(phase, instance_type, took);
+        let res = save_state(&Self::path(), &timings.to_toml());
+        if let Err(err) = res {
+            tracing::warn!("Failed to save timings: {err}");
+        }
+    }
+}
+
+/// How many runs `RunLog` keeps.
+const MAX_RUNS: usize = 50;
+
+/// A remote command run by `Run` or `Build`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Run {
+    /// Unix time it finished at.
+    pub at: u64,
+    pub instance: String,
+    /// Program the command started, see `program_name`. The full command
+    /// line may hold secrets, so it isn't kept.
+    pub program: String,
+    pub code: u32,
+}
+
+/// The latest runs, oldest first, for `report`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunLog(pub Vec<Run>);
+
+impl RunLog {
+    fn path() -> PathBuf {
+        state_dir().join("runs.toml")
+    }
+
+    pub fn load() -> RunLog {
+        std::fs::read_to_string(Self::path())
+            .map(|src| Self::parse(&src))
+            .unwrap_or_default()
+    }
+
+    /// Malformed entries are skipped, like malformed timings.
+    pub fn parse(src: &str) -> RunLog {
+        let Ok(table) = toml::parse(src) else {
+            return RunLog::default();
+        };
+        let Some(Value::Array(runs)) = table.get("run").map(|i| &i.value) else {
+            return RunLog::default();
+        };
+        let parse_one = |t: &toml::Table| {
+            let get = |key: &str| t.get(key).map(|i| &i.value);
+            match (get("at"), get("instance"), get("program"), get("code")) {
+                (
+                    Some(Value::Integer(at)),
+                    Some(Value::String(instance)),
+                    Some(Value::String(program)),
+                    Some(Value::Integer(code)),
+                ) => Some(Run {
+                    at: (*at).max(0) as u64,
+                    instance: instance.clone(),
+                    program: program.clone(),
+                    code: (*code).clamp(0, u32::MAX as i64) as u32,
+                }),
+                _ => None,
+            }
+        };
+        RunLog(
+            runs.iter()
+                .filter_map(|r| match r {
+                    Value::Table(t) => parse_one(t),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    pub fn to_toml(&self) -> String {
+        self.0
+            .iter()
+            .map(|r| {
+                format!(
+                    "[[run]]\nat = {}\ninstance = {}\nprogram = {}\ncode = {}\n",
+                    r.at,
+                    quote(&r.instance),
+                    quote(&r.program),
+                    r.code
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Append `run`, dropping the oldest past `MAX_RUNS`.
+    pub fn add(&mut self, run: Run) {
+        self.0.push(run);
+        let excess = self.0.len().saturating_sub(MAX_RUNS);
+        self.0.drain(..excess);
+    }
+
+    /// Record a run of `command`, keeping only its program name. Like
+    /// stats, failing to save is only logged.
+    pub fn record(instance: &str, command: &str, code: u32) {
+        let at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut log = Self::load();
+        log.add(Run {
+            at,
+            instance: instance.into(),
+            program: program_name(command).into(),
+            code,
+        });
+        let res = save_state(&Self::path(), &log.to_toml());
+        if let Err(err) = res {
+            tracing::warn!("Failed to save the run log: {err}");
+        }
+    }
+}
+
+/// Name of the program `command` starts, skipping leading `VAR=value`
+/// assignments and the directory, e.g. `cargo` for `RUST_LOG=debug
+/// ~/.cargo/bin/cargo test`.
+pub fn program_name(command: &str) -> &str {
+    let program = command
+        .split_whitespace()
+        .find(|word| {
+            !word
+                .split_once('=')
+                .is_some_and(|(var, _)| is_var_name(var))
+        })
+        .unwrap_or_default();
+    program.rsplit('/').next().unwrap_or(program)
+}
+
+fn is_var_name(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Transport of a `Profile` that connected straight to the instance.
+pub const DIRECT: &str = "direct";
+
+/// Connection parameters that last worked for an instance, so the next
+/// connection skips detecting them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    /// The instance's id, or for a bastion, see `bastion_profile_id`.
+    pub instance_id: String,
+    pub user: String,
+    pub port: u16,
+    /// `DIRECT`, or the URL of the WebSocket relay.
+    pub transport: String,
+    /// SHA256 fingerprint of the instance's host key.
+    pub host_key: String,
+    /// Address last connected to.
+    pub address: String,
+    /// Whether commands run on a PTY print their output without a login
+    /// shell, once `run` probed it. See `Session::probe_stdout`.
+    pub plain_stdout: Option<bool>,
+}
+
+/// Id of the profile pinning the host key of the bastion at `host:port`.
+pub fn bastion_profile_id(host: &str, port: u16) -> String {
+    format!("bastion:{host}:{port}")
+}
+
+/// Connection profiles by instance, stored in `profiles.toml`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Profiles(pub Vec<Profile>);
+
+impl Profiles {
+    fn path() -> PathBuf {
+        state_dir().join("profiles.toml")
+    }
+
+    pub fn load() -> Profiles {
+        std::fs::read_to_string(Self::path())
+            .map(|src| Self::parse(&src))
+            .unwrap_or_default()
+    }
+
+    /// Malformed entries are skipped, like malformed runs.
+    pub fn parse(src: &str) -> Profiles {
+        let Ok(table) = toml::parse(src) else {
+            return Profiles::default();
+        };
+        let Some(Value::Array(profiles)) = table.get("profile").map(|i| &i.value) else {
+            return Profiles::default();
+        };
+        let parse_one = |t: &toml::Table| {
+            let string = |key: &str| match t.get(key).map(|i| &i.value) {
+                Some(Value::String(s)) => Some(s.clone()),
+                _ => None,
+            };
+            Some(Profile {
+                instance_id: string("instance_id")?,
+                user: string("user")?,
+                port: match t.get("port").map(|i| &i.value) {
+                    Some(Value::Integer(p)) => u16::try_from(*p).ok()?,
+                    _ => return None,
+                },
+                transport: string("transport")?,
+                host_key: string("host_key")?,
+                address: string("address")?,
+                plain_stdout: match t.get("plain_stdout").map(|i| &i.value) {
+                    Some(Value::Boolean(b)) => Some(*b),
+                    _ => None,
+                },
+            })
+        };
+        Profiles(
+            profiles
+                .iter()
+                .filter_map(|p| match p {
+                    Value::Table(t) => parse_one(t),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    pub fn to_toml(&self) -> String {
+        self.0
+            .iter()
+            .map(|p| {
+                let mut profile = format!(
+                    "[[profile]]\ninstance_id = {}\nuser = {}\nport = {}\ntransport = {}\nhost_key = {}\naddress = {}\n",
+                    quote(&p.instance_id),
+                    quote(&p.user),
+                    p.port,
+                    quote(&p.transport),
+                    quote(&p.host_key),
+                    quote(&p.address)
+                );
+                if let Some(plain) = p.plain_stdout {
+                    profile += &format!("plain_stdout = {plain}\n");
+                }
+                profile
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn get(&self, instance_id: &str) -> Option<&Profile> {
+        self.0.iter().find(|p| p.instance_id == instance_id)
+    }
+
+    /// Add `profile`, replacing the instance's previous one.
+    pub fn add(&mut self, profile: Profile) {
+        self.forget(&profile.instance_id);
+        self.0.push(profile);
+    }
+
+    /// Drop the profile of `instance_id`, returning whether it had one.
+    pub fn forget(&mut self, instance_id: &str) -> bool {
+        let before = self.0.len();
+        self.0.retain(|p| p.instance_id != instance_id);
+        self.0.len() != before
+    }
+
+    /// Save, only logging failures like stats.
+    pub fn save(&self) {
+        let res = save_state(&Self::path(), &self.to_toml());
+        if let Err(err) = res {
+            tracing::warn!("Failed to save connection profiles: {err}");
+        }
+    }
+
+    /// Remember whether `instance_id` prints output without a login
+    /// shell, if it has a profile.
+    pub fn record_plain_stdout(instance_id: &str, plain: bool) {
+        let mut profiles = Self::load();
+        if let Some(profile) = profiles.0.iter_mut().find(|p| p.instance_id == instance_id) {
+            profile.plain_stdout = Some(plain);
+            profiles.save();
+        }
+    }
+
+    /// Remember `profile` for next time.
+    pub fn record(profile: Profile) {
+        let mut profiles = Self::load();
+        if profiles.get(&profile.instance_id) != Some(&profile) {
+            profiles.add(profile);
+            profiles.save();
+        }
+    }
+}
+
+/// An ingress rule opened for the length of a session by `--open-port`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionRule {
+    pub group_id: String,
+    /// Port spec, eg. `8888/tcp`.
+    pub port: String,
+    pub cidr: String,
+    /// Process of the session, whose rules are abandoned once it's gone.
+    pub pid: u32,
+    /// When that process started, so a later one reusing `pid` isn't
+    /// taken for it. Unknown for rules recorded by older versions.
+    pub started: Option<String>,
+}
+
+/// Rules opened by sessions still running, or that never got to revoke
+/// them (eg. killed), stored in `session-rules.toml`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SessionRules(pub Vec<SessionRule>);
+
+impl SessionRules {
+    fn path() -> PathBuf {
+        state_dir().join("session-rules.toml")
+    }
+
+    pub fn load() -> SessionRules {
+        std::fs::read_to_string(Self::path())
+            .map(|src| Self::parse(&src))
+            .unwrap_or_default()
+    }
+
+    /// Malformed entries are skipped, like malformed profiles.
+    pub fn parse(src: &str) -> SessionRules {
+        let Ok(table) = toml::parse(src) else {
+            return SessionRules::default();
+        };
+        let Some(Value::Array(rules)) = table.get("rule").map(|i| &i.value) else {
+            return SessionRules::default();
+        };
+        let parse_one = |t: &toml::Table| {
+            let string = |key: &str| match t.get(key).map(|i| &i.value) {
+                Some(Value::String(s)) => Some(s.clone()),
+                _ => None,
+            };
+            Some(SessionRule {
+                group_id: string("group_id")?,
+                port: string("port")?,
+                cidr: string("cidr")?,
+                pid: match t.get("pid").map(|i| &i.value) {
+                    Some(Value::Integer(p)) => u32::try_from(*p).ok()?,
+                    _ => return None,
+                },
+                started: string("started"),
+            })
+        };
+        SessionRules(
+            rules
+                .iter()
+                .filter_map(|r| match r {
+                    Value::Table(t) => parse_one(t),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    pub fn to_toml(&self) -> String {
+        self.0
+            .iter()
+            .map(|r| {
+                let mut rule = format!(
+                    "[[rule]]\ngroup_id = {}\nport = {}\ncidr = {}\npid = {}\n",
+                    quote(&r.group_id),
+                    quote(&r.port),
+                    quote(&r.cidr),
+                    r.pid
+                );
+                if let Some(started) = &r.started {
+                    rule += &format!("started = {}\n", quote(started));
+                }
+                rule
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Save, only logging failures like stats.
+    pub fn save(&self) {
+        let res = save_state(&Self::path(), &self.to_toml());
+        if let Err(err) = res {
+            tracing::warn!("Failed to save session rules: {err}");
+        }
+    }
+
+    /// Load, change with `update` and save the rules, holding a lock so
+    /// concurrent sessions don't lose each other's changes.
+    fn update(update: impl FnOnce(&mut SessionRules)) {
+        let lock = std::fs::create_dir_all(state_dir()).and_then(|_| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(state_dir().join("session-rules.lock"))
+        });
+        // Released when the file is closed.
+        match &lock {
+            Ok(file) => unsafe {
+                libc::flock(file.as_raw_fd(), libc::LOCK_EX);
+            },
+            Err(err) => tracing::warn!("Failed to lock session rules: {err}"),
+        }
+        let mut stored = Self::load();
+        update(&mut stored);
+        stored.save();
+    }
+
+    /// Remember `rules` as opened by this process.
+    pub fn record(rules: &[SessionRule]) {
+        Self::update(|stored| stored.0.extend(rules.iter().cloned()));
+    }
+
+    /// Forget `rules`, once revoked.
+    pub fn forget(rules: &[SessionRule]) {
+        Self::update(|stored| stored.0.retain(|r| !rules.contains(r)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        program_name, Profile, Profiles, Run, RunLog, SessionRule, SessionRules, Stats, Timings,
+        DIRECT, MAX_RUNS,
+    };
+
+    #[test]
+    fn stats_round_trip() {
+        let stats = Stats {
+            transfer_bytes: 1 << 40,
+            jobs_succeeded: 3,
+            jobs_failed: 1,
+        };
+        pretty_assertions::assert_eq!(Stats::parse(&stats.to_toml()), stats);
+        pretty_assertions::assert_eq!(Stats::parse("jobs_failed = \"x\""), Stats::default());
+    }
+
+    #[test]
+    fn average_timings() {
+        let mut timings = Timings::default();
+        timings.add("ready", "t3.micro", Duration::from_secs(90));
+        timings.add("ready", "t3.micro", Duration::from_secs(120));
+        timings.add("stopped", "t3.micro", Duration::from_secs(30));
+
+        let timings = Timings::parse(&timings.to_toml());
+        let cases = [
+            ("ready", "t3.micro", Some(105)),
+            ("stopped", "t3.micro", Some(30)),
+            ("ready", "g5.xlarge", None),
+        ];
+        for (phase, instance_type, expected) in cases {
+            println!("phase = {phase}, instance_type = {instance_type}");
+            pretty_assertions::assert_eq!(
+                timings.expected(phase, instance_type),
+                expected.map(Duration::from_secs)
+            );
+        }
+        pretty_assertions::assert_eq!(timings.0[0].samples, 2);
+    }
+
+    #[test]
+    fn run_log_round_trip() {
+        let mut log = RunLog::default();
+        for i in 0..MAX_RUNS as u64 + 2 {
+            log.add(Run {
+                at: i,
+                instance: "happy:otter".into(),
+                program: "we\"ird\\name\n".into(),
+                code: (i % 2) as u32,
+            });
+        }
+        pretty_assertions::assert_eq!(log.0.len(), MAX_RUNS);
+        pretty_assertions::assert_eq!(log.0[0].at, 2);
+        pretty_assertions::assert_eq!(RunLog::parse(&log.to_toml()), log);
+
+        let cases = [
+            ("python3 -c \"print(1)\"", "python3"),
+            ("RUST_LOG=debug ~/.cargo/bin/cargo test", "cargo"),
+            ("./bench --token=s3cret", "bench"),
+            ("", ""),
+        ];
+        for (command, expected) in cases {
+            println!("command = {command}");
+            pretty_assertions::assert_eq!(program_name(command), expected);
+        }
+    }
+
+    #[test]
+    fn profiles_round_trip() {
+        let profile = |id: &str, user: &str| Profile {
+            instance_id: id.into(),
+            user: user.into(),
+            port: 22,
+            transport: DIRECT.into(),
+            host_key: "SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s".into(),
+            address: "ec2-1-2-3-4.compute.amazonaws.com".into(),
+            plain_stdout: (user == "admin").then_some(false),
+        };
+        let mut profiles = Profiles::default();
+        profiles.add(profile("i-1", "ubuntu"));
+        profiles.add(profile("i-2", "ec2-user"));
+        profiles.add(profile("i-1", "admin"));
+
+        pretty_assertions::assert_eq!(profiles.0.len(), 2);
+        pretty_assertions::assert_eq!(profiles.get("i-1").map(|p| p.user.as_str()), Some("admin"));
+        pretty_assertions::assert_eq!(Profiles::parse(&profiles.to_toml()), profiles);
+        assert!(profiles.forget("i-2"));
+        assert!(!profiles.forget("i-2"));
+    }
+
+    #[test]
+    fn session_rules_round_trip() {
+        let rules = SessionRules(vec![
+            SessionRule {
+                group_id: "sg-0123".into(),
+                port: "8888/tcp".into(),
+                cidr: "1.2.3.4/32".into(),
+                pid: 4242,
+                started: Some("123456".into()),
+            },
+            SessionRule {
+                group_id: "sg-0123".into(),
+                port: "9000-9100/udp".into(),
+                cidr: "2001:db8::1/128".into(),
+                pid: 4242,
+                started: None,
+            },
+        ]);
+        pretty_assertions::assert_eq!(SessionRules::parse(&rules.to_toml()), rules);
+        pretty_assertions::assert_eq!(
+            SessionRules::parse("[[rule]]\ngroup_id = \"sg-0123\"\npid = -1"),
+            SessionRules::default()
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Clean up remote output, which arrives in frames that may end in the
+//! middle of a multi-byte UTF-8 character or an ANSI escape sequence.
+
+/// Decodes UTF-8 across frames, holding back an incomplete trailing
+/// character until the rest of it arrives. Invalid bytes become U+FFFD.
+#[derive(Debug, Default)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    pub fn feed(&mut self, data: &[u8]) -> String {
+        self.pending.extend_from_slice(data);
+        let mut out = String::new();
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(s) => {
+                    out.push_str(s);
+                    self.pending.clear();
+                    break;
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    out.push_str(&String::from_utf8_lossy(&self.pending[..valid]));
+                    match e.error_len() {
+                        Some(len) => {
+                            out.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.drain(..valid + len);
+                        }
+                        // Truncated character, wait for the next frame.
+                        None => {
+                            self.pending.drain(..valid);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Flushes a character left truncated at the end of the stream.
+    pub fn finish(&mut self) -> String {
+        let out = String::from_utf8_lossy(&self.pending).to_string();
+        self.pending.clear();
+        out
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Escape {
+    #[default]
+    None,
+    /// Right after ESC.
+    Start,
+    /// `ESC (`, `ESC #` etc., ended by the next non-intermediate byte.
+    Intermediate,
+    /// `ESC [`, ended by a byte in `@`..=`~`.
+    Csi,
+    /// `ESC ]`, ended by BEL or `ESC \`.
+    Osc,
+    OscEsc,
+}
+
+/// Strips ANSI escape sequences and other control characters, and turns
+/// `\r\n` (as sent by a PTY) and lone `\r` (progress bars) into `\n`.
+#[derive(Debug, Default)]
+pub struct AnsiStripper {
+    escape: Escape,
+    /// A `\r` ended the last frame, so a leading `\n` is part of it.
+    after_cr: bool,
+}
+
+impl AnsiStripper {
+    pub fn feed(&mut self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            if std::mem::take(&mut self.after_cr) && c == '\n' {
+                continue;
+            }
+            self.escape = match (self.escape, c) {
+                (Escape::None, '\x1b') => Escape::Start,
+                (Escape::None, '\r') => {
+                    self.after_cr = true;
+                    out.push('\n');
+                    Escape::None
+                }
+                (Escape::None, c) => {
+                    if !c.is_control() || c == '\n' || c == '\t' {
+                        out.push(c);
+                    }
+                    Escape::None
+                }
+                (Escape::Start, '[') => Escape::Csi,
+                (Escape::Start, ']') => Escape::Osc,
+                (Escape::Start | Escape::Intermediate, ' '..='/') => Escape::Intermediate,
+                (Escape::Start | Escape::Intermediate, _) => Escape::None,
+                (Escape::Csi, '@'..='~') => Escape::None,
+                (Escape::Csi, _) => Escape::Csi,
+                (Escape::Osc, '\x07') => Escape::None,
+                (Escape::Osc, '\x1b') => Escape::OscEsc,
+                (Escape::Osc, _) => Escape::Osc,
+                (Escape::OscEsc, _) => Escape::None,
+            };
+        }
+        out
+    }
+}
+
+/// Decodes a remote stream to UTF-8, optionally stripping ANSI escapes
+/// for output that doesn't go to a terminal.
+#[derive(Debug, Default)]
+pub struct OutputGuard {
+    decoder: Utf8Decoder,
+    stripper: Option<AnsiStripper>,
+}
+
+impl OutputGuard {
+    pub fn new(strip_ansi: bool) -> Self {
+        OutputGuard {
+            decoder: Utf8Decoder::default(),
+            stripper: strip_ansi.then(AnsiStripper::default),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> String {
+        let text = self.decoder.feed(data);
+        match &mut self.stripper {
+            Some(s) => s.feed(&text),
+            None => text,
+        }
+    }
+
+    pub fn finish(&mut self) -> String {
+        let text = self.decoder.finish();
+        match &mut self.stripper {
+            Some(s) => s.feed(&text),
+            None => text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OutputGuard;
+
+    #[test]
+    fn guard_split_frames() {
+        let cases: [(&[&[u8]], bool, &str); 5] = [
+            // "é" and "✓" split across frames.
+            (&[b"caf\xc3", b"\xa9 \xe2\x9c", b"\x93"], false, "café ✓"),
+            (&[b"ok\xff\n"], false, "ok\u{fffd}\n"),
+            (&[b"\xe2\x9c"], false, "\u{fffd}"),
+            (
+                &[
+                    b"\x1b[1;3",
+                    b"2mred\x1b",
+                    b"[0m\r",
+                    b"\ndone\x1b]0;title\x07",
+                ],
+                true,
+                "red\ndone",
+            ),
+            (&[b"10%\r50%\r\x1b(B100%\r\n"], true, "10%\n50%\n100%\n"),
+        ];
+
+        for (frames, strip_ansi, expected) in cases {
+            println!("frames = {frames:?}, strip_ansi = {strip_ansi}");
+            let mut guard = OutputGuard::new(strip_ansi);
+            let mut out: String = frames.iter().map(|f| guard.feed(f)).collect();
+            out.push_str(&guard.finish());
+            pretty_assertions::assert_eq!(out, expected);
+        }
+    }
+}
@@ -0,0 +1,617 @@
+//! Incremental uploads for `Sync`: only files that differ from their remote
+//! copy are transferred.
+//!
+//! Files are compared by size and modification time, which is copied to
+//! the remote file after each upload. With `checksum`, same sized files
+//! are compared by SHA-256 instead. Remote digests are computed on the
+//! instance, by `sha256sum` runs batched over many files, falling back to
+//! reading the file over SFTP.
+//!
+//! When rsync is installed on both ends, `rsync` hands the transfer over
+//! to it instead, see `Rsync`.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use russh_sftp::{client::SftpSession, protocol::FileAttributes};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    progress::Progress,
+    ssh::{write_remote_file, Session},
+    state::state_dir,
+    util::{biject_paths, calc_prefix},
+};
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SyncSummary {
+    pub uploaded: u64,
+    pub bytes: u64,
+    pub unchanged: u64,
+    pub deleted: u64,
+}
+
+/// Size and modification time (seconds) of a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stat {
+    len: u64,
+    mtime: Option<u32>,
+}
+
+/// Whether the local file must be uploaded without comparing contents.
+/// Files without a known modification time are always uploaded.
+fn changed(local: Stat, remote: Option<Stat>, checksum: bool) -> bool {
+    match remote {
+        None => true,
+        Some(remote) if remote.len != local.len => true,
+        Some(_) if checksum => false,
+        Some(remote) => remote.mtime.is_none() || remote.mtime != local.mtime,
+    }
+}
+
+fn local_stat(path: &Path) -> std::io::Result<Stat> {
+    let meta = std::fs::metadata(path)?;
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as u32);
+    Ok(Stat {
+        len: meta.len(),
+        mtime,
+    })
+}
+
+fn local_digest(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+async fn remote_digest(sftp: &SftpSession, path: &str) -> anyhow::Result<String> {
+    let mut file = sftp.open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Longest command `remote_digests` runs, well below the 128 KiB Linux
+/// allows for a single argument (the command is run as `sh -c <command>`).
+const MAX_DIGEST_COMMAND: usize = 64 * 1024;
+
+/// Commands printing the SHA-256 of each of `paths` in `sha256sum`
+/// format, split so none is longer than `max_len` (unless a single path
+/// is). Files are hashed on all cores of the instance.
+fn digest_commands(paths: &[String], max_len: usize) -> Vec<String> {
+    const PREFIX: &str = "printf '%s\\0'";
+    const SUFFIX: &str = " | xargs -0 -n 64 -P \"$(nproc)\" sha256sum --";
+    let mut commands = vec![];
+    let mut command = String::from(PREFIX);
+    for path in paths {
+        let arg = format!(" {}", shell_escape::escape(path.as_str().into()));
+        if command.len() > PREFIX.len() && command.len() + arg.len() + SUFFIX.len() > max_len {
+            commands.push(command + SUFFIX);
+            command = String::from(PREFIX);
+        }
+        command.push_str(&arg);
+    }
+    if command.len() > PREFIX.len() {
+        commands.push(command + SUFFIX);
+    }
+    commands
+}
+
+/// Digests by path from `sha256sum` output. Lines of paths with a newline
+/// or backslash start with `\`, and have those escaped.
+fn parse_digests(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (escaped, line) = match line.strip_prefix('\\') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let (digest, path) = line.split_once("  ").or_else(|| line.split_once(" *"))?;
+            if digest.len() != 64 {
+                return None;
+            }
+            let path = if escaped {
+                unescape(path)
+            } else {
+                path.to_string()
+            };
+            Some((path, digest.to_string()))
+        })
+        .collect()
+}
+
+/// Undo the `\n` and `\\` escapes of `sha256sum`.
+fn unescape(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// SHA-256 of the remote `paths`, hashed on the instance in a few batched
+/// commands rather than read back one by one. Files that couldn't be
+/// hashed (eg. `sha256sum` is missing) are left out.
+async fn remote_digests(
+    session: &Session,
+    paths: &[String],
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut digests = HashMap::new();
+    for command in digest_commands(paths, MAX_DIGEST_COMMAND) {
+        // Exits non-zero when any file failed, the others are still listed.
+        let output = session.exec_output(&command).await?;
+        digests.extend(parse_digests(&String::from_utf8_lossy(&output.stdout)));
+    }
+    Ok(digests)
+}
+
+/// Remote files and directories under `root` whose local counterpart under
+/// `local_root` doesn't exist. Directories come before their contents.
+async fn stale_remote_paths(
+    sftp: &SftpSession,
+    root: &str,
+    local_root: &Path,
+) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let (mut files, mut dirs) = (vec![], vec![]);
+    let mut pending = vec![(root.to_string(), local_root.to_path_buf(), false)];
+    while let Some((dir, local_dir, stale)) = pending.pop() {
+        for entry in sftp.read_dir(&dir).await? {
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let remote = format!("{dir}/{name}");
+            let local = local_dir.join(&name);
+            let missing = stale || !local.exists();
+            if entry.file_type().is_dir() {
+                if missing {
+                    dirs.push(remote.clone());
+                }
+                pending.push((remote, local, missing));
+            } else if missing {
+                files.push(remote);
+            }
+        }
+    }
+    Ok((files, dirs))
+}
+
+/// Upload the files within `src` that changed to `dst`, laid out like
+/// `Session::upload`. With `delete`, remote files that no longer exist
+/// locally are removed. Files ignored by .gitignore or matching `exclude`
+/// are never uploaded, but aren't deleted either as long as they exist
+/// locally.
+#[tracing::instrument(skip(session), fields(phase = "transfer"))]
+pub async fn sync(
+    session: &Session,
+    src: Option<String>,
+    dst: Option<String>,
+    exclude: &[String],
+    checksum: bool,
+    delete: bool,
+) -> anyhow::Result<SyncSummary> {
+    let src_path = std::fs::canonicalize(src.unwrap_or(".".into()))?;
+    let prefix = calc_prefix(src_path.clone())?;
+
+    let sftp = session.open_sftp_session().await?;
+    let dst_abs_path = sftp.canonicalize(dst.unwrap_or(".".into())).await?;
+    if !sftp.metadata(&dst_abs_path).await?.is_dir() {
+        anyhow::bail!("Dst must be a dir!");
+    }
+
+    let mut summary = SyncSummary::default();
+    let mut pending: Vec<(PathBuf, String, Stat)> = vec![];
+    // Same sized files, to compare by checksum.
+    let mut compare: Vec<(PathBuf, String, Stat)> = vec![];
+    for entry in biject_paths(
+        src_path.to_str().unwrap(),
+        prefix.to_str().unwrap_or(""),
+        &dst_abs_path,
+        exclude,
+    )? {
+        let (local_pth, combined, is_dir) = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                tracing::error!("ERROR: {}", err);
+                continue;
+            }
+        };
+        let remote = combined.to_str().unwrap().to_string();
+        if is_dir {
+            if !sftp.try_exists(&remote).await.unwrap_or(false) {
+                sftp.create_dir(&remote).await?;
+            }
+            continue;
+        }
+
+        let stat = local_stat(&local_pth)?;
+        let remote_stat = sftp.metadata(&remote).await.ok().map(|m| Stat {
+            len: m.len(),
+            mtime: m.mtime,
+        });
+        if changed(stat, remote_stat, checksum) {
+            pending.push((local_pth, remote, stat));
+        } else if checksum {
+            compare.push((local_pth, remote, stat));
+        } else {
+            summary.unchanged += 1;
+        }
+    }
+
+    if !compare.is_empty() {
+        let paths: Vec<String> = compare.iter().map(|(_, r, _)| r.clone()).collect();
+        let digests = remote_digests(session, &paths).await?;
+        for (local_pth, remote, stat) in compare {
+            let remote_digest = match digests.get(&remote) {
+                Some(digest) => digest.clone(),
+                None => remote_digest(&sftp, &remote).await?,
+            };
+            if local_digest(&local_pth)? != remote_digest {
+                pending.push((local_pth, remote, stat));
+            } else {
+                summary.unchanged += 1;
+            }
+        }
+    }
+
+    let total_bytes = pending.iter().map(|(_, _, s)| s.len).sum();
+    let mut progress = Progress::new(pending.len(), total_bytes);
+    for (local_pth, remote, stat) in &pending {
+        tracing::info!("Uploading {:?} to {remote}", local_pth);
+        summary.bytes += write_remote_file(&sftp, local_pth, remote, &mut progress).await?;
+        summary.uploaded += 1;
+        if let Some(mtime) = stat.mtime {
+            let mut attrs = FileAttributes::empty();
+            attrs.atime = Some(mtime);
+            attrs.mtime = Some(mtime);
+            sftp.set_metadata(remote, attrs).await?;
+        }
+        progress.file_done();
+    }
+    progress.finish();
+
+    if delete {
+        let root_name = src_path.file_name().unwrap_or_default().to_string_lossy();
+        let root = format!("{dst_abs_path}/{root_name}");
+        if src_path.is_dir() && sftp.try_exists(&root).await? {
+            let (files, dirs) = stale_remote_paths(&sftp, &root, &src_path).await?;
+            for file in &files {
+                tracing::info!("Deleting {file}");
+                sftp.remove_file(file).await?;
+            }
+            for dir in dirs.iter().rev() {
+                tracing::info!("Deleting {dir}");
+                sftp.remove_dir(dir).await?;
+            }
+            summary.deleted = (files.len() + dirs.len()) as u64;
+        }
+    }
+
+    sftp.close().await?;
+    Ok(summary)
+}
+
+/// Sync through rsync, over the system `ssh` with korasi's key.
+///
+/// The `ssh` command reaches the instance like korasi's own client: on
+/// `port`, through `bastion`, with `certificate`, and only trusting
+/// `host_key` when it is known. The relay can't be reached by `ssh`, so
+/// callers sync over SFTP when one is set.
+pub struct Rsync {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub ssh_key: String,
+    /// User, host and port of the bastion to jump through.
+    pub bastion: Option<(String, String, u16)>,
+    pub certificate: Option<PathBuf>,
+    /// Host key of the instance in OpenSSH format.
+    pub host_key: Option<String>,
+    /// Host key of the bastion in OpenSSH format.
+    pub bastion_host_key: Option<String>,
+    pub exclude: Vec<String>,
+    pub checksum: bool,
+    pub delete: bool,
+}
+
+/// Name the host key of the instance is looked up by in the `known_hosts`
+/// file of `Rsync`, as host names are reused across instances.
+const HOST_KEY_ALIAS: &str = "korasi-instance";
+
+/// Like `HOST_KEY_ALIAS`, for the host key of the bastion.
+const BASTION_HOST_KEY_ALIAS: &str = "korasi-bastion";
+
+/// Write the `known_hosts` file trusting `host_key` and `bastion_host_key`
+/// for `Rsync`, in the state directory rather than the shared temp one,
+/// and only readable by the user. It is created afresh, never through an
+/// existing file or link.
+fn write_known_hosts(
+    host_key: Option<&str>,
+    bastion_host_key: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    let dir = state_dir();
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("known-hosts-{}", std::process::id()));
+    // Left behind by a crashed run that had the same pid.
+    let _ = std::fs::remove_file(&path);
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    if let Some(key) = host_key {
+        writeln!(file, "{HOST_KEY_ALIAS} {key}")?;
+    }
+    if let Some(key) = bastion_host_key {
+        writeln!(file, "{BASTION_HOST_KEY_ALIAS} {key}")?;
+    }
+    Ok(path)
+}
+
+impl Rsync {
+    /// The `ssh` command rsync connects with, checking the host keys that
+    /// are known against `known_hosts` when given.
+    fn ssh_command(&self, known_hosts: Option<&Path>) -> String {
+        let escape = |s: &str| shell_escape::escape(s.to_string().into()).to_string();
+        let mut ssh = format!("ssh -i {} -p {}", escape(&self.ssh_key), self.port);
+        if let Some(cert) = &self.certificate {
+            ssh += &format!(" -o CertificateFile={}", escape(&cert.to_string_lossy()));
+        }
+        if let Some((user, host, port)) = &self.bastion {
+            let check = match known_hosts.filter(|_| self.bastion_host_key.is_some()) {
+                Some(path) => format!(
+                    "-o StrictHostKeyChecking=yes -o HostKeyAlias={BASTION_HOST_KEY_ALIAS} \
+                     -o UserKnownHostsFile={}",
+                    escape(&path.to_string_lossy())
+                ),
+                None => "-o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null".into(),
+            };
+            let jump = format!(
+                "ProxyCommand=ssh -i {} -p {port} {check} -o LogLevel=ERROR -W %h:%p {user}@{host}",
+                escape(&self.ssh_key)
+            );
+            ssh += &format!(" -o {}", escape(&jump));
+        }
+        match known_hosts.filter(|_| self.host_key.is_some()) {
+            Some(path) => {
+                ssh += &format!(
+                    " -o StrictHostKeyChecking=yes -o HostKeyAlias={HOST_KEY_ALIAS} -o UserKnownHostsFile={}",
+                    escape(&path.to_string_lossy())
+                )
+            }
+            None => ssh += " -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null",
+        }
+        ssh + " -o LogLevel=ERROR"
+    }
+
+    /// Arguments laying out `src` under `dst` like `sync`.
+    pub fn args(&self, src: &Path, dst: Option<&str>, known_hosts: Option<&Path>) -> Vec<String> {
+        let mut args = vec![
+            "-az".to_string(),
+            "--stats".into(),
+            "-e".into(),
+            self.ssh_command(known_hosts),
+            // Skip what .gitignore files ignore, like uploads do.
+            "--filter=:- .gitignore".into(),
+        ];
+        args.extend(self.exclude.iter().map(|e| format!("--exclude={e}")));
+        if self.checksum {
+            args.push("--checksum".into());
+        }
+        if self.delete {
+            args.push("--delete".into());
+        }
+        // No trailing slash, so a directory is copied into `dst` by name.
+        args.push(src.to_string_lossy().trim_end_matches('/').to_string());
+        args.push(format!("{}@{}:{}", self.user, self.host, dst.unwrap_or("")));
+        args
+    }
+
+    /// Run rsync, after checking it is installed locally and on the
+    /// instance. Returns rsync's exit code.
+    #[tracing::instrument(skip_all, fields(phase = "transfer"))]
+    pub async fn run(
+        &self,
+        session: &Session,
+        src: Option<String>,
+        dst: Option<String>,
+    ) -> anyhow::Result<i32> {
+        if std::process::Command::new("rsync")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            anyhow::bail!("rsync is not installed locally, use `--mode sftp` instead.");
+        }
+        if session.exec_output("command -v rsync").await?.code != 0 {
+            anyhow::bail!("rsync is not installed on the instance, use `--mode sftp` instead.");
+        }
+
+        let src_path = std::fs::canonicalize(src.unwrap_or(".".into()))?;
+        let known_hosts = if self.host_key.is_some() || self.bastion_host_key.is_some() {
+            Some(write_known_hosts(
+                self.host_key.as_deref(),
+                self.bastion_host_key.as_deref(),
+            )?)
+        } else {
+            None
+        };
+        let args = self.args(&src_path, dst.as_deref(), known_hosts.as_deref());
+        tracing::info!("Running rsync {:?}", args);
+        // The runtime is single threaded, keep it free for the SSH session.
+        let status = tokio::task::spawn_blocking(move || {
+            std::process::Command::new("rsync").args(&args).status()
+        })
+        .await?;
+        if let Some(path) = known_hosts {
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(status?.code().unwrap_or(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{changed, digest_commands, parse_digests, Rsync, Stat};
+
+    #[test]
+    fn detect_changed_files() {
+        let stat = |len, mtime| Stat { len, mtime };
+        let local = stat(100, Some(1_700_000_000));
+        let cases = [
+            (None, false, true),
+            (Some(stat(100, Some(1_700_000_000))), false, false),
+            (Some(stat(100, Some(1_600_000_000))), false, true),
+            (Some(stat(100, None)), false, true),
+            (Some(stat(99, Some(1_700_000_000))), false, true),
+            (Some(stat(99, Some(1_700_000_000))), true, true),
+            // Same size, so the contents decide.
+            (Some(stat(100, Some(1_600_000_000))), true, false),
+        ];
+
+        for (remote, checksum, expected) in cases {
+            println!("remote = {remote:?}, checksum = {checksum}");
+            pretty_assertions::assert_eq!(changed(local, remote, checksum), expected);
+        }
+    }
+
+    #[test]
+    fn batch_digest_commands() {
+        let paths: Vec<String> = ["/src/a.rs", "/src/b c.rs", "/src/d.rs"]
+            .map(String::from)
+            .into();
+        let suffix = " | xargs -0 -n 64 -P \"$(nproc)\" sha256sum --";
+        pretty_assertions::assert_eq!(
+            digest_commands(&paths, 1024),
+            vec![format!(
+                "printf '%s\\0' /src/a.rs '/src/b c.rs' /src/d.rs{suffix}"
+            )]
+        );
+
+        let commands = digest_commands(&paths, 80);
+        pretty_assertions::assert_eq!(
+            commands,
+            vec![
+                format!("printf '%s\\0' /src/a.rs{suffix}"),
+                format!("printf '%s\\0' '/src/b c.rs'{suffix}"),
+                format!("printf '%s\\0' /src/d.rs{suffix}"),
+            ]
+        );
+        assert!(digest_commands(&[], 1024).is_empty());
+    }
+
+    #[test]
+    fn parse_sha256sum_output() {
+        let a = "a".repeat(64);
+        let b = "b".repeat(64);
+        let output = format!(
+            "{a}  /src/a.rs\n\\{b}  /src/new\\nline\\\\n.rs\nsha256sum: /src/gone.rs: No such file or directory\n"
+        );
+        let digests = parse_digests(&output);
+
+        pretty_assertions::assert_eq!(digests.len(), 2);
+        pretty_assertions::assert_eq!(digests.get("/src/a.rs"), Some(&a));
+        pretty_assertions::assert_eq!(digests.get("/src/new\nline\\n.rs"), Some(&b));
+    }
+
+    #[test]
+    fn build_rsync_args() {
+        let rsync = Rsync {
+            user: "ubuntu".into(),
+            host: "ec2-1-2-3-4.compute.amazonaws.com".into(),
+            port: 22,
+            ssh_key: "/home/me/.ssh/my key.pem".into(),
+            bastion: None,
+            certificate: None,
+            host_key: None,
+            bastion_host_key: None,
+            exclude: vec!["target".into()],
+            checksum: true,
+            delete: true,
+        };
+        let args = rsync.args(Path::new("/work/proj/"), Some("code"), None);
+
+        pretty_assertions::assert_eq!(
+            args,
+            vec![
+                "-az",
+                "--stats",
+                "-e",
+                "ssh -i '/home/me/.ssh/my key.pem' -p 22 -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null -o LogLevel=ERROR",
+                "--filter=:- .gitignore",
+                "--exclude=target",
+                "--checksum",
+                "--delete",
+                "/work/proj",
+                "ubuntu@ec2-1-2-3-4.compute.amazonaws.com:code",
+            ]
+        );
+    }
+
+    #[test]
+    fn rsync_ssh_like_korasi() {
+        let rsync = Rsync {
+            user: "ubuntu".into(),
+            host: "10.0.1.5".into(),
+            port: 2222,
+            ssh_key: "/k.pem".into(),
+            bastion: Some(("ec2-user".into(), "bastion.example.com".into(), 22)),
+            certificate: Some("/k-ca.pub".into()),
+            host_key: Some("ssh-ed25519 AAAA".into()),
+            bastion_host_key: Some("ssh-ed25519 BBBB".into()),
+            exclude: vec![],
+            checksum: false,
+            delete: false,
+        };
+        let args = rsync.args(Path::new("/src"), None, Some(Path::new("/tmp/known")));
+
+        pretty_assertions::assert_eq!(
+            args[3],
+            concat!(
+                "ssh -i /k.pem -p 2222 -o CertificateFile=/k-ca.pub",
+                " -o 'ProxyCommand=ssh -i /k.pem -p 22 -o StrictHostKeyChecking=yes",
+                " -o HostKeyAlias=korasi-bastion -o UserKnownHostsFile=/tmp/known",
+                " -o LogLevel=ERROR -W %h:%p ec2-user@bastion.example.com'",
+                " -o StrictHostKeyChecking=yes -o HostKeyAlias=korasi-instance",
+                " -o UserKnownHostsFile=/tmp/known -o LogLevel=ERROR",
+            )
+        );
+    }
+}
@@ -0,0 +1,497 @@
+//! Tracing setup, with optional export of spans over OTLP.
+//!
+//! Command handlers, EC2 calls and SSH operations are instrumented with
+//! `tracing` spans. In debug mode their durations are logged when they
+//! close. When `OTEL_EXPORTER_OTLP_ENDPOINT` (or the traces specific
+//! `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`) is set, finished spans are also
+//! sent to the collector as OTLP/HTTP JSON when the command exits. Only
+//! the program name of `command` fields is exported, never its arguments.
+//!
+//! Spans tagged with a `phase` field are also summed up. With `--timings`,
+//! or long commands in debug mode, a breakdown of where the time went is
+//! printed at the end.
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Write,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    span, Subscriber,
+};
+use tracing_subscriber::{
+    filter::LevelFilter, fmt::format::FmtSpan, layer::Context, prelude::*, registry::LookupSpan,
+    Layer,
+};
+
+use crate::{json::quote, progress::format_duration};
+
+const SERVICE_NAME: &str = "korasi";
+
+/// Phases of the timing summary, keyed by the `phase` field of spans.
+const PHASES: [(&str, &str); 5] = [
+    ("api", "API wait"),
+    ("boot", "instance boot"),
+    ("handshake", "SSH handshake"),
+    ("transfer", "transfer"),
+    ("exec", "remote execution"),
+];
+
+/// Spans kept for export; later ones are dropped until the next flush.
+const MAX_BUFFERED_SPANS: usize = 4096;
+
+/// Commands that finish quicker don't print the timing summary in debug
+/// mode.
+const SUMMARY_THRESHOLD: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FinishedSpan {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: Option<[u8; 8]>,
+    pub name: String,
+    pub start: u128,
+    pub end: u128,
+    pub attributes: Vec<(String, String)>,
+}
+
+/// Stored in the span extensions while the span is open.
+struct OpenSpan {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    start: u128,
+    attributes: Vec<(String, String)>,
+}
+
+struct Attributes<'a>(&'a mut Vec<(String, String)>);
+
+impl Visit for Attributes<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0
+            .push((field.name().into(), exported_value(field.name(), value)));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let value = format!("{value:?}");
+        self.0
+            .push((field.name().into(), exported_value(field.name(), &value)));
+    }
+}
+
+/// Commands may carry secrets in their arguments, so only the program (or
+/// subcommand) name leaves the machine.
+pub fn exported_value(field: &str, value: &str) -> String {
+    if field != "command" {
+        return value.into();
+    }
+    value
+        .trim_start_matches('"')
+        .split(|c: char| c.is_whitespace() || matches!(c, '"' | '{' | '('))
+        .next()
+        .unwrap_or_default()
+        .into()
+}
+
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos())
+}
+
+/// Random enough for span ids, without pulling in an RNG.
+fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(now_nanos());
+    hasher.finish()
+}
+
+/// Collects finished spans for [`OtlpExporter::flush`].
+struct OtlpLayer {
+    spans: Arc<Mutex<Vec<FinishedSpan>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|p| {
+            p.extensions()
+                .get::<OpenSpan>()
+                .map(|o| (o.trace_id, o.span_id))
+        });
+        let trace_id = parent.map_or_else(
+            || {
+                let mut id = [0; 16];
+                id[..8].copy_from_slice(&random_u64().to_be_bytes());
+                id[8..].copy_from_slice(&random_u64().to_be_bytes());
+                id
+            },
+            |(trace_id, _)| trace_id,
+        );
+
+        let mut attributes = vec![];
+        attrs.record(&mut Attributes(&mut attributes));
+        span.extensions_mut().insert(OpenSpan {
+            trace_id,
+            span_id: random_u64().to_be_bytes(),
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            start: now_nanos(),
+            attributes,
+        });
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(open) = span.extensions_mut().get_mut::<OpenSpan>() {
+                values.record(&mut Attributes(&mut open.attributes));
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(open) = span.extensions_mut().remove::<OpenSpan>() else {
+            return;
+        };
+        if let Ok(mut spans) = self.spans.lock() {
+            if spans.len() >= MAX_BUFFERED_SPANS {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            spans.push(FinishedSpan {
+                trace_id: open.trace_id,
+                span_id: open.span_id,
+                parent_span_id: open.parent_span_id,
+                name: span.name().to_string(),
+                start: open.start,
+                end: now_nanos(),
+                attributes: open.attributes,
+            });
+        }
+    }
+}
+
+/// Stored in the extensions of the outermost span of a phase.
+struct PhaseSpan {
+    index: usize,
+    start: Instant,
+}
+
+struct PhaseField(Option<usize>);
+
+impl Visit for PhaseField {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "phase" {
+            self.0 = PHASES.iter().position(|(key, _)| *key == value);
+        }
+    }
+
+    fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {}
+}
+
+/// Sums up the time spent in each phase.
+struct TimingLayer {
+    totals: Arc<Mutex<[Duration; PHASES.len()]>>,
+}
+
+impl<S> Layer<S> for TimingLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        // Nested phases, eg. an EC2 call made while waiting for boot, are
+        // already counted by the outer one.
+        if span
+            .scope()
+            .skip(1)
+            .any(|s| s.extensions().get::<PhaseSpan>().is_some())
+        {
+            return;
+        }
+
+        let mut phase = PhaseField(None);
+        attrs.record(&mut phase);
+        if let Some(index) = phase.0 {
+            span.extensions_mut().insert(PhaseSpan {
+                index,
+                start: Instant::now(),
+            });
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(phase) = span.extensions_mut().remove::<PhaseSpan>() else {
+            return;
+        };
+        if let Ok(mut totals) = self.totals.lock() {
+            totals[phase.index] += phase.start.elapsed();
+        }
+    }
+}
+
+fn format_secs(d: Duration) -> String {
+    if d < Duration::from_secs(60) {
+        format!("{:.1}s", d.as_secs_f64())
+    } else {
+        format_duration(d)
+    }
+}
+
+/// Time spent in each phase, with the remainder (local work, prompts)
+/// reported as `other`.
+pub fn format_breakdown(total: Duration, phases: &[Duration]) -> String {
+    let mut out = format!("Finished in {}\n", format_secs(total));
+    let mut rest = total;
+    for ((_, label), d) in PHASES.iter().zip(phases) {
+        if !d.is_zero() {
+            out.push_str(&format!("  {label:<18}{:>8}\n", format_secs(*d)));
+            rest = rest.saturating_sub(*d);
+        }
+    }
+    out.push_str(&format!("  {:<18}{:>8}\n", "other", format_secs(rest)));
+    out
+}
+
+pub struct OtlpExporter {
+    endpoint: String,
+    spans: Arc<Mutex<Vec<FinishedSpan>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl OtlpExporter {
+    /// Send the spans collected so far to the collector.
+    ///
+    /// Export failures are logged, never returned, so they can't fail the
+    /// command being traced.
+    pub async fn flush(&self) {
+        let spans = match self.spans.lock() {
+            Ok(mut spans) => std::mem::take(&mut *spans),
+            Err(_) => return,
+        };
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            eprintln!("Dropped {dropped} spans over the limit of {MAX_BUFFERED_SPANS}.");
+        }
+        if spans.is_empty() {
+            return;
+        }
+
+        let res = reqwest::Client::new()
+            .post(&self.endpoint)
+            .header("content-type", "application/json")
+            .body(encode_spans(&spans))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        if let Err(err) = res {
+            eprintln!("Failed to export traces to {}: {err}", self.endpoint);
+        }
+    }
+}
+
+fn traces_endpoint() -> Option<String> {
+    if let Ok(endpoint) = std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+        return Some(endpoint);
+    }
+    std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .map(|e| format!("{}/v1/traces", e.trim_end_matches('/')))
+}
+
+/// Handle to finish telemetry before exiting.
+pub struct Telemetry {
+    exporter: Option<OtlpExporter>,
+    totals: Arc<Mutex<[Duration; PHASES.len()]>>,
+    started: Instant,
+    debug: bool,
+    timings: bool,
+}
+
+impl Telemetry {
+    /// Export pending spans and print the timing summary, if asked for.
+    pub async fn finish(self) {
+        if let Some(exporter) = &self.exporter {
+            exporter.flush().await;
+        }
+
+        let total = self.started.elapsed();
+        if self.timings || (self.debug && total >= SUMMARY_THRESHOLD) {
+            if let Ok(totals) = self.totals.lock() {
+                eprint!("{}", format_breakdown(total, &*totals));
+            }
+        }
+    }
+}
+
+/// Install the global subscriber. Logs of `INFO` and above only go to
+/// stderr in `debug` mode. See `Telemetry::finish` for `timings`.
+pub fn init(debug: bool, timings: bool) -> Telemetry {
+    let fmt = debug.then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_filter(LevelFilter::INFO)
+    });
+    let exporter = traces_endpoint().map(|endpoint| OtlpExporter {
+        endpoint,
+        spans: Arc::default(),
+        dropped: Arc::default(),
+    });
+    let otlp = exporter.as_ref().map(|e| OtlpLayer {
+        spans: e.spans.clone(),
+        dropped: e.dropped.clone(),
+    });
+    let totals = Arc::default();
+    let timing = TimingLayer {
+        totals: Arc::clone(&totals),
+    };
+
+    tracing_subscriber::registry()
+        .with(fmt)
+        .with(otlp)
+        .with(timing)
+        .init();
+
+    Telemetry {
+        exporter,
+        totals,
+        started: Instant::now(),
+        debug,
+        timings,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Encode spans as an OTLP `ExportTraceServiceRequest` in JSON.
+pub fn encode_spans(spans: &[FinishedSpan]) -> String {
+    let attribute = |key: &str, value: &str| {
+        format!(
+            "{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}",
+            quote(key),
+            quote(value)
+        )
+    };
+
+    let spans = spans
+        .iter()
+        .map(|s| {
+            let mut json = format!(
+                "{{\"traceId\":\"{}\",\"spanId\":\"{}\",",
+                hex(&s.trace_id),
+                hex(&s.span_id)
+            );
+            if let Some(parent) = s.parent_span_id {
+                let _ = write!(json, "\"parentSpanId\":\"{}\",", hex(&parent));
+            }
+            let _ = write!(
+                json,
+                "\"name\":{},\"kind\":1,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[{}]}}",
+                quote(&s.name),
+                s.start,
+                s.end,
+                s.attributes
+                    .iter()
+                    .map(|(k, v)| attribute(k, v))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+            json
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(
+        "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{}]}},\"scopeSpans\":[{{\"scope\":{{\"name\":\"{SERVICE_NAME}\"}},\"spans\":[{spans}]}}]}}]}}",
+        attribute("service.name", SERVICE_NAME)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{encode_spans, exported_value, format_breakdown, FinishedSpan};
+
+    #[test]
+    fn export_program_name_only() {
+        let cases = [
+            ("command", "pg_dump --password hunter2 db", "pg_dump"),
+            ("command", "Run { command: \"ls\" }", "Run"),
+            ("command", "\"make -j8\"", "make"),
+            ("command", "", ""),
+            ("phase", "exec now", "exec now"),
+        ];
+        for (field, value, expected) in cases {
+            println!("{field} = {value}");
+            pretty_assertions::assert_eq!(exported_value(field, value), expected);
+        }
+    }
+
+    #[test]
+    fn phase_breakdown() {
+        let secs = Duration::from_secs;
+        pretty_assertions::assert_eq!(
+            format_breakdown(
+                secs(200),
+                &[secs(3), secs(150), Duration::ZERO, secs(20), secs(5)]
+            ),
+            concat!(
+                "Finished in 3m 20s\n",
+                "  API wait              3.0s\n",
+                "  instance boot       2m 30s\n",
+                "  transfer             20.0s\n",
+                "  remote execution      5.0s\n",
+                "  other                22.0s\n",
+            )
+        );
+    }
+
+    #[test]
+    fn encode_otlp_json() {
+        let span = FinishedSpan {
+            trace_id: [1; 16],
+            span_id: [0xab; 8],
+            parent_span_id: None,
+            name: "exec".into(),
+            start: 1,
+            end: 2,
+            attributes: vec![("command".into(), "echo \"hi\"\n".into())],
+        };
+
+        pretty_assertions::assert_eq!(
+            encode_spans(&[span]),
+            concat!(
+                r#"{"resourceSpans":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"korasi"}}]},"#,
+                r#""scopeSpans":[{"scope":{"name":"korasi"},"spans":[{"traceId":"01010101010101010101010101010101","#,
+                r#""spanId":"abababababababab","name":"exec","kind":1,"startTimeUnixNano":"1","endTimeUnixNano":"2","#,
+                r#""attributes":[{"key":"command","value":{"stringValue":"echo \"hi\"\n"}}]}]}]}]}"#,
+            )
+        );
+    }
+}
@@ -0,0 +1,56 @@
+//! Tiny `{{ var }}` templating used to render bundled scripts.
+
+use std::collections::HashMap;
+
+use crate::ec2::EC2Error;
+
+/// Replace every `{{ name }}` placeholder with its value in `vars`.
+///
+/// Unknown variables are an error, so typos in templates are caught
+/// before anything is sent to an instance.
+pub fn render(template: &str, vars: &HashMap<&str, String>) -> Result<String, EC2Error> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| EC2Error::new("Unclosed `{{` in template"))?;
+        let name = after[..end].trim();
+        let value = vars
+            .get(name)
+            .ok_or_else(|| EC2Error::new(format!("Unknown template variable `{name}`")))?;
+        out.push_str(value);
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::render;
+
+    #[test]
+    fn render_vars() {
+        let vars = HashMap::from([("name", "foo".to_string()), ("arch", "arm64".into())]);
+
+        let cases = [
+            ("no vars", Some("no vars")),
+            ("{{name}}-{{ arch }}", Some("foo-arm64")),
+            ("echo {{  name }} done", Some("echo foo done")),
+            ("{{ missing }}", None),
+            ("{{ name", None),
+        ];
+
+        for (input, expected) in cases {
+            println!("input = {input}");
+            pretty_assertions::assert_eq!(render(input, &vars).ok().as_deref(), expected);
+        }
+    }
+}
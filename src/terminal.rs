@@ -0,0 +1,182 @@
+//! Local terminal settings forwarded with PTY requests.
+
+use russh::Pty;
+
+/// Local `termios` control characters and their SSH opcodes (RFC 4254,
+/// section 8).
+#[cfg(unix)]
+const CONTROL_CHARS: &[(Pty, usize)] = &[
+    (Pty::VINTR, libc::VINTR),
+    (Pty::VQUIT, libc::VQUIT),
+    (Pty::VERASE, libc::VERASE),
+    (Pty::VKILL, libc::VKILL),
+    (Pty::VEOF, libc::VEOF),
+    (Pty::VEOL, libc::VEOL),
+    (Pty::VEOL2, libc::VEOL2),
+    (Pty::VSTART, libc::VSTART),
+    (Pty::VSTOP, libc::VSTOP),
+    (Pty::VSUSP, libc::VSUSP),
+    (Pty::VREPRINT, libc::VREPRINT),
+    (Pty::VWERASE, libc::VWERASE),
+    (Pty::VLNEXT, libc::VLNEXT),
+    (Pty::VDISCARD, libc::VDISCARD),
+];
+
+#[cfg(unix)]
+const INPUT_FLAGS: &[(Pty, libc::tcflag_t)] = &[
+    (Pty::IGNPAR, libc::IGNPAR),
+    (Pty::PARMRK, libc::PARMRK),
+    (Pty::INPCK, libc::INPCK),
+    (Pty::ISTRIP, libc::ISTRIP),
+    (Pty::INLCR, libc::INLCR),
+    (Pty::IGNCR, libc::IGNCR),
+    (Pty::ICRNL, libc::ICRNL),
+    (Pty::IXON, libc::IXON),
+    (Pty::IXANY, libc::IXANY),
+    (Pty::IXOFF, libc::IXOFF),
+    (Pty::IMAXBEL, libc::IMAXBEL),
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+    (Pty::IUTF8, libc::IUTF8),
+];
+
+#[cfg(unix)]
+const LOCAL_FLAGS: &[(Pty, libc::tcflag_t)] = &[
+    (Pty::ISIG, libc::ISIG),
+    (Pty::ICANON, libc::ICANON),
+    (Pty::ECHO, libc::ECHO),
+    (Pty::ECHOE, libc::ECHOE),
+    (Pty::ECHOK, libc::ECHOK),
+    (Pty::ECHONL, libc::ECHONL),
+    (Pty::NOFLSH, libc::NOFLSH),
+    (Pty::TOSTOP, libc::TOSTOP),
+    (Pty::IEXTEN, libc::IEXTEN),
+    (Pty::ECHOCTL, libc::ECHOCTL),
+    (Pty::ECHOKE, libc::ECHOKE),
+    (Pty::PENDIN, libc::PENDIN),
+];
+
+#[cfg(unix)]
+const OUTPUT_FLAGS: &[(Pty, libc::tcflag_t)] = &[
+    (Pty::OPOST, libc::OPOST),
+    (Pty::ONLCR, libc::ONLCR),
+    (Pty::OCRNL, libc::OCRNL),
+    (Pty::ONOCR, libc::ONOCR),
+    (Pty::ONLRET, libc::ONLRET),
+];
+
+#[cfg(unix)]
+const CONTROL_FLAGS: &[(Pty, libc::tcflag_t)] = &[
+    (Pty::CS7, libc::CS7),
+    (Pty::CS8, libc::CS8),
+    (Pty::PARENB, libc::PARENB),
+    (Pty::PARODD, libc::PARODD),
+];
+
+/// Baud rate of a `speed_t`, defaulting to 38400 like OpenSSH.
+#[cfg(unix)]
+fn baud(speed: libc::speed_t) -> u32 {
+    match speed {
+        libc::B300 => 300,
+        libc::B1200 => 1200,
+        libc::B2400 => 2400,
+        libc::B4800 => 4800,
+        libc::B9600 => 9600,
+        libc::B19200 => 19200,
+        libc::B57600 => 57600,
+        libc::B115200 => 115200,
+        libc::B230400 => 230400,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        libc::B460800 => 460800,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        libc::B921600 => 921600,
+        _ => 38400,
+    }
+}
+
+/// Encode terminal settings as SSH terminal modes.
+#[cfg(unix)]
+pub fn encode_modes(t: &libc::termios) -> Vec<(Pty, u32)> {
+    let mut modes: Vec<(Pty, u32)> = CONTROL_CHARS
+        .iter()
+        .map(|(op, i)| (*op, t.c_cc[*i] as u32))
+        .collect();
+    let flags = [
+        (INPUT_FLAGS, t.c_iflag),
+        (LOCAL_FLAGS, t.c_lflag),
+        (OUTPUT_FLAGS, t.c_oflag),
+    ];
+    for (table, value) in flags {
+        modes.extend(table.iter().map(|(op, f)| (*op, (value & f != 0) as u32)));
+    }
+    // CS7 and CS8 are values of the CSIZE mask rather than single bits.
+    modes.extend(CONTROL_FLAGS.iter().map(|(op, f)| {
+        let set = match *op {
+            Pty::CS7 | Pty::CS8 => t.c_cflag & libc::CSIZE == *f,
+            _ => t.c_cflag & f != 0,
+        };
+        (*op, set as u32)
+    }));
+
+    // SAFETY: `t` is a valid termios.
+    let (ispeed, ospeed) = unsafe { (libc::cfgetispeed(t), libc::cfgetospeed(t)) };
+    modes.push((Pty::TTY_OP_ISPEED, baud(ispeed)));
+    modes.push((Pty::TTY_OP_OSPEED, baud(ospeed)));
+    modes
+}
+
+/// Terminal modes of stdin, or none when it isn't a terminal.
+///
+/// Must be called before the terminal is put in raw mode, otherwise the
+/// remote PTY would be raw as well and echo nothing.
+pub fn local_modes() -> Vec<(Pty, u32)> {
+    #[cfg(unix)]
+    {
+        // SAFETY: termios is plain data, filled in by tcgetattr.
+        let mut t: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut t) } == 0 {
+            return encode_modes(&t);
+        }
+    }
+    vec![]
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use russh::Pty;
+
+    use super::{baud, encode_modes};
+
+    #[test]
+    fn encode_terminal_modes() {
+        // SAFETY: termios is plain data.
+        let mut t: libc::termios = unsafe { std::mem::zeroed() };
+        t.c_cc[libc::VINTR] = 3;
+        t.c_lflag = libc::ICANON | libc::ECHO;
+        t.c_iflag = libc::ICRNL;
+        t.c_cflag = libc::CS8;
+
+        // SAFETY: `t` is a valid termios.
+        unsafe { libc::cfsetspeed(&mut t, libc::B115200) };
+
+        let modes = encode_modes(&t);
+        let mode = |op: Pty| modes.iter().find(|(o, _)| *o == op).map(|(_, v)| *v);
+        let cases = [
+            (Pty::VINTR, Some(3)),
+            (Pty::ECHO, Some(1)),
+            (Pty::ICANON, Some(1)),
+            (Pty::ISIG, Some(0)),
+            (Pty::ICRNL, Some(1)),
+            (Pty::CS8, Some(1)),
+            (Pty::CS7, Some(0)),
+            (Pty::TTY_OP_ISPEED, Some(115200)),
+            (Pty::TTY_OP_OSPEED, Some(115200)),
+        ];
+
+        for (op, expected) in cases {
+            println!("op = {op:?}");
+            pretty_assertions::assert_eq!(mode(op), expected);
+        }
+        // Unknown speeds fall back to 38400.
+        pretty_assertions::assert_eq!(baud(libc::B0), 38400);
+    }
+}
@@ -0,0 +1,566 @@
+//! Parser for the subset of TOML used by `korasi.toml`.
+//!
+//! Supports comments, `[tables]`, `[[arrays.of.tables]]`, dotted keys,
+//! basic/literal strings, integers, floats, booleans, (multi-line) arrays
+//! and inline tables. Dates and multi-line strings are not supported.
+//!
+//! Every key remembers the line it was defined on, so config errors can
+//! point at the offending line.
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+        }
+    }
+}
+
+/// A value along with the line (1-based) it was defined on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub line: usize,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table(pub BTreeMap<String, Item>);
+
+impl Table {
+    pub fn get(&self, key: &str) -> Option<&Item> {
+        self.0.get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Item)> {
+        self.0.iter()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(line: usize, message: impl Into<String>) -> Self {
+        ParseError {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse(input: &str) -> Result<Table, ParseError> {
+    Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+        line: 1,
+    }
+    .parse()
+}
+
+/// `s` as a basic string, escaped so `parse` reads it back unchanged.
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn err(&self, message: impl Into<String>) -> ParseError {
+        ParseError::new(self.line, message)
+    }
+
+    fn expect(&mut self, want: char) -> Result<(), ParseError> {
+        match self.bump() {
+            Some(c) if c == want => Ok(()),
+            Some(c) => Err(self.err(format!("expected `{want}`, found `{c}`"))),
+            None => Err(self.err(format!("expected `{want}`, found end of file"))),
+        }
+    }
+
+    /// Skip spaces and tabs on the current line.
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip whitespace, newlines and comments.
+    fn skip_all(&mut self) {
+        loop {
+            self.skip_ws();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n' | '\r') => {
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Only whitespace and a comment may follow a key/value or header.
+    fn expect_eol(&mut self) -> Result<(), ParseError> {
+        self.skip_ws();
+        self.skip_comment();
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some('\r') if self.peek_at(1) == Some('\n') => Ok(()),
+            Some(c) => Err(self.err(format!("unexpected `{c}` after value"))),
+        }
+    }
+
+    fn parse(mut self) -> Result<Table, ParseError> {
+        let mut root = Table::default();
+        let mut current: Vec<String> = vec![];
+
+        loop {
+            self.skip_all();
+            match self.peek() {
+                None => break,
+                Some('[') => {
+                    let line = self.line;
+                    self.bump();
+                    let is_array = self.peek() == Some('[');
+                    if is_array {
+                        self.bump();
+                    }
+                    self.skip_ws();
+                    let path = self.parse_key_path()?;
+                    self.skip_ws();
+                    self.expect(']')?;
+                    if is_array {
+                        self.expect(']')?;
+                        let (last, parent) = path.split_last().unwrap();
+                        let parent = table_at(&mut root, parent, line)?;
+                        let item = parent.0.entry(last.clone()).or_insert(Item {
+                            line,
+                            value: Value::Array(vec![]),
+                        });
+                        match &mut item.value {
+                            Value::Array(arr) => arr.push(Value::Table(Table::default())),
+                            other => {
+                                return Err(ParseError::new(
+                                    line,
+                                    format!(
+                                        "`{}` is already defined as a {}",
+                                        path.join("."),
+                                        other.type_name()
+                                    ),
+                                ))
+                            }
+                        }
+                    } else {
+                        table_at(&mut root, &path, line)?;
+                    }
+                    current = path;
+                    self.expect_eol()?;
+                }
+                Some(_) => {
+                    let line = self.line;
+                    let table = table_at(&mut root, &current, line)?;
+                    self.parse_key_value(table)?;
+                    self.expect_eol()?;
+                }
+            }
+        }
+
+        Ok(root)
+    }
+
+    fn parse_key_value(&mut self, table: &mut Table) -> Result<(), ParseError> {
+        let line = self.line;
+        let path = self.parse_key_path()?;
+        self.skip_ws();
+        self.expect('=')?;
+        self.skip_ws();
+        let value = self.parse_value()?;
+
+        let (last, parent) = path.split_last().unwrap();
+        let table = table_at(table, parent, line)?;
+        if table.0.contains_key(last) {
+            return Err(ParseError::new(
+                line,
+                format!("duplicate key `{}`", path.join(".")),
+            ));
+        }
+        table.0.insert(last.clone(), Item { line, value });
+        Ok(())
+    }
+
+    fn parse_key_path(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut path = vec![self.parse_key()?];
+        loop {
+            self.skip_ws();
+            if self.peek() != Some('.') {
+                break;
+            }
+            self.bump();
+            self.skip_ws();
+            path.push(self.parse_key()?);
+        }
+        Ok(path)
+    }
+
+    fn parse_key(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some('"') => self.parse_basic_string(),
+            Some('\'') => self.parse_literal_string(),
+            _ => {
+                let mut key = String::new();
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        key.push(c);
+                        self.bump();
+                    } else {
+                        break;
+                    }
+                }
+                if key.is_empty() {
+                    return Err(self.err("expected a key"));
+                }
+                Ok(key)
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('"') => self.parse_basic_string().map(Value::String),
+            Some('\'') => self.parse_literal_string().map(Value::String),
+            Some('[') => self.parse_array(),
+            Some('{') => self.parse_inline_table(),
+            Some('t' | 'f') => {
+                let word = self.take_while(|c| c.is_ascii_alphabetic());
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => Err(self.err(format!("invalid value `{word}`"))),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' => self.parse_number(),
+            Some(c) => Err(self.err(format!("invalid value starting with `{c}`"))),
+            None => Err(self.err("expected a value, found end of file")),
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let mut s = String::new();
+        while let Some(c) = self.peek() {
+            if !f(c) {
+                break;
+            }
+            s.push(c);
+            self.bump();
+        }
+        s
+    }
+
+    fn parse_number(&mut self) -> Result<Value, ParseError> {
+        let raw = self.take_while(|c| c.is_ascii_alphanumeric() || "+-._".contains(c));
+        let cleaned = raw.replace('_', "");
+        if let Ok(i) = cleaned.parse::<i64>() {
+            return Ok(Value::Integer(i));
+        }
+        cleaned
+            .parse::<f64>()
+            .map(Value::Float)
+            .map_err(|_| self.err(format!("invalid number `{raw}`")))
+    }
+
+    fn parse_basic_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err(self.err("unterminated string")),
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let c = match self.bump() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('u') => {
+                            let hex: String = (0..4).filter_map(|_| self.bump()).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.err(format!("invalid escape `\\u{hex}`")))?
+                        }
+                        Some(c) => return Err(self.err(format!("invalid escape `\\{c}`"))),
+                        None => return Err(self.err("unterminated string")),
+                    };
+                    s.push(c);
+                }
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn parse_literal_string(&mut self) -> Result<String, ParseError> {
+        self.expect('\'')?;
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err(self.err("unterminated string")),
+                Some('\'') => return Ok(s),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, ParseError> {
+        self.expect('[')?;
+        let mut values = vec![];
+        loop {
+            self.skip_all();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(values));
+            }
+            values.push(self.parse_value()?);
+            self.skip_all();
+            match self.bump() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return Err(self.err("expected `,` or `]` in array")),
+            }
+        }
+    }
+
+    fn parse_inline_table(&mut self) -> Result<Value, ParseError> {
+        self.expect('{')?;
+        let mut table = Table::default();
+        self.skip_ws();
+        if self.peek() == Some('}') {
+            self.bump();
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_ws();
+            self.parse_key_value(&mut table)?;
+            self.skip_ws();
+            match self.bump() {
+                Some(',') => {}
+                Some('}') => return Ok(Value::Table(table)),
+                _ => return Err(self.err("expected `,` or `}` in inline table")),
+            }
+        }
+    }
+}
+
+/// Walk (and create) nested tables along `path`. Arrays of tables
+/// resolve to their last element, like TOML does.
+fn table_at<'a>(
+    root: &'a mut Table,
+    path: &[String],
+    line: usize,
+) -> Result<&'a mut Table, ParseError> {
+    let mut table = root;
+    for (i, key) in path.iter().enumerate() {
+        let item = table.0.entry(key.clone()).or_insert(Item {
+            line,
+            value: Value::Table(Table::default()),
+        });
+        let type_name = item.value.type_name();
+        let not_a_table = || {
+            ParseError::new(
+                line,
+                format!(
+                    "`{}` is already defined as a {type_name}",
+                    path[..=i].join(".")
+                ),
+            )
+        };
+        table = match &mut item.value {
+            Value::Table(t) => t,
+            Value::Array(arr) => match arr.last_mut() {
+                Some(Value::Table(t)) => t,
+                _ => return Err(not_a_table()),
+            },
+            _ => return Err(not_a_table()),
+        };
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, quote, Value};
+
+    #[test]
+    fn quote_round_trip() {
+        let cases = [
+            "plain",
+            "say \"hi\"",
+            "C:\\path",
+            "two\nlines\ttab\r",
+            "bell\u{7}",
+        ];
+        for s in cases {
+            println!("s = {s:?}");
+            let root = parse(&format!("key = {}", quote(s))).unwrap();
+            pretty_assertions::assert_eq!(
+                root.get("key").map(|i| &i.value),
+                Some(&Value::String(s.into()))
+            );
+        }
+    }
+
+    #[test]
+    fn parse_document() {
+        let doc = r#"
+# comment
+name = "korasi" # trailing
+literal = 'C:\path'
+count = 1_000
+ratio = 0.5
+enabled = true
+ports = [
+    22,
+    8080, # jupyter
+]
+
+[verify]
+commands = ["cargo --version", "echo \"hi\""]
+timeout.secs = 60
+
+[[hooks]]
+run = "a"
+
+[[hooks]]
+run = "b"
+env = { A = "1", B = 2 }
+"#;
+        let root = parse(doc).unwrap();
+
+        pretty_assertions::assert_eq!(
+            root.get("name").unwrap().value,
+            Value::String("korasi".into())
+        );
+        pretty_assertions::assert_eq!(root.get("name").unwrap().line, 3);
+        pretty_assertions::assert_eq!(
+            root.get("literal").unwrap().value,
+            Value::String("C:\\path".into())
+        );
+        pretty_assertions::assert_eq!(root.get("count").unwrap().value, Value::Integer(1000));
+        pretty_assertions::assert_eq!(root.get("ratio").unwrap().value, Value::Float(0.5));
+        pretty_assertions::assert_eq!(
+            root.get("ports").unwrap().value,
+            Value::Array(vec![Value::Integer(22), Value::Integer(8080)])
+        );
+
+        let Value::Table(verify) = &root.get("verify").unwrap().value else {
+            panic!("verify is not a table");
+        };
+        pretty_assertions::assert_eq!(
+            verify.get("commands").unwrap().value,
+            Value::Array(vec![
+                Value::String("cargo --version".into()),
+                Value::String("echo \"hi\"".into())
+            ])
+        );
+        assert!(matches!(
+            verify.get("timeout").unwrap().value,
+            Value::Table(_)
+        ));
+
+        let Value::Array(hooks) = &root.get("hooks").unwrap().value else {
+            panic!("hooks is not an array");
+        };
+        pretty_assertions::assert_eq!(hooks.len(), 2);
+        let Value::Table(second) = &hooks[1] else {
+            panic!("hook is not a table");
+        };
+        assert!(matches!(second.get("env").unwrap().value, Value::Table(_)));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("a = ", 1),
+            ("a = 1\na = 2", 2),
+            ("\n\nb = \"open", 3),
+            ("c = [1, 2", 1),
+            ("d = 1 2", 1),
+            ("e = 1\n[e]", 2),
+        ];
+
+        for (input, line) in cases {
+            println!("input = {input:?}");
+            let err = parse(input).unwrap_err();
+            println!("err = {err}");
+            pretty_assertions::assert_eq!(err.line, line);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Human friendly sizes, rates and durations for flags and config, eg.
+//! `200GiB`, `125MiB/s` or `1h30m`.
+//!
+//! Bare numbers keep the unit the flag had before units were accepted, so
+//! `--disk-size 200` still means 200 GiB.
+
+use std::time::Duration;
+
+pub const KIB: u64 = 1024;
+pub const MIB: u64 = 1024 * KIB;
+pub const GIB: u64 = 1024 * MIB;
+
+const SIZE_UNITS: &[(&str, u64)] = &[
+    ("b", 1),
+    ("kb", 1000),
+    ("mb", 1_000_000),
+    ("gb", 1_000_000_000),
+    ("tb", 1_000_000_000_000),
+    ("k", KIB),
+    ("m", MIB),
+    ("g", GIB),
+    ("t", 1024 * GIB),
+    ("kib", KIB),
+    ("mib", MIB),
+    ("gib", GIB),
+    ("tib", 1024 * GIB),
+];
+
+const DURATION_UNITS: &[(&str, u64)] = &[
+    ("ms", 1),
+    ("s", 1000),
+    ("m", 60_000),
+    ("h", 3_600_000),
+    ("d", 86_400_000),
+];
+
+/// Split `12.5GiB` into `(12.5, "gib")`.
+fn split_number(s: &str) -> Option<(f64, String)> {
+    let s = s.trim();
+    let end = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let value = s[..end].parse::<f64>().ok()?;
+    Some((value, s[end..].trim().to_ascii_lowercase()))
+}
+
+/// Size in bytes. Bare numbers are in `default_unit` bytes.
+pub fn parse_size(s: &str, default_unit: u64) -> Result<u64, String> {
+    let (value, unit) = split_number(s).ok_or_else(|| format!("invalid size `{s}`"))?;
+    let multiplier = if unit.is_empty() {
+        default_unit
+    } else {
+        SIZE_UNITS
+            .iter()
+            .find(|(u, _)| *u == unit)
+            .map(|(_, m)| *m)
+            .ok_or_else(|| format!("invalid size `{s}`, expected a unit like MB, GiB or TiB"))?
+    };
+    Ok((value * multiplier as f64).round() as u64)
+}
+
+/// Rate in bytes per second, eg. `10MB/s`. Bare numbers are in
+/// `default_unit` bytes per second.
+pub fn parse_rate(s: &str, default_unit: u64) -> Result<u64, String> {
+    let size = s.trim().strip_suffix("/s").unwrap_or(s);
+    parse_size(size, default_unit)
+        .map_err(|_| format!("invalid rate `{s}`, expected eg. `10MB/s` or `125MiB/s`"))
+}
+
+/// Duration made of one or more `<number><unit>` parts, eg. `90s` or
+/// `1h30m`. A bare number is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let err = || format!("invalid duration `{s}`, expected eg. `90s`, `5m` or `1h30m`");
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(err());
+    }
+    if let Ok(secs) = rest.parse::<f64>() {
+        return Duration::try_from_secs_f64(secs).map_err(|_| err());
+    }
+
+    let mut millis = 0.0;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .ok_or_else(err)?;
+        let value: f64 = rest[..digits].parse().map_err(|_| err())?;
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit = rest[..unit_len].to_ascii_lowercase();
+        let (_, multiplier) = DURATION_UNITS
+            .iter()
+            .find(|(u, _)| *u == unit)
+            .ok_or_else(err)?;
+        millis += value * *multiplier as f64;
+        rest = &rest[unit_len..];
+    }
+    Ok(Duration::from_millis(millis.round() as u64))
+}
+
+/// `--disk-size`: whole GiB, as EBS volumes are sized.
+pub fn parse_disk_size(s: &str) -> Result<i32, String> {
+    let bytes = parse_size(s, GIB)?;
+    if bytes % GIB != 0 {
+        return Err(format!(
+            "invalid size `{s}`, volumes are sized in whole GiB"
+        ));
+    }
+    i32::try_from(bytes / GIB).map_err(|_| format!("size `{s}` is too large"))
+}
+
+/// `--throughput`: whole MiB/s, as EBS throughput is provisioned.
+pub fn parse_throughput(s: &str) -> Result<i32, String> {
+    let rate = parse_rate(s, MIB)?;
+    if rate % MIB != 0 {
+        return Err(format!(
+            "invalid rate `{s}`, throughput is set in whole MiB/s"
+        ));
+    }
+    i32::try_from(rate / MIB).map_err(|_| format!("rate `{s}` is too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse_disk_size, parse_duration, parse_rate, parse_size, GIB, MIB};
+
+    #[test]
+    fn parse_units() {
+        let sizes = [
+            ("200", Ok(200 * GIB)),
+            ("200GiB", Ok(200 * GIB)),
+            ("1.5 TiB", Ok(1536 * GIB)),
+            ("500MB", Ok(500_000_000)),
+            (
+                "12XB",
+                Err("invalid size `12XB`, expected a unit like MB, GiB or TiB".to_string()),
+            ),
+            ("GiB", Err("invalid size `GiB`".to_string())),
+        ];
+        for (input, expected) in sizes {
+            println!("size = {input}");
+            pretty_assertions::assert_eq!(parse_size(input, GIB), expected);
+        }
+
+        pretty_assertions::assert_eq!(parse_rate("10MB/s", MIB), Ok(10_000_000));
+        pretty_assertions::assert_eq!(parse_rate("125", MIB), Ok(125 * MIB));
+        pretty_assertions::assert_eq!(parse_disk_size("1TiB"), Ok(1024));
+        assert!(parse_disk_size("1.5GiB").is_err());
+
+        let durations = [
+            ("90", Ok(Duration::from_secs(90))),
+            ("90s", Ok(Duration::from_secs(90))),
+            ("1h30m", Ok(Duration::from_secs(5400))),
+            ("8h", Ok(Duration::from_secs(8 * 3600))),
+            ("250ms", Ok(Duration::from_millis(250))),
+            (
+                "5 minutes",
+                Err(
+                    "invalid duration `5 minutes`, expected eg. `90s`, `5m` or `1h30m`".to_string(),
+                ),
+            ),
+            (
+                "h",
+                Err("invalid duration `h`, expected eg. `90s`, `5m` or `1h30m`".to_string()),
+            ),
+        ];
+        for (input, expected) in durations {
+            println!("duration = {input}");
+            pretty_assertions::assert_eq!(parse_duration(input), expected);
+        }
+    }
+}
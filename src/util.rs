@@ -2,19 +2,29 @@
 
 use ignore::Error;
 use std::{
+    collections::HashMap,
     fmt::{self, Display},
     io::Write,
+    net::IpAddr,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use aws_sdk_ec2::types::{
-    Image, Instance, InstanceStateName, InstanceType, KeyFormat, KeyPairInfo, KeyType,
+    Address, Image, Instance, InstanceStateName, InstanceType, KeyFormat, KeyPairInfo, KeyType,
 };
-use ignore::Walk;
-use inquire::{InquireError, MultiSelect, Select};
+use aws_types::SdkConfig as AwsSdkConfig;
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
+use inquire::{Confirm, InquireError, MultiSelect, Select};
 
-use crate::ec2::SSH_KEY_NAME;
-use crate::ec2::{EC2Error, EC2Impl as EC2};
+use crate::ec2::{EC2Error, EC2Impl as EC2, Ec2Api, InstanceHealth, TagSelector};
+use crate::{
+    copy::{find_instance, named},
+    pricing::{hourly_prices, on_demand_hourly},
+    progress::format_duration,
+    ssh::ConnectOptions,
+    state::Profiles,
+};
 
 #[derive(Default)]
 pub struct UtilImpl;
@@ -22,7 +32,18 @@ pub struct UtilImpl;
 impl UtilImpl {
     /// Utility to perform a GET request and return the body as UTF-8, or an appropriate EC2Error.
     pub async fn do_get(url: &str) -> Result<String, EC2Error> {
-        reqwest::get(url)
+        Self::do_get_from(url, None).await
+    }
+
+    /// Like `do_get`, connecting from `local_address` when given, eg. the
+    /// unspecified address of a family to only go over IPv4 or IPv6.
+    pub async fn do_get_from(url: &str, local_address: Option<IpAddr>) -> Result<String, EC2Error> {
+        reqwest::Client::builder()
+            .local_address(local_address)
+            .build()
+            .map_err(|e| EC2Error::new(format!("Could not build a client for {url}: {e:?}")))?
+            .get(url)
+            .send()
             .await
             .map_err(|e| EC2Error::new(format!("Could not request ip from {url}: {e:?}")))?
             .error_for_status()
@@ -44,7 +65,7 @@ impl UtilImpl {
         save_location: String,
     ) -> Result<Option<KeyPairInfo>, EC2Error> {
         match ec2
-            .create_key_pair(SSH_KEY_NAME, KeyType::Ed25519, KeyFormat::Pem)
+            .create_key_pair(ec2.key_name(), KeyType::Ed25519, KeyFormat::Pem)
             .await
         {
             Ok((info, material)) => {
@@ -58,7 +79,7 @@ impl UtilImpl {
             Err(err) => {
                 // NOTE: This assumes user already saved the private key locally.
                 tracing::warn!("No key pair is created. Err = {}", err);
-                let output = ec2.list_key_pair(SSH_KEY_NAME).await?;
+                let output = ec2.list_key_pair(ec2.key_name()).await?;
                 if !output.is_empty() {
                     tracing::info!(
                         "Reuse existing key pair: {:?}",
@@ -75,6 +96,48 @@ impl UtilImpl {
 }
 
 #[cfg(unix)]
+impl UtilImpl {
+    /// Like `create_or_get_keypair`, but import the public key at
+    /// `public_key_path` rather than have EC2 create one.
+    pub async fn import_or_get_keypair(
+        ec2: &EC2,
+        public_key_path: &str,
+    ) -> Result<Option<KeyPairInfo>, EC2Error> {
+        let public_key = std::fs::read_to_string(public_key_path).map_err(|e| {
+            EC2Error::new(format!("Failed to read public key {public_key_path} ({e})"))
+        })?;
+        match ec2.import_key_pair(ec2.key_name(), &public_key).await {
+            Ok(info) => Ok(Some(info)),
+            Err(err) => {
+                tracing::warn!("No key pair is imported. Err = {}", err);
+                let output = ec2.list_key_pair(ec2.key_name()).await?;
+                if let Some(info) = output.first() {
+                    tracing::warn!(
+                        "Reuse existing key pair {:?}, which may not match {public_key_path}. Use --key-name to import it under another name.",
+                        info.key_name().unwrap_or_default()
+                    );
+                    Ok(Some(info.clone()))
+                } else {
+                    tracing::error!("No instance is created since no key pair could be imported.");
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+/// Private key next to the OpenSSH public key at `public_key_path`.
+pub fn private_key_path(public_key_path: &str) -> anyhow::Result<String> {
+    public_key_path
+        .strip_suffix(".pub")
+        .map(str::to_string)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "{public_key_path} is not a .pub file, pass its private key with --ssh-key"
+            )
+        })
+}
+
 fn open_file_with_perm(path: &PathBuf, mode: u32) -> Result<std::fs::File, EC2Error> {
     use std::os::unix::fs::OpenOptionsExt;
     std::fs::OpenOptions::new()
@@ -105,20 +168,169 @@ impl Display for ScenarioImage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{}: {}",
+            "{}: {} ({}, {})",
             self.0.name().unwrap_or("(unknown)"),
-            self.0.description().unwrap_or("unknown")
+            self.0.description().unwrap_or("unknown"),
+            self.0.image_id().unwrap_or("unknown"),
+            self.0.creation_date().unwrap_or("unknown")
         )
     }
 }
 
+/// Instance type shown with its hourly on-demand and spot prices, when
+/// known, so machines aren't picked blind to cost.
+#[derive(PartialEq, Debug, Clone)]
+pub struct MachineOption {
+    pub instance_type: InstanceType,
+    pub on_demand: Option<f64>,
+    pub spot: Option<f64>,
+}
+
+impl Display for MachineOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.instance_type.as_str();
+        match (self.on_demand, self.spot) {
+            (None, None) => write!(f, "{name}"),
+            (Some(d), None) => write!(f, "{name:<16} ${d:.4}/h"),
+            (None, Some(s)) => write!(f, "{name:<16} spot ${s:.4}/h"),
+            (Some(d), Some(s)) => write!(f, "{name:<16} ${d:.4}/h, spot ${s:.4}/h"),
+        }
+    }
+}
+
+/// Address doesn't impl Display either.
+#[derive(PartialEq, Debug, Clone)]
+pub struct AddressOption(pub Address);
+
+impl Display for AddressOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({})",
+            self.0.public_ip().unwrap_or("unknown"),
+            self.0.allocation_id().unwrap_or("unknown"),
+        )?;
+        match self.0.instance_id() {
+            Some(id) => write!(f, " -> {id}"),
+            None => write!(f, ", unassociated"),
+        }
+    }
+}
+
+pub async fn select_address(ec2: &EC2, prompt: &str) -> anyhow::Result<Address> {
+    let mut options: Vec<AddressOption> = ec2
+        .describe_addresses()
+        .await?
+        .into_iter()
+        .map(AddressOption)
+        .collect();
+
+    match options.len() {
+        0 => anyhow::bail!("No elastic IPs allocated, run `eip allocate` first."),
+        // Still confirm, associating or releasing isn't undone by a retry.
+        1 => {
+            let address = options.remove(0);
+            let ok = Confirm::new(&format!("Use {address}?"))
+                .with_default(false)
+                .prompt()?;
+            if !ok {
+                return Err(InquireError::OperationCanceled.into());
+            }
+            Ok(address.0)
+        }
+        _ => Ok(Select::new(prompt, options).with_vim_mode(true).prompt()?.0),
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct SelectOption {
     pub name: String,
     pub instance_id: String,
     pub public_dns_name: Option<String>,
+    pub private_ip: Option<String>,
+    /// AMI the instance was launched from.
+    pub image_id: Option<String>,
     state: Option<InstanceStateName>,
     instance_type: Option<InstanceType>,
+    /// Value of the `application` tag.
+    project: Option<String>,
+    /// Unix time the instance was last started.
+    launch_time: Option<i64>,
+    /// Why the instance can't take SSH connections right now, eg. failing
+    /// status checks, see `annotate_health`.
+    trouble: Option<String>,
+}
+
+impl SelectOption {
+    /// Public DNS name to connect to.
+    pub fn host(&self) -> anyhow::Result<String> {
+        self.public_dns_name
+            .clone()
+            .filter(|h| !h.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("{} has no public DNS name", self.instance_id))
+    }
+
+    /// Address to connect to with `connect`: the private IP through a
+    /// bastion, which sits in the instance's network, otherwise the public
+    /// DNS name.
+    pub fn address(&self, connect: &ConnectOptions) -> anyhow::Result<String> {
+        if connect.bastion.is_none() {
+            return self.host();
+        }
+        self.private_ip
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("{} has no private IP", self.instance_id))
+    }
+
+    /// Uptime and hourly cost of running instances, eg. `up 2h 05m, $0.096/h`.
+    fn hints(&self) -> Option<String> {
+        if self.state != Some(InstanceStateName::Running) {
+            return None;
+        }
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?
+            .as_secs() as i64;
+        let mut hints = vec![];
+        if let Some(launched) = self.launch_time {
+            let uptime = std::time::Duration::from_secs((now - launched).max(0) as u64);
+            hints.push(format!("up {}", format_duration(uptime)));
+        }
+        if let Some(hourly) = self.hourly() {
+            hints.push(format!("${hourly:.3}/h"));
+        }
+        (!hints.is_empty()).then(|| hints.join(", "))
+    }
+
+    pub fn state(&self) -> Option<&InstanceStateName> {
+        self.state.as_ref()
+    }
+
+    pub fn instance_type(&self) -> Option<&str> {
+        self.instance_type.as_ref().map(|t| t.as_str())
+    }
+
+    /// On-demand price of the instance type, if known.
+    pub fn hourly(&self) -> Option<f64> {
+        self.instance_type
+            .as_ref()
+            .and_then(|t| on_demand_hourly(t.as_str()))
+    }
+}
+
+/// Count and on-demand cost of `instances`, eg. `3 instance(s), about
+/// $0.288/h`.
+pub fn spend_summary(instances: &[SelectOption]) -> String {
+    let total = instances
+        .iter()
+        .filter_map(|i| i.hourly())
+        .fold(0.0, |a, b| a + b);
+    let unknown = instances.iter().filter(|i| i.hourly().is_none()).count();
+    let mut summary = format!("{} instance(s), about ${total:.3}/h", instances.len());
+    if unknown > 0 {
+        summary.push_str(&format!(" (price of {unknown} unknown)"));
+    }
+    summary
 }
 
 impl fmt::Display for SelectOption {
@@ -131,7 +343,14 @@ impl fmt::Display for SelectOption {
             self.instance_type.as_ref().unwrap(),
             self.instance_id,
             status
-        )
+        )?;
+        if let Some(hints) = self.hints() {
+            write!(f, " ({hints})")?;
+        }
+        if let Some(trouble) = &self.trouble {
+            write!(f, " [{trouble}]")?;
+        }
+        Ok(())
     }
 }
 
@@ -141,13 +360,18 @@ impl From<Instance> for SelectOption {
             state: value.state().unwrap().name().cloned(),
             instance_id: value.instance_id().unwrap().to_string(),
             public_dns_name: value.public_dns_name().map(str::to_string),
+            private_ip: value.private_ip_address().map(str::to_string),
+            image_id: value.image_id().map(str::to_string),
+            launch_time: value.launch_time().map(|t| t.secs()),
             ..SelectOption::default()
         };
 
         opt.instance_type = value.instance_type().cloned();
         for t in value.tags() {
-            if t.key() == Some("Name") {
-                opt.name = t.value().unwrap().to_owned();
+            match t.key() {
+                Some("Name") => opt.name = t.value().unwrap().to_owned(),
+                Some("application") => opt.project = t.value().map(str::to_string),
+                _ => {}
             }
         }
 
@@ -155,6 +379,14 @@ impl From<Instance> for SelectOption {
     }
 }
 
+/// Instance types of `instances`, for timing waits on them.
+pub fn instance_types(instances: &[SelectOption]) -> Vec<String> {
+    instances
+        .iter()
+        .filter_map(|i| i.instance_type().map(str::to_string))
+        .collect()
+}
+
 /// Express list of instance ids as a comma separated string.
 pub fn ids_to_str(ids: Vec<SelectOption>) -> String {
     ids.iter()
@@ -163,21 +395,212 @@ pub fn ids_to_str(ids: Vec<SelectOption>) -> String {
         .join(",")
 }
 
+/// Leave instances that can't take SSH connections out of the pickers,
+/// rather than only flagging them.
+static HIDE_UNHEALTHY: AtomicBool = AtomicBool::new(false);
+
+pub fn hide_unhealthy() {
+    HIDE_UNHEALTHY.store(true, Ordering::Relaxed);
+}
+
+/// Flag running `options` failing their status checks, per `health`, or
+/// without any address to connect to, public or (through a bastion)
+/// private.
+fn annotate_health(options: &mut [SelectOption], health: &HashMap<String, InstanceHealth>) {
+    for opt in options
+        .iter_mut()
+        .filter(|o| o.state == Some(InstanceStateName::Running))
+    {
+        opt.trouble = match health.get(&opt.instance_id) {
+            Some(h) if h.is_impaired() => Some(h.to_string()),
+            _ if opt.host().is_err() && opt.private_ip.is_none() => Some("no address".into()),
+            _ => None,
+        };
+    }
+}
+
+/// Annotate `options` with their health, then drop the troubled ones when
+/// hiding them. Health lookup failures only cost the annotations.
+async fn check_health(ec2: &impl Ec2Api, mut options: Vec<SelectOption>) -> Vec<SelectOption> {
+    let running: Vec<String> = options
+        .iter()
+        .filter(|o| o.state == Some(InstanceStateName::Running))
+        .map(|o| o.instance_id.clone())
+        .collect();
+    let health = match ec2.describe_instance_health(running).await {
+        Ok(health) => health,
+        Err(err) => {
+            tracing::debug!("Could not check instance health: {err}");
+            HashMap::new()
+        }
+    };
+    annotate_health(&mut options, &health);
+    if HIDE_UNHEALTHY.load(Ordering::Relaxed) {
+        let before = options.len();
+        options.retain(|o| o.trouble.is_none());
+        if options.len() < before {
+            println!(
+                "Hiding {} instance(s) that can't take SSH connections.",
+                before - options.len()
+            );
+        }
+    }
+    options
+}
+
+/// Entry of the instance pickers. Instances are listed in groups, each led
+/// by a header that can't be picked.
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
+enum InstanceChoice {
+    Header(String),
+    Instance(SelectOption),
+    /// Picks every instance but the ticked ones, in `multi_select_instances`.
+    /// Selecting all (→) and none (←) are built in.
+    AllExceptTicked,
+}
+
+impl fmt::Display for InstanceChoice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstanceChoice::Header(title) => write!(f, "── {title} ──"),
+            InstanceChoice::Instance(opt) => write!(f, "  {opt}"),
+            InstanceChoice::AllExceptTicked => write!(f, "(all except the ticked instances)"),
+        }
+    }
+}
+
+/// Order of the state groups in pickers.
+fn state_rank(state: Option<&InstanceStateName>) -> u8 {
+    match state {
+        Some(InstanceStateName::Running) => 0,
+        Some(InstanceStateName::Pending) => 1,
+        Some(InstanceStateName::Stopping) => 2,
+        Some(InstanceStateName::Stopped) => 3,
+        _ => 4,
+    }
+}
+
+/// Options grouped by state, then project tag, with a header per group.
+fn grouped_choices(mut options: Vec<SelectOption>) -> Vec<InstanceChoice> {
+    options.sort_by(|a, b| {
+        (state_rank(a.state.as_ref()), &a.project, &a.name).cmp(&(
+            state_rank(b.state.as_ref()),
+            &b.project,
+            &b.name,
+        ))
+    });
+
+    let mut choices = vec![];
+    let mut group = None;
+    for opt in options {
+        let key = (opt.state.clone(), opt.project.clone());
+        if group.as_ref() != Some(&key) {
+            let state = key.0.as_ref().map_or("unknown", |s| s.as_str());
+            let title = match &key.1 {
+                Some(project) => format!("{state} · {project}"),
+                None => state.to_string(),
+            };
+            choices.push(InstanceChoice::Header(title));
+            group = Some(key);
+        }
+        choices.push(InstanceChoice::Instance(opt));
+    }
+    choices
+}
+
+/// Instances in one of `statuses` with all of the `tags`, without
+/// prompting, for scripted operations on a group of instances.
+pub async fn tagged_instances(
+    ec2: &impl Ec2Api,
+    statuses: Vec<InstanceStateName>,
+    tags: &[TagSelector],
+) -> Result<Vec<SelectOption>, EC2Error> {
+    let instances = ec2.describe_instance_tagged(statuses, tags).await?;
+    let tags = tags
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!("{} instance(s) tagged {tags}.", instances.len());
+    Ok(instances.into_iter().map(|i| i.into()).collect())
+}
+
+/// All non-terminated instances, with the status checks of the running
+/// ones.
+pub async fn active_instances(
+    ec2: &impl Ec2Api,
+) -> Result<(Vec<Instance>, HashMap<String, InstanceHealth>), EC2Error> {
+    let instances = ec2.describe_instance_tagged(vec![], &[]).await?;
+    let health = ec2
+        .describe_instance_health(
+            instances
+                .iter()
+                .filter_map(|i| i.instance_id().map(str::to_string))
+                .collect(),
+        )
+        .await?;
+    Ok((instances, health))
+}
+
+/// Pick instances in one of `statuses` (all non-terminated when empty).
+///
+/// When `auto_select` is set, a single candidate is picked without
+/// prompting. With `confirm`, the selection is listed and has to be
+/// confirmed before `action` is carried out, otherwise the operation is
+/// canceled.
 pub async fn multi_select_instances(
     ec2: &EC2,
     prompt: &str,
     statuses: Vec<InstanceStateName>,
+    auto_select: bool,
+    confirm: Option<&str>,
 ) -> Result<Vec<SelectOption>, InquireError> {
     // Get all instances tagged by this tool.
-    let instances = ec2.describe_instance(statuses).await.unwrap();
-    let options: Vec<SelectOption> = instances.into_iter().map(|i| i.into()).collect();
+    let instances = ec2
+        .describe_instance(statuses)
+        .await
+        .map_err(|e| InquireError::Custom(e.into()))?;
+    let options = check_health(ec2, instances.into_iter().map(|i| i.into()).collect()).await;
 
-    if options.len() == 1 {
-        return Ok(vec![options[0].to_owned()]);
+    let chosen = if auto_select && options.len() == 1 {
+        options
+    } else {
+        let mut choices = grouped_choices(options.clone());
+        if options.len() > 1 {
+            choices.push(InstanceChoice::AllExceptTicked);
+        }
+        let picked = MultiSelect::new(prompt, choices)
+            .with_vim_mode(true)
+            .prompt()?;
+
+        let mut invert = false;
+        let mut ticked = vec![];
+        for choice in picked {
+            match choice {
+                InstanceChoice::Instance(opt) => ticked.push(opt.instance_id),
+                InstanceChoice::AllExceptTicked => invert = true,
+                InstanceChoice::Header(_) => {}
+            }
+        }
+        options
+            .into_iter()
+            .filter(|o| ticked.contains(&o.instance_id) != invert)
+            .collect()
+    };
+
+    if let Some(action) = confirm {
+        if !chosen.is_empty() {
+            println!("The following instances will be {action}:");
+            for c in &chosen {
+                println!("  {} ({})", c.instance_id, c.name);
+            }
+            if !Confirm::new("Proceed?").with_default(false).prompt()? {
+                return Err(InquireError::OperationCanceled);
+            }
+        }
     }
-    MultiSelect::new(prompt, options)
-        .with_vim_mode(true)
-        .prompt()
+    Ok(chosen)
 }
 
 pub async fn select_instance(
@@ -185,25 +608,226 @@ pub async fn select_instance(
     prompt: &str,
     statuses: Vec<InstanceStateName>,
 ) -> Result<SelectOption, InquireError> {
-    let instances = ec2.describe_instance(statuses).await.unwrap();
-    let options: Vec<SelectOption> = instances.into_iter().map(|i| i.into()).collect();
+    let instances = ec2
+        .describe_instance(statuses)
+        .await
+        .map_err(|e| InquireError::Custom(e.into()))?;
+    let options = check_health(ec2, instances.into_iter().map(|i| i.into()).collect()).await;
 
     if options.len() == 1 {
         return Ok(options[0].to_owned());
     }
-    Select::new(prompt, options).with_vim_mode(true).prompt()
+
+    let choices = grouped_choices(options);
+    // Start on the first instance rather than its group header.
+    let mut cursor = 1;
+    loop {
+        let picked = Select::new(prompt, choices.clone())
+            .with_vim_mode(true)
+            .with_starting_cursor(cursor)
+            .raw_prompt()?;
+        match picked.value {
+            InstanceChoice::Instance(opt) => return Ok(opt),
+            // Picking a header moves on to its first instance.
+            _ => cursor = (picked.index + 1).min(choices.len() - 1),
+        }
+    }
+}
+
+/// Pick an instance type. On-demand prices are those of the region of
+/// `config`, see `hourly_prices`, spot prices come from the region's
+/// current spot price history.
+pub async fn select_machine(
+    ec2: &EC2,
+    config: &AwsSdkConfig,
+    prompt: &str,
+) -> anyhow::Result<InstanceType> {
+    let types: Vec<String> = InstanceType::values()
+        .iter()
+        .map(|t| t.to_string())
+        .collect();
+    let (on_demand, spot) = tokio::join!(hourly_prices(config, &types), ec2.describe_spot_prices());
+    let spot = spot.unwrap_or_else(|err| {
+        tracing::warn!("Could not get spot prices: {err}");
+        Default::default()
+    });
+    let options: Vec<MachineOption> = InstanceType::values()
+        .iter()
+        .map(|t| MachineOption {
+            instance_type: InstanceType::from(*t),
+            on_demand: on_demand.get(*t).copied(),
+            spot: spot.get(*t).copied(),
+        })
+        .collect();
+
+    let chosen = Select::new(prompt, options).with_vim_mode(true).prompt()?;
+    Ok(chosen.instance_type)
+}
+
+/// Like `find_instance`, but asks which one is meant when several
+/// instances share the name and there is a terminal to ask on.
+pub fn pick_instance(instances: &[SelectOption], name: &str) -> anyhow::Result<SelectOption> {
+    let matches = named(instances, name);
+    if matches.len() > 1 && termion::is_tty(&std::io::stdin()) {
+        let options: Vec<SelectOption> = matches.into_iter().cloned().collect();
+        let prompt = format!("Several instances are named `{name}`, which one?");
+        return Ok(Select::new(&prompt, options).with_vim_mode(true).prompt()?);
+    }
+    find_instance(instances, name).cloned()
+}
+
+/// Most recent images shown by `select_image`.
+const MAX_IMAGE_OPTIONS: usize = 25;
+
+pub async fn select_image(
+    ec2: &EC2,
+    prompt: &str,
+    owner: &str,
+    architectures: Vec<String>,
+    name_pattern: &str,
+) -> anyhow::Result<Image> {
+    let mut images = ec2
+        .describe_images(owner, architectures, name_pattern)
+        .await?;
+    if images.is_empty() {
+        anyhow::bail!("No images owned by {owner} match `{name_pattern}`.");
+    }
+    images.truncate(MAX_IMAGE_OPTIONS);
+
+    let options: Vec<ScenarioImage> = images.into_iter().map(|i| i.into()).collect();
+    let chosen = Select::new(prompt, options).with_vim_mode(true).prompt()?;
+    Ok(chosen.0)
+}
+
+/// How long `stop_on_exit` waits for the user to keep the instance.
+const STOP_GRACE_PERIOD: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// Stop `instance_ids` (comma separated) once a session is over, unless the
+/// user presses Enter within the grace period. `yes` stops right away.
+pub async fn stop_on_exit(ec2: &EC2, instance_ids: &str, yes: bool) -> Result<(), EC2Error> {
+    if !yes {
+        println!(
+            "Stopping {instance_ids} in {}s. Press Enter to keep it running.",
+            STOP_GRACE_PERIOD.as_secs()
+        );
+        // Read on a plain thread: it is left blocked on stdin if nobody
+        // answers, which must not hold up the runtime on exit.
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut line = String::new();
+            if std::io::stdin().read_line(&mut line).is_ok() {
+                let _ = tx.send(());
+            }
+        });
+        let answered = tokio::task::spawn_blocking(move || rx.recv_timeout(STOP_GRACE_PERIOD))
+            .await
+            .is_ok_and(|r| r.is_ok());
+        if answered {
+            println!("Leaving {instance_ids} running.");
+            return Ok(());
+        }
+    }
+    ec2.stop_instances(instance_ids, false).await
+}
+
+/// Login users of common distros, matched against the AMI name,
+/// description and platform details.
+const DISTRO_USERS: &[(&str, &str)] = &[
+    ("ubuntu", "ubuntu"),
+    ("debian", "admin"),
+    ("centos", "centos"),
+    ("fedora", "fedora"),
+    ("rocky", "rocky"),
+    ("bitnami", "bitnami"),
+    ("amzn", "ec2-user"),
+    ("amazon linux", "ec2-user"),
+    ("al2023", "ec2-user"),
+    ("red hat", "ec2-user"),
+    ("rhel", "ec2-user"),
+    ("suse", "ec2-user"),
+    ("almalinux", "ec2-user"),
+];
+
+/// Login users by AMI owner, for images whose name says nothing.
+const OWNER_USERS: &[(&str, &str)] = &[
+    ("099720109477", "ubuntu"),   // Canonical
+    ("136693071363", "admin"),    // Debian
+    ("137112412989", "ec2-user"), // Amazon
+    ("309956199498", "ec2-user"), // Red Hat
+];
+
+/// The default login user of an AMI, if its distro is recognized.
+pub fn default_user(image: &Image) -> Option<&'static str> {
+    let text = [image.name(), image.description(), image.platform_details()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    DISTRO_USERS
+        .iter()
+        .find(|(needle, _)| text.contains(needle))
+        .or_else(|| {
+            OWNER_USERS
+                .iter()
+                .find(|(id, _)| image.owner_id() == Some(id))
+        })
+        .map(|(_, user)| *user)
+}
+
+/// User to connect to `instance` as: `--user`, else `[launch] user` from
+/// korasi.toml, else the one of its connection profile, else detected
+/// from its AMI, else ubuntu.
+pub async fn resolve_user(
+    ec2: &EC2,
+    user: Option<String>,
+    configured: Option<&String>,
+    instance: &SelectOption,
+) -> String {
+    if let Some(user) = user.or(configured.cloned()) {
+        return user;
+    }
+    if let Some(profile) = Profiles::load().get(&instance.instance_id) {
+        return profile.user.clone();
+    }
+    let detected = match &instance.image_id {
+        Some(image_id) => match ec2.describe_image(image_id).await {
+            Ok(image) => default_user(&image),
+            Err(err) => {
+                tracing::debug!("Could not describe {image_id}: {err}");
+                None
+            }
+        },
+        None => None,
+    };
+    let user = detected.unwrap_or("ubuntu").to_string();
+    tracing::info!("Connecting to {} as {user}", instance.name);
+    user
 }
 
 pub fn calc_prefix(pth: PathBuf) -> std::io::Result<PathBuf> {
     Ok(pth.parent().unwrap_or(Path::new("")).to_path_buf())
 }
 
+/// Local path, remote path and whether it is a directory.
+pub type PathPair = (PathBuf, PathBuf, bool);
+
+/// Pairs every path under `src_path`, other than those ignored by
+/// .gitignore or matching an `exclude` glob, with its path under
+/// `dst_folder`.
 pub fn biject_paths<'a>(
     src_path: &str,
     prefix: &'a str,
     dst_folder: &'a str,
-) -> Vec<Result<(PathBuf, PathBuf, bool), Error>> {
-    Walk::new(src_path)
+    exclude: &[String],
+) -> Result<Vec<Result<PathPair, Error>>, Error> {
+    let mut overrides = OverrideBuilder::new(src_path);
+    for glob in exclude {
+        overrides.add(&format!("!{glob}"))?;
+    }
+    Ok(WalkBuilder::new(src_path)
+        .overrides(overrides.build()?)
+        .build()
         .map(move |result| match result {
             Ok(entry) => {
                 let is_dir = match entry.metadata() {
@@ -227,19 +851,33 @@ pub fn biject_paths<'a>(
             }
             Err(err) => Err(err),
         })
-        .collect()
+        .collect())
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
+        collections::HashMap,
         fs::remove_file,
         path::{Path, PathBuf},
     };
 
     use crate::util::biject_paths;
 
-    use super::{calc_prefix, open_file_with_perm};
+    use aws_sdk_ec2::types::InstanceStateName;
+
+    use aws_sdk_ec2::types::{Image, Instance, InstanceType, SummaryStatus};
+
+    use super::{
+        active_instances, annotate_health, calc_prefix, default_user, grouped_choices, ids_to_str,
+        open_file_with_perm, spend_summary, tagged_instances, InstanceChoice, MachineOption,
+        SelectOption,
+    };
+    use crate::{
+        ec2::{Ec2Api, InstanceHealth, TagSelector},
+        mock::{instance, MockEc2},
+        ssh::ConnectOptions,
+    };
 
     #[test]
     fn open_readonly_file() {
@@ -308,7 +946,7 @@ mod tests {
         ];
 
         for (x, y, z) in cases {
-            for result in biject_paths(x, y, z) {
+            for result in biject_paths(x, y, z, &[]).unwrap() {
                 match result {
                     Ok(entry) => {
                         println!("entry = {:?}", entry);
@@ -321,4 +959,238 @@ mod tests {
             println!();
         }
     }
+
+    #[test]
+    fn group_picker_options() {
+        let opt = |name: &str, state, project: Option<&str>| SelectOption {
+            name: name.into(),
+            state: Some(state),
+            project: project.map(str::to_string),
+            ..SelectOption::default()
+        };
+        let choices = grouped_choices(vec![
+            opt("c", InstanceStateName::Stopped, Some("hpc")),
+            opt("b", InstanceStateName::Running, Some("web")),
+            opt("a", InstanceStateName::Running, Some("hpc")),
+            opt("d", InstanceStateName::Running, Some("hpc")),
+        ]);
+
+        let lines: Vec<String> = choices
+            .iter()
+            .map(|c| match c {
+                InstanceChoice::Instance(o) => o.name.clone(),
+                other => other.to_string(),
+            })
+            .collect();
+        pretty_assertions::assert_eq!(
+            lines,
+            vec![
+                "── running · hpc ──",
+                "a",
+                "d",
+                "── running · web ──",
+                "b",
+                "── stopped · hpc ──",
+                "c",
+            ]
+        );
+    }
+
+    #[test]
+    fn address_through_bastion() {
+        let opt = SelectOption {
+            instance_id: "i-1".into(),
+            public_dns_name: Some("a.example.com".into()),
+            private_ip: Some("10.0.0.5".into()),
+            ..SelectOption::default()
+        };
+        let bastion = ConnectOptions {
+            bastion: Some("ubuntu@jump.example.com".into()),
+            ..ConnectOptions::default()
+        };
+        let cases = [
+            (ConnectOptions::default(), "a.example.com"),
+            (bastion, "10.0.0.5"),
+        ];
+        for (connect, expected) in cases {
+            println!("connect = {connect:?}");
+            pretty_assertions::assert_eq!(opt.address(&connect).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn health_annotations() {
+        let opt = |id: &str, state, host: Option<&str>| SelectOption {
+            instance_id: id.into(),
+            state: Some(state),
+            public_dns_name: host.map(str::to_string),
+            ..SelectOption::default()
+        };
+        let mut options = vec![
+            opt("i-ok", InstanceStateName::Running, Some("a.example.com")),
+            opt(
+                "i-impaired",
+                InstanceStateName::Running,
+                Some("b.example.com"),
+            ),
+            opt("i-no-route", InstanceStateName::Running, None),
+            opt("i-stopped", InstanceStateName::Stopped, None),
+        ];
+        let health = HashMap::from([(
+            "i-impaired".to_string(),
+            InstanceHealth {
+                system_status: Some(SummaryStatus::Ok),
+                instance_status: Some(SummaryStatus::Impaired),
+                events: vec![],
+            },
+        )]);
+        annotate_health(&mut options, &health);
+
+        let cases = [
+            ("i-ok", None),
+            (
+                "i-impaired",
+                Some("IMPAIRED (system = ok, instance = impaired)"),
+            ),
+            ("i-no-route", Some("no address")),
+            ("i-stopped", None),
+        ];
+        for ((id, expected), opt) in cases.into_iter().zip(&options) {
+            println!("id = {id}");
+            pretty_assertions::assert_eq!(opt.trouble.as_deref(), expected);
+        }
+    }
+
+    #[test]
+    fn summarize_spend() {
+        let opt = |instance_type| SelectOption {
+            instance_type: Some(instance_type),
+            ..SelectOption::default()
+        };
+        let cases = [
+            (vec![], "0 instance(s), about $0.000/h"),
+            (
+                vec![opt(InstanceType::T3Micro), opt(InstanceType::T3Micro)],
+                "2 instance(s), about $0.021/h",
+            ),
+            (
+                vec![
+                    opt(InstanceType::T3Micro),
+                    opt(InstanceType::from("x9.huge")),
+                ],
+                "2 instance(s), about $0.010/h (price of 1 unknown)",
+            ),
+        ];
+
+        for (instances, expected) in cases {
+            println!("instances = {}", instances.len());
+            pretty_assertions::assert_eq!(spend_summary(&instances), expected);
+        }
+    }
+
+    #[test]
+    fn machine_option_prices() {
+        let option = |on_demand, spot| MachineOption {
+            instance_type: InstanceType::T3Micro,
+            on_demand,
+            spot,
+        };
+        let cases = [
+            (option(None, None), "t3.micro"),
+            (option(Some(0.0104), None), "t3.micro         $0.0104/h"),
+            (
+                option(None, Some(0.0031)),
+                "t3.micro         spot $0.0031/h",
+            ),
+            (
+                option(Some(0.0104), Some(0.0031)),
+                "t3.micro         $0.0104/h, spot $0.0031/h",
+            ),
+        ];
+
+        for (option, expected) in cases {
+            println!("option = {option:?}");
+            pretty_assertions::assert_eq!(option.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn detect_default_user() {
+        let image = |name: &str, owner: &str| {
+            Image::builder()
+                .name(name)
+                .owner_id(owner)
+                .platform_details("Linux/UNIX")
+                .build()
+        };
+        let cases = [
+            (
+                image(
+                    "ubuntu/images/hvm-ssd/ubuntu-jammy-22.04-amd64-server-20240207",
+                    "099720109477",
+                ),
+                Some("ubuntu"),
+            ),
+            (
+                image(
+                    "al2023-ami-2023.3.20240219.0-kernel-6.1-x86_64",
+                    "137112412989",
+                ),
+                Some("ec2-user"),
+            ),
+            (
+                image("debian-12-amd64-20240201-1644", "136693071363"),
+                Some("admin"),
+            ),
+            (
+                image("CentOS Stream 9 x86_64 20240215", "125523088429"),
+                Some("centos"),
+            ),
+            (image("my-golden-image", "099720109477"), Some("ubuntu")),
+            (image("my-golden-image", "123456789012"), None),
+        ];
+
+        for (image, expected) in cases {
+            println!("name = {:?}", image.name());
+            pretty_assertions::assert_eq!(default_user(&image), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn list_and_delete_on_mock() {
+        let ec2 = MockEc2::with_instances(vec![
+            instance("i-a", "a", InstanceStateName::Running, &[("team", "ml")]),
+            instance("i-b", "b", InstanceStateName::Stopped, &[("team", "ml")]),
+            instance("i-c", "c", InstanceStateName::Running, &[("team", "web")]),
+            instance("i-d", "d", InstanceStateName::Terminated, &[("team", "ml")]),
+        ]);
+        let ids = |instances: &[Instance]| {
+            instances
+                .iter()
+                .filter_map(|i| i.instance_id())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        let (instances, health) = active_instances(&ec2).await.unwrap();
+        pretty_assertions::assert_eq!(ids(&instances), vec!["i-a", "i-b", "i-c"]);
+        let mut healthy: Vec<_> = health.keys().cloned().collect();
+        healthy.sort();
+        pretty_assertions::assert_eq!(healthy, vec!["i-a", "i-c"]);
+
+        let tags = ["team=ml".parse::<TagSelector>().unwrap()];
+        let chosen = tagged_instances(&ec2, vec![InstanceStateName::Running], &tags)
+            .await
+            .unwrap();
+        let chosen = ids_to_str(chosen);
+        pretty_assertions::assert_eq!(chosen, "i-a");
+
+        ec2.delete_instances(&chosen, false).await.unwrap();
+        let (instances, _) = active_instances(&ec2).await.unwrap();
+        pretty_assertions::assert_eq!(ids(&instances), vec!["i-b", "i-c"]);
+        pretty_assertions::assert_eq!(
+            ec2.calls().last().map(String::as_str),
+            Some("describe_instance_health i-b,i-c")
+        );
+    }
 }
@@ -0,0 +1,108 @@
+//! Post-create checks declared under `[verify]` in `korasi.toml`.
+//!
+//! Catches broken bootstrap scripts right after `Create`, rather than at
+//! first use of the instance.
+
+use std::time::Duration;
+
+use crate::{
+    config::VerifyConfig,
+    ec2::EC2Impl as EC2,
+    ssh::{ConnectOptions, Session},
+};
+
+/// Default time for an instance to pass its status checks.
+const READY_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+pub struct CheckResult {
+    pub name: String,
+    pub passed: bool,
+    /// Output of the check, shown when it fails.
+    pub detail: String,
+}
+
+/// Remote command that succeeds if something listens on TCP `port`.
+pub fn port_check_command(port: u16) -> String {
+    format!("ss -ltn | awk 'NR > 1 {{ print $4 }}' | grep -qE '[:.]{port}$'")
+}
+
+/// Waits for the startup script to finish, then runs every check.
+pub async fn run_checks(session: &Session, cfg: &VerifyConfig) -> anyhow::Result<Vec<CheckResult>> {
+    let mut checks = vec![(
+        "startup script finished".to_string(),
+        "if command -v cloud-init >/dev/null; then cloud-init status --wait; fi".to_string(),
+    )];
+    checks.extend(
+        cfg.commands
+            .iter()
+            .map(|c| (format!("`{c}` exits 0"), c.clone())),
+    );
+    checks.extend(
+        cfg.ports
+            .iter()
+            .map(|p| (format!("port {p} is listening"), port_check_command(*p))),
+    );
+
+    let mut results = vec![];
+    for (name, command) in checks {
+        tracing::info!("Running check: {name}");
+        let output = session.exec_output(&command).await?;
+        let mut detail = String::from_utf8_lossy(&output.stdout).to_string();
+        detail.push_str(&String::from_utf8_lossy(&output.stderr));
+        results.push(CheckResult {
+            name,
+            passed: output.code == 0,
+            detail: detail.trim_end().to_string(),
+        });
+    }
+
+    Ok(results)
+}
+
+pub fn print_report(results: &[CheckResult]) {
+    for r in results {
+        println!("[{}] {}", if r.passed { "PASS" } else { "FAIL" }, r.name);
+        if !r.passed && !r.detail.is_empty() {
+            for line in r.detail.lines() {
+                println!("    {line}");
+            }
+        }
+    }
+}
+
+/// Waits for a newly created instance and runs the configured checks on it.
+pub async fn verify_instance(
+    ec2: &EC2,
+    instance_id: &str,
+    user: &str,
+    ssh_key: &str,
+    connect: &ConnectOptions,
+    cfg: &VerifyConfig,
+) -> anyhow::Result<()> {
+    println!("Waiting for {instance_id} to pass status checks...");
+    let timeout = cfg
+        .timeout
+        .map(Duration::from_secs)
+        .unwrap_or(READY_TIMEOUT);
+    ec2.wait_for_instance_ready(instance_id, Some(timeout))
+        .await?;
+
+    let instance = ec2.get_instance(instance_id).await?;
+    let host = instance
+        .public_dns_name()
+        .filter(|h| !h.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("{instance_id} has no public DNS name to verify"))?;
+
+    let mut session = Session::connect(user, host.into(), ssh_key.into(), connect).await?;
+    let results = run_checks(&session, cfg).await?;
+    session.close().await?;
+
+    print_report(&results);
+    let failed = results.iter().filter(|r| !r.passed).count();
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} post-create checks failed.", results.len());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,505 @@
+//! SSH over a WebSocket relay, for networks that block outbound port 22
+//! but allow 443. Rather than the instance, sessions connect to the relay:
+//!
+//! ```text
+//! GET /path?host=<instance>&port=22 HTTP/1.1
+//! Upgrade: websocket
+//! ```
+//!
+//! which answers `101 Switching Protocols` once it has connected to that
+//! address, then pipes binary messages to and from it. The relay's address
+//! must be let in, eg. with `korasi ports open 22 --cidr <relay ip>/32`.
+//!
+//! `korasi relay deploy` provisions one: a small instance in the SSH
+//! security group running `RELAY_SCRIPT` on port 443. It only connects to
+//! private addresses on the SSH port, so it isn't an open proxy. The relay
+//! speaks plain `ws://`, the SSH stream inside is encrypted already.
+
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::TcpStream,
+};
+
+/// Appended to the handshake key, see RFC 6455.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// Buffer between the SSH session and the relay.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Largest frame accepted from the relay, which sends at most
+/// `BUFFER_SIZE` at once.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Name of the instance `korasi relay deploy` launches.
+pub const RELAY_NAME: &str = "korasi-relay";
+
+/// Tag marking the relay instance.
+pub const RELAY_TAG: &str = "korasi-relay";
+
+/// Port the provisioned relay listens on.
+pub const RELAY_PORT: u16 = 443;
+
+/// Instance type of the provisioned relay.
+pub const RELAY_INSTANCE_TYPE: &str = "t4g.nano";
+
+/// User data running a relay on `RELAY_PORT` that forwards to `@SSH_PORT@`
+/// of private addresses only.
+const RELAY_SCRIPT: &str = r#"#!/bin/bash
+set -euo pipefail
+cat > /usr/local/bin/korasi-relay <<'EOF'
+import asyncio, base64, hashlib, ipaddress, socket, struct, urllib.parse
+
+GUID = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11"
+SSH_PORT = @SSH_PORT@
+MAX_FRAME_SIZE = 16 * 1024 * 1024
+
+
+def unmask(data, mask):
+    key = (mask * (len(data) // 4 + 1))[: len(data)]
+    return (int.from_bytes(data, "big") ^ int.from_bytes(key, "big")).to_bytes(len(data), "big")
+
+
+def frame(opcode, payload):
+    n = len(payload)
+    if n < 126:
+        head = struct.pack(">BB", 0x80 | opcode, n)
+    elif n < 65536:
+        head = struct.pack(">BBH", 0x80 | opcode, 126, n)
+    else:
+        head = struct.pack(">BBQ", 0x80 | opcode, 127, n)
+    return head + payload
+
+
+async def client_to_instance(reader, writer, upstream):
+    while True:
+        head = await reader.readexactly(2)
+        opcode, n = head[0] & 0x0F, head[1] & 0x7F
+        if n == 126:
+            (n,) = struct.unpack(">H", await reader.readexactly(2))
+        elif n == 127:
+            (n,) = struct.unpack(">Q", await reader.readexactly(8))
+        if n > MAX_FRAME_SIZE:
+            return
+        mask = await reader.readexactly(4) if head[1] & 0x80 else b"\0\0\0\0"
+        payload = unmask(await reader.readexactly(n), mask)
+        if opcode in (0x0, 0x1, 0x2):
+            upstream.write(payload)
+            await upstream.drain()
+        elif opcode == 0x9:
+            writer.write(frame(0xA, payload))
+            await writer.drain()
+        elif opcode == 0x8:
+            return
+
+
+async def instance_to_client(upstream, writer):
+    while True:
+        data = await upstream.read(65536)
+        if not data:
+            writer.write(frame(0x8, b""))
+            await writer.drain()
+            return
+        writer.write(frame(0x2, data))
+        await writer.drain()
+
+
+async def handle(reader, writer):
+    try:
+        request = (await reader.readuntil(b"\r\n\r\n")).decode("latin-1").split("\r\n")
+        headers = {}
+        for line in request[1:]:
+            name, _, value = line.partition(":")
+            headers[name.strip().lower()] = value.strip()
+        query = urllib.parse.parse_qs(urllib.parse.urlsplit(request[0].split(" ")[1]).query)
+        host, port = query["host"][0], int(query["port"][0])
+        infos = await asyncio.get_running_loop().getaddrinfo(host, port, type=socket.SOCK_STREAM)
+        address = infos[0][4][0]
+        if port != SSH_PORT or not ipaddress.ip_address(address).is_private:
+            writer.write(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+            writer.close()
+            return
+        up_reader, up_writer = await asyncio.open_connection(address, port)
+    except Exception:
+        writer.write(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+        writer.close()
+        return
+    try:
+        key = headers["sec-websocket-key"].encode()
+        accept = base64.b64encode(hashlib.sha1(key + GUID).digest()).decode()
+        writer.write(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n"
+            f"Connection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n".encode()
+        )
+        tasks = [
+            asyncio.ensure_future(client_to_instance(reader, writer, up_writer)),
+            asyncio.ensure_future(instance_to_client(up_reader, writer)),
+        ]
+        _, pending = await asyncio.wait(tasks, return_when=asyncio.FIRST_COMPLETED)
+        for task in pending:
+            task.cancel()
+    finally:
+        up_writer.close()
+        writer.close()
+
+
+async def main():
+    server = await asyncio.start_server(handle, None, @RELAY_PORT@)
+    async with server:
+        await server.serve_forever()
+
+
+asyncio.run(main())
+EOF
+cat > /etc/systemd/system/korasi-relay.service <<'EOF'
+[Unit]
+Description=korasi WebSocket relay
+After=network-online.target
+
+[Service]
+ExecStart=/usr/bin/python3 /usr/local/bin/korasi-relay
+DynamicUser=yes
+AmbientCapabilities=CAP_NET_BIND_SERVICE
+Restart=always
+
+[Install]
+WantedBy=multi-user.target
+EOF
+systemctl daemon-reload
+systemctl enable --now korasi-relay.service
+"#;
+
+/// User data of the relay, forwarding to sshd on `ssh_port`.
+pub fn relay_user_data(ssh_port: u16) -> String {
+    RELAY_SCRIPT
+        .replace("@SSH_PORT@", &ssh_port.to_string())
+        .replace("@RELAY_PORT@", &RELAY_PORT.to_string())
+}
+
+/// `--relay` URL of the relay instance at `host`.
+pub fn relay_url(host: &str) -> String {
+    format!("ws://{host}:{RELAY_PORT}/ssh")
+}
+
+/// Where the relay listens, from a `wss://` or `ws://` URL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayUrl {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl RelayUrl {
+    pub fn parse(url: &str) -> anyhow::Result<RelayUrl> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("wss://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("ws://") {
+            (false, rest)
+        } else {
+            anyhow::bail!("Relay URL {url} should start with wss:// or ws://");
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse()?),
+            None => (authority, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() {
+            anyhow::bail!("Relay URL {url} has no host");
+        }
+        Ok(RelayUrl {
+            tls,
+            host: host.into(),
+            port,
+            path: path.into(),
+        })
+    }
+
+    /// Request target asking the relay for `host:port`.
+    fn target(&self, host: &str, port: u16) -> String {
+        let sep = if self.path.contains('?') { '&' } else { '?' };
+        format!("{}{sep}host={}&port={port}", self.path, query_escape(host))
+    }
+}
+
+/// Percent-encode `value` for a query string, keeping unreserved characters.
+fn query_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// `Sec-WebSocket-Accept` the relay must answer `key` with.
+pub fn accept_key(key: &str) -> String {
+    let digest = Sha1::digest(format!("{key}{GUID}").as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+/// Bytes from the OS RNG, for handshake keys and frame masks.
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).expect("the OS random number generator failed");
+    bytes
+}
+
+/// A final frame of `opcode`. Frames sent to the relay must be masked.
+pub fn encode_frame(opcode: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    frame.extend(mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+/// Splits what the relay sends into frames.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The next complete frame as `(opcode, payload)`, if any.
+    pub fn next_frame(&mut self) -> anyhow::Result<Option<(u8, Vec<u8>)>> {
+        let buf = &self.buf;
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let opcode = buf[0] & 0x0f;
+        let masked = buf[1] & 0x80 != 0;
+        let (len, mut at) = match buf[1] & 0x7f {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+            127 if buf.len() >= 10 => {
+                let len = u64::from_be_bytes(buf[2..10].try_into()?);
+                (usize::try_from(len)?, 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (len as usize, 2),
+        };
+        if len > MAX_FRAME_SIZE {
+            anyhow::bail!("Relay sent a frame of {len} bytes, more than {MAX_FRAME_SIZE}");
+        }
+        let mask = if masked {
+            let Some(mask) = buf.get(at..at + 4) else {
+                return Ok(None);
+            };
+            at += 4;
+            Some([mask[0], mask[1], mask[2], mask[3]])
+        } else {
+            None
+        };
+        if buf.len() < at + len {
+            return Ok(None);
+        }
+        let mut payload: Vec<u8> = self.buf.drain(..at + len).skip(at).collect();
+        if let Some(mask) = mask {
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= mask[i % 4];
+            }
+        }
+        Ok(Some((opcode, payload)))
+    }
+}
+
+/// Open a connection to `host:port` through the relay at `relay`. Bytes
+/// written to the returned stream reach `host:port`, and the other way.
+pub async fn connect(relay: &str, host: &str, port: u16) -> anyhow::Result<DuplexStream> {
+    let url = RelayUrl::parse(relay)?;
+    let tcp = TcpStream::connect((url.host.as_str(), url.port)).await?;
+    tcp.set_nodelay(true)?;
+    if url.tls {
+        let connector = tokio_native_tls::TlsConnector::from(
+            tokio_native_tls::native_tls::TlsConnector::new()?,
+        );
+        let stream = connector.connect(&url.host, tcp).await?;
+        tunnel(stream, &url, host, port).await
+    } else {
+        tunnel(tcp, &url, host, port).await
+    }
+}
+
+async fn tunnel<S>(
+    mut stream: S,
+    url: &RelayUrl,
+    host: &str,
+    port: u16,
+) -> anyhow::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let key = base64::engine::general_purpose::STANDARD.encode(random_bytes::<16>());
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        url.target(host, port),
+        url.host
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // sshd speaks first, so its banner may arrive along with the response.
+    let mut response = vec![];
+    let mut chunk = [0; 1024];
+    let end = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Relay {}:{} closed the connection", url.host, url.port);
+        }
+        response.extend_from_slice(&chunk[..n]);
+        if let Some(i) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&response[..end]).to_string();
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("101") {
+        anyhow::bail!("Relay refused to connect to {host}:{port}: {status}");
+    }
+    let accept = head.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("sec-websocket-accept")
+            .then(|| value.trim().to_string())
+    });
+    if accept != Some(accept_key(&key)) {
+        anyhow::bail!("Relay answered with a wrong Sec-WebSocket-Accept");
+    }
+
+    let mut decoder = Decoder::default();
+    decoder.push(&response[end..]);
+    let (local, remote) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move {
+        if let Err(err) = pump(stream, remote, decoder).await {
+            tracing::warn!("Relay connection closed: {err}");
+        }
+    });
+    Ok(local)
+}
+
+/// Move bytes between `local` and the relay until either side closes.
+async fn pump<S>(stream: S, local: DuplexStream, mut decoder: Decoder) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut relay_read, mut relay_write) = tokio::io::split(stream);
+    let (mut local_read, mut local_write) = tokio::io::split(local);
+    let mut up = vec![0; BUFFER_SIZE];
+    let mut down = vec![0; BUFFER_SIZE];
+    loop {
+        while let Some((opcode, payload)) = decoder.next_frame()? {
+            match opcode {
+                BINARY | TEXT | CONTINUATION => local_write.write_all(&payload).await?,
+                PING => {
+                    relay_write
+                        .write_all(&encode_frame(PONG, &payload, random_bytes()))
+                        .await?
+                }
+                CLOSE => return Ok(()),
+                _ => {}
+            }
+        }
+        tokio::select! {
+            n = local_read.read(&mut up) => {
+                let n = n?;
+                if n == 0 {
+                    relay_write.write_all(&encode_frame(CLOSE, &[], random_bytes())).await?;
+                    return Ok(());
+                }
+                relay_write.write_all(&encode_frame(BINARY, &up[..n], random_bytes())).await?;
+            }
+            n = relay_read.read(&mut down) => {
+                let n = n?;
+                if n == 0 {
+                    return Ok(());
+                }
+                decoder.push(&down[..n]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept_key, encode_frame, Decoder, RelayUrl, BINARY, MAX_FRAME_SIZE};
+
+    #[test]
+    fn websocket_frames() {
+        // Example of RFC 6455.
+        pretty_assertions::assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let mut decoder = Decoder::default();
+        for len in [5, 200, 70_000] {
+            println!("len = {len}");
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let frame = encode_frame(BINARY, &payload, [1, 2, 3, 4]);
+            let (head, tail) = frame.split_at(frame.len() / 2);
+            decoder.push(head);
+            pretty_assertions::assert_eq!(decoder.next_frame().unwrap(), None);
+            decoder.push(tail);
+            pretty_assertions::assert_eq!(decoder.next_frame().unwrap(), Some((BINARY, payload)));
+        }
+
+        // A hostile length is refused before anything is buffered for it.
+        for len in [MAX_FRAME_SIZE as u64 + 1, u64::MAX] {
+            println!("len = {len}");
+            let mut decoder = Decoder::default();
+            decoder.push(&[0x80 | BINARY, 127]);
+            decoder.push(&len.to_be_bytes());
+            assert!(decoder.next_frame().is_err());
+        }
+
+        let cases = [
+            (
+                "wss://relay.example.com/ssh",
+                Some((true, "relay.example.com", 443, "/ssh")),
+            ),
+            ("ws://localhost:8080", Some((false, "localhost", 8080, "/"))),
+            ("https://relay.example.com", None),
+        ];
+        for (url, expected) in cases {
+            println!("url = {url}");
+            let parsed = RelayUrl::parse(url)
+                .ok()
+                .map(|u| (u.tls, u.host, u.port, u.path));
+            let expected = expected
+                .map(|(tls, host, port, path)| (tls, host.to_string(), port, path.to_string()));
+            pretty_assertions::assert_eq!(parsed, expected);
+        }
+
+        let url = RelayUrl::parse("wss://relay.example.com/ssh?token=abc").unwrap();
+        pretty_assertions::assert_eq!(
+            url.target("fe80::1%eth0&x", 22),
+            "/ssh?token=abc&host=fe80%3A%3A1%25eth0%26x&port=22"
+        );
+    }
+}